@@ -1,8 +1,9 @@
 import http from "../http-common";
 
 class FaeryDataService {
-    getAll() {
-        return http.get("/faeries");
+    // Results come a page at a time; pass the previous response's X-Next-Cursor header to get the next one
+    getAll(cursor) {
+        return http.get("/faeries", { params: { cursor } });
     }
 
     get(id) {
@@ -25,9 +26,9 @@ class FaeryDataService {
         return http.delete(`/faeries`);
     }
 
-    // findByName(name) {
-    //     return http.get(`/faeries?name=${name}`);
-    // }
+    findByName(name, cursor) {
+        return http.get(`/faeries`, { params: { name, cursor } });
+    }
 }
 
 export default new FaeryDataService();
\ No newline at end of file
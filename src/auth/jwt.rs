@@ -1,12 +1,16 @@
+use std::convert::Infallible;
 use std::sync::Arc;
+use axum::async_trait;
 use axum::body::Body;
-use axum::extract::{Request, State};
+use axum::extract::{FromRequestParts, Request, State};
+use axum::http::request::Parts;
 use axum::Json;
 use axum::middleware::Next;
 use axum::response::IntoResponse;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use axum_extra::extract::cookie::CookieJar;
-use http::{header, StatusCode};
+use http::{header, HeaderMap, StatusCode};
 use base64::{engine::general_purpose, Engine as _};
 use uuid::Uuid;
 use crate::DrossManagerState;
@@ -16,6 +20,8 @@ use crate::prelude::*;
 pub struct JWTAuthMiddleware {
     pub user: PlayerData,
     pub access_token_uuid: uuid::Uuid,
+    // When the access token expires, in seconds
+    pub access_token_expires: i64,
 }
 
 #[derive(Debug, Serialize)]
@@ -33,25 +39,54 @@ impl From<jsonwebtoken::errors::Error> for JWTErrorResponse {
     }
 }
 
+// access_token finds the caller's JWT in the access_token cookie or a bearer Authorization header.
+fn access_token(cookie_jar: &CookieJar, headers: &HeaderMap) -> Option<String> {
+    cookie_jar.get("access_token")
+        .map(|cookie| cookie.value().to_string())
+        .or_else(|| {
+            headers
+                .get(header::AUTHORIZATION)
+                .and_then(|auth_header| auth_header.to_str().ok())
+                .and_then(|auth_value| auth_value.strip_prefix("Bearer ").map(|token| token.to_string()))
+        })
+}
+
+// CallerRole identifies who is making a request so responses can be masked for them.
+// Unlike authenticate it never rejects: anonymous or invalid tokens are treated as a non-admin.
+#[derive(Debug, Clone, Default)]
+pub struct CallerRole {
+    #[allow(dead_code)]
+    pub player_id: Option<i64>,
+    pub is_admin: bool,
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<DrossManagerState>> for CallerRole {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, app_state: &Arc<DrossManagerState>) -> Result<Self, Self::Rejection> {
+        if let Some(auth) = parts.extensions.get::<JWTAuthMiddleware>() {
+            return Ok(CallerRole { player_id: auth.user.id, is_admin: auth.user.is_admin });
+        }
+        let cookie_jar = CookieJar::from_headers(&parts.headers);
+        let token_details = match access_token(&cookie_jar, &parts.headers)
+            .and_then(|token| verify_jwt_token(app_state.jwt_key_pair.public_key.to_owned(), &token).ok()) {
+            Some(token_details) => token_details,
+            None => return Ok(CallerRole::default()),
+        };
+        match app_state.player_repository.get(token_details.user_id).await {
+            Ok(player) if player.is_active() => Ok(CallerRole { player_id: player.id, is_admin: player.is_admin }),
+            _ => Ok(CallerRole::default()),
+        }
+    }
+}
+
 pub async fn authenticate(
     cookie_jar: CookieJar,
     State(app_state): State<Arc<DrossManagerState>>,
     mut req: Request<Body>,
     next: Next)  -> Result<impl IntoResponse, (StatusCode, Json<JWTErrorResponse>)> {
-    let access_token = cookie_jar.get("access_token")
-        .map(|cookie| cookie.value().to_string())
-        .or_else(|| {
-            req.headers()
-                .get(header::AUTHORIZATION)
-                .and_then(|auth_header| auth_header.to_str().ok())
-                .and_then(|auth_value| {
-                    if auth_value.starts_with("Bearer ") {
-                        Some(auth_value[7..].to_string())
-                    } else {
-                        None
-                    }
-                })
-        });
+    let access_token = access_token(&cookie_jar, req.headers());
 
     let access_token = access_token.ok_or_else(|| {
         let error_response = JWTErrorResponse {
@@ -80,53 +115,51 @@ pub async fn authenticate(
             (StatusCode::UNAUTHORIZED, Json(error_response))
         })?;
 
-    // let redis_token_user_id = redis_client
-    //     .get::<_, String>(access_token_uuid.clone().to_string())
-    //     .await
-    //     .map_err(|_| {
-    //         let error_response = ErrorResponse {
-    //             status: "error",
-    //             message: "Token is invalid or session has expired".to_string(),
-    //         };
-    //         (StatusCode::UNAUTHORIZED, Json(error_response))
-    //     })?;
-    //
-    // let user_id_uuid = uuid::Uuid::parse_str(&redis_token_user_id).map_err(|_| {
-    //     let error_response = ErrorResponse {
-    //         status: "fail",
-    //         message: "Token is invalid or session has expired".to_string(),
-    //     };
-    //     (StatusCode::UNAUTHORIZED, Json(error_response))
-    // })?;
-    //
-    // let user = sqlx::query_as!(User, "SELECT * FROM users WHERE id = $1", user_id_uuid)
-    //     .fetch_optional(&data.db)
-    //     .await
-    //     .map_err(|e| {
-    //         let error_response = ErrorResponse {
-    //             status: "fail",
-    //             message: format!("Error fetching user from database: {}", e),
-    //         };
-    //         (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response))
-    //     })?;
-    //
-    // let user = user.ok_or_else(|| {
-    //     let error_response = ErrorResponse {
-    //         status: "fail",
-    //         message: "The user belonging to this token no longer exists".to_string(),
-    //     };
-    //     (StatusCode::UNAUTHORIZED, Json(error_response))
-    // })?;
-    //
-    // req.extensions_mut().insert(JWTAuthMiddleware {
-    //     user,
-    //     access_token_uuid,
-    // });
+    let user = app_state.player_repository.get(access_token_details.user_id).await.map_err(|_| {
+        let error_response = JWTErrorResponse {
+            status: "fail",
+            message: "The user belonging to this token no longer exists".to_string(),
+        };
+        (StatusCode::UNAUTHORIZED, Json(error_response))
+    })?;
+
+    if !user.is_active() {
+        let error_response = JWTErrorResponse {
+            status: "fail",
+            message: "This account hasn't been activated yet".to_string(),
+        };
+        return Err((StatusCode::FORBIDDEN, Json(error_response)));
+    }
+
+    req.extensions_mut().insert(JWTAuthMiddleware {
+        user: user.into(),
+        access_token_uuid,
+        access_token_expires: access_token_details.expires_in.unwrap_or_default(),
+    });
     Ok(next.run(req).await)
 
 }
+// require_admin rejects requests from players who aren't admins.
+// It must run after authenticate, which provides the JWTAuthMiddleware extension.
+pub async fn require_admin(
+    req: Request<Body>,
+    next: Next) -> Result<impl IntoResponse, (StatusCode, Json<JWTErrorResponse>)> {
+    let is_admin = req.extensions()
+        .get::<JWTAuthMiddleware>()
+        .map(|auth| auth.user.is_admin)
+        .unwrap_or(false);
+    if !is_admin {
+        let error_response = JWTErrorResponse {
+            status: "fail",
+            message: "You must be an admin to do that".to_string(),
+        };
+        return Err((StatusCode::FORBIDDEN, Json(error_response)));
+    }
+    Ok(next.run(req).await)
+}
 //
-fn generate_jwt_token(user_id: i64, ttl: i64, private_key: String) -> Result<TokenDetails, JWTErrorResponse> {
+#[allow(dead_code)]
+pub(crate) fn generate_jwt_token(user_id: i64, ttl: i64, private_key: String) -> Result<TokenDetails, JWTErrorResponse> {
     let bytes_private_key = general_purpose::STANDARD.decode(private_key).unwrap();
     let decoded_private_key = String::from_utf8(bytes_private_key).unwrap();
     let now = chrono::Utc::now();
@@ -156,6 +189,32 @@ fn generate_jwt_token(user_id: i64, ttl: i64, private_key: String) -> Result<Tok
     token_details.token = Some(token);
     Ok(token_details)
 }
+// sign_claims signs arbitrary claims with the service's private key (RS256), so anyone holding
+// the public key can check them offline.
+pub fn sign_claims<T: Serialize>(claims: &T, private_key: String) -> Result<String, JWTErrorResponse> {
+    let bytes_private_key = general_purpose::STANDARD.decode(private_key).unwrap();
+    let decoded_private_key = String::from_utf8(bytes_private_key).unwrap();
+    let header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256);
+    Ok(jsonwebtoken::encode(
+        &header,
+        claims,
+        &jsonwebtoken::EncodingKey::from_rsa_pem(decoded_private_key.as_bytes())?,
+    )?)
+}
+
+// verify_claims checks claims signed with sign_claims and returns them. Expired claims are rejected.
+pub fn verify_claims<T: DeserializeOwned>(token: &str, public_key: String) -> Result<T, jsonwebtoken::errors::Error> {
+    let bytes_public_key = general_purpose::STANDARD.decode(public_key).unwrap();
+    let decoded_public_key = String::from_utf8(bytes_public_key).unwrap();
+    let validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::RS256);
+    let decoded = jsonwebtoken::decode::<T>(
+        token,
+        &jsonwebtoken::DecodingKey::from_rsa_pem(decoded_public_key.as_bytes())?,
+        &validation,
+    )?;
+    Ok(decoded.claims)
+}
+
 pub fn verify_jwt_token(
     public_key: String,
     token: &str,
@@ -178,7 +237,7 @@ pub fn verify_jwt_token(
         token: None,
         token_uuid,
         user_id,
-        expires_in: None,
+        expires_in: Some(decoded.claims.exp),
     })
 }
 #[derive(Debug, Serialize, Deserialize)]
@@ -1 +1 @@
-mod jwt;
\ No newline at end of file
+pub mod jwt;
\ No newline at end of file
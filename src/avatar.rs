@@ -0,0 +1,191 @@
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use image::{DynamicImage, ImageFormat, ImageReader, Limits};
+
+// Uploaded avatars are scaled down to fit within this many pixels
+const MAX_DIMENSION: u32 = 1024;
+const THUMBNAIL_DIMENSION: u32 = 128;
+// Anything bigger than this is refused before decoding
+const MAX_SOURCE_DIMENSION: u32 = 8192;
+
+#[derive(Debug, PartialEq)]
+pub enum AvatarError {
+    TooLarge,
+    UnsupportedType,
+    NotFound,
+    Storage,
+}
+
+impl From<std::io::Error> for AvatarError {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::NotFound => AvatarError::NotFound,
+            _ => {
+                log::error!("Avatar storage error: {:?}", err);
+                AvatarError::Storage
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AvatarSize {
+    Full,
+    Thumbnail,
+}
+
+// AvatarStore keeps faery portraits on the local disk. Every upload is re-encoded as PNG,
+// which also strips any metadata the original carried, and gets a small thumbnail.
+pub struct AvatarStore {
+    root: PathBuf,
+    max_bytes: usize,
+}
+
+impl AvatarStore {
+    pub fn new(root: impl Into<PathBuf>, max_bytes: usize) -> AvatarStore {
+        AvatarStore {
+            root: root.into(),
+            max_bytes,
+        }
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    pub fn path(&self, faery_id: i64, size: AvatarSize) -> PathBuf {
+        match size {
+            AvatarSize::Full => self.root.join(format!("{}.png", faery_id)),
+            AvatarSize::Thumbnail => self.root.join(format!("{}.thumb.png", faery_id)),
+        }
+    }
+
+    // save checks and converts an upload, then writes the avatar and its thumbnail.
+    pub async fn save(&self, faery_id: i64, bytes: Vec<u8>) -> Result<(), AvatarError> {
+        if bytes.len() > self.max_bytes {
+            return Err(AvatarError::TooLarge);
+        }
+        let (full, thumbnail) = tokio::task::spawn_blocking(move || render(&bytes))
+            .await
+            .map_err(|_| AvatarError::Storage)??;
+        tokio::fs::create_dir_all(&self.root).await?;
+        write_atomic(&self.path(faery_id, AvatarSize::Full), &full).await?;
+        write_atomic(&self.path(faery_id, AvatarSize::Thumbnail), &thumbnail).await?;
+        Ok(())
+    }
+
+    pub async fn load(&self, faery_id: i64, size: AvatarSize) -> Result<(Vec<u8>, std::fs::Metadata), AvatarError> {
+        let path = self.path(faery_id, size);
+        let metadata = tokio::fs::metadata(&path).await?;
+        Ok((tokio::fs::read(&path).await?, metadata))
+    }
+
+    // remove deletes a faery's avatar files. Missing files aren't an error.
+    pub async fn remove(&self, faery_id: i64) -> Result<bool, AvatarError> {
+        let mut removed = false;
+        for size in [AvatarSize::Full, AvatarSize::Thumbnail] {
+            match tokio::fs::remove_file(self.path(faery_id, size)).await {
+                Ok(_) => removed = true,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {},
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(removed)
+    }
+
+    // merge hands the source's avatar to the target when the target has none of its own, and
+    // otherwise removes it, so a merged-away faery never leaves files behind.
+    pub async fn merge(&self, source_id: i64, target_id: i64) -> Result<(), AvatarError> {
+        if tokio::fs::try_exists(self.path(target_id, AvatarSize::Full)).await? {
+            self.remove(source_id).await?;
+            return Ok(());
+        }
+        for size in [AvatarSize::Full, AvatarSize::Thumbnail] {
+            match tokio::fs::rename(self.path(source_id, size), self.path(target_id, size)).await {
+                Ok(_) => {},
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {},
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+// render decodes an upload and returns the PNG-encoded avatar and thumbnail.
+fn render(bytes: &[u8]) -> Result<(Vec<u8>, Vec<u8>), AvatarError> {
+    let mut reader = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|_| AvatarError::UnsupportedType)?;
+    match reader.format() {
+        Some(ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::WebP) => {},
+        _ => return Err(AvatarError::UnsupportedType),
+    }
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_SOURCE_DIMENSION);
+    limits.max_image_height = Some(MAX_SOURCE_DIMENSION);
+    reader.limits(limits);
+    let image = reader.decode().map_err(|_| AvatarError::UnsupportedType)?;
+
+    let full = if image.width() > MAX_DIMENSION || image.height() > MAX_DIMENSION {
+        image.thumbnail(MAX_DIMENSION, MAX_DIMENSION)
+    } else {
+        image
+    };
+    let thumbnail = full.thumbnail(THUMBNAIL_DIMENSION, THUMBNAIL_DIMENSION);
+    Ok((encode(&full)?, encode(&thumbnail)?))
+}
+
+fn encode(image: &DynamicImage) -> Result<Vec<u8>, AvatarError> {
+    let mut bytes = Cursor::new(Vec::new());
+    image.write_to(&mut bytes, ImageFormat::Png).map_err(|_| AvatarError::Storage)?;
+    Ok(bytes.into_inner())
+}
+
+// write_atomic writes to a temporary file first, so readers never see half an image.
+async fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), AvatarError> {
+    let temporary = path.with_extension("tmp");
+    tokio::fs::write(&temporary, bytes).await?;
+    tokio::fs::rename(&temporary, path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageFormat, RgbImage};
+    use super::{encode, render, AvatarError, AvatarSize, AvatarStore};
+
+    #[test]
+    fn test_render_avatar() {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(2000, 500));
+        let (full, thumbnail) = render(&encode(&image).unwrap()).unwrap();
+        let full = image::load_from_memory_with_format(&full, ImageFormat::Png).unwrap();
+        let thumbnail = image::load_from_memory_with_format(&thumbnail, ImageFormat::Png).unwrap();
+        assert_eq!((full.width(), full.height()), (1024, 256));
+        assert_eq!((thumbnail.width(), thumbnail.height()), (128, 32));
+    }
+
+    #[test]
+    fn test_render_rejects_other_files() {
+        assert_eq!(render(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>").err(), Some(AvatarError::UnsupportedType));
+        assert_eq!(render(b"not an image").err(), Some(AvatarError::UnsupportedType));
+    }
+
+    #[tokio::test]
+    async fn test_merge_avatars() {
+        let store = AvatarStore::new(std::env::temp_dir().join(format!("dross-manager-merge-avatars-{}", std::process::id())), 1024 * 1024);
+        let png = encode(&DynamicImage::ImageRgb8(RgbImage::new(4, 4))).unwrap();
+        store.save(1, png.clone()).await.unwrap();
+
+        // A target without an avatar takes the source's
+        store.merge(1, 2).await.unwrap();
+        assert_eq!(store.load(1, AvatarSize::Full).await.err(), Some(AvatarError::NotFound));
+        assert!(store.load(2, AvatarSize::Thumbnail).await.is_ok());
+
+        // One with its own keeps it, and the source's is dropped
+        store.save(3, png).await.unwrap();
+        store.merge(3, 2).await.unwrap();
+        assert_eq!(store.load(3, AvatarSize::Full).await.err(), Some(AvatarError::NotFound));
+        assert!(store.load(2, AvatarSize::Full).await.is_ok());
+        store.remove(2).await.unwrap();
+    }
+}
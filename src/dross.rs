@@ -1,4 +1,9 @@
+use crate::DrossManagerState;
+use crate::live::publish_entry;
+use crate::messages::send_receipt;
+use crate::repository::{RepositoryError, RepositoryResult};
 use crate::repository::faery::Model;
+use crate::repository::ledger::{Entry, EntryKind};
 
 // transfer_dross takes a sender and a receiver and an amount of dross to transfer.
 // It returns a Result that is Ok(()) if the transfer was successful and Err(()) if it was not.
@@ -14,9 +19,47 @@ pub fn transfer_dross(sender: &mut Model, receiver: &mut Model, amount: u32) ->
     }
 }
 
+// announce_entry queues a receipt for the faery's owner and pushes the change to their open streams.
+// The balance has changed either way, so a failed receipt or live update is only logged.
+pub async fn announce_entry(state: &DrossManagerState, entry: &Entry, counterparty: Option<&str>) {
+    if let Err(err) = send_receipt(state, entry, counterparty).await {
+        log::error!("Error queueing a receipt for ledger entry {:?}: {:?}", entry.id, err);
+    }
+    if let Err(err) = publish_entry(state, entry).await {
+        log::error!("Error publishing ledger entry {:?}: {:?}", entry.id, err);
+    }
+}
+
+// adjust_balance applies a signed change to a faery's dross and records it in the ledger, both or neither.
+// Every balance change should pass through here so the ledger stays the faery's history.
+pub async fn adjust_balance(
+    state: &DrossManagerState,
+    faery_id: i64,
+    amount: i64,
+    kind: EntryKind,
+    memo: String
+) -> RepositoryResult<Entry> {
+    adjust_balance_with(state, faery_id, amount, kind, memo, None).await
+}
+
+// adjust_balance_with is adjust_balance with the name of whoever is on the other side.
+pub async fn adjust_balance_with(
+    state: &DrossManagerState,
+    faery_id: i64,
+    amount: i64,
+    kind: EntryKind,
+    memo: String,
+    counterparty: Option<&str>
+) -> RepositoryResult<Entry> {
+    let entry = state.ledger_repository.adjust(Entry::new(faery_id, amount, 0, kind, memo)).await?;
+    announce_entry(state, &entry, counterparty).await;
+    Ok(entry)
+}
+
 pub trait DrossHolder {
     fn increment_dross(&mut self, amount: u32) -> DrossResult;
     fn decrement_dross(&mut self, amount: u32) -> DrossResult;
+    #[allow(dead_code)]
     fn dross(&self) -> DrossResult;
 }
 
@@ -30,3 +73,71 @@ pub enum DrossError {
     InvalidIncrement,
     InvalidDecrement,
 }
+
+impl From<DrossError> for RepositoryError {
+    fn from(_: DrossError) -> Self {
+        RepositoryError::InvalidModel
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repository::Repository;
+    use crate::repository::faery::Model;
+    use crate::repository::ledger::{Entry, EntryKind};
+    use crate::repository::notification::NotificationQuery;
+    use crate::repository::preference::{Delivery, NotificationPreferences};
+    use crate::testing;
+    use super::{adjust_balance, adjust_balance_with, announce_entry};
+
+    #[tokio::test]
+    async fn test_ledger_writes_queue_receipts() {
+        let state = testing::state().await;
+        let player_id = testing::create_player(&state, "wendy@example.com", false).await;
+        let faery_id = state.faery_repository.create(Some(Model::new("Tink".to_string(), "wendy@example.com".to_string(), false, 0, None))).await.unwrap();
+        let stray_id = state.faery_repository.create(Some(Model::new("Stray".to_string(), "nobody@example.com".to_string(), false, 0, None))).await.unwrap();
+
+        adjust_balance_with(&state, faery_id, 5, EntryKind::Transfer, "Thimbles".to_string(), Some("Peter")).await.unwrap();
+        let outbox = state.email_repository.outbox(None).await.unwrap();
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox[0].recipient, "wendy@example.com");
+        assert!(outbox[0].body.contains("Tink's balance changed by +5 dross."));
+        assert!(outbox[0].body.contains("With: Peter"));
+        assert!(outbox[0].body.contains("New balance: 5"));
+        assert!(outbox[0].unsubscribe_url.is_some());
+        assert_eq!(state.notification_repository.for_player(player_id, &NotificationQuery::default()).await.unwrap().unread, 1);
+
+        // Faeries nobody has claimed still get their entry, just no receipt
+        adjust_balance_with(&state, stray_id, 5, EntryKind::Grant, "Found".to_string(), None).await.unwrap();
+        assert_eq!(state.ledger_repository.get_for_faery(stray_id).await.unwrap().len(), 1);
+        assert_eq!(state.email_repository.outbox(None).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_receipts_follow_preferences() {
+        let state = testing::state().await;
+        let player_id = testing::create_player(&state, "wendy@example.com", false).await;
+        let faery_id = state.faery_repository.create(Some(Model::new("Tink".to_string(), "wendy@example.com".to_string(), false, 0, None))).await.unwrap();
+
+        let preferences = NotificationPreferences { transactions: Delivery::Off, ..NotificationPreferences::default() };
+        state.preference_repository.store(player_id, &preferences).await.unwrap();
+        adjust_balance(&state, faery_id, 5, EntryKind::Grant, "Welcome".to_string()).await.unwrap();
+        assert!(state.email_repository.outbox(None).await.unwrap().is_empty());
+        assert!(state.preference_repository.held().await.unwrap().is_empty());
+
+        let preferences = NotificationPreferences { transactions: Delivery::DailyDigest, ..NotificationPreferences::default() };
+        state.preference_repository.store(player_id, &preferences).await.unwrap();
+        adjust_balance(&state, faery_id, 5, EntryKind::Grant, "Welcome".to_string()).await.unwrap();
+        assert!(state.email_repository.outbox(None).await.unwrap().is_empty());
+        assert_eq!(state.preference_repository.held().await.unwrap()[&player_id].len(), 1);
+
+        // Opening balances aren't news to anyone
+        state.preference_repository.store(player_id, &NotificationPreferences::default()).await.unwrap();
+        let opening = Entry::new(faery_id, 5, 5, EntryKind::Opening, "Opening balance".to_string());
+        let id = state.ledger_repository.save(opening.clone()).await.unwrap();
+        announce_entry(&state, &Entry { id: Some(id), ..opening }, None).await;
+        assert!(state.email_repository.outbox(None).await.unwrap().is_empty());
+        adjust_balance(&state, faery_id, 5, EntryKind::Grant, "Welcome".to_string()).await.unwrap();
+        assert_eq!(state.email_repository.outbox(None).await.unwrap().len(), 1);
+    }
+}
@@ -1,41 +1,134 @@
 use std::sync::Arc;
-use axum::extract::{Path, State};
-use axum::extract::rejection::JsonRejection;
-use axum::http::StatusCode;
+use axum::extract::{Path, Query, State};
+use axum::extract::rejection::{JsonRejection, QueryRejection};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
 use axum::Json;
-use axum::response::{IntoResponse, Response};
+use axum::response::{IntoResponse, Redirect, Response};
 use crate::DrossManagerState;
-use crate::repository::{Repository, RepositoryError};
-use crate::repository::faery::{CreateFaeryRequest, Model};
+use crate::auth::jwt::CallerRole;
+use crate::dross::adjust_balance;
+use crate::repository::{mask, Repository, RepositoryError};
+use crate::repository::faery::{CreateFaeryRequest, FaeryQuery, FaeryResponse, Model};
+use crate::repository::ledger::EntryKind;
 
-pub async fn list_faeries(State(state): State<Arc<DrossManagerState>>) -> Response {
-    log::info!("Getting all faeries");
-    let res = state.clone().faery_repository.get_all().await;
-    match res {
-        Ok(res) => {
-            log::info!("Got {} faeries", res.len());
-            (StatusCode::OK, Json(res)).into_response()
+pub mod achievement;
+pub mod archive;
+pub mod attribute;
+pub mod avatar;
+pub mod broadcast;
+pub mod group;
+pub mod live;
+pub mod ledger;
+pub mod mail;
+pub mod merge;
+pub mod notification;
+pub mod player;
+pub mod preference;
+pub mod privacy;
+pub mod registration;
+pub mod snapshot;
+pub mod template;
+pub mod stats;
+
+pub async fn list_faeries(
+    State(state): State<Arc<DrossManagerState>>,
+    role: CallerRole,
+    query: Result<Query<FaeryQuery>, QueryRejection>
+) -> Response {
+    let Query(query) = match query {
+        Ok(query) => query,
+        Err(err) => {
+            log::error!("Error parsing faery query: {:?}", err);
+            return (StatusCode::BAD_REQUEST, Json(err.body_text())).into_response();
+        }
+    };
+    log::info!("Searching faeries: {:?}", query);
+    match state.faery_repository.search(&query).await {
+        Ok(page) => {
+            log::info!("Got {} of {} faeries", page.faeries.len(), page.total);
+            let mut headers = HeaderMap::new();
+            headers.insert("X-Total-Count", HeaderValue::from(page.total));
+            if let Some(cursor) = page.next_cursor.and_then(|cursor| HeaderValue::from_str(&cursor).ok()) {
+                headers.insert("X-Next-Cursor", cursor);
+            }
+            (StatusCode::OK, headers, Json(mask::<Model, _>(&page.faeries, role.is_admin))).into_response()
         },
+        Err(RepositoryError::InvalidModel) => (StatusCode::BAD_REQUEST, Json("Invalid cursor")).into_response(),
+        Err(err) => {
+            log::error!("Error searching faeries: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn get_faery_tags(State(state): State<Arc<DrossManagerState>>, Path(faery_id): Path<i64>) -> Response {
+    if let Err(err) = state.faery_repository.get(faery_id).await {
+        return (StatusCode::NOT_FOUND, Json(err)).into_response();
+    }
+    match state.faery_repository.tags_for(faery_id).await {
+        Ok(tags) => (StatusCode::OK, Json(tags)).into_response(),
         Err(err) => {
-            log::error!("Error getting all faeries: {:?}", err);
+            log::error!("Error getting tags for faery {}: {:?}", faery_id, err);
             (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
         }
     }
 }
 
-pub async fn get_faery(State(state): State<Arc<DrossManagerState>>, Path(faery_id): Path<i64>) -> Response {
+pub async fn set_faery_tags(
+    State(state): State<Arc<DrossManagerState>>,
+    Path(faery_id): Path<i64>,
+    payload: Result<Json<Vec<String>>, JsonRejection>
+) -> Response {
+    let tags = match payload {
+        Ok(Json(tags)) => tags,
+        Err(err) => {
+            log::error!("Error setting tags for faery {}: {:?}", faery_id, err);
+            let repo_error: RepositoryError = err.into();
+            return (StatusCode::BAD_REQUEST, Json(repo_error)).into_response();
+        }
+    };
+    if let Err(err) = state.faery_repository.get(faery_id).await {
+        return (StatusCode::NOT_FOUND, Json(err)).into_response();
+    }
+    match state.faery_repository.set_tags(faery_id, &tags).await {
+        Ok(_) => get_faery_tags(State(state), Path(faery_id)).await,
+        Err(err) => {
+            log::error!("Error setting tags for faery {}: {:?}", faery_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn get_faery(State(state): State<Arc<DrossManagerState>>, role: CallerRole, Path(faery_id): Path<i64>) -> Response {
     log::info!("Getting faery {}", faery_id);
     let res = state.clone().faery_repository.get(faery_id).await;
     match res {
         Ok(res) => {
             log::info!("Got faery {}", faery_id);
-            (StatusCode::OK, Json(res)).into_response()
+            let details = match state.achievement_repository.badges_for(faery_id).await {
+                Ok(badges) => state.attribute_repository.values_for(faery_id).await.map(|attributes| (badges, attributes)),
+                Err(err) => Err(err),
+            };
+            match details {
+                Ok((badges, attributes)) => {
+                    let response = FaeryResponse { faery: res, badges, attributes };
+                    (StatusCode::OK, Json(mask::<Model, _>(&response, role.is_admin))).into_response()
+                },
+                Err(err) => {
+                    log::error!("Error getting details for faery {}: {:?}", faery_id, err);
+                    (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+                }
+            }
         },
         Err(repo_err) => {
             log::error!("Error getting faery {}: {:?}", faery_id, repo_err);
             match repo_err {
                 crate::repository::RepositoryError::NotFound => {
-                    (StatusCode::NOT_FOUND, Json("Not Found")).into_response()
+                    // Faeries merged into another one resolve to the faery they were merged into
+                    match state.faery_repository.redirect_for(faery_id).await {
+                        Ok(Some(target_id)) => Redirect::permanent(&format!("/api/faeries/{}", target_id)).into_response(),
+                        _ => (StatusCode::NOT_FOUND, Json("Not Found")).into_response(),
+                    }
                 },
                 _ => {
                     (StatusCode::INTERNAL_SERVER_ERROR, Json("Internal Server Error")).into_response()
@@ -47,6 +140,7 @@ pub async fn get_faery(State(state): State<Arc<DrossManagerState>>, Path(faery_i
 
 pub async fn update_faery(
     State(state): State<Arc<DrossManagerState>>,
+    role: CallerRole,
     Path(faery_id): Path<i64>,
     payload: Result<Json<Model>, JsonRejection>
 ) -> Response {
@@ -57,12 +151,36 @@ pub async fn update_faery(
                 return (StatusCode::BAD_REQUEST, Json("ID mismatch")).into_response();
             }
             log::info!("Updating faery {}: {:?}", faery_id, payload);
-            match state.clone().faery_repository.save(payload.clone()).await {
-                Ok(_) => {
-                    (StatusCode::OK, Json(payload)).into_response()
+            let existing = match state.faery_repository.get(faery_id).await {
+                Ok(existing) => existing,
+                Err(err) => return (StatusCode::NOT_FOUND, Json(err)).into_response(),
+            };
+            // The leaderboard choice and archive state aren't edited here, so carry them over from the stored faery.
+            // Saving leaves the balance alone; an edited balance goes through the ledger as a change below.
+            let faery = Model {
+                leaderboard_opt_out: existing.leaderboard_opt_out,
+                deleted_at: existing.deleted_at,
+                ..payload.clone()
+            };
+            if let Err(err) = state.faery_repository.save(faery.clone()).await {
+                log::error!("Error updating faery {}: {:?}", faery_id, err);
+                return (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response();
+            }
+            let change = payload.dross as i64 - existing.dross as i64;
+            if change == 0 {
+                return (StatusCode::OK, Json(mask::<Model, _>(&Model { dross: existing.dross, ..faery }, role.is_admin))).into_response();
+            }
+            let kind = if change > 0 { EntryKind::Grant } else { EntryKind::Spend };
+            match adjust_balance(&state, faery_id, change, kind, "Balance edited".to_string()).await {
+                Ok(entry) => {
+                    if let Err(err) = achievement::award_achievements(&state, faery_id).await {
+                        log::error!("Error awarding achievements to faery {}: {:?}", faery_id, err);
+                    }
+                    let faery = Model { dross: entry.balance as u32, ..faery };
+                    (StatusCode::OK, Json(mask::<Model, _>(&faery, role.is_admin))).into_response()
                 },
                 Err(err) => {
-                    log::error!("Error updating faery {}: {:?}", faery_id, err);
+                    log::error!("Error recording ledger entry for faery {}: {:?}", faery_id, err);
                     (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
                 }
             }
@@ -70,13 +188,14 @@ pub async fn update_faery(
         Err(err) => {
             log::error!("Error updating faery {}: {:?}", faery_id, err);
             let repo_error: RepositoryError = err.into();
-            return (StatusCode::BAD_REQUEST, Json(repo_error)).into_response();
+            (StatusCode::BAD_REQUEST, Json(repo_error)).into_response()
         }
     }
 }
 
 pub async fn create_faery(
     State(state): State<Arc<DrossManagerState>>,
+    role: CallerRole,
     payload: Result<Json<CreateFaeryRequest>, JsonRejection>
 ) -> Response {
     match payload {
@@ -85,7 +204,7 @@ pub async fn create_faery(
             let faery: Model = payload.into();
             match state.clone().faery_repository.create(Some(faery.clone())).await {
                 Ok(_) => {
-                    (StatusCode::CREATED, Json(faery)).into_response()
+                    (StatusCode::CREATED, Json(mask::<Model, _>(&faery, role.is_admin))).into_response()
                 },
                 Err(err) => {
                     log::error!("Error creating faery: {:?}", err);
@@ -96,7 +215,7 @@ pub async fn create_faery(
         Err(err) => {
             log::error!("Error creating faery: {:?}", err);
             let repo_error: RepositoryError = err.into();
-            return (StatusCode::BAD_REQUEST, Json(repo_error)).into_response();
+            (StatusCode::BAD_REQUEST, Json(repo_error)).into_response()
         }
     }
 
@@ -0,0 +1,154 @@
+use std::sync::Arc;
+use axum::extract::{Path, State};
+use axum::extract::rejection::JsonRejection;
+use axum::http::StatusCode;
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use crate::DrossManagerState;
+use crate::auth::jwt::CallerRole;
+use crate::dross::adjust_balance;
+use crate::repository::{mask, Repository, RepositoryError, RepositoryResult};
+use crate::repository::achievement::{Achievement, Badge, GrantBadgeRequest};
+use crate::repository::ledger::EntryKind;
+
+// award_achievements checks every achievement the faery hasn't earned yet against its ledger
+// and awards the ones whose criteria are now met. Call it after any ledger change.
+pub(crate) async fn award_achievements(state: &DrossManagerState, faery_id: i64) -> RepositoryResult<Vec<Badge>> {
+    let summary = state.ledger_repository.summary(faery_id).await?;
+    let mut awarded = Vec::new();
+    for achievement in state.achievement_repository.unearned(faery_id).await? {
+        if achievement.criteria.is_met(&summary) {
+            awarded.push(grant(state, faery_id, &achievement, false).await?);
+        }
+    }
+    Ok(awarded)
+}
+
+async fn grant(state: &DrossManagerState, faery_id: i64, achievement: &Achievement, manual: bool) -> RepositoryResult<Badge> {
+    let achievement_id = achievement.id.ok_or(RepositoryError::InvalidModel)?;
+    state.achievement_repository.award(faery_id, achievement_id, manual).await?;
+    log::info!("Awarded achievement {} to faery {}", achievement_id, faery_id);
+    if achievement.reward > 0 {
+        adjust_balance(
+            state,
+            faery_id,
+            achievement.reward as i64,
+            EntryKind::Reward,
+            format!("Achievement: {}", achievement.name)
+        ).await?;
+    }
+    Ok(Badge {
+        achievement_id,
+        name: achievement.name.clone(),
+        description: achievement.description.clone(),
+        awarded_at: chrono::Utc::now().timestamp_millis(),
+        manual,
+    })
+}
+
+pub async fn list_achievements(State(state): State<Arc<DrossManagerState>>, role: CallerRole) -> Response {
+    match state.achievement_repository.get_all().await {
+        Ok(res) => (StatusCode::OK, Json(mask::<Achievement, _>(&res, role.is_admin))).into_response(),
+        Err(err) => {
+            log::error!("Error getting all achievements: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn create_achievement(
+    State(state): State<Arc<DrossManagerState>>,
+    payload: Result<Json<Achievement>, JsonRejection>
+) -> Response {
+    match payload {
+        Ok(Json(payload)) => {
+            log::info!("Creating achievement: {:?}", payload);
+            let achievement = Achievement { id: None, ..payload };
+            match state.achievement_repository.create(Some(achievement.clone())).await {
+                Ok(id) => {
+                    (StatusCode::CREATED, Json(Achievement { id: Some(id), ..achievement })).into_response()
+                },
+                Err(err) => {
+                    log::error!("Error creating achievement: {:?}", err);
+                    (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+                }
+            }
+        },
+        Err(err) => {
+            log::error!("Error creating achievement: {:?}", err);
+            let repo_error: RepositoryError = err.into();
+            (StatusCode::BAD_REQUEST, Json(repo_error)).into_response()
+        }
+    }
+}
+
+pub async fn update_achievement(
+    State(state): State<Arc<DrossManagerState>>,
+    Path(achievement_id): Path<i64>,
+    payload: Result<Json<Achievement>, JsonRejection>
+) -> Response {
+    match payload {
+        Ok(Json(payload)) => {
+            if payload.id != Some(achievement_id) {
+                log::error!("Error updating achievement {}: ID mismatch", achievement_id);
+                return (StatusCode::BAD_REQUEST, Json("ID mismatch")).into_response();
+            }
+            match state.achievement_repository.save(payload.clone()).await {
+                Ok(_) => (StatusCode::OK, Json(payload)).into_response(),
+                Err(err) => {
+                    log::error!("Error updating achievement {}: {:?}", achievement_id, err);
+                    (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+                }
+            }
+        },
+        Err(err) => {
+            log::error!("Error updating achievement {}: {:?}", achievement_id, err);
+            let repo_error: RepositoryError = err.into();
+            (StatusCode::BAD_REQUEST, Json(repo_error)).into_response()
+        }
+    }
+}
+
+pub async fn delete_achievement(State(state): State<Arc<DrossManagerState>>, Path(achievement_id): Path<i64>) -> Response {
+    log::info!("Deleting achievement {}", achievement_id);
+    match state.achievement_repository.delete(achievement_id).await {
+        Ok(_) => (StatusCode::NO_CONTENT, Json("")).into_response(),
+        Err(err) => {
+            log::error!("Error deleting achievement {}: {:?}", achievement_id, err);
+            (StatusCode::NOT_FOUND, Json(err)).into_response()
+        }
+    }
+}
+
+// grant_badge lets an admin award any achievement by hand, including ones with manual criteria.
+pub async fn grant_badge(
+    State(state): State<Arc<DrossManagerState>>,
+    Path(faery_id): Path<i64>,
+    payload: Result<Json<GrantBadgeRequest>, JsonRejection>
+) -> Response {
+    let request = match payload {
+        Ok(Json(request)) => request,
+        Err(err) => {
+            log::error!("Error granting badge to faery {}: {:?}", faery_id, err);
+            let repo_error: RepositoryError = err.into();
+            return (StatusCode::BAD_REQUEST, Json(repo_error)).into_response();
+        }
+    };
+    if let Err(err) = state.faery_repository.get(faery_id).await {
+        return (StatusCode::NOT_FOUND, Json(err)).into_response();
+    }
+    let achievement = match state.achievement_repository.get(request.achievement_id).await {
+        Ok(achievement) => achievement,
+        Err(err) => return (StatusCode::NOT_FOUND, Json(err)).into_response(),
+    };
+    match grant(&state, faery_id, &achievement, true).await {
+        Ok(badge) => (StatusCode::CREATED, Json(badge)).into_response(),
+        Err(RepositoryError::AlreadyExists) => {
+            (StatusCode::CONFLICT, Json(RepositoryError::AlreadyExists)).into_response()
+        },
+        Err(err) => {
+            log::error!("Error granting badge to faery {}: {:?}", faery_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
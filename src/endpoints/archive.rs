@@ -0,0 +1,200 @@
+use std::sync::Arc;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use crate::DrossManagerState;
+use crate::repository::{mask, Repository};
+use crate::repository::faery::Model as Faery;
+use crate::repository::player::{Model as Player, PlayerResponse};
+
+// PurgeQuery guards permanent deletion: the caller has to repeat the record's id as `confirm`.
+#[derive(Debug, Deserialize)]
+pub struct PurgeQuery {
+    pub confirm: Option<i64>,
+}
+
+impl PurgeQuery {
+    fn confirms(&self, id: i64) -> bool {
+        self.confirm == Some(id)
+    }
+}
+
+fn unconfirmed(id: i64) -> Response {
+    (StatusCode::PRECONDITION_REQUIRED, Json(format!("Purging is permanent; repeat the request with ?confirm={}", id))).into_response()
+}
+
+// Mark: Faeries
+
+pub async fn list_archived_faeries(State(state): State<Arc<DrossManagerState>>) -> Response {
+    match state.faery_repository.archived().await {
+        Ok(faeries) => (StatusCode::OK, Json(mask::<Faery, _>(&faeries, true))).into_response(),
+        Err(err) => {
+            log::error!("Error getting archived faeries: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn restore_faery(State(state): State<Arc<DrossManagerState>>, Path(faery_id): Path<i64>) -> Response {
+    log::info!("Restoring faery {}", faery_id);
+    match state.faery_repository.restore(faery_id).await {
+        Ok(_) => match state.faery_repository.get(faery_id).await {
+            Ok(faery) => (StatusCode::OK, Json(mask::<Faery, _>(&faery, true))).into_response(),
+            Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response(),
+        },
+        Err(err) => {
+            log::error!("Error restoring faery {}: {:?}", faery_id, err);
+            (StatusCode::NOT_FOUND, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn purge_faery(
+    State(state): State<Arc<DrossManagerState>>,
+    Path(faery_id): Path<i64>,
+    Query(query): Query<PurgeQuery>
+) -> Response {
+    if !query.confirms(faery_id) {
+        return unconfirmed(faery_id);
+    }
+    log::info!("Purging faery {}", faery_id);
+    match state.faery_repository.purge(faery_id).await {
+        Ok(_) => {
+            if let Err(err) = state.avatar_store.remove(faery_id).await {
+                log::error!("Error removing avatar of purged faery {}: {:?}", faery_id, err);
+            }
+            (StatusCode::NO_CONTENT, Json("")).into_response()
+        },
+        Err(err) => {
+            log::error!("Error purging faery {}: {:?}", faery_id, err);
+            (StatusCode::NOT_FOUND, Json(err)).into_response()
+        }
+    }
+}
+
+// Mark: Players
+
+pub async fn list_archived_players(State(state): State<Arc<DrossManagerState>>) -> Response {
+    match state.player_repository.archived().await {
+        Ok(players) => {
+            let players: Vec<PlayerResponse> = players.into_iter().map(PlayerResponse::from).collect();
+            (StatusCode::OK, Json(mask::<Player, _>(&players, true))).into_response()
+        },
+        Err(err) => {
+            log::error!("Error getting archived players: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn restore_player(State(state): State<Arc<DrossManagerState>>, Path(player_id): Path<i64>) -> Response {
+    log::info!("Restoring player {}", player_id);
+    match state.player_repository.restore(player_id).await {
+        Ok(_) => match state.player_repository.get(player_id).await {
+            Ok(player) => (StatusCode::OK, Json(mask::<Player, _>(&PlayerResponse::from(player), true))).into_response(),
+            Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response(),
+        },
+        Err(err) => {
+            log::error!("Error restoring player {}: {:?}", player_id, err);
+            (StatusCode::NOT_FOUND, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn purge_player(
+    State(state): State<Arc<DrossManagerState>>,
+    Path(player_id): Path<i64>,
+    Query(query): Query<PurgeQuery>
+) -> Response {
+    if !query.confirms(player_id) {
+        return unconfirmed(player_id);
+    }
+    log::info!("Purging player {}", player_id);
+    match state.player_repository.purge(player_id).await {
+        Ok(_) => (StatusCode::NO_CONTENT, Json("")).into_response(),
+        Err(err) => {
+            log::error!("Error purging player {}: {:?}", player_id, err);
+            (StatusCode::NOT_FOUND, Json(err)).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+    use http::StatusCode;
+    use crate::repository::Repository;
+    use crate::repository::faery::Model as Faery;
+    use crate::repository::group::{Group, GroupRole};
+    use crate::repository::ledger::{Entry, EntryKind};
+    use crate::testing;
+
+    #[tokio::test]
+    async fn test_archive_restore_and_purge_faery() {
+        let state = testing::state().await;
+        let admin_id = testing::create_player(&state, "admin@example.com", true).await;
+        let admin = testing::token(&state, admin_id, 60);
+        let faery_id = state.faery_repository.create(Some(Faery::new("Puck".to_string(), "puck@example.com".to_string(), false, 5, None))).await.unwrap();
+        state.faery_repository.set_tags(faery_id, &["seelie".to_string()]).await.unwrap();
+        let group_id = state.group_repository.save(Group { id: None, name: "Seelie Court".to_string(), kind: String::new(), parent_id: None, treasury: 0 }).await.unwrap();
+        state.group_repository.set_member(group_id, faery_id, GroupRole::Member).await.unwrap();
+        state.ledger_repository.save(Entry::new(faery_id, 5, 5, EntryKind::Grant, "Welcome".to_string())).await.unwrap();
+        let send = |method: &str, uri: String| crate::router(state.clone()).oneshot(testing::request(method, &uri, Some(&admin), None));
+
+        // Purging needs the faery to be archived first
+        assert_eq!(send("DELETE", format!("/api/archive/faeries/{0}?confirm={0}", faery_id)).await.unwrap().status(), StatusCode::NOT_FOUND);
+
+        assert_eq!(send("DELETE", format!("/api/faeries/{}", faery_id)).await.unwrap().status(), StatusCode::NO_CONTENT);
+        assert_eq!(send("GET", format!("/api/faeries/{}", faery_id)).await.unwrap().status(), StatusCode::NOT_FOUND);
+        let archived = testing::json(send("GET", "/api/archive/faeries".to_string()).await.unwrap()).await;
+        assert_eq!(archived[0]["id"], faery_id);
+
+        assert_eq!(send("POST", format!("/api/archive/faeries/{}/restore", faery_id)).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(send("GET", format!("/api/faeries/{}", faery_id)).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(state.faery_repository.archived().await.unwrap().len(), 0);
+
+        assert_eq!(send("DELETE", format!("/api/faeries/{}", faery_id)).await.unwrap().status(), StatusCode::NO_CONTENT);
+        assert_eq!(send("DELETE", format!("/api/archive/faeries/{}", faery_id)).await.unwrap().status(), StatusCode::PRECONDITION_REQUIRED);
+        assert_eq!(send("DELETE", format!("/api/archive/faeries/{0}?confirm={0}", faery_id)).await.unwrap().status(), StatusCode::NO_CONTENT);
+        assert_eq!(state.faery_repository.archived().await.unwrap().len(), 0);
+        assert!(state.faery_repository.tags_for(faery_id).await.unwrap().is_empty());
+        assert!(state.group_repository.members(group_id).await.unwrap().is_empty());
+        // The ledger keeps its history so the hash chain still verifies
+        assert_eq!(state.ledger_repository.get_for_faery(faery_id).await.unwrap().len(), 1);
+        assert_eq!(send("POST", format!("/api/archive/faeries/{}/restore", faery_id)).await.unwrap().status(), StatusCode::NOT_FOUND);
+        // The purged id isn't handed to the next faery, so its ledger history can't be inherited
+        let next_id = state.faery_repository.create(Some(Faery::new("Mab".to_string(), "mab@example.com".to_string(), false, 0, None))).await.unwrap();
+        assert!(next_id > faery_id);
+    }
+
+    #[tokio::test]
+    async fn test_archive_restore_and_purge_player() {
+        let state = testing::state().await;
+        let admin_id = testing::create_player(&state, "admin@example.com", true).await;
+        let admin = testing::token(&state, admin_id, 60);
+        let player_id = testing::create_player(&state, "player@example.com", false).await;
+        let send = |method: &str, uri: String| crate::router(state.clone()).oneshot(testing::request(method, &uri, Some(&admin), None));
+
+        assert_eq!(send("DELETE", format!("/api/players/{}", player_id)).await.unwrap().status(), StatusCode::NO_CONTENT);
+        assert!(state.player_repository.get(player_id).await.is_err());
+        let players = testing::json(send("GET", "/api/players".to_string()).await.unwrap()).await;
+        assert_eq!(players.as_array().unwrap().len(), 1);
+        let archived = testing::json(send("GET", "/api/archive/players".to_string()).await.unwrap()).await;
+        assert_eq!(archived[0]["player"]["id"], player_id);
+        // An archived player's token no longer works
+        let player = testing::token(&state, player_id, 60);
+        let response = crate::router(state.clone()).oneshot(testing::request("GET", "/api/me", Some(&player), None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        assert_eq!(send("POST", format!("/api/archive/players/{}/restore", player_id)).await.unwrap().status(), StatusCode::OK);
+        assert!(state.player_repository.get(player_id).await.is_ok());
+
+        assert_eq!(send("DELETE", format!("/api/players/{}", player_id)).await.unwrap().status(), StatusCode::NO_CONTENT);
+        assert_eq!(send("DELETE", format!("/api/archive/players/{}?confirm=1", player_id)).await.unwrap().status(), StatusCode::PRECONDITION_REQUIRED);
+        assert_eq!(send("DELETE", format!("/api/archive/players/{0}?confirm={0}", player_id)).await.unwrap().status(), StatusCode::NO_CONTENT);
+        assert!(state.player_repository.archived().await.unwrap().is_empty());
+        assert_eq!(send("POST", format!("/api/archive/players/{}/restore", player_id)).await.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,138 @@
+use std::sync::Arc;
+use axum::extract::{Path, State};
+use axum::extract::rejection::JsonRejection;
+use axum::http::StatusCode;
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use crate::DrossManagerState;
+use crate::repository::{Repository, RepositoryError};
+use crate::repository::attribute::{validate_attributes, AttributeDefinition, Attributes};
+
+pub async fn list_attributes(State(state): State<Arc<DrossManagerState>>) -> Response {
+    match state.attribute_repository.get_all().await {
+        Ok(definitions) => (StatusCode::OK, Json(definitions)).into_response(),
+        Err(err) => {
+            log::error!("Error getting attribute definitions: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn create_attribute(
+    State(state): State<Arc<DrossManagerState>>,
+    payload: Result<Json<AttributeDefinition>, JsonRejection>
+) -> Response {
+    let definition = match payload {
+        Ok(Json(payload)) => AttributeDefinition { id: None, ..payload },
+        Err(err) => {
+            log::error!("Error creating attribute: {:?}", err);
+            let repo_error: RepositoryError = err.into();
+            return (StatusCode::BAD_REQUEST, Json(repo_error)).into_response();
+        }
+    };
+    if !definition.is_valid_key() {
+        return (StatusCode::BAD_REQUEST, Json("Attribute keys may only use a-z, 0-9 and _")).into_response();
+    }
+    log::info!("Creating attribute: {:?}", definition);
+    match state.attribute_repository.save(definition.clone()).await {
+        Ok(id) => (StatusCode::CREATED, Json(AttributeDefinition { id: Some(id), ..definition })).into_response(),
+        Err(err) => {
+            log::error!("Error creating attribute {}: {:?}", definition.key, err);
+            (StatusCode::CONFLICT, Json(err)).into_response()
+        }
+    }
+}
+
+// Changing an attribute's rules doesn't revalidate values that were already stored.
+pub async fn update_attribute(
+    State(state): State<Arc<DrossManagerState>>,
+    Path(attribute_id): Path<i64>,
+    payload: Result<Json<AttributeDefinition>, JsonRejection>
+) -> Response {
+    let definition = match payload {
+        Ok(Json(payload)) => payload,
+        Err(err) => {
+            log::error!("Error updating attribute {}: {:?}", attribute_id, err);
+            let repo_error: RepositoryError = err.into();
+            return (StatusCode::BAD_REQUEST, Json(repo_error)).into_response();
+        }
+    };
+    if definition.id != Some(attribute_id) {
+        return (StatusCode::BAD_REQUEST, Json("ID mismatch")).into_response();
+    }
+    if !definition.is_valid_key() {
+        return (StatusCode::BAD_REQUEST, Json("Attribute keys may only use a-z, 0-9 and _")).into_response();
+    }
+    if let Err(err) = state.attribute_repository.get(attribute_id).await {
+        return (StatusCode::NOT_FOUND, Json(err)).into_response();
+    }
+    match state.attribute_repository.save(definition.clone()).await {
+        Ok(_) => (StatusCode::OK, Json(definition)).into_response(),
+        Err(err) => {
+            log::error!("Error updating attribute {}: {:?}", attribute_id, err);
+            (StatusCode::CONFLICT, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn delete_attribute(State(state): State<Arc<DrossManagerState>>, Path(attribute_id): Path<i64>) -> Response {
+    log::info!("Deleting attribute {}", attribute_id);
+    match state.attribute_repository.delete(attribute_id).await {
+        Ok(_) => (StatusCode::NO_CONTENT, Json("")).into_response(),
+        Err(RepositoryError::NotFound) => (StatusCode::NOT_FOUND, Json(RepositoryError::NotFound)).into_response(),
+        Err(err) => {
+            log::error!("Error deleting attribute {}: {:?}", attribute_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn get_faery_attributes(State(state): State<Arc<DrossManagerState>>, Path(faery_id): Path<i64>) -> Response {
+    if let Err(err) = state.faery_repository.get(faery_id).await {
+        return (StatusCode::NOT_FOUND, Json(err)).into_response();
+    }
+    match state.attribute_repository.values_for(faery_id).await {
+        Ok(attributes) => (StatusCode::OK, Json(attributes)).into_response(),
+        Err(err) => {
+            log::error!("Error getting attributes for faery {}: {:?}", faery_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+// update_faery_attributes sets the attributes in the request and leaves the others alone.
+// Setting an attribute to null removes it.
+pub async fn update_faery_attributes(
+    State(state): State<Arc<DrossManagerState>>,
+    Path(faery_id): Path<i64>,
+    payload: Result<Json<Attributes>, JsonRejection>
+) -> Response {
+    let update = match payload {
+        Ok(Json(update)) => update,
+        Err(err) => {
+            let repo_error: RepositoryError = err.into();
+            return (StatusCode::BAD_REQUEST, Json(repo_error)).into_response();
+        }
+    };
+    if let Err(err) = state.faery_repository.get(faery_id).await {
+        return (StatusCode::NOT_FOUND, Json(err)).into_response();
+    }
+    let (definitions, current) = match (
+        state.attribute_repository.get_all().await,
+        state.attribute_repository.values_for(faery_id).await
+    ) {
+        (Ok(definitions), Ok(current)) => (definitions, current),
+        (Err(err), _) | (_, Err(err)) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response(),
+    };
+    let changes = match validate_attributes(&definitions, &current, &update) {
+        Ok(changes) => changes,
+        Err(errors) => return (StatusCode::UNPROCESSABLE_ENTITY, Json(errors)).into_response(),
+    };
+    match state.attribute_repository.apply(faery_id, &changes).await {
+        Ok(_) => get_faery_attributes(State(state), Path(faery_id)).await,
+        Err(err) => {
+            log::error!("Error updating attributes for faery {}: {:?}", faery_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
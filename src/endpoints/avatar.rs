@@ -0,0 +1,94 @@
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use axum::extract::{Multipart, Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use crate::DrossManagerState;
+use crate::avatar::{AvatarError, AvatarSize};
+use crate::repository::Repository;
+
+// How long browsers may cache an avatar before checking it again
+const CACHE_CONTROL: &str = "public, max-age=3600";
+
+fn error_response(err: AvatarError) -> Response {
+    match err {
+        AvatarError::TooLarge => (StatusCode::PAYLOAD_TOO_LARGE, Json("Avatar is too large")).into_response(),
+        AvatarError::UnsupportedType => {
+            (StatusCode::UNSUPPORTED_MEDIA_TYPE, Json("Avatars must be PNG, JPEG, GIF or WebP images")).into_response()
+        },
+        AvatarError::NotFound => (StatusCode::NOT_FOUND, Json("Not Found")).into_response(),
+        AvatarError::Storage => (StatusCode::INTERNAL_SERVER_ERROR, Json("Couldn't store the avatar")).into_response(),
+    }
+}
+
+// upload_avatar accepts a multipart form with the image in an "avatar" field.
+pub async fn upload_avatar(
+    State(state): State<Arc<DrossManagerState>>,
+    Path(faery_id): Path<i64>,
+    mut multipart: Multipart
+) -> Response {
+    if let Err(err) = state.faery_repository.get(faery_id).await {
+        return (StatusCode::NOT_FOUND, Json(err)).into_response();
+    }
+    let bytes = loop {
+        match multipart.next_field().await {
+            Ok(Some(field)) if field.name() == Some("avatar") => {
+                if !field.content_type().is_some_and(|content_type| content_type.starts_with("image/")) {
+                    return error_response(AvatarError::UnsupportedType);
+                }
+                match field.bytes().await {
+                    Ok(bytes) => break bytes,
+                    // Bodies over the limit are cut off while reading
+                    Err(err) => return (err.status(), Json(err.body_text())).into_response(),
+                }
+            },
+            Ok(Some(_)) => continue,
+            Ok(None) => return (StatusCode::BAD_REQUEST, Json("Missing avatar field")).into_response(),
+            Err(err) => return (err.status(), Json(err.body_text())).into_response(),
+        }
+    };
+    log::info!("Saving avatar for faery {} ({} bytes)", faery_id, bytes.len());
+    match state.avatar_store.save(faery_id, bytes.to_vec()).await {
+        Ok(_) => (StatusCode::NO_CONTENT, Json("")).into_response(),
+        Err(err) => {
+            log::error!("Error saving avatar for faery {}: {:?}", faery_id, err);
+            error_response(err)
+        }
+    }
+}
+
+async fn serve(state: &DrossManagerState, faery_id: i64, size: AvatarSize, headers: HeaderMap) -> Response {
+    let (bytes, metadata) = match state.avatar_store.load(faery_id, size).await {
+        Ok(avatar) => avatar,
+        Err(err) => return error_response(err),
+    };
+    let modified = metadata.modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| modified.as_millis())
+        .unwrap_or(0);
+    let etag = format!("\"{}-{}\"", modified, metadata.len());
+    let cache_headers = [(header::ETAG, etag.clone()), (header::CACHE_CONTROL, CACHE_CONTROL.to_string())];
+    if headers.get(header::IF_NONE_MATCH).and_then(|value| value.to_str().ok()) == Some(etag.as_str()) {
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+    (StatusCode::OK, [(header::CONTENT_TYPE, "image/png".to_string())], cache_headers, bytes).into_response()
+}
+
+pub async fn get_avatar(State(state): State<Arc<DrossManagerState>>, Path(faery_id): Path<i64>, headers: HeaderMap) -> Response {
+    serve(&state, faery_id, AvatarSize::Full, headers).await
+}
+
+pub async fn get_avatar_thumbnail(State(state): State<Arc<DrossManagerState>>, Path(faery_id): Path<i64>, headers: HeaderMap) -> Response {
+    serve(&state, faery_id, AvatarSize::Thumbnail, headers).await
+}
+
+pub async fn delete_avatar(State(state): State<Arc<DrossManagerState>>, Path(faery_id): Path<i64>) -> Response {
+    log::info!("Deleting avatar for faery {}", faery_id);
+    match state.avatar_store.remove(faery_id).await {
+        Ok(true) => (StatusCode::NO_CONTENT, Json("")).into_response(),
+        Ok(false) => error_response(AvatarError::NotFound),
+        Err(err) => error_response(err),
+    }
+}
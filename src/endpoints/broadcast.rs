@@ -0,0 +1,135 @@
+use std::sync::Arc;
+use axum::extract::{Path, State};
+use axum::extract::rejection::JsonRejection;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use crate::DrossManagerState;
+use crate::auth::jwt::JWTAuthMiddleware;
+use crate::mailer::Email;
+use crate::messages::{broadcast_audience, broadcast_variables, render, send_template};
+use crate::repository::{Repository, RepositoryError, RepositoryResult};
+use crate::repository::broadcast::{BroadcastAudience, BroadcastRequest};
+
+// BroadcastPreview is a broadcast as the caller would get it, and how many players it would reach.
+#[derive(Debug, Serialize)]
+pub struct BroadcastPreview {
+    pub email: Email,
+    pub recipients: usize,
+}
+
+async fn validate(state: &DrossManagerState, payload: Result<Json<BroadcastRequest>, JsonRejection>) -> Result<BroadcastRequest, Response> {
+    let request = match payload {
+        Ok(Json(request)) => request,
+        Err(err) => {
+            let repo_error: RepositoryError = err.into();
+            return Err((StatusCode::BAD_REQUEST, Json(repo_error)).into_response());
+        }
+    };
+    if !request.is_valid() {
+        return Err((StatusCode::BAD_REQUEST, Json(RepositoryError::InvalidModel)).into_response());
+    }
+    if let (BroadcastAudience::Group, Some(group_id)) = (request.audience, request.group_id) {
+        if let Err(err) = state.group_repository.get(group_id).await {
+            return Err((StatusCode::BAD_REQUEST, Json(err)).into_response());
+        }
+    }
+    Ok(request)
+}
+
+pub async fn list_broadcasts(State(state): State<Arc<DrossManagerState>>) -> Response {
+    match state.broadcast_repository.get_all().await {
+        Ok(broadcasts) => (StatusCode::OK, Json(broadcasts)).into_response(),
+        Err(err) => {
+            log::error!("Error listing broadcasts: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn get_broadcast(State(state): State<Arc<DrossManagerState>>, Path(broadcast_id): Path<i64>) -> Response {
+    match state.broadcast_repository.get(broadcast_id).await {
+        Ok(broadcast) => (StatusCode::OK, Json(broadcast)).into_response(),
+        Err(err) => (StatusCode::NOT_FOUND, Json(err)).into_response(),
+    }
+}
+
+// create_broadcast schedules a broadcast. The broadcast task sends it within a minute of
+// scheduled_at, or of now if that's already passed, so the request never waits on the audience.
+pub async fn create_broadcast(
+    State(state): State<Arc<DrossManagerState>>,
+    Extension(auth): Extension<JWTAuthMiddleware>,
+    payload: Result<Json<BroadcastRequest>, JsonRejection>
+) -> Response {
+    let request = match validate(&state, payload).await {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    let broadcast = request.into_broadcast(auth.user.id);
+    log::info!("Creating broadcast {:?} for {:?}", broadcast.subject, broadcast.audience);
+    let broadcast_id = match state.broadcast_repository.save(broadcast).await {
+        Ok(broadcast_id) => broadcast_id,
+        Err(err) => {
+            log::error!("Error creating broadcast: {:?}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response();
+        }
+    };
+    match state.broadcast_repository.get(broadcast_id).await {
+        Ok(broadcast) => (StatusCode::CREATED, Json(broadcast)).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response(),
+    }
+}
+
+pub async fn cancel_broadcast(State(state): State<Arc<DrossManagerState>>, Path(broadcast_id): Path<i64>) -> Response {
+    log::info!("Cancelling broadcast {}", broadcast_id);
+    match state.broadcast_repository.cancel(broadcast_id).await {
+        Ok(_) => get_broadcast(State(state), Path(broadcast_id)).await,
+        Err(err) => (StatusCode::NOT_FOUND, Json(err)).into_response(),
+    }
+}
+
+async fn preview(state: &DrossManagerState, to: &str, request: BroadcastRequest) -> RepositoryResult<BroadcastPreview> {
+    let broadcast = request.into_broadcast(None);
+    let recipients = broadcast_audience(state, &broadcast).await?.len();
+    let email = render(state, "broadcast", to, &broadcast_variables(&broadcast)).await?;
+    Ok(BroadcastPreview { email, recipients })
+}
+
+pub async fn preview_broadcast(
+    State(state): State<Arc<DrossManagerState>>,
+    Extension(auth): Extension<JWTAuthMiddleware>,
+    payload: Result<Json<BroadcastRequest>, JsonRejection>
+) -> Response {
+    let request = match validate(&state, payload).await {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    match preview(&state, &auth.user.auth_email, request).await {
+        Ok(preview) => (StatusCode::OK, Json(preview)).into_response(),
+        Err(err) => {
+            log::error!("Error previewing broadcast: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+// test_broadcast sends the broadcast to the caller only, regardless of their preferences.
+pub async fn test_broadcast(
+    State(state): State<Arc<DrossManagerState>>,
+    Extension(auth): Extension<JWTAuthMiddleware>,
+    payload: Result<Json<BroadcastRequest>, JsonRejection>
+) -> Response {
+    let request = match validate(&state, payload).await {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    let variables = broadcast_variables(&request.into_broadcast(None));
+    match send_template(&state, "broadcast", &auth.user.auth_email, variables).await {
+        Ok(_) => (StatusCode::ACCEPTED, Json("Queued")).into_response(),
+        Err(err) => {
+            log::error!("Error sending a test broadcast: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
@@ -0,0 +1,341 @@
+use std::sync::Arc;
+use axum::extract::{Path, State};
+use axum::extract::rejection::JsonRejection;
+use axum::http::StatusCode;
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use crate::DrossManagerState;
+use crate::dross::announce_entry;
+use crate::endpoints::achievement::award_achievements;
+use crate::repository::{Repository, RepositoryError, RepositoryResult};
+use crate::repository::group::{Group, GroupMember, MembershipRequest};
+use crate::repository::ledger::{Entry, EntryKind};
+
+// GroupResponse is a group along with its members.
+#[derive(Debug, Serialize)]
+pub struct GroupResponse {
+    #[serde(flatten)]
+    pub group: Group,
+    pub members: Vec<GroupMember>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GroupGrantRequest {
+    pub amount: u32,
+    pub memo: String,
+    // Also grant to members of every group beneath this one
+    #[serde(default)]
+    pub include_subgroups: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GroupGrantResult {
+    pub granted: Vec<i64>,
+}
+
+// TreasuryRequest changes a group's treasury. A positive amount is a deposit and a negative one a
+// withdrawal. With a faery_id the dross comes from, or is paid out to, that faery's balance.
+#[derive(Debug, Deserialize)]
+pub struct TreasuryRequest {
+    pub amount: i64,
+    pub memo: String,
+    #[serde(default)]
+    pub faery_id: Option<i64>,
+}
+
+fn bad_request(err: JsonRejection) -> Response {
+    let repo_error: RepositoryError = err.into();
+    (StatusCode::BAD_REQUEST, Json(repo_error)).into_response()
+}
+
+fn error_response(err: RepositoryError) -> Response {
+    match err {
+        RepositoryError::NotFound => (StatusCode::NOT_FOUND, Json(err)).into_response(),
+        RepositoryError::InvalidModel => (StatusCode::BAD_REQUEST, Json(err)).into_response(),
+        RepositoryError::AlreadyExists => (StatusCode::CONFLICT, Json(err)).into_response(),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response(),
+    }
+}
+
+// check_parent stops a group from being placed under itself or one of its own subgroups.
+async fn check_parent(state: &DrossManagerState, group_id: Option<i64>, parent_id: Option<i64>) -> RepositoryResult<()> {
+    let parent_id = match parent_id {
+        Some(parent_id) => parent_id,
+        None => return Ok(()),
+    };
+    state.group_repository.get(parent_id).await?;
+    if let Some(group_id) = group_id {
+        if parent_id == group_id || state.group_repository.ancestors(parent_id).await?.contains(&group_id) {
+            return Err(RepositoryError::InvalidModel);
+        }
+    }
+    Ok(())
+}
+
+pub async fn list_groups(State(state): State<Arc<DrossManagerState>>) -> Response {
+    match state.group_repository.get_all().await {
+        Ok(groups) => (StatusCode::OK, Json(groups)).into_response(),
+        Err(err) => {
+            log::error!("Error getting all groups: {:?}", err);
+            error_response(err)
+        }
+    }
+}
+
+pub async fn get_group(State(state): State<Arc<DrossManagerState>>, Path(group_id): Path<i64>) -> Response {
+    let group = match state.group_repository.get(group_id).await {
+        Ok(group) => group,
+        Err(err) => return error_response(err),
+    };
+    match state.group_repository.members(group_id).await {
+        Ok(members) => (StatusCode::OK, Json(GroupResponse { group, members })).into_response(),
+        Err(err) => {
+            log::error!("Error getting members of group {}: {:?}", group_id, err);
+            error_response(err)
+        }
+    }
+}
+
+pub async fn get_faery_groups(State(state): State<Arc<DrossManagerState>>, Path(faery_id): Path<i64>) -> Response {
+    match state.group_repository.groups_for(faery_id).await {
+        Ok(groups) => (StatusCode::OK, Json(groups)).into_response(),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn create_group(
+    State(state): State<Arc<DrossManagerState>>,
+    payload: Result<Json<Group>, JsonRejection>
+) -> Response {
+    let group = match payload {
+        Ok(Json(payload)) => Group { id: None, treasury: 0, ..payload },
+        Err(err) => return bad_request(err),
+    };
+    if let Err(err) = check_parent(&state, None, group.parent_id).await {
+        return error_response(err);
+    }
+    log::info!("Creating group: {:?}", group);
+    match state.group_repository.save(group.clone()).await {
+        Ok(id) => (StatusCode::CREATED, Json(Group { id: Some(id), ..group })).into_response(),
+        Err(err) => {
+            log::error!("Error creating group: {:?}", err);
+            error_response(err)
+        }
+    }
+}
+
+pub async fn update_group(
+    State(state): State<Arc<DrossManagerState>>,
+    Path(group_id): Path<i64>,
+    payload: Result<Json<Group>, JsonRejection>
+) -> Response {
+    let payload = match payload {
+        Ok(Json(payload)) => payload,
+        Err(err) => return bad_request(err),
+    };
+    if payload.id != Some(group_id) {
+        return (StatusCode::BAD_REQUEST, Json("ID mismatch")).into_response();
+    }
+    let existing = match state.group_repository.get(group_id).await {
+        Ok(existing) => existing,
+        Err(err) => return error_response(err),
+    };
+    if let Err(err) = check_parent(&state, Some(group_id), payload.parent_id).await {
+        return error_response(err);
+    }
+    let group = Group { treasury: existing.treasury, ..payload };
+    match state.group_repository.save(group.clone()).await {
+        Ok(_) => (StatusCode::OK, Json(group)).into_response(),
+        Err(err) => {
+            log::error!("Error updating group {}: {:?}", group_id, err);
+            error_response(err)
+        }
+    }
+}
+
+// Deleting a group with dross left in its treasury is refused, so nothing disappears silently.
+pub async fn delete_group(State(state): State<Arc<DrossManagerState>>, Path(group_id): Path<i64>) -> Response {
+    match state.group_repository.get(group_id).await {
+        Ok(group) if group.treasury != 0 => {
+            return (StatusCode::CONFLICT, Json("Empty the group's treasury before deleting it")).into_response();
+        },
+        Ok(_) => {},
+        Err(err) => return error_response(err),
+    }
+    log::info!("Deleting group {}", group_id);
+    match state.group_repository.delete(group_id).await {
+        Ok(_) => (StatusCode::NO_CONTENT, Json("")).into_response(),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn set_member(
+    State(state): State<Arc<DrossManagerState>>,
+    Path((group_id, faery_id)): Path<(i64, i64)>,
+    payload: Result<Json<MembershipRequest>, JsonRejection>
+) -> Response {
+    let request = match payload {
+        Ok(Json(request)) => request,
+        Err(err) => return bad_request(err),
+    };
+    if let Err(err) = state.group_repository.get(group_id).await {
+        return error_response(err);
+    }
+    if let Err(err) = state.faery_repository.get(faery_id).await {
+        return error_response(err);
+    }
+    match state.group_repository.set_member(group_id, faery_id, request.role).await {
+        Ok(_) => get_group(State(state), Path(group_id)).await,
+        Err(err) => {
+            log::error!("Error adding faery {} to group {}: {:?}", faery_id, group_id, err);
+            error_response(err)
+        }
+    }
+}
+
+pub async fn remove_member(
+    State(state): State<Arc<DrossManagerState>>,
+    Path((group_id, faery_id)): Path<(i64, i64)>
+) -> Response {
+    match state.group_repository.remove_member(group_id, faery_id).await {
+        Ok(_) => (StatusCode::NO_CONTENT, Json("")).into_response(),
+        Err(err) => error_response(err),
+    }
+}
+
+// grant_group gives every member the same amount of dross, each with their own ledger entry.
+// The grant is all or nothing: if any member can't take it, nobody is granted anything.
+pub async fn grant_group(
+    State(state): State<Arc<DrossManagerState>>,
+    Path(group_id): Path<i64>,
+    payload: Result<Json<GroupGrantRequest>, JsonRejection>
+) -> Response {
+    let request = match payload {
+        Ok(Json(request)) => request,
+        Err(err) => return bad_request(err),
+    };
+    if request.amount == 0 {
+        return (StatusCode::BAD_REQUEST, Json(RepositoryError::InvalidModel)).into_response();
+    }
+    let group = match state.group_repository.get(group_id).await {
+        Ok(group) => group,
+        Err(err) => return error_response(err),
+    };
+    let members = match state.group_repository.member_ids(group_id, request.include_subgroups).await {
+        Ok(members) => members,
+        Err(err) => return error_response(err),
+    };
+    log::info!("Granting {} dross to {} members of group {}", request.amount, members.len(), group.name);
+    let entries = members.iter()
+        .map(|faery_id| Entry::new(*faery_id, request.amount as i64, 0, EntryKind::Grant, format!("{} ({})", request.memo, group.name)))
+        .collect();
+    let entries = match state.ledger_repository.adjust_all(entries).await {
+        Ok(entries) => entries,
+        Err(err) => {
+            log::error!("Error granting dross to group {}: {:?}", group_id, err);
+            return error_response(err);
+        }
+    };
+    for entry in &entries {
+        announce_entry(&state, entry, Some(&group.name)).await;
+        if let Err(err) = award_achievements(&state, entry.faery_id).await {
+            log::error!("Error awarding achievements to faery {}: {:?}", entry.faery_id, err);
+        }
+    }
+    (StatusCode::OK, Json(GroupGrantResult { granted: members })).into_response()
+}
+
+pub async fn get_treasury(State(state): State<Arc<DrossManagerState>>, Path(group_id): Path<i64>) -> Response {
+    if let Err(err) = state.group_repository.get(group_id).await {
+        return error_response(err);
+    }
+    match state.group_repository.treasury_history(group_id).await {
+        Ok(entries) => (StatusCode::OK, Json(entries)).into_response(),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn update_treasury(
+    State(state): State<Arc<DrossManagerState>>,
+    Path(group_id): Path<i64>,
+    payload: Result<Json<TreasuryRequest>, JsonRejection>
+) -> Response {
+    let request = match payload {
+        Ok(Json(request)) => request,
+        Err(err) => return bad_request(err),
+    };
+    if request.amount == 0 {
+        return (StatusCode::BAD_REQUEST, Json(RepositoryError::InvalidModel)).into_response();
+    }
+    let group = match state.group_repository.get(group_id).await {
+        Ok(group) => group,
+        Err(err) => return error_response(err),
+    };
+    let result = match request.faery_id {
+        Some(faery_id) => {
+            let ledger_memo = match request.amount > 0 {
+                true => format!("Paid into {} treasury: {}", group.name, request.memo),
+                false => format!("Paid from {} treasury: {}", group.name, request.memo),
+            };
+            match state.group_repository.transfer(group_id, faery_id, request.amount, request.memo, ledger_memo).await {
+                Ok((treasury_entry, entry)) => {
+                    announce_entry(&state, &entry, Some(&group.name)).await;
+                    Ok(treasury_entry)
+                },
+                Err(err) => Err(err),
+            }
+        },
+        None => state.group_repository.adjust_treasury(group_id, request.amount, request.memo).await,
+    };
+    match result {
+        Ok(entry) => (StatusCode::OK, Json(entry)).into_response(),
+        Err(err) => {
+            log::error!("Error updating treasury of group {}: {:?}", group_id, err);
+            error_response(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+    use http::StatusCode;
+    use serde_json::json;
+    use crate::repository::Repository;
+    use crate::repository::faery::Model as Faery;
+    use crate::repository::group::{Group, GroupRole};
+    use crate::testing;
+
+    #[tokio::test]
+    async fn test_treasury_and_grants_move_all_or_nothing() {
+        let state = testing::state().await;
+        let admin_id = testing::create_player(&state, "admin@example.com", true).await;
+        let admin = testing::token(&state, admin_id, 60);
+        let puck = state.faery_repository.create(Some(Faery::new("Puck".to_string(), "puck@example.com".to_string(), false, 10, None))).await.unwrap();
+        let mab = state.faery_repository.create(Some(Faery::new("Mab".to_string(), "mab@example.com".to_string(), false, u32::MAX, None))).await.unwrap();
+        let group_id = state.group_repository.save(Group { id: None, name: "Seelie Court".to_string(), kind: String::new(), parent_id: None, treasury: 0 }).await.unwrap();
+        state.group_repository.set_member(group_id, puck, GroupRole::Member).await.unwrap();
+        let send = |uri: String, body: serde_json::Value| crate::router(state.clone()).oneshot(testing::request("POST", &uri, Some(&admin), Some(body)));
+        let treasury = format!("/api/groups/{}/treasury", group_id);
+
+        assert_eq!(send(treasury.clone(), json!({ "amount": 4, "memo": "Dues", "faery_id": puck })).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(state.faery_repository.get(puck).await.unwrap().dross, 6);
+        // A faery that can't cover the payment leaves the treasury untouched
+        assert_eq!(send(treasury.clone(), json!({ "amount": 7, "memo": "Dues", "faery_id": puck })).await.unwrap().status(), StatusCode::BAD_REQUEST);
+        // And a payout the faery can't hold leaves the treasury its dross
+        assert_eq!(send(treasury.clone(), json!({ "amount": -4, "memo": "Prize", "faery_id": mab })).await.unwrap().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(state.group_repository.get(group_id).await.unwrap().treasury, 4);
+        assert_eq!(state.group_repository.treasury_history(group_id).await.unwrap().len(), 1);
+
+        // One member who can't take the grant stops it for everyone
+        state.group_repository.set_member(group_id, mab, GroupRole::Member).await.unwrap();
+        let grant = format!("/api/groups/{}/grant", group_id);
+        assert_eq!(send(grant.clone(), json!({ "amount": 1, "memo": "Midsummer" })).await.unwrap().status(), StatusCode::BAD_REQUEST);
+        assert_eq!((state.faery_repository.get(puck).await.unwrap().dross, state.faery_repository.get(mab).await.unwrap().dross), (6, u32::MAX));
+        state.group_repository.remove_member(group_id, mab).await.unwrap();
+        let granted = testing::json(send(grant, json!({ "amount": 1, "memo": "Midsummer" })).await.unwrap()).await;
+        assert_eq!(granted["granted"], json!([puck]));
+        assert_eq!(state.faery_repository.get(puck).await.unwrap().dross, 7);
+    }
+}
@@ -0,0 +1,200 @@
+use std::sync::Arc;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use crate::DrossManagerState;
+use crate::auth::jwt::{sign_claims, CallerRole};
+use crate::reconcile::{reconcile, ReconcileQuery};
+use crate::repository::{mask, Repository};
+use crate::repository::ledger::{verify_chain, ChainVerification, Entry};
+
+// Signed exports can be checked offline for this long
+const EXPORT_TTL_DAYS: i64 = 365;
+const EXPORT_PURPOSE: &str = "ledger-export";
+
+#[derive(Debug, Deserialize)]
+pub struct BalanceQuery {
+    // Milliseconds since the epoch; defaults to now
+    pub at: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BalanceResponse {
+    pub faery_id: i64,
+    pub at: i64,
+    pub balance: i64,
+}
+
+pub async fn get_ledger(State(state): State<Arc<DrossManagerState>>, role: CallerRole, Path(faery_id): Path<i64>) -> Response {
+    log::info!("Getting ledger for faery {}", faery_id);
+    match state.ledger_repository.get_for_faery(faery_id).await {
+        Ok(entries) => (StatusCode::OK, Json(mask::<Entry, _>(&entries, role.is_admin))).into_response(),
+        Err(err) => {
+            log::error!("Error getting ledger for faery {}: {:?}", faery_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+// get_balance answers "how much dross did this faery have at a given moment" from the ledger.
+pub async fn get_balance(
+    State(state): State<Arc<DrossManagerState>>,
+    Path(faery_id): Path<i64>,
+    Query(query): Query<BalanceQuery>
+) -> Response {
+    let at = query.at.unwrap_or_else(|| Utc::now().timestamp_millis());
+    if let Err(err) = state.faery_repository.get(faery_id).await {
+        return (StatusCode::NOT_FOUND, Json(err)).into_response();
+    }
+    match state.ledger_repository.balance_at(faery_id, at).await {
+        Ok(balance) => (StatusCode::OK, Json(BalanceResponse { faery_id, at, balance })).into_response(),
+        Err(err) => {
+            log::error!("Error getting balance for faery {} at {}: {:?}", faery_id, at, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+// reconcile_ledger compares every stored balance against the ledger.
+// Pass ?correct=true to record adjustment entries for any mismatches.
+pub async fn reconcile_ledger(State(state): State<Arc<DrossManagerState>>, Query(query): Query<ReconcileQuery>) -> Response {
+    log::info!("Reconciling ledger (correct: {})", query.correct);
+    match reconcile(&state, query.correct).await {
+        Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+        Err(err) => {
+            log::error!("Error reconciling ledger: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LedgerExport {
+    pub entries: Vec<Entry>,
+    pub verification: ChainVerification,
+    pub exported_at: i64,
+    // An RS256 JWT over ExportClaims, checkable with the key from /api/ledger/public-key
+    pub signature: String,
+}
+
+// ExportClaims pins an export to the chain head it was taken at.
+// Offline, recompute the chain from the entries and compare it with these claims.
+// verified is false when the chain was already broken at export time, so a signature
+// never vouches for a tampered ledger.
+#[derive(Debug, Serialize)]
+pub struct ExportClaims {
+    pub sub: String,
+    pub purpose: String,
+    pub head_hash: String,
+    pub entries: usize,
+    pub verified: bool,
+    pub exp: i64,
+    pub iat: i64,
+}
+
+// verify_ledger walks the whole hash chain and reports whether any entry was altered or removed.
+pub async fn verify_ledger(State(state): State<Arc<DrossManagerState>>) -> Response {
+    match state.ledger_repository.chain().await {
+        Ok((entries, head)) => {
+            let verification = verify_chain(&entries, &head);
+            if !verification.valid {
+                log::error!("Ledger hash chain is broken: {:?}", verification);
+            }
+            (StatusCode::OK, Json(verification)).into_response()
+        },
+        Err(err) => {
+            log::error!("Error verifying ledger: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+// Exports are signed with their own key, so a signature can never be mistaken for an access token.
+pub async fn export_ledger(State(state): State<Arc<DrossManagerState>>) -> Response {
+    let Some(signing_key) = &state.ledger_signing_key else {
+        return (StatusCode::SERVICE_UNAVAILABLE, Json("Ledger exports need a signing key")).into_response();
+    };
+    let (entries, head) = match state.ledger_repository.chain().await {
+        Ok(chain) => chain,
+        Err(err) => {
+            log::error!("Error exporting ledger: {:?}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response();
+        }
+    };
+    let verification = verify_chain(&entries, &head);
+    if !verification.valid {
+        log::error!("Exporting a ledger with a broken hash chain: {:?}", verification);
+    }
+    let now = Utc::now();
+    let exported_at = now.timestamp();
+    let claims = ExportClaims {
+        sub: "ledger".to_string(),
+        purpose: EXPORT_PURPOSE.to_string(),
+        head_hash: verification.head_hash.clone(),
+        entries: verification.entries,
+        verified: verification.valid,
+        exp: (now + Duration::days(EXPORT_TTL_DAYS)).timestamp(),
+        iat: exported_at,
+    };
+    match sign_claims(&claims, signing_key.private_key.clone()) {
+        Ok(signature) => {
+            let export = LedgerExport { entries, verification, exported_at, signature };
+            (StatusCode::OK, Json(mask::<Entry, _>(&export, true))).into_response()
+        },
+        Err(err) => {
+            log::error!("Error signing ledger export: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+// get_public_key returns the PEM encoded key that ledger export signatures are checked against.
+pub async fn get_public_key(State(state): State<Arc<DrossManagerState>>) -> Response {
+    let Some(signing_key) = &state.ledger_signing_key else {
+        return (StatusCode::NOT_FOUND, Json("Ledger exports aren't signed")).into_response();
+    };
+    match general_purpose::STANDARD.decode(&signing_key.public_key) {
+        Ok(key) => (StatusCode::OK, String::from_utf8_lossy(&key).to_string()).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json("Invalid public key")).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+    use http::StatusCode;
+    use crate::auth::jwt::verify_claims;
+    use crate::dross::adjust_balance;
+    use crate::repository::Repository;
+    use crate::repository::faery::Model;
+    use crate::repository::ledger::EntryKind;
+    use crate::testing;
+
+    #[tokio::test]
+    async fn test_export_is_signed_with_the_ledger_key() {
+        let state = testing::state().await;
+        let admin_id = testing::create_player(&state, "admin@example.com", true).await;
+        let admin = testing::token(&state, admin_id, 60);
+        let faery_id = state.faery_repository.create(Some(Model::new("Puck".to_string(), "puck@example.com".to_string(), false, 0, None))).await.unwrap();
+        adjust_balance(&state, faery_id, 5, EntryKind::Grant, "Welcome".to_string()).await.unwrap();
+        let send = |uri: &str| crate::router(state.clone()).oneshot(testing::request("GET", uri, Some(&admin), None));
+
+        let verification = testing::json(send("/api/ledger/verify").await.unwrap()).await;
+        assert_eq!((verification["valid"].as_bool(), verification["entries"].as_u64()), (Some(true), Some(1)));
+
+        let export = testing::json(send("/api/admin/ledger/export").await.unwrap()).await;
+        let signature = export["signature"].as_str().unwrap();
+        let signing_key = state.ledger_signing_key.as_ref().unwrap().public_key.clone();
+        let claims: serde_json::Value = verify_claims(signature, signing_key).unwrap();
+        assert_eq!(claims["head_hash"], verification["head_hash"]);
+        assert_eq!(claims["verified"], true);
+        // An export signature is no use as an access token
+        assert!(verify_claims::<serde_json::Value>(signature, state.jwt_key_pair.public_key.clone()).is_err());
+        let response = crate::router(state.clone()).oneshot(testing::request("GET", "/api/me", Some(signature), None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
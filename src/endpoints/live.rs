@@ -0,0 +1,169 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::Extension;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use chrono::Utc;
+use futures::{stream, Stream, StreamExt};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Instant;
+use crate::DrossManagerState;
+use crate::auth::jwt::JWTAuthMiddleware;
+use crate::live::{BalanceUpdate, LiveEvent, LiveEventKind};
+use crate::repository::Repository;
+
+// How often an open stream checks that its player can still sign in
+const RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+fn to_event(event: &LiveEvent) -> Result<Event, Infallible> {
+    Ok(Event::default().id(event.id.to_string()).event(event.kind.as_str()).data(event.data.to_string()))
+}
+
+async fn current_balances(state: &DrossManagerState, player_id: i64, email: &str) -> Vec<BalanceUpdate> {
+    match state.faery_repository.owned_by(email).await {
+        Ok(faeries) => faeries.iter()
+            .filter(|faery| faery.deleted_at.is_none())
+            .filter_map(|faery| Some(BalanceUpdate { faery_id: faery.id?, balance: faery.dross() as i64 }))
+            .collect(),
+        Err(err) => {
+            log::error!("Error getting balances for player {}: {:?}", player_id, err);
+            vec![]
+        }
+    }
+}
+
+// still_signed_in is false once the stream's token has expired, or its player has been
+// archived, erased or deactivated.
+async fn still_signed_in(state: &DrossManagerState, player_id: i64, token_expires: i64) -> bool {
+    if Utc::now().timestamp() >= token_expires {
+        return false;
+    }
+    matches!(state.player_repository.get(player_id).await, Ok(player) if player.is_active())
+}
+
+// get_my_stream pushes the caller's balance changes, transactions and notifications as they
+// happen. It starts with the current balance of each of their faeries, then replays anything
+// they missed since Last-Event-ID. A client that missed more than the replay buffer holds gets a
+// resync event with fresh balances instead, and should reload everything else.
+// The stream closes when the caller's token expires or their account can no longer sign in.
+pub async fn get_my_stream(
+    State(state): State<Arc<DrossManagerState>>,
+    Extension(auth): Extension<JWTAuthMiddleware>,
+    headers: HeaderMap
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let player_id = auth.user.id.unwrap_or_default();
+    let last_event_id = headers.get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    let subscription = state.live_events.subscribe(player_id, last_event_id);
+    let balances = current_balances(&state, player_id, &auth.user.auth_email).await;
+
+    let opening: Vec<Result<Event, Infallible>> = if subscription.missed {
+        log::info!("Resyncing the stream for player {}, whose Last-Event-ID is older than the replay buffer", player_id);
+        let data = serde_json::json!({ "balances": balances });
+        vec![Ok(Event::default().id(subscription.last_id.to_string()).event(LiveEventKind::Resync.as_str()).data(data.to_string()))]
+    } else {
+        // Balances are sent without an ID, so they don't move the client's Last-Event-ID
+        balances.iter()
+            .filter_map(|update| Some(Ok(Event::default().event(LiveEventKind::Balance.as_str()).data(serde_json::to_string(update).ok()?))))
+            .chain(subscription.replay.iter().map(|event| to_event(event)))
+            .collect()
+    };
+
+    let token_expires = auth.access_token_expires;
+    let expires_at = Instant::now() + Duration::from_secs((token_expires - Utc::now().timestamp()).max(0) as u64);
+    let first_check = (Instant::now() + RECHECK_INTERVAL).min(expires_at);
+    let live = stream::unfold((subscription.receiver, first_check), move |(mut receiver, mut next_check)| {
+        let state = state.clone();
+        async move {
+            loop {
+                match tokio::time::timeout_at(next_check, receiver.recv()).await {
+                    Ok(Ok(event)) => return Some((to_event(&event), (receiver, next_check))),
+                    // Closing makes the client reconnect with its Last-Event-ID and catch up from the replay buffer
+                    Ok(Err(RecvError::Lagged(skipped))) => {
+                        log::warn!("Closing the stream for player {} after it fell {} events behind", player_id, skipped);
+                        return None;
+                    },
+                    Ok(Err(RecvError::Closed)) => return None,
+                    Err(_) => {
+                        if !still_signed_in(&state, player_id, token_expires).await {
+                            log::info!("Closing the stream for player {}, who is no longer signed in", player_id);
+                            return None;
+                        }
+                        next_check = (Instant::now() + RECHECK_INTERVAL).min(expires_at);
+                    },
+                }
+            }
+        }
+    });
+
+    log::info!("Player {} opened a live stream", player_id);
+    Sse::new(stream::iter(opening).chain(live)).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use futures::StreamExt;
+    use tower::ServiceExt;
+    use http::StatusCode;
+    use crate::dross::adjust_balance;
+    use crate::repository::Repository;
+    use crate::repository::faery::Model;
+    use crate::repository::ledger::EntryKind;
+    use crate::testing;
+
+    // next_frame reads what the stream sends next, or None once it has closed.
+    async fn next_frame(body: &mut axum::body::BodyDataStream) -> Option<String> {
+        let frame = tokio::time::timeout(Duration::from_secs(5), body.next()).await.expect("the stream stalled")?;
+        Some(String::from_utf8(frame.unwrap().to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_stream_pushes_updates_until_the_player_is_archived() {
+        let state = testing::state().await;
+        let admin_id = testing::create_player(&state, "admin@example.com", true).await;
+        let player_id = testing::create_player(&state, "wendy@example.com", false).await;
+        let faery_id = state.faery_repository.create(Some(Model::new("Tink".to_string(), "wendy@example.com".to_string(), false, 5, None))).await.unwrap();
+        let token = testing::token(&state, player_id, 60);
+
+        let response = crate::router(state.clone()).oneshot(testing::request("GET", "/api/me/stream", Some(&token), None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = response.into_body().into_data_stream();
+        let opening = next_frame(&mut body).await.unwrap();
+        assert!(opening.contains("event: balance"));
+        assert!(opening.contains(&format!("\"faery_id\":{},\"balance\":5", faery_id)));
+
+        adjust_balance(&state, faery_id, 2, EntryKind::Grant, "Welcome".to_string()).await.unwrap();
+        let mut received = String::new();
+        while !received.contains("\"balance\":7") {
+            received.push_str(&next_frame(&mut body).await.unwrap());
+        }
+        assert!(received.contains("event: transaction"));
+
+        let admin = testing::token(&state, admin_id, 60);
+        let archived = crate::router(state.clone()).oneshot(testing::request("DELETE", &format!("/api/players/{}", player_id), Some(&admin), None)).await.unwrap();
+        assert_eq!(archived.status(), StatusCode::NO_CONTENT);
+        // Whatever was already published is sent, then the stream closes
+        while next_frame(&mut body).await.is_some() {}
+    }
+
+    #[tokio::test]
+    async fn test_stale_last_event_id_gets_a_resync() {
+        let state = testing::state().await;
+        let player_id = testing::create_player(&state, "wendy@example.com", false).await;
+        state.faery_repository.create(Some(Model::new("Tink".to_string(), "wendy@example.com".to_string(), false, 5, None))).await.unwrap();
+        let token = testing::token(&state, player_id, 60);
+
+        let mut request = testing::request("GET", "/api/me/stream", Some(&token), None);
+        request.headers_mut().insert("Last-Event-ID", "1".parse().unwrap());
+        let response = crate::router(state.clone()).oneshot(request).await.unwrap();
+        let mut body = response.into_body().into_data_stream();
+        let opening = next_frame(&mut body).await.unwrap();
+        assert!(opening.contains("event: resync"));
+        assert!(opening.contains("\"balances\":[{"));
+        assert!(opening.contains("\"balance\":5"));
+    }
+}
@@ -0,0 +1,172 @@
+use std::sync::Arc;
+use axum::extract::{Path, Query, State};
+use axum::extract::rejection::JsonRejection;
+use axum::http::StatusCode;
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use chrono::Utc;
+use serde::Deserialize;
+use crate::DrossManagerState;
+use crate::mailer::{DeliveryEvent, MailWebhook};
+use crate::repository::{Repository, RepositoryError};
+use crate::repository::email::{OutboxMessage, OutboxStatus, SuppressionReason};
+
+#[derive(Debug, Deserialize)]
+pub struct OutboxQuery {
+    pub status: Option<OutboxStatus>,
+}
+
+pub async fn get_mail_status(State(state): State<Arc<DrossManagerState>>) -> Response {
+    match state.email_repository.status().await {
+        Ok(summary) => (StatusCode::OK, Json(summary)).into_response(),
+        Err(err) => {
+            log::error!("Error getting mail status: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn list_outbox(State(state): State<Arc<DrossManagerState>>, Query(query): Query<OutboxQuery>) -> Response {
+    match state.email_repository.outbox(query.status).await {
+        Ok(messages) => {
+            let messages: Vec<OutboxMessage> = messages.into_iter().map(OutboxMessage::redacted).collect();
+            (StatusCode::OK, Json(messages)).into_response()
+        },
+        Err(err) => {
+            log::error!("Error listing the outbox: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn get_outbox_message(State(state): State<Arc<DrossManagerState>>, Path(message_id): Path<i64>) -> Response {
+    match state.email_repository.get(message_id).await {
+        Ok(message) => (StatusCode::OK, Json(message.redacted())).into_response(),
+        Err(err) => (StatusCode::NOT_FOUND, Json(err)).into_response(),
+    }
+}
+
+// resend_outbox_message only applies to dead-lettered messages; pending ones are already queued.
+pub async fn resend_outbox_message(State(state): State<Arc<DrossManagerState>>, Path(message_id): Path<i64>) -> Response {
+    log::info!("Resending email {}", message_id);
+    match state.email_repository.resend(message_id).await {
+        Ok(_) => get_outbox_message(State(state), Path(message_id)).await,
+        Err(err) => {
+            log::error!("Error resending email {}: {:?}", message_id, err);
+            (StatusCode::NOT_FOUND, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn list_suppressions(State(state): State<Arc<DrossManagerState>>) -> Response {
+    match state.email_repository.suppressions().await {
+        Ok(suppressions) => (StatusCode::OK, Json(suppressions)).into_response(),
+        Err(err) => {
+            log::error!("Error listing suppressed addresses: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+// delete_suppression lets email go to an address again, e.g. once a player has fixed their mailbox.
+pub async fn delete_suppression(State(state): State<Arc<DrossManagerState>>, Path(email): Path<String>) -> Response {
+    log::info!("Lifting the suppression on {}", email);
+    match state.email_repository.unsuppress(&email).await {
+        Ok(_) => (StatusCode::NO_CONTENT, Json("")).into_response(),
+        Err(err) => (StatusCode::NOT_FOUND, Json(err)).into_response(),
+    }
+}
+
+// receive_mail_webhook takes delivery events from the mail provider. Bounced and complaining
+// addresses are suppressed; every other event is acknowledged and ignored. Each signed webhook
+// is only accepted once.
+pub async fn receive_mail_webhook(
+    State(state): State<Arc<DrossManagerState>>,
+    payload: Result<Json<MailWebhook>, JsonRejection>
+) -> Response {
+    let Some(signing_key) = &state.mail_webhook_key else {
+        return (StatusCode::NOT_FOUND, Json("Not Found")).into_response();
+    };
+    let webhook = match payload {
+        Ok(Json(webhook)) => webhook,
+        Err(err) => {
+            log::error!("Error parsing mail webhook: {:?}", err);
+            let repo_error: RepositoryError = err.into();
+            return (StatusCode::BAD_REQUEST, Json(repo_error)).into_response();
+        }
+    };
+    let now = Utc::now().timestamp();
+    if !webhook.verify(signing_key, now) {
+        log::error!("Rejected a mail webhook with a bad signature");
+        return (StatusCode::UNAUTHORIZED, Json("Invalid signature")).into_response();
+    }
+    if !state.webhook_replays.claim(&webhook.signature, now) {
+        log::error!("Rejected a replayed mail webhook");
+        return (StatusCode::CONFLICT, Json("Webhook already received")).into_response();
+    }
+    let result = match webhook.event() {
+        Some(DeliveryEvent::Bounced { recipient, detail }) => {
+            log::info!("Suppressing {} after a bounce: {}", recipient, detail);
+            match state.email_repository.suppress(&recipient, SuppressionReason::Bounced, &detail).await {
+                Ok(_) => state.broadcast_repository.mark_bounced(&recipient).await,
+                Err(err) => Err(err),
+            }
+        },
+        Some(DeliveryEvent::Complained { recipient }) => {
+            log::info!("Suppressing {} after a complaint", recipient);
+            state.email_repository.suppress(&recipient, SuppressionReason::Complained, "Marked as spam").await
+        },
+        None => Ok(()),
+    };
+    match result {
+        Ok(_) => (StatusCode::OK, Json("OK")).into_response(),
+        Err(err) => {
+            log::error!("Error handling mail webhook: {:?}", err);
+            state.webhook_replays.release(&webhook.signature);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use tower::ServiceExt;
+    use http::StatusCode;
+    use crate::mailer::webhook_signature;
+    use crate::testing;
+
+    fn bounce(timestamp: &str, token: &str, signature: &str) -> serde_json::Value {
+        serde_json::json!({
+            "signature": { "timestamp": timestamp, "token": token, "signature": signature },
+            "event-data": {
+                "event": "failed",
+                "severity": "permanent",
+                "recipient": "wendy@example.com",
+                "delivery-status": { "message": "", "description": "No such mailbox" }
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_signed_bounces_flag_players_once() {
+        let state = testing::state().await;
+        let player_id = testing::create_player(&state, "wendy@example.com", false).await;
+        let token = testing::token(&state, player_id, 60);
+        let timestamp = Utc::now().timestamp().to_string();
+        let signed = bounce(&timestamp, "token-1", &webhook_signature(testing::WEBHOOK_KEY, &timestamp, "token-1"));
+        let send = |body: serde_json::Value| crate::router(state.clone()).oneshot(testing::request("POST", "/api/webhooks/mail", None, Some(body)));
+
+        let forged = bounce(&timestamp, "token-1", &webhook_signature("other-key", &timestamp, "token-1"));
+        assert_eq!(send(forged).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+        assert_eq!(send(signed.clone()).await.unwrap().status(), StatusCode::OK);
+        let suppressions = state.email_repository.suppressions().await.unwrap();
+        assert_eq!(suppressions.len(), 1);
+        let me = crate::router(state.clone()).oneshot(testing::request("GET", "/api/me", Some(&token), None)).await.unwrap();
+        assert_eq!(testing::json(me).await["player"]["email_suppressed"], "bounced");
+
+        // The same signed request can't be sent twice
+        assert_eq!(send(signed).await.unwrap().status(), StatusCode::CONFLICT);
+    }
+}
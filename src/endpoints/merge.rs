@@ -0,0 +1,148 @@
+use std::sync::Arc;
+use axum::extract::{Path, State};
+use axum::extract::rejection::JsonRejection;
+use axum::http::StatusCode;
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use crate::DrossManagerState;
+use crate::dross::announce_entry;
+use crate::endpoints::achievement::award_achievements;
+use crate::repository::{mask, Repository, RepositoryError, RepositoryResult};
+use crate::repository::achievement::Badge;
+use crate::repository::faery::Model;
+
+#[derive(Debug, Deserialize)]
+pub struct MergeRequest {
+    // The duplicate faery, which is archived once merged
+    pub source_id: i64,
+    // Preview the merge without changing anything
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+// MergeResult describes the target faery as it looks (or would look) after the merge.
+#[derive(Debug, Serialize)]
+pub struct MergeResult {
+    pub dry_run: bool,
+    pub target: Model,
+    pub source: Model,
+    // Dross moved from the source onto the target
+    pub transferred: u32,
+    // Ledger entries of the source that now show up in the target's history
+    pub ledger_entries: usize,
+    // Badges the target gains from the source
+    pub badges: Vec<Badge>,
+}
+
+async fn preview(state: &DrossManagerState, source: Model, target: Model) -> RepositoryResult<MergeResult> {
+    let (source_id, target_id) = (source.id.unwrap_or(0), target.id.unwrap_or(0));
+    let ledger_entries = state.ledger_repository.get_for_faery(source_id).await?.len();
+    let held = state.achievement_repository.badges_for(target_id).await?;
+    let badges = state.achievement_repository.badges_for(source_id).await?
+        .into_iter()
+        .filter(|badge| !held.iter().any(|h| h.achievement_id == badge.achievement_id))
+        .collect();
+    let transferred = source.dross;
+    // The merge refuses a balance the target can't hold, so the preview does too
+    let dross = target.dross.checked_add(transferred).ok_or(RepositoryError::InvalidModel)?;
+    let target = Model { dross, ..target };
+    Ok(MergeResult { dry_run: true, target, source, transferred, ledger_entries, badges })
+}
+
+// The source's ledger entries are hashed into the chain, so they can't be rewritten to the target.
+// Instead the repository moves the balance with a pair of transfer entries, and the redirect folds
+// the source's history into the target's ledger view.
+async fn merge(state: &DrossManagerState, source: &Model, target_id: i64) -> RepositoryResult<()> {
+    let source_id = source.id.unwrap_or(0);
+    if let Some(credit) = state.faery_repository.merge(source_id, target_id).await? {
+        // The source is archived now, so only the target's owner hears about the move
+        announce_entry(state, &credit, Some(source.name())).await;
+    }
+    // The merge is already committed, so trouble with the files is only logged
+    if let Err(err) = state.avatar_store.merge(source_id, target_id).await {
+        log::error!("Error moving the avatar of faery {} to {}: {:?}", source_id, target_id, err);
+    }
+    award_achievements(state, target_id).await?;
+    Ok(())
+}
+
+pub async fn merge_faery(
+    State(state): State<Arc<DrossManagerState>>,
+    Path(target_id): Path<i64>,
+    payload: Result<Json<MergeRequest>, JsonRejection>
+) -> Response {
+    let request = match payload {
+        Ok(Json(request)) => request,
+        Err(err) => {
+            log::error!("Error merging into faery {}: {:?}", target_id, err);
+            let repo_error: RepositoryError = err.into();
+            return (StatusCode::BAD_REQUEST, Json(repo_error)).into_response();
+        }
+    };
+    if request.source_id == target_id {
+        return (StatusCode::BAD_REQUEST, Json("A faery can't be merged into itself")).into_response();
+    }
+    let target = match state.faery_repository.get(target_id).await {
+        Ok(target) => target,
+        Err(err) => return (StatusCode::NOT_FOUND, Json(err)).into_response(),
+    };
+    let source = match state.faery_repository.get(request.source_id).await {
+        Ok(source) => source,
+        Err(err) => return (StatusCode::NOT_FOUND, Json(err)).into_response(),
+    };
+    let result = match preview(&state, source.clone(), target.clone()).await {
+        Ok(result) => result,
+        Err(RepositoryError::InvalidModel) => {
+            return (StatusCode::BAD_REQUEST, Json("The target can't hold the combined balance")).into_response();
+        },
+        Err(err) => {
+            log::error!("Error previewing merge of faery {} into {}: {:?}", request.source_id, target_id, err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response();
+        }
+    };
+    if request.dry_run {
+        return (StatusCode::OK, Json(mask::<Model, _>(&result, true))).into_response();
+    }
+    log::info!("Merging faery {} into {}", request.source_id, target_id);
+    match merge(&state, &source, target_id).await {
+        Ok(_) => {
+            let result = MergeResult { dry_run: false, ..result };
+            (StatusCode::OK, Json(mask::<Model, _>(&result, true))).into_response()
+        },
+        Err(RepositoryError::InvalidModel) => {
+            (StatusCode::BAD_REQUEST, Json("The target can't hold the combined balance")).into_response()
+        },
+        Err(err) => {
+            log::error!("Error merging faery {} into {}: {:?}", request.source_id, target_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+    use http::StatusCode;
+    use serde_json::json;
+    use crate::repository::Repository;
+    use crate::repository::faery::Model as Faery;
+    use crate::testing;
+
+    #[tokio::test]
+    async fn test_merge_refuses_a_balance_the_target_cant_hold() {
+        let state = testing::state().await;
+        let admin_id = testing::create_player(&state, "admin@example.com", true).await;
+        let admin = testing::token(&state, admin_id, 60);
+        let target_id = state.faery_repository.create(Some(Faery::new("Mab".to_string(), "mab@example.com".to_string(), false, u32::MAX, None))).await.unwrap();
+        let source_id = state.faery_repository.create(Some(Faery::new("Puck".to_string(), "puck@example.com".to_string(), false, 1, None))).await.unwrap();
+        let uri = format!("/api/faeries/{}/merge", target_id);
+
+        for dry_run in [true, false] {
+            let body = json!({ "source_id": source_id, "dry_run": dry_run });
+            let response = crate::router(state.clone()).oneshot(testing::request("POST", &uri, Some(&admin), Some(body))).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        assert_eq!(state.faery_repository.get(source_id).await.unwrap().dross, 1);
+    }
+}
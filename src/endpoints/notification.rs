@@ -0,0 +1,52 @@
+use std::sync::Arc;
+use axum::extract::{Path, Query, State};
+use axum::extract::rejection::QueryRejection;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use axum::response::{IntoResponse, Response};
+use crate::DrossManagerState;
+use crate::auth::jwt::JWTAuthMiddleware;
+use crate::repository::notification::NotificationQuery;
+
+// list_my_notifications returns the caller's notifications, newest first, with their unread count.
+pub async fn list_my_notifications(
+    State(state): State<Arc<DrossManagerState>>,
+    Extension(auth): Extension<JWTAuthMiddleware>,
+    query: Result<Query<NotificationQuery>, QueryRejection>
+) -> Response {
+    let Query(query) = match query {
+        Ok(query) => query,
+        Err(err) => return (StatusCode::BAD_REQUEST, Json(err.body_text())).into_response(),
+    };
+    let player_id = auth.user.id.unwrap_or_default();
+    match state.notification_repository.for_player(player_id, &query).await {
+        Ok(page) => (StatusCode::OK, Json(page)).into_response(),
+        Err(err) => {
+            log::error!("Error listing notifications for player {}: {:?}", player_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn mark_read(
+    State(state): State<Arc<DrossManagerState>>,
+    Extension(auth): Extension<JWTAuthMiddleware>,
+    Path(notification_id): Path<i64>
+) -> Response {
+    let player_id = auth.user.id.unwrap_or_default();
+    match state.notification_repository.mark_read(player_id, notification_id).await {
+        Ok(_) => (StatusCode::NO_CONTENT, Json("")).into_response(),
+        Err(err) => (StatusCode::NOT_FOUND, Json(err)).into_response(),
+    }
+}
+
+pub async fn mark_all_read(State(state): State<Arc<DrossManagerState>>, Extension(auth): Extension<JWTAuthMiddleware>) -> Response {
+    let player_id = auth.user.id.unwrap_or_default();
+    match state.notification_repository.mark_all_read(player_id).await {
+        Ok(_) => (StatusCode::NO_CONTENT, Json("")).into_response(),
+        Err(err) => {
+            log::error!("Error marking notifications read for player {}: {:?}", player_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
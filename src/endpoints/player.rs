@@ -0,0 +1,296 @@
+use std::sync::Arc;
+use axum::extract::{Path, State};
+use axum::extract::rejection::JsonRejection;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use axum::response::{IntoResponse, Response};
+use crate::{messages, statement, DrossManagerState};
+use crate::auth::jwt::JWTAuthMiddleware;
+use crate::endpoints::registration;
+use crate::repository::{mask, Repository, RepositoryError};
+use crate::repository::player::{Model, PlayerRequest, PlayerResponse, PlayerUpdateRequest};
+
+// Mark: Admin
+
+// email_conflict refuses an address another player, archived or not, already signs in with or is verifying.
+async fn email_conflict(state: &DrossManagerState, email: &str, player_id: Option<i64>) -> Option<Response> {
+    match state.player_repository.email_in_use(email, player_id).await {
+        Ok(false) => None,
+        Ok(true) => Some((StatusCode::CONFLICT, Json(RepositoryError::AlreadyExists)).into_response()),
+        Err(err) => {
+            log::error!("Error checking whether {} is in use: {:?}", email, err);
+            Some((StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response())
+        }
+    }
+}
+
+pub async fn list_players(State(state): State<Arc<DrossManagerState>>) -> Response {
+    log::info!("Getting all players");
+    match state.player_repository.get_all().await {
+        Ok(players) => {
+            let players: Vec<PlayerResponse> = players.into_iter().map(PlayerResponse::from).collect();
+            (StatusCode::OK, Json(mask::<Model, _>(&players, true))).into_response()
+        },
+        Err(err) => {
+            log::error!("Error getting all players: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn get_player(State(state): State<Arc<DrossManagerState>>, Path(player_id): Path<i64>) -> Response {
+    match state.player_repository.get(player_id).await {
+        Ok(player) => (StatusCode::OK, Json(mask::<Model, _>(&PlayerResponse::from(player), true))).into_response(),
+        Err(RepositoryError::NotFound) => (StatusCode::NOT_FOUND, Json("Not Found")).into_response(),
+        Err(err) => {
+            log::error!("Error getting player {}: {:?}", player_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn create_player(
+    State(state): State<Arc<DrossManagerState>>,
+    payload: Result<Json<PlayerRequest>, JsonRejection>
+) -> Response {
+    match payload {
+        Ok(Json(payload)) => {
+            log::info!("Creating player: {:?}", payload);
+            if let Some(response) = email_conflict(&state, &payload.auth_email, None).await {
+                return response;
+            }
+            let player: Model = payload.into();
+            match state.player_repository.create(Some(player.clone())).await {
+                Ok(id) => {
+                    let player = Model { id: Some(id), ..player };
+                    (StatusCode::CREATED, Json(mask::<Model, _>(&PlayerResponse::from(player), true))).into_response()
+                },
+                Err(RepositoryError::AlreadyExists) => (StatusCode::CONFLICT, Json(RepositoryError::AlreadyExists)).into_response(),
+                Err(err) => {
+                    log::error!("Error creating player: {:?}", err);
+                    (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+                }
+            }
+        },
+        Err(err) => {
+            log::error!("Error creating player: {:?}", err);
+            let repo_error: RepositoryError = err.into();
+            (StatusCode::BAD_REQUEST, Json(repo_error)).into_response()
+        }
+    }
+}
+
+pub async fn update_player(
+    State(state): State<Arc<DrossManagerState>>,
+    Path(player_id): Path<i64>,
+    payload: Result<Json<PlayerUpdateRequest>, JsonRejection>
+) -> Response {
+    let request = match payload {
+        Ok(Json(request)) => request,
+        Err(err) => {
+            log::error!("Error updating player {}: {:?}", player_id, err);
+            let repo_error: RepositoryError = err.into();
+            return (StatusCode::BAD_REQUEST, Json(repo_error)).into_response();
+        }
+    };
+    let existing = match state.player_repository.get(player_id).await {
+        Ok(existing) => existing,
+        Err(_) => return (StatusCode::NOT_FOUND, Json("Not Found")).into_response(),
+    };
+    log::info!("Updating player {}: {:?}", player_id, request);
+    if let Some(response) = email_conflict(&state, &request.auth_email, Some(player_id)).await {
+        return response;
+    }
+    // Login tokens and registration state aren't part of the request, so carry them over from the stored player
+    let player = Model {
+        id: Some(player_id),
+        auth_token: existing.auth_token,
+        auth_token_expires: existing.auth_token_expires,
+        status: existing.status,
+        verification_token: existing.verification_token,
+        verification_expires: existing.verification_expires,
+        deleted_at: existing.deleted_at,
+        pending_email: existing.pending_email,
+        email_suppressed: existing.email_suppressed,
+        ..Model::from(request)
+    };
+    match state.player_repository.save(player.clone()).await {
+        Ok(_) => (StatusCode::OK, Json(mask::<Model, _>(&PlayerResponse::from(player), true))).into_response(),
+        Err(RepositoryError::AlreadyExists) => (StatusCode::CONFLICT, Json(RepositoryError::AlreadyExists)).into_response(),
+        Err(err) => {
+            log::error!("Error updating player {}: {:?}", player_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn delete_player(
+    State(state): State<Arc<DrossManagerState>>,
+    Extension(auth): Extension<JWTAuthMiddleware>,
+    Path(player_id): Path<i64>
+) -> Response {
+    if auth.user.id == Some(player_id) {
+        return (StatusCode::CONFLICT, Json("You can't delete your own account")).into_response();
+    }
+    log::info!("Deleting player {}", player_id);
+    match state.player_repository.delete(player_id).await {
+        Ok(_) => {
+            state.live_events.disconnect(player_id);
+            (StatusCode::NO_CONTENT, Json("")).into_response()
+        },
+        Err(err) => {
+            log::error!("Error deleting player {}: {:?}", player_id, err);
+            (StatusCode::NOT_FOUND, Json(err)).into_response()
+        }
+    }
+}
+
+// Mark: Self-service
+
+// A player always sees their own details, so self-service responses are masked as for an admin.
+pub async fn get_me(Extension(auth): Extension<JWTAuthMiddleware>) -> Response {
+    (StatusCode::OK, Json(mask::<Model, _>(&PlayerResponse { player: auth.user }, true))).into_response()
+}
+
+// get_my_statement previews the next weekly statement, covering the time since the last one.
+pub async fn get_my_statement(
+    State(state): State<Arc<DrossManagerState>>,
+    Extension(auth): Extension<JWTAuthMiddleware>
+) -> Response {
+    let player_id = auth.user.id.unwrap_or_default();
+    let player = match state.player_repository.get(player_id).await {
+        Ok(player) => player,
+        Err(err) => return (StatusCode::NOT_FOUND, Json(err)).into_response(),
+    };
+    match statement::preview_statement(&state, &player).await {
+        Ok(preview) => (StatusCode::OK, Json(preview)).into_response(),
+        Err(err) => {
+            log::error!("Error previewing the statement for player {}: {:?}", player_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+// update_me lets a player edit their own profile. PlayerRequest has no is_admin field,
+// and the stored admin flag is always kept, so players can't promote themselves.
+// A new email is only used once the player follows the link sent to it.
+pub async fn update_me(
+    State(state): State<Arc<DrossManagerState>>,
+    Extension(auth): Extension<JWTAuthMiddleware>,
+    payload: Result<Json<PlayerRequest>, JsonRejection>
+) -> Response {
+    let request = match payload {
+        Ok(Json(request)) => request,
+        Err(err) => {
+            let repo_error: RepositoryError = err.into();
+            return (StatusCode::BAD_REQUEST, Json(repo_error)).into_response();
+        }
+    };
+    let player_id = match auth.user.id {
+        Some(player_id) => player_id,
+        None => return (StatusCode::UNAUTHORIZED, Json("Unknown player")).into_response(),
+    };
+    let mut player = match state.player_repository.get(player_id).await {
+        Ok(player) => player,
+        Err(_) => return (StatusCode::NOT_FOUND, Json("Not Found")).into_response(),
+    };
+    log::info!("Player {} updating their profile", player_id);
+    player.apply_profile(request);
+    let token = match player.pending_email.clone() {
+        Some(email) => {
+            match state.player_repository.email_in_use(&email, Some(player_id)).await {
+                Ok(false) => Some(player.start_verification()),
+                Ok(true) => return (StatusCode::CONFLICT, Json(RepositoryError::AlreadyExists)).into_response(),
+                Err(err) => {
+                    log::error!("Error checking the new email for player {}: {:?}", player_id, err);
+                    return (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response();
+                }
+            }
+        },
+        None => None,
+    };
+    if let Err(err) = state.player_repository.save(player.clone()).await {
+        log::error!("Error updating player {}: {:?}", player_id, err);
+        return (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response();
+    }
+    if let (Some(email), Some(token)) = (&player.pending_email, token) {
+        let link = registration::verification_link(&state, &token);
+        if let Err(err) = messages::send_verification_link(&state, email, &link).await {
+            log::error!("Error sending verification email to player {}: {:?}", player_id, err);
+        }
+    }
+    (StatusCode::OK, Json(mask::<Model, _>(&PlayerResponse::from(player), true))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+    use http::StatusCode;
+    use serde_json::json;
+    use crate::repository::Repository;
+    use crate::repository::player::PlayerStatus;
+    use crate::testing;
+
+    #[tokio::test]
+    async fn test_update_player_keeps_status() {
+        let state = testing::state().await;
+        let admin_id = testing::create_player(&state, "admin@example.com", true).await;
+        let player_id = testing::create_player(&state, "player@example.com", false).await;
+        let mut player = state.player_repository.get(player_id).await.unwrap();
+        player.status = PlayerStatus::PendingApproval;
+        state.player_repository.save(player).await.unwrap();
+
+        let body = json!({ "first_name": "John", "last_name": "Darling", "auth_email": "player@example.com", "mailing_address": "", "is_admin": false });
+        let request = testing::request("PUT", &format!("/api/players/{}", player_id), Some(&testing::token(&state, admin_id, 60)), Some(body));
+        let response = crate::router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let player = state.player_repository.get(player_id).await.unwrap();
+        assert_eq!(player.first_name, "John");
+        assert_eq!(player.status, PlayerStatus::PendingApproval);
+    }
+
+    #[tokio::test]
+    async fn test_update_me_verifies_a_new_email() {
+        let state = testing::state().await;
+        let player_id = testing::create_player(&state, "player@example.com", false).await;
+        testing::create_player(&state, "taken@example.com", false).await;
+        let token = testing::token(&state, player_id, 60);
+        let profile = |email: &str| json!({ "first_name": "Wendy", "last_name": "Darling", "auth_email": email, "mailing_address": "", "is_admin": true });
+
+        let request = testing::request("PUT", "/api/me", Some(&token), Some(profile("taken@example.com")));
+        let response = crate::router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let request = testing::request("PUT", "/api/me", Some(&token), Some(profile("new@example.com")));
+        let response = crate::router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let player = state.player_repository.get(player_id).await.unwrap();
+        assert_eq!(player.auth_email, "player@example.com");
+        assert_eq!(player.pending_email.as_deref(), Some("new@example.com"));
+        assert!(!player.is_admin);
+
+        let uri = format!("/api/register/verify?token={}", player.verification_token.unwrap());
+        let response = crate::router(state.clone()).oneshot(testing::request("GET", &uri, None, None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let player = state.player_repository.get(player_id).await.unwrap();
+        assert_eq!(player.auth_email, "new@example.com");
+        assert_eq!(player.pending_email, None);
+        assert_eq!(player.status, PlayerStatus::Active);
+    }
+
+    #[tokio::test]
+    async fn test_admins_cant_reuse_an_email() {
+        let state = testing::state().await;
+        let admin_id = testing::create_player(&state, "admin@example.com", true).await;
+        let player_id = testing::create_player(&state, "player@example.com", false).await;
+        let admin = testing::token(&state, admin_id, 60);
+        let send = |method: &str, uri: String, body| crate::router(state.clone()).oneshot(testing::request(method, &uri, Some(&admin), Some(body)));
+        let profile = |email: &str| json!({ "first_name": "John", "last_name": "Darling", "auth_email": email, "mailing_address": "", "is_admin": false });
+
+        assert_eq!(send("POST", "/api/players".to_string(), profile("Player@example.com")).await.unwrap().status(), StatusCode::CONFLICT);
+        assert_eq!(send("PUT", format!("/api/players/{}", player_id), profile("ADMIN@example.com")).await.unwrap().status(), StatusCode::CONFLICT);
+        assert_eq!(state.player_repository.get(player_id).await.unwrap().auth_email, "player@example.com");
+        assert_eq!(send("PUT", format!("/api/players/{}", player_id), profile("player@example.com")).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(send("POST", "/api/players".to_string(), profile("john@example.com")).await.unwrap().status(), StatusCode::CREATED);
+    }
+}
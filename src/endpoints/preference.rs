@@ -0,0 +1,93 @@
+use std::sync::Arc;
+use axum::extract::{Query, State};
+use axum::extract::rejection::JsonRejection;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use axum::response::{Html, IntoResponse, Response};
+use serde::Deserialize;
+use crate::DrossManagerState;
+use crate::auth::jwt::{verify_claims, JWTAuthMiddleware};
+use crate::messages::UnsubscribeClaims;
+use crate::repository::{Repository, RepositoryError};
+use crate::repository::preference::NotificationPreferences;
+use crate::repository::template::escape_html;
+
+#[derive(Debug, Deserialize)]
+pub struct UnsubscribeQuery {
+    pub token: String,
+}
+
+pub async fn get_my_preferences(
+    State(state): State<Arc<DrossManagerState>>,
+    Extension(auth): Extension<JWTAuthMiddleware>
+) -> Response {
+    let player_id = auth.user.id.unwrap_or_default();
+    match state.preference_repository.get(player_id).await {
+        Ok(preferences) => (StatusCode::OK, Json(preferences)).into_response(),
+        Err(err) => {
+            log::error!("Error getting preferences for player {}: {:?}", player_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn update_my_preferences(
+    State(state): State<Arc<DrossManagerState>>,
+    Extension(auth): Extension<JWTAuthMiddleware>,
+    payload: Result<Json<NotificationPreferences>, JsonRejection>
+) -> Response {
+    let preferences = match payload {
+        Ok(Json(preferences)) => preferences,
+        Err(err) => {
+            let repo_error: RepositoryError = err.into();
+            return (StatusCode::BAD_REQUEST, Json(repo_error)).into_response();
+        }
+    };
+    let player_id = auth.user.id.unwrap_or_default();
+    log::info!("Updating notification preferences for player {}", player_id);
+    match state.preference_repository.store(player_id, &preferences).await {
+        Ok(_) => (StatusCode::OK, Json(preferences)).into_response(),
+        Err(err) => {
+            log::error!("Error updating preferences for player {}: {:?}", player_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+fn unsubscribe_claims(state: &DrossManagerState, token: &str) -> Option<(i64, UnsubscribeClaims)> {
+    let claims: UnsubscribeClaims = verify_claims(token, state.jwt_key_pair.public_key.to_owned()).ok()?;
+    claims.player_id().map(|player_id| (player_id, claims))
+}
+
+// unsubscribe_page is where the link in an email leads. It only asks for confirmation, since
+// mail scanners follow links; the form posts back to unsubscribe.
+pub async fn unsubscribe_page(State(state): State<Arc<DrossManagerState>>, Query(query): Query<UnsubscribeQuery>) -> Response {
+    let Some((_, claims)) = unsubscribe_claims(&state, &query.token) else {
+        return (StatusCode::BAD_REQUEST, Html("This unsubscribe link isn't valid.".to_string())).into_response();
+    };
+    let what = match claims.category {
+        Some(category) => format!("{} emails", category.as_str()),
+        None => "all notification emails".to_string(),
+    };
+    Html(format!(
+        "<!DOCTYPE html><html><body><form method=\"post\" action=\"/api/unsubscribe?token={}\">\
+        <p>Stop sending me {}?</p><button type=\"submit\">Unsubscribe</button></form></body></html>",
+        escape_html(&query.token),
+        what
+    )).into_response()
+}
+
+// unsubscribe handles both the confirmation form and one-click List-Unsubscribe-Post requests.
+pub async fn unsubscribe(State(state): State<Arc<DrossManagerState>>, Query(query): Query<UnsubscribeQuery>) -> Response {
+    let Some((player_id, claims)) = unsubscribe_claims(&state, &query.token) else {
+        return (StatusCode::BAD_REQUEST, Html("This unsubscribe link isn't valid.".to_string())).into_response();
+    };
+    log::info!("Unsubscribing player {} from {:?}", player_id, claims.category);
+    match state.preference_repository.unsubscribe(player_id, claims.category).await {
+        Ok(_) => Html("You've been unsubscribed. You can change this any time in your notification settings.".to_string()).into_response(),
+        Err(err) => {
+            log::error!("Error unsubscribing player {}: {:?}", player_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Html("Something went wrong; please try again later.".to_string())).into_response()
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use std::sync::Arc;
+use axum::extract::{Path, State};
+use axum::extract::rejection::JsonRejection;
+use axum::http::{header, StatusCode};
+use axum::{Extension, Json};
+use axum::response::{IntoResponse, Response};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use crate::DrossManagerState;
+use crate::auth::jwt::JWTAuthMiddleware;
+use crate::repository::{mask, Repository, RepositoryError, RepositoryResult};
+use crate::repository::achievement::Badge;
+use crate::repository::attribute::Attributes;
+use crate::repository::email::SentEmail;
+use crate::repository::faery::Model as Faery;
+use crate::repository::ledger::Entry;
+use crate::repository::player::{Model as Player, PlayerData};
+
+// PlayerExport is everything we hold about a player, for GET /api/me/export.
+#[derive(Debug, Serialize)]
+pub struct PlayerExport {
+    pub exported_at: i64,
+    pub player: PlayerData,
+    pub faeries: Vec<FaeryExport>,
+    pub emails: Vec<SentEmail>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FaeryExport {
+    pub faery: Faery,
+    pub tags: Vec<String>,
+    pub attributes: Attributes,
+    pub badges: Vec<Badge>,
+    pub ledger: Vec<Entry>,
+}
+
+// ErasureRequest has the player retype their email address to confirm the erasure.
+#[derive(Debug, Deserialize)]
+pub struct ErasureRequest {
+    pub confirm_email: String,
+}
+
+async fn export(state: &DrossManagerState, player: PlayerData) -> RepositoryResult<PlayerExport> {
+    let mut faeries = Vec::new();
+    for faery in state.faery_repository.owned_by(&player.auth_email).await? {
+        let faery_id = faery.id.unwrap_or(0);
+        faeries.push(FaeryExport {
+            tags: state.faery_repository.tags_for(faery_id).await?,
+            attributes: state.attribute_repository.values_for(faery_id).await?,
+            badges: state.achievement_repository.badges_for(faery_id).await?,
+            ledger: state.ledger_repository.get_for_faery(faery_id).await?,
+            faery,
+        });
+    }
+    Ok(PlayerExport {
+        exported_at: Utc::now().timestamp_millis(),
+        emails: state.email_repository.sent_to(&player.auth_email).await?,
+        player,
+        faeries,
+    })
+}
+
+pub async fn export_me(State(state): State<Arc<DrossManagerState>>, Extension(auth): Extension<JWTAuthMiddleware>) -> Response {
+    let player_id = auth.user.id.unwrap_or(0);
+    log::info!("Exporting data for player {}", player_id);
+    match export(&state, auth.user).await {
+        Ok(archive) => {
+            let disposition = format!("attachment; filename=\"fe-vault-player-{}.json\"", player_id);
+            (StatusCode::OK, [(header::CONTENT_DISPOSITION, disposition)], Json(mask::<Player, _>(&archive, true))).into_response()
+        },
+        Err(err) => {
+            log::error!("Error exporting data for player {}: {:?}", player_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+async fn erase(state: &DrossManagerState, player_id: i64) -> Response {
+    log::info!("Erasing personal data of player {}", player_id);
+    match state.player_repository.erase(player_id).await {
+        Ok(_) => {
+            state.live_events.disconnect(player_id);
+            (StatusCode::NO_CONTENT, Json("")).into_response()
+        },
+        Err(RepositoryError::NotFound) => (StatusCode::NOT_FOUND, Json(RepositoryError::NotFound)).into_response(),
+        Err(err) => {
+            log::error!("Error erasing player {}: {:?}", player_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+// erase_me anonymizes the caller's account and signs them out for good.
+pub async fn erase_me(
+    State(state): State<Arc<DrossManagerState>>,
+    Extension(auth): Extension<JWTAuthMiddleware>,
+    payload: Result<Json<ErasureRequest>, JsonRejection>
+) -> Response {
+    let request = match payload {
+        Ok(Json(request)) => request,
+        Err(err) => {
+            let repo_error: RepositoryError = err.into();
+            return (StatusCode::BAD_REQUEST, Json(repo_error)).into_response();
+        }
+    };
+    if !request.confirm_email.trim().eq_ignore_ascii_case(&auth.user.auth_email) {
+        return (StatusCode::BAD_REQUEST, Json("confirm_email doesn't match your account")).into_response();
+    }
+    match auth.user.id {
+        Some(player_id) => erase(&state, player_id).await,
+        None => (StatusCode::UNAUTHORIZED, Json("Unknown player")).into_response(),
+    }
+}
+
+pub async fn erase_player(
+    State(state): State<Arc<DrossManagerState>>,
+    Extension(auth): Extension<JWTAuthMiddleware>,
+    Path(player_id): Path<i64>
+) -> Response {
+    if auth.user.id == Some(player_id) {
+        return (StatusCode::CONFLICT, Json("Use /api/me/erase to erase your own account")).into_response();
+    }
+    erase(&state, player_id).await
+}
+
+// export_player lets an admin produce the same archive on a player's behalf.
+pub async fn export_player(State(state): State<Arc<DrossManagerState>>, Path(player_id): Path<i64>) -> Response {
+    let player = match state.player_repository.get(player_id).await {
+        Ok(player) => PlayerData::from(player),
+        Err(err) => return (StatusCode::NOT_FOUND, Json(err)).into_response(),
+    };
+    match export(&state, player).await {
+        Ok(archive) => (StatusCode::OK, Json(mask::<Player, _>(&archive, true))).into_response(),
+        Err(err) => {
+            log::error!("Error exporting data for player {}: {:?}", player_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+    use http::StatusCode;
+    use serde_json::json;
+    use crate::mailer::Email;
+    use crate::repository::Repository;
+    use crate::repository::faery::Model as Faery;
+    use crate::repository::ledger::{verify_chain, Entry, EntryKind};
+    use crate::testing;
+
+    #[tokio::test]
+    async fn test_erase_me_pseudonymizes_and_drops_mail() {
+        let state = testing::state().await;
+        let player_id = testing::create_player(&state, "wendy@example.com", false).await;
+        let token = testing::token(&state, player_id, 60);
+        let faery_id = state.faery_repository.create(Some(Faery::new("Tink".to_string(), "Wendy@example.com".to_string(), false, 5, None))).await.unwrap();
+        state.ledger_repository.save(Entry::new(faery_id, 5, 5, EntryKind::Grant, "Welcome".to_string())).await.unwrap();
+        state.email_repository.queue(Email::new("wendy@example.com", "Welcome", "Your login link")).await.unwrap();
+        state.email_repository.deliver_due(10).await.unwrap();
+        state.email_repository.queue(Email::new("wendy@example.com", "Receipt", "You got 5 dross")).await.unwrap();
+        state.email_repository.queue(Email::new("john@example.com", "Receipt", "You got 5 dross")).await.unwrap();
+
+        let erase = |email: &str| testing::request("POST", "/api/me/erase", Some(&token), Some(json!({ "confirm_email": email })));
+        let response = crate::router(state.clone()).oneshot(erase("john@example.com")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = crate::router(state.clone()).oneshot(erase("wendy@example.com")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let pseudonym = format!("erased-player-{}@invalid", player_id);
+        let player = state.player_repository.archived().await.unwrap().remove(0);
+        assert_eq!((player.first_name.as_str(), player.auth_email.as_str(), player.mailing_address.as_str()), ("Erased", pseudonym.as_str(), ""));
+        assert_eq!(state.faery_repository.get(faery_id).await.unwrap().email(), pseudonym);
+        // The ledger is untouched, so the chain still verifies
+        assert_eq!(state.ledger_repository.get_for_faery(faery_id).await.unwrap().len(), 1);
+        let (entries, head) = state.ledger_repository.chain().await.unwrap();
+        assert!(verify_chain(&entries, &head).valid);
+        // The email log keeps a pseudonymous record, but no queued or sent message survives
+        assert!(state.email_repository.sent_to("wendy@example.com").await.unwrap().is_empty());
+        assert_eq!(state.email_repository.sent_to(&pseudonym).await.unwrap().len(), 1);
+        let outbox = state.email_repository.outbox(None).await.unwrap();
+        assert_eq!(outbox.iter().map(|message| message.recipient.as_str()).collect::<Vec<_>>(), vec!["john@example.com"]);
+
+        // Their token stops working once the account is archived
+        let response = crate::router(state.clone()).oneshot(testing::request("GET", "/api/me", Some(&token), None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
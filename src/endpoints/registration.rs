@@ -0,0 +1,205 @@
+use std::sync::Arc;
+use axum::extract::{Path, Query, State};
+use axum::extract::rejection::JsonRejection;
+use axum::http::StatusCode;
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use chrono::Utc;
+use serde::Deserialize;
+use crate::DrossManagerState;
+use crate::messages;
+use crate::repository::{mask, Repository, RepositoryError};
+use crate::repository::player::{Model, PlayerRequest, PlayerResponse, PlayerStatus};
+use crate::repository::settings::RegistrationSettings;
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyQuery {
+    pub token: String,
+}
+
+// register creates an unverified player and emails them a link to confirm their address.
+pub async fn register(
+    State(state): State<Arc<DrossManagerState>>,
+    payload: Result<Json<PlayerRequest>, JsonRejection>
+) -> Response {
+    let request = match payload {
+        Ok(Json(request)) => request,
+        Err(err) => {
+            let repo_error: RepositoryError = err.into();
+            return (StatusCode::BAD_REQUEST, Json(repo_error)).into_response();
+        }
+    };
+    let settings: RegistrationSettings = match state.settings_repository.load(RegistrationSettings::KEY).await {
+        Ok(settings) => settings,
+        Err(err) => {
+            log::error!("Error loading registration settings: {:?}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response();
+        }
+    };
+    if settings.is_blocked(&request.auth_email) {
+        log::info!("Rejected registration from blocked address {}", request.auth_email);
+        return (StatusCode::FORBIDDEN, Json("Registration isn't available for this email address")).into_response();
+    }
+    // Archived players keep their address, so it can't be registered again until they're purged.
+    // The unique index on auth_email is what actually stops duplicates; this just answers sooner.
+    match state.player_repository.email_in_use(&request.auth_email, None).await {
+        Ok(false) => {},
+        Ok(true) => return (StatusCode::CONFLICT, Json(RepositoryError::AlreadyExists)).into_response(),
+        Err(err) => {
+            log::error!("Error checking a registration email: {:?}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response();
+        }
+    }
+
+    let mut player: Model = request.into();
+    let token = player.start_verification();
+    player.status = PlayerStatus::PendingVerification;
+    let player_id = match state.player_repository.create(Some(player.clone())).await {
+        Ok(player_id) => player_id,
+        Err(RepositoryError::AlreadyExists) => return (StatusCode::CONFLICT, Json(RepositoryError::AlreadyExists)).into_response(),
+        Err(err) => {
+            log::error!("Error registering player: {:?}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response();
+        }
+    };
+    log::info!("Registered player {}, awaiting verification", player_id);
+
+    if let Err(err) = messages::send_verification_link(&state, &player.auth_email, &verification_link(&state, &token)).await {
+        log::error!("Error sending verification email to player {}: {:?}", player_id, err);
+    }
+    let player = Model { id: Some(player_id), ..player };
+    (StatusCode::CREATED, Json(mask::<Model, _>(&PlayerResponse::from(player), true))).into_response()
+}
+
+pub(crate) fn verification_link(state: &DrossManagerState, token: &str) -> String {
+    format!("{}/api/register/verify?token={}", state.app_url, token)
+}
+
+// verify_registration activates the player behind a verification link, or hands them to an
+// admin for approval when that's required. For a player who changed their email, it confirms
+// the new address instead.
+pub async fn verify_registration(
+    State(state): State<Arc<DrossManagerState>>,
+    Query(query): Query<VerifyQuery>
+) -> Response {
+    let mut player = match state.player_repository.find_by_verification_token(&query.token).await {
+        Ok(player) => player,
+        Err(_) => return (StatusCode::NOT_FOUND, Json("This link is invalid or has already been used")).into_response(),
+    };
+    if player.verification_expires.unwrap_or(0) < Utc::now().timestamp_millis() {
+        return (StatusCode::GONE, Json("This link has expired")).into_response();
+    }
+    match player.pending_email.clone() {
+        Some(email) => {
+            if state.player_repository.email_in_use(&email, player.id).await.unwrap_or(true) {
+                return (StatusCode::CONFLICT, Json(RepositoryError::AlreadyExists)).into_response();
+            }
+        },
+        None => {
+            let settings: RegistrationSettings = state.settings_repository
+                .load(RegistrationSettings::KEY)
+                .await
+                .unwrap_or_default();
+            player.status = if settings.require_approval { PlayerStatus::PendingApproval } else { PlayerStatus::Active };
+        }
+    }
+    player.verify_email();
+    match state.player_repository.save(player.clone()).await {
+        Ok(_) => {
+            log::info!("Player {:?} verified their email ({:?})", player.id, player.status);
+            (StatusCode::OK, Json(mask::<Model, _>(&PlayerResponse::from(player), true))).into_response()
+        },
+        Err(RepositoryError::AlreadyExists) => (StatusCode::CONFLICT, Json(RepositoryError::AlreadyExists)).into_response(),
+        Err(err) => {
+            log::error!("Error verifying player {:?}: {:?}", player.id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+// Mark: Admin
+
+pub async fn approve_player(State(state): State<Arc<DrossManagerState>>, Path(player_id): Path<i64>) -> Response {
+    let mut player = match state.player_repository.get(player_id).await {
+        Ok(player) => player,
+        Err(_) => return (StatusCode::NOT_FOUND, Json("Not Found")).into_response(),
+    };
+    if player.status != PlayerStatus::PendingApproval {
+        return (StatusCode::CONFLICT, Json("Player isn't awaiting approval")).into_response();
+    }
+    player.status = PlayerStatus::Active;
+    match state.player_repository.save(player.clone()).await {
+        Ok(_) => {
+            log::info!("Approved player {}", player_id);
+            (StatusCode::OK, Json(mask::<Model, _>(&PlayerResponse::from(player), true))).into_response()
+        },
+        Err(err) => {
+            log::error!("Error approving player {}: {:?}", player_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn get_registration_settings(State(state): State<Arc<DrossManagerState>>) -> Response {
+    match state.settings_repository.load::<RegistrationSettings>(RegistrationSettings::KEY).await {
+        Ok(settings) => (StatusCode::OK, Json(settings)).into_response(),
+        Err(err) => {
+            log::error!("Error loading registration settings: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn update_registration_settings(
+    State(state): State<Arc<DrossManagerState>>,
+    payload: Result<Json<RegistrationSettings>, JsonRejection>
+) -> Response {
+    let settings = match payload {
+        Ok(Json(settings)) => settings,
+        Err(err) => {
+            let repo_error: RepositoryError = err.into();
+            return (StatusCode::BAD_REQUEST, Json(repo_error)).into_response();
+        }
+    };
+    log::info!("Updating registration settings: {:?}", settings);
+    match state.settings_repository.store(RegistrationSettings::KEY, &settings).await {
+        Ok(_) => (StatusCode::OK, Json(settings)).into_response(),
+        Err(err) => {
+            log::error!("Error saving registration settings: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+    use http::StatusCode;
+    use serde_json::json;
+    use crate::repository::{Repository, RepositoryError};
+    use crate::repository::player::Model;
+    use crate::testing;
+
+    #[tokio::test]
+    async fn test_register_rejects_addresses_in_use() {
+        let state = testing::state().await;
+        let archived_id = testing::create_player(&state, "archived@example.com", false).await;
+        state.player_repository.delete(archived_id).await.unwrap();
+        let register = |email: &str| {
+            let body = json!({ "first_name": "Peter", "last_name": "Pan", "auth_email": email, "mailing_address": "" });
+            testing::request("POST", "/api/register", None, Some(body))
+        };
+
+        let response = crate::router(state.clone()).oneshot(register("Archived@example.com")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = crate::router(state.clone()).oneshot(register("new@example.com")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = crate::router(state.clone()).oneshot(register("new@example.com")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        // Two registrations racing past the check still can't share an address
+        let player = state.player_repository.get(archived_id + 1).await.unwrap();
+        let duplicate = Model { id: None, auth_email: "NEW@example.com".to_string(), ..player };
+        assert!(matches!(state.player_repository.create(Some(duplicate)).await, Err(RepositoryError::AlreadyExists)));
+    }
+}
@@ -0,0 +1,90 @@
+use std::sync::Arc;
+use axum::extract::{Path, State};
+use axum::extract::rejection::JsonRejection;
+use axum::http::StatusCode;
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use crate::DrossManagerState;
+use crate::repository::{mask, Repository, RepositoryError};
+use crate::repository::snapshot::{diff, CreateSnapshotRequest, Snapshot};
+
+pub async fn list_snapshots(State(state): State<Arc<DrossManagerState>>) -> Response {
+    match state.snapshot_repository.get_all().await {
+        Ok(res) => (StatusCode::OK, Json(mask::<Snapshot, _>(&res, true))).into_response(),
+        Err(err) => {
+            log::error!("Error getting all snapshots: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn create_snapshot(
+    State(state): State<Arc<DrossManagerState>>,
+    payload: Result<Json<CreateSnapshotRequest>, JsonRejection>
+) -> Response {
+    let request = match payload {
+        Ok(Json(request)) => request,
+        Err(err) => {
+            log::error!("Error creating snapshot: {:?}", err);
+            let repo_error: RepositoryError = err.into();
+            return (StatusCode::BAD_REQUEST, Json(repo_error)).into_response();
+        }
+    };
+    log::info!("Taking snapshot {}", request.name);
+    let snapshot = Snapshot { id: None, name: request.name, taken_at: 0, balances: vec![] };
+    let created = match state.snapshot_repository.create(Some(snapshot)).await {
+        Ok(id) => state.snapshot_repository.get(id).await,
+        Err(err) => Err(err),
+    };
+    match created {
+        Ok(snapshot) => (StatusCode::CREATED, Json(mask::<Snapshot, _>(&snapshot, true))).into_response(),
+        Err(RepositoryError::AlreadyExists) => {
+            (StatusCode::CONFLICT, Json(RepositoryError::AlreadyExists)).into_response()
+        },
+        Err(err) => {
+            log::error!("Error creating snapshot: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn get_snapshot(State(state): State<Arc<DrossManagerState>>, Path(snapshot_id): Path<i64>) -> Response {
+    match state.snapshot_repository.get(snapshot_id).await {
+        Ok(snapshot) => (StatusCode::OK, Json(mask::<Snapshot, _>(&snapshot, true))).into_response(),
+        Err(RepositoryError::NotFound) => (StatusCode::NOT_FOUND, Json("Not Found")).into_response(),
+        Err(err) => {
+            log::error!("Error getting snapshot {}: {:?}", snapshot_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn delete_snapshot(State(state): State<Arc<DrossManagerState>>, Path(snapshot_id): Path<i64>) -> Response {
+    log::info!("Deleting snapshot {}", snapshot_id);
+    match state.snapshot_repository.delete(snapshot_id).await {
+        Ok(_) => (StatusCode::NO_CONTENT, Json("")).into_response(),
+        Err(RepositoryError::NotFound) => (StatusCode::NOT_FOUND, Json(RepositoryError::NotFound)).into_response(),
+        Err(err) => {
+            log::error!("Error deleting snapshot {}: {:?}", snapshot_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn diff_snapshots(
+    State(state): State<Arc<DrossManagerState>>,
+    Path((snapshot_id, other_id)): Path<(i64, i64)>
+) -> Response {
+    let before = state.snapshot_repository.get(snapshot_id).await;
+    let after = state.snapshot_repository.get(other_id).await;
+    match (before, after) {
+        (Ok(before), Ok(after)) => (StatusCode::OK, Json(diff(&before, &after))).into_response(),
+        (Err(RepositoryError::NotFound), _) | (_, Err(RepositoryError::NotFound)) => {
+            (StatusCode::NOT_FOUND, Json("Not Found")).into_response()
+        },
+        (Err(err), _) | (_, Err(err)) => {
+            log::error!("Error diffing snapshots {} and {}: {:?}", snapshot_id, other_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
@@ -0,0 +1,18 @@
+use std::sync::Arc;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use crate::DrossManagerState;
+use crate::stats::StatsQuery;
+
+pub async fn get_stats(State(state): State<Arc<DrossManagerState>>, Query(query): Query<StatsQuery>) -> Response {
+    log::info!("Getting economy statistics: {:?}", query);
+    match state.stats_cache.get_or_compute(&state, query).await {
+        Ok(stats) => (StatusCode::OK, Json(stats)).into_response(),
+        Err(err) => {
+            log::error!("Error computing statistics: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
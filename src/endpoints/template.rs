@@ -0,0 +1,144 @@
+use std::sync::Arc;
+use axum::extract::{Path, State};
+use axum::extract::rejection::JsonRejection;
+use axum::http::StatusCode;
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use crate::DrossManagerState;
+use crate::repository::{Repository, RepositoryError};
+use crate::repository::settings::Branding;
+use crate::repository::template::{EmailTemplate, Variables};
+
+// Previews are rendered for this address; nothing is sent
+const PREVIEW_RECIPIENT: &str = "preview@example.com";
+
+// Mark: Branding
+
+pub async fn get_branding(State(state): State<Arc<DrossManagerState>>) -> Response {
+    match state.settings_repository.load::<Branding>(Branding::KEY).await {
+        Ok(branding) => (StatusCode::OK, Json(branding)).into_response(),
+        Err(err) => {
+            log::error!("Error loading branding: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+pub async fn update_branding(
+    State(state): State<Arc<DrossManagerState>>,
+    payload: Result<Json<Branding>, JsonRejection>
+) -> Response {
+    let branding = match payload {
+        Ok(Json(branding)) => branding,
+        Err(err) => {
+            let repo_error: RepositoryError = err.into();
+            return (StatusCode::BAD_REQUEST, Json(repo_error)).into_response();
+        }
+    };
+    let problems = branding.problems();
+    if !problems.is_empty() {
+        return (StatusCode::UNPROCESSABLE_ENTITY, Json(problems)).into_response();
+    }
+    log::info!("Updating branding: {:?}", branding);
+    match state.settings_repository.store(Branding::KEY, &branding).await {
+        Ok(_) => (StatusCode::OK, Json(branding)).into_response(),
+        Err(err) => {
+            log::error!("Error saving branding: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+// Mark: Templates
+
+pub async fn list_templates(State(state): State<Arc<DrossManagerState>>) -> Response {
+    match state.template_repository.get_all().await {
+        Ok(templates) => (StatusCode::OK, Json(templates)).into_response(),
+        Err(err) => {
+            log::error!("Error listing email templates: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+// get_template_versions lists every version of a template, newest first.
+pub async fn get_template_versions(State(state): State<Arc<DrossManagerState>>, Path(name): Path<String>) -> Response {
+    if EmailTemplate::builtin(&name).is_none() {
+        return (StatusCode::NOT_FOUND, Json(RepositoryError::NotFound)).into_response();
+    }
+    match state.template_repository.versions(&name).await {
+        Ok(versions) => (StatusCode::OK, Json(versions)).into_response(),
+        Err(err) => {
+            log::error!("Error getting versions of email template {}: {:?}", name, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+async fn save_version(state: &DrossManagerState, template: EmailTemplate) -> Response {
+    let name = template.name.clone();
+    match state.template_repository.save(template).await {
+        Ok(id) => match state.template_repository.get(id).await {
+            Ok(template) => (StatusCode::CREATED, Json(template)).into_response(),
+            Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response(),
+        },
+        Err(RepositoryError::NotFound) => (StatusCode::NOT_FOUND, Json(RepositoryError::NotFound)).into_response(),
+        Err(err) => {
+            log::error!("Error saving email template {}: {:?}", name, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response()
+        }
+    }
+}
+
+// update_template saves the request as the newest version of the template.
+pub async fn update_template(
+    State(state): State<Arc<DrossManagerState>>,
+    Path(name): Path<String>,
+    payload: Result<Json<EmailTemplate>, JsonRejection>
+) -> Response {
+    let template = match payload {
+        Ok(Json(template)) => EmailTemplate { id: None, name: name.clone(), ..template },
+        Err(err) => {
+            let repo_error: RepositoryError = err.into();
+            return (StatusCode::BAD_REQUEST, Json(repo_error)).into_response();
+        }
+    };
+    log::info!("Saving a new version of email template {}", name);
+    save_version(&state, template).await
+}
+
+// restore_template_version copies an old version, built-in ones included, as the newest version.
+pub async fn restore_template_version(
+    State(state): State<Arc<DrossManagerState>>,
+    Path((name, version)): Path<(String, i64)>
+) -> Response {
+    let template = match state.template_repository.version(&name, version).await {
+        Ok(template) => template,
+        Err(err) => return (StatusCode::NOT_FOUND, Json(err)).into_response(),
+    };
+    log::info!("Restoring version {} of email template {}", version, name);
+    save_version(&state, template).await
+}
+
+// preview_template renders the current version with the given variables, without sending it.
+pub async fn preview_template(
+    State(state): State<Arc<DrossManagerState>>,
+    Path(name): Path<String>,
+    payload: Result<Json<Variables>, JsonRejection>
+) -> Response {
+    let variables = match payload {
+        Ok(Json(variables)) => variables,
+        Err(err) => {
+            let repo_error: RepositoryError = err.into();
+            return (StatusCode::BAD_REQUEST, Json(repo_error)).into_response();
+        }
+    };
+    let template = match state.template_repository.latest(&name).await {
+        Ok(template) => template,
+        Err(err) => return (StatusCode::NOT_FOUND, Json(err)).into_response(),
+    };
+    match state.settings_repository.load::<Branding>(Branding::KEY).await {
+        Ok(branding) => (StatusCode::OK, Json(template.render(PREVIEW_RECIPIENT, &variables, &branding))).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, Json(err)).into_response(),
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use chrono::Utc;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use crate::DrossManagerState;
+use crate::repository::{Repository, RepositoryError, RepositoryResult};
+use crate::repository::ledger::Entry;
+use crate::repository::notification::Notification;
+
+// How many recent events are kept for clients reconnecting with Last-Event-ID
+const REPLAY_CAPACITY: usize = 1024;
+// How many events one player's streams can fall behind before they're closed to catch up
+const PLAYER_CHANNEL_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LiveEventKind {
+    Balance,
+    Transaction,
+    Notification,
+    // Sent instead of a replay when the client missed more than the buffer holds
+    Resync,
+}
+
+impl LiveEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LiveEventKind::Balance => "balance",
+            LiveEventKind::Transaction => "transaction",
+            LiveEventKind::Notification => "notification",
+            LiveEventKind::Resync => "resync",
+        }
+    }
+}
+
+// LiveEvent is something a player's open streams should hear about.
+#[derive(Debug, Clone, Serialize)]
+pub struct LiveEvent {
+    pub id: u64,
+    pub player_id: i64,
+    pub kind: LiveEventKind,
+    pub data: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BalanceUpdate {
+    pub faery_id: i64,
+    pub balance: i64,
+}
+
+// Subscription is what a newly opened stream starts from.
+pub struct Subscription {
+    // The player's events after their Last-Event-ID
+    pub replay: Vec<Arc<LiveEvent>>,
+    // Set when events after their Last-Event-ID have already left the replay buffer
+    pub missed: bool,
+    // The latest event ID, so a resync can move the client's Last-Event-ID past the gap
+    pub last_id: u64,
+    pub receiver: broadcast::Receiver<Arc<LiveEvent>>,
+}
+
+struct Streams {
+    next_id: u64,
+    events: VecDeque<Arc<LiveEvent>>,
+    // The buffer holds every event after this ID
+    replay_from: u64,
+    channels: HashMap<i64, broadcast::Sender<Arc<LiveEvent>>>,
+}
+
+// LiveEvents fans events out to each player's open streams through a channel of their own, and
+// keeps the most recent ones so a client that drops can pick up where it left off.
+pub struct LiveEvents {
+    streams: Mutex<Streams>,
+    capacity: usize,
+}
+
+impl Default for LiveEvents {
+    fn default() -> Self {
+        LiveEvents::new(REPLAY_CAPACITY)
+    }
+}
+
+impl LiveEvents {
+    pub fn new(capacity: usize) -> LiveEvents {
+        // IDs start at the current time so they keep increasing across restarts, and a
+        // client's Last-Event-ID from before one doesn't hide newer events
+        let first_id = Utc::now().timestamp_millis() as u64 * 1000;
+        LiveEvents {
+            streams: Mutex::new(Streams {
+                next_id: first_id,
+                events: VecDeque::with_capacity(capacity),
+                replay_from: first_id,
+                channels: HashMap::new(),
+            }),
+            capacity,
+        }
+    }
+
+    pub fn publish<T: Serialize>(&self, player_id: i64, kind: LiveEventKind, data: &T) {
+        let data = serde_json::to_value(data).unwrap_or(serde_json::Value::Null);
+        // Holding the lock while sending keeps the replay buffer and the channels in the same order
+        let mut streams = self.streams.lock().unwrap();
+        streams.next_id += 1;
+        let event = Arc::new(LiveEvent { id: streams.next_id, player_id, kind, data });
+        if streams.events.len() == self.capacity {
+            if let Some(evicted) = streams.events.pop_front() {
+                streams.replay_from = evicted.id;
+            }
+        }
+        streams.events.push_back(event.clone());
+        // Sending only fails once all of the player's streams have closed
+        let closed = match streams.channels.get(&player_id) {
+            Some(sender) => sender.send(event).is_err(),
+            None => false,
+        };
+        if closed {
+            streams.channels.remove(&player_id);
+        }
+    }
+
+    // subscribe opens a stream for a player, with their events after `last_event_id` that are
+    // still in the replay buffer.
+    pub fn subscribe(&self, player_id: i64, last_event_id: Option<u64>) -> Subscription {
+        let mut streams = self.streams.lock().unwrap();
+        streams.channels.retain(|_, sender| sender.receiver_count() > 0);
+        let receiver = streams.channels
+            .entry(player_id)
+            .or_insert_with(|| broadcast::channel(PLAYER_CHANNEL_CAPACITY).0)
+            .subscribe();
+        let missed = last_event_id.is_some_and(|last_event_id| last_event_id < streams.replay_from);
+        let replay = match last_event_id {
+            Some(last_event_id) if !missed => streams.events.iter()
+                .filter(|event| event.player_id == player_id && event.id > last_event_id)
+                .cloned()
+                .collect(),
+            _ => vec![],
+        };
+        Subscription { replay, missed, last_id: streams.next_id, receiver }
+    }
+
+    // disconnect closes a player's open streams, once they've sent what was already published.
+    pub fn disconnect(&self, player_id: i64) {
+        self.streams.lock().unwrap().channels.remove(&player_id);
+    }
+}
+
+// publish_entry tells the owner of a ledger entry's faery about the transaction and the new
+// balance. Faeries without a player account have nobody to tell.
+pub async fn publish_entry(state: &DrossManagerState, entry: &Entry) -> RepositoryResult<()> {
+    let faery = state.faery_repository.get(entry.faery_id).await?;
+    let player_id = match state.player_repository.find_by_email(faery.email()).await {
+        Ok(player) => player.id.ok_or(RepositoryError::InvalidModel)?,
+        Err(RepositoryError::NotFound) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    state.live_events.publish(player_id, LiveEventKind::Transaction, entry);
+    state.live_events.publish(player_id, LiveEventKind::Balance, &BalanceUpdate { faery_id: entry.faery_id, balance: entry.balance });
+    Ok(())
+}
+
+pub fn publish_notification(state: &DrossManagerState, notification: &Notification) {
+    state.live_events.publish(notification.player_id, LiveEventKind::Notification, notification);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dross::adjust_balance;
+    use crate::repository::Repository;
+    use crate::repository::faery::Model;
+    use crate::repository::ledger::EntryKind;
+    use crate::testing;
+    use super::{LiveEventKind, LiveEvents};
+
+    #[tokio::test]
+    async fn test_replay_and_fan_out() {
+        let events = LiveEvents::new(4);
+        let start = events.subscribe(1, None);
+        assert!(start.replay.is_empty());
+
+        events.publish(1, LiveEventKind::Notification, &"first");
+        let mut other = events.subscribe(2, None).receiver;
+        events.publish(2, LiveEventKind::Notification, &"someone else's");
+        let mut receiver = events.subscribe(1, None).receiver;
+        events.publish(1, LiveEventKind::Notification, &"second");
+        assert_eq!(receiver.recv().await.unwrap().data, "second");
+        // Each player only hears their own events
+        assert_eq!(other.recv().await.unwrap().data, "someone else's");
+        assert!(other.try_recv().is_err());
+
+        let subscription = events.subscribe(1, Some(start.last_id));
+        assert!(!subscription.missed);
+        assert_eq!(subscription.replay.iter().map(|event| event.data.as_str().unwrap()).collect::<Vec<_>>(), vec!["first", "second"]);
+        let first_id = subscription.replay[0].id;
+        assert_eq!(events.subscribe(1, Some(first_id)).replay.len(), 1);
+
+        // Only the most recent events are kept for replay; older IDs need a resync
+        for _ in 0..4 {
+            events.publish(2, LiveEventKind::Balance, &0);
+        }
+        let subscription = events.subscribe(1, Some(first_id));
+        assert!(subscription.missed);
+        assert!(subscription.replay.is_empty());
+
+        events.disconnect(1);
+        assert!(receiver.recv().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_publish_entry_reaches_the_owner() {
+        let state = testing::state().await;
+        let player_id = testing::create_player(&state, "wendy@example.com", false).await;
+        let faery_id = state.faery_repository.create(Some(Model::new("Tink".to_string(), "wendy@example.com".to_string(), false, 0, None))).await.unwrap();
+        let stray_id = state.faery_repository.create(Some(Model::new("Stray".to_string(), "nobody@example.com".to_string(), false, 0, None))).await.unwrap();
+        let mut receiver = state.live_events.subscribe(player_id, None).receiver;
+
+        adjust_balance(&state, faery_id, 5, EntryKind::Grant, "Welcome".to_string()).await.unwrap();
+        let published: Vec<_> = std::iter::from_fn(|| receiver.try_recv().ok()).collect();
+        // The receipt's notification comes first
+        assert_eq!(published.iter().map(|event| event.kind).collect::<Vec<_>>(), vec![
+            LiveEventKind::Notification,
+            LiveEventKind::Transaction,
+            LiveEventKind::Balance,
+        ]);
+        assert_eq!(published[1].data["memo"], "Welcome");
+        assert_eq!(published[2].data, serde_json::json!({ "faery_id": faery_id, "balance": 5 }));
+
+        // Nobody owns the stray, so nothing is published
+        adjust_balance(&state, stray_id, 5, EntryKind::Grant, "Found".to_string()).await.unwrap();
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,438 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use lettre::message::header::{ContentType, HeaderName, HeaderValue};
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+// Email is a message ready to be handed to a Mailer. `body` is the plain-text part; when
+// there's `html` too, both are sent as alternatives.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+    pub html: Option<String>,
+    // Overrides the mailer's sender address
+    pub from: Option<String>,
+    // Sent as a one-click List-Unsubscribe header
+    pub unsubscribe_url: Option<String>,
+    // Carries a login token or verification link: admins never see the body, and it's
+    // dropped from the outbox once sent
+    #[serde(skip)]
+    pub sensitive: bool,
+}
+
+impl Email {
+    pub fn new(to: &str, subject: &str, body: &str) -> Email {
+        Email {
+            to: to.to_string(),
+            subject: subject.to_string(),
+            body: body.to_string(),
+            html: None,
+            from: None,
+            unsubscribe_url: None,
+            sensitive: false,
+        }
+    }
+
+    pub fn to_message(&self, from: &Mailbox) -> Result<Message, MailerError> {
+        let to: Mailbox = self.to.parse().map_err(|_| MailerError::InvalidAddress)?;
+        let from = match &self.from {
+            Some(sender) => sender.parse().map_err(|_| MailerError::InvalidAddress)?,
+            None => from.clone(),
+        };
+        let mut builder = Message::builder()
+            .from(from)
+            .to(to)
+            .subject(self.subject.clone());
+        if let Some(unsubscribe_url) = &self.unsubscribe_url {
+            builder = builder
+                .raw_header(HeaderValue::new(HeaderName::new_from_ascii_str("List-Unsubscribe"), format!("<{}>", unsubscribe_url)))
+                .raw_header(HeaderValue::new(
+                    HeaderName::new_from_ascii_str("List-Unsubscribe-Post"),
+                    "List-Unsubscribe=One-Click".to_string()
+                ));
+        }
+        match &self.html {
+            Some(html) => builder.multipart(MultiPart::alternative_plain_html(self.body.clone(), html.clone())),
+            None => builder.header(ContentType::TEXT_PLAIN).body(self.body.clone()),
+        }.map_err(|_| MailerError::InvalidAddress)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MailerError {
+    InvalidAddress,
+    // The transport couldn't deliver the message; trying again later may work
+    Transport(String),
+}
+
+// Mailer delivers email. It's built once at startup and shared.
+#[shuttle_runtime::async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<(), MailerError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmtpTls {
+    // TLS from the start of the connection, usually port 465
+    Wrapper,
+    // Upgrade a plain connection, usually port 587
+    StartTls,
+    // Plain text, only for local test servers
+    None,
+}
+
+impl From<&str> for SmtpTls {
+    fn from(tls: &str) -> Self {
+        match tls.to_lowercase().as_str() {
+            "starttls" => SmtpTls::StartTls,
+            "none" => SmtpTls::None,
+            _ => SmtpTls::Wrapper,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: Option<u16>,
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(config: SmtpConfig, from: Mailbox) -> Result<SmtpMailer, MailerError> {
+        let builder = match config.tls {
+            SmtpTls::Wrapper => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host),
+            SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host),
+            SmtpTls::None => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host)),
+        }.map_err(|err| MailerError::Transport(err.to_string()))?;
+        let builder = match config.port {
+            Some(port) => builder.port(port),
+            None => builder,
+        };
+        let builder = match (config.username, config.password) {
+            (Some(username), Some(password)) => builder.credentials(Credentials::new(username, password)),
+            _ => builder,
+        };
+        Ok(SmtpMailer {
+            transport: builder.build(),
+            from,
+        })
+    }
+}
+
+#[shuttle_runtime::async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> Result<(), MailerError> {
+        let message = email.to_message(&self.from)?;
+        log::info!("Sending email to {}.", email.to);
+        match self.transport.send(message).await {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                log::error!("Failed to send email: {}", err);
+                Err(MailerError::Transport(err.to_string()))
+            }
+        }
+    }
+}
+
+// FileMailer writes each email to a .eml file instead of sending it, for development.
+pub struct FileMailer {
+    dir: PathBuf,
+    from: Mailbox,
+}
+
+impl FileMailer {
+    pub fn new(dir: impl Into<PathBuf>, from: Mailbox) -> FileMailer {
+        FileMailer {
+            dir: dir.into(),
+            from,
+        }
+    }
+}
+
+#[shuttle_runtime::async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: &Email) -> Result<(), MailerError> {
+        let message = email.to_message(&self.from)?;
+        let path = self.dir.join(format!("{}-{}.eml", Utc::now().timestamp_millis(), uuid::Uuid::new_v4()));
+        let written = match tokio::fs::create_dir_all(&self.dir).await {
+            Ok(_) => tokio::fs::write(&path, message.formatted()).await,
+            Err(err) => Err(err),
+        };
+        match written {
+            Ok(_) => {
+                log::info!("Wrote email to {} at {}", email.to, path.display());
+                Ok(())
+            },
+            Err(err) => Err(MailerError::Transport(err.to_string())),
+        }
+    }
+}
+
+// MemoryMailer keeps every email it's given, so tests can check what was sent.
+#[derive(Default)]
+pub struct MemoryMailer {
+    sent: Mutex<Vec<Email>>,
+}
+
+impl MemoryMailer {
+    #[allow(dead_code)]
+    pub fn sent(&self) -> Vec<Email> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[shuttle_runtime::async_trait]
+impl Mailer for MemoryMailer {
+    async fn send(&self, email: &Email) -> Result<(), MailerError> {
+        email.to.parse::<Mailbox>().map_err(|_| MailerError::InvalidAddress)?;
+        self.sent.lock().unwrap().push(email.clone());
+        Ok(())
+    }
+}
+
+// Mark: Delivery events
+
+// Webhooks signed longer ago than this are rejected, so a captured request can't be replayed
+const WEBHOOK_MAX_AGE_SECONDS: i64 = 15 * 60;
+
+// DeliveryEvent is a mail provider reporting that an address shouldn't get any more email.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeliveryEvent {
+    Bounced { recipient: String, detail: String },
+    Complained { recipient: String },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebhookSignature {
+    pub timestamp: String,
+    pub token: String,
+    pub signature: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct WebhookDeliveryStatus {
+    #[serde(default)]
+    pub message: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebhookEventData {
+    pub event: String,
+    #[serde(default)]
+    pub severity: Option<String>,
+    #[serde(default)]
+    pub recipient: String,
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default, rename = "delivery-status")]
+    pub delivery_status: WebhookDeliveryStatus,
+}
+
+// MailWebhook is a Mailgun-style delivery event, signed with the webhook signing key.
+#[derive(Debug, Deserialize)]
+pub struct MailWebhook {
+    pub signature: WebhookSignature,
+    #[serde(rename = "event-data")]
+    pub event_data: WebhookEventData,
+}
+
+impl MailWebhook {
+    // verify checks the signature, and that it was made recently. `now` is in seconds.
+    pub fn verify(&self, signing_key: &str, now: i64) -> bool {
+        let Ok(timestamp) = self.signature.timestamp.parse::<i64>() else {
+            return false;
+        };
+        if (now - timestamp).abs() > WEBHOOK_MAX_AGE_SECONDS {
+            return false;
+        }
+        let Some(signature) = decode_hex(&self.signature.signature) else {
+            return false;
+        };
+        webhook_mac(signing_key, &self.signature.timestamp, &self.signature.token).verify_slice(&signature).is_ok()
+    }
+
+    // event is the bounce or complaint this webhook reports. Temporary failures are retried by
+    // the provider and other events are informational, so they're None.
+    pub fn event(&self) -> Option<DeliveryEvent> {
+        let data = &self.event_data;
+        if data.recipient.is_empty() {
+            return None;
+        }
+        match data.event.as_str() {
+            "failed" if data.severity.as_deref() == Some("permanent") => {
+                let detail = [&data.delivery_status.description, &data.delivery_status.message]
+                    .into_iter()
+                    .find(|detail| !detail.is_empty())
+                    .cloned()
+                    .or_else(|| data.reason.clone())
+                    .unwrap_or_default();
+                Some(DeliveryEvent::Bounced { recipient: data.recipient.clone(), detail })
+            },
+            "complained" => Some(DeliveryEvent::Complained { recipient: data.recipient.clone() }),
+            _ => None,
+        }
+    }
+}
+
+// WebhookReplayGuard remembers the tokens of recently accepted webhooks, so a captured request
+// can't be sent again while its signature is still fresh. Tokens are forgotten once their
+// timestamp is too old to verify anyway.
+#[derive(Default)]
+pub struct WebhookReplayGuard {
+    seen: Mutex<HashMap<String, i64>>,
+}
+
+impl WebhookReplayGuard {
+    // claim records a verified webhook's token, and is false if it was already used. `now` is in seconds.
+    pub fn claim(&self, signature: &WebhookSignature, now: i64) -> bool {
+        let Ok(timestamp) = signature.timestamp.parse::<i64>() else {
+            return false;
+        };
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, timestamp| now - *timestamp <= WEBHOOK_MAX_AGE_SECONDS);
+        match seen.entry(signature.token.clone()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(timestamp);
+                true
+            }
+        }
+    }
+
+    // release forgets a token whose webhook couldn't be handled, so the provider's retry is accepted.
+    pub fn release(&self, signature: &WebhookSignature) {
+        self.seen.lock().unwrap().remove(&signature.token);
+    }
+}
+
+fn webhook_mac(signing_key: &str, timestamp: &str, token: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(signing_key.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(token.as_bytes());
+    mac
+}
+
+// webhook_signature signs a webhook the way the provider does, so a local stand-in can send them.
+#[allow(dead_code)]
+pub fn webhook_signature(signing_key: &str, timestamp: &str, token: &str) -> String {
+    webhook_mac(signing_key, timestamp, token).finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use lettre::message::Mailbox;
+    use super::{webhook_signature, DeliveryEvent, Email, MailWebhook, MailerError, SmtpTls, WebhookReplayGuard};
+
+    #[test]
+    fn test_message_headers() {
+        let from: Mailbox = "Fe-Vault <noreply@example.com>".parse().unwrap();
+        let message = Email::new("someone@example.com", "Hello", "Body").to_message(&from).unwrap();
+        let formatted = String::from_utf8(message.formatted()).unwrap();
+        assert!(formatted.contains("From: Fe-Vault <noreply@example.com>"));
+        assert!(formatted.contains("To: someone@example.com"));
+        assert!(formatted.contains("Subject: Hello"));
+
+        let invalid = Email::new("not an address", "Hello", "Body").to_message(&from);
+        assert_eq!(invalid.err(), Some(MailerError::InvalidAddress));
+    }
+
+    #[test]
+    fn test_html_alternative() {
+        let from: Mailbox = "Fe-Vault <noreply@example.com>".parse().unwrap();
+        let email = Email {
+            html: Some("<p>Body</p>".to_string()),
+            from: Some("Moonfall <game@example.com>".to_string()),
+            ..Email::new("someone@example.com", "Hello", "Body")
+        };
+        let formatted = String::from_utf8(email.to_message(&from).unwrap().formatted()).unwrap();
+        assert!(formatted.contains("From: Moonfall <game@example.com>"));
+        assert!(formatted.contains("multipart/alternative"));
+        assert!(formatted.contains("Content-Type: text/plain"));
+        assert!(formatted.contains("Content-Type: text/html"));
+        assert!(!formatted.contains("List-Unsubscribe"));
+
+        let email = Email { unsubscribe_url: Some("https://example.com/u?token=t".to_string()), ..email };
+        let formatted = String::from_utf8(email.to_message(&from).unwrap().formatted()).unwrap();
+        assert!(formatted.contains("List-Unsubscribe: <https://example.com/u?token=t>"));
+        assert!(formatted.contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click"));
+    }
+
+    #[test]
+    fn test_tls_setting() {
+        assert_eq!(SmtpTls::from("STARTTLS"), SmtpTls::StartTls);
+        assert_eq!(SmtpTls::from("none"), SmtpTls::None);
+        assert_eq!(SmtpTls::from("wrapper"), SmtpTls::Wrapper);
+    }
+
+    fn webhook(event: &str, severity: &str, signature: &str) -> MailWebhook {
+        serde_json::from_value(serde_json::json!({
+            "signature": { "timestamp": "1700000000", "token": "abc123", "signature": signature },
+            "event-data": {
+                "event": event,
+                "severity": severity,
+                "recipient": "gone@example.com",
+                "delivery-status": { "message": "", "description": "No such mailbox" }
+            }
+        })).unwrap()
+    }
+
+    #[test]
+    fn test_webhook_signature() {
+        let signature = webhook_signature("key-secret", "1700000000", "abc123");
+        let signed = webhook("failed", "permanent", &signature);
+        assert!(signed.verify("key-secret", 1_700_000_060));
+        assert!(!signed.verify("key-other", 1_700_000_060));
+        // Too old to be trusted
+        assert!(!signed.verify("key-secret", 1_700_100_000));
+        assert!(!webhook("failed", "permanent", "not hex").verify("key-secret", 1_700_000_060));
+    }
+
+    #[test]
+    fn test_webhook_replay_guard() {
+        let guard = WebhookReplayGuard::default();
+        let signed = webhook("failed", "permanent", &webhook_signature("key-secret", "1700000000", "abc123"));
+        assert!(guard.claim(&signed.signature, 1_700_000_060));
+        assert!(!guard.claim(&signed.signature, 1_700_000_120));
+        guard.release(&signed.signature);
+        assert!(guard.claim(&signed.signature, 1_700_000_120));
+        // Forgotten once the signature has expired
+        assert!(guard.claim(&signed.signature, 1_700_100_000));
+    }
+
+    #[test]
+    fn test_webhook_events() {
+        assert_eq!(
+            webhook("failed", "permanent", "").event(),
+            Some(DeliveryEvent::Bounced { recipient: "gone@example.com".to_string(), detail: "No such mailbox".to_string() })
+        );
+        assert_eq!(webhook("failed", "temporary", "").event(), None);
+        assert_eq!(webhook("complained", "", "").event(), Some(DeliveryEvent::Complained { recipient: "gone@example.com".to_string() }));
+        assert_eq!(webhook("delivered", "", "").event(), None);
+    }
+}
@@ -1,18 +1,30 @@
 mod dross;
 mod endpoints;
+mod live;
+mod mailer;
+mod messages;
 mod migrations;
 mod version;
 mod auth;
+mod avatar;
 mod prelude;
+mod reconcile;
 mod repository;
+mod statement;
+mod stats;
+mod tasks;
+#[cfg(test)]
+mod testing;
 
 use std::net::SocketAddr;
-use axum::{routing::get, Router};
+use axum::{middleware, routing::{delete, get, post, put}, Router};
+use axum::extract::DefaultBodyLimit;
 use tower_http::services::ServeDir;
 use libsql::Connection;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
-use http::{Method};
+use http::{HeaderName, Method};
 use prelude::*;
 
 use tower::{ServiceBuilder};
@@ -26,7 +38,27 @@ pub struct DrossManagerState {
     pub player_repository: Arc<PlayerRepository>,
     pub faery_repository: Arc<FaeryRepository>,
     pub email_repository: Arc<EmailRepository>,
-    pub jwt_key_pair: JWTKeyPair
+    pub ledger_repository: Arc<LedgerRepository>,
+    pub achievement_repository: Arc<AchievementRepository>,
+    pub snapshot_repository: Arc<SnapshotRepository>,
+    pub settings_repository: Arc<SettingsRepository>,
+    pub group_repository: Arc<GroupRepository>,
+    pub attribute_repository: Arc<AttributeRepository>,
+    pub template_repository: Arc<TemplateRepository>,
+    pub preference_repository: Arc<PreferenceRepository>,
+    pub broadcast_repository: Arc<BroadcastRepository>,
+    pub notification_repository: Arc<NotificationRepository>,
+    pub avatar_store: avatar::AvatarStore,
+    pub stats_cache: stats::StatsCache,
+    pub live_events: live::LiveEvents,
+    pub jwt_key_pair: JWTKeyPair,
+    // Signs ledger exports; exports are turned off without one
+    pub ledger_signing_key: Option<JWTKeyPair>,
+    // Public base URL used to build links in emails
+    pub app_url: String,
+    // Verifies delivery event webhooks; they're turned off without one
+    pub mail_webhook_key: Option<String>,
+    pub webhook_replays: mailer::WebhookReplayGuard,
 }
 
 pub struct JWTKeyPair {
@@ -34,10 +66,139 @@ pub struct JWTKeyPair {
     pub private_key: String
 }
 
+// build_mailer picks the mail transport from MAIL_TRANSPORT: smtp (the default), file or memory.
+fn build_mailer(store: &shuttle_secrets::SecretStore) -> Arc<dyn mailer::Mailer> {
+    let from = store.get("MAIL_FROM")
+        .unwrap_or_else(|| "Fe-Vault <noreply@fe-vault.thehe.art>".to_string())
+        .parse()
+        .expect("MAIL_FROM must be a valid mailbox");
+    match store.get("MAIL_TRANSPORT").unwrap_or_default().as_str() {
+        "file" => Arc::new(mailer::FileMailer::new(store.get("MAIL_DIR").unwrap_or_else(|| "mail".to_string()), from)),
+        "memory" => Arc::new(mailer::MemoryMailer::default()),
+        _ => {
+            let config = mailer::SmtpConfig {
+                host: store.get("SMTP_HOST").unwrap_or_else(|| "smtp.mailgun.org".to_string()),
+                port: store.get("SMTP_PORT").and_then(|port| port.parse().ok()),
+                tls: store.get("SMTP_TLS").unwrap_or_default().as_str().into(),
+                username: store.get("SMTP_USER").or_else(|| store.get("MAILGUN_USER")),
+                password: store.get("SMTP_PASSWORD").or_else(|| store.get("MAILGUN_PASSWORD")),
+            };
+            Arc::new(mailer::SmtpMailer::new(config, from).expect("Couldn't set up the SMTP transport"))
+        }
+    }
+}
+
 async fn hello_world() -> &'static str {
     "Hello, world!"
 }
 
+// router builds the app's routes. Admin routes need an admin's token, player routes any active
+// player's, and the rest are open.
+fn router(state: Arc<DrossManagerState>) -> Router {
+    log::info!("Creating CORS middleware");
+    let cors = CorsLayer::new()
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .expose_headers([HeaderName::from_static("x-total-count"), HeaderName::from_static("x-next-cursor")]);
+
+    log::info!("Creating router");
+    let admin_routes = Router::new()
+        .route("/api/players", get(endpoints::player::list_players).post(endpoints::player::create_player))
+        .route("/api/players/:player_id", get(endpoints::player::get_player).put(endpoints::player::update_player).delete(endpoints::player::delete_player))
+        .route("/api/players/:player_id/approve", post(endpoints::registration::approve_player))
+        .route("/api/players/:player_id/export", get(endpoints::privacy::export_player))
+        .route("/api/players/:player_id/erase", post(endpoints::privacy::erase_player))
+        .route("/api/admin/registration", get(endpoints::registration::get_registration_settings).put(endpoints::registration::update_registration_settings))
+        .route("/api/faeries", post(endpoints::create_faery))
+        .route("/api/faeries/:faery_id", put(endpoints::update_faery).delete(endpoints::delete_faery))
+        .route("/api/faeries/:faery_id/badges", post(endpoints::achievement::grant_badge))
+        .route("/api/faeries/:faery_id/tags", put(endpoints::set_faery_tags))
+        .route("/api/faeries/:faery_id/merge", post(endpoints::merge::merge_faery))
+        .route("/api/faeries/:faery_id/avatar", put(endpoints::avatar::upload_avatar)
+            // Leave room for the multipart framing around the image itself
+            .layer(DefaultBodyLimit::max(state.avatar_store.max_bytes() + 64 * 1024))
+            .delete(endpoints::avatar::delete_avatar))
+        .route("/api/attributes", post(endpoints::attribute::create_attribute))
+        .route("/api/attributes/:attribute_id", put(endpoints::attribute::update_attribute).delete(endpoints::attribute::delete_attribute))
+        .route("/api/faeries/:faery_id/attributes", put(endpoints::attribute::update_faery_attributes))
+        .route("/api/groups", post(endpoints::group::create_group))
+        .route("/api/groups/:group_id", put(endpoints::group::update_group).delete(endpoints::group::delete_group))
+        .route("/api/groups/:group_id/members/:faery_id", put(endpoints::group::set_member).delete(endpoints::group::remove_member))
+        .route("/api/groups/:group_id/grant", post(endpoints::group::grant_group))
+        .route("/api/groups/:group_id/treasury", get(endpoints::group::get_treasury).post(endpoints::group::update_treasury))
+        .route("/api/archive/faeries", get(endpoints::archive::list_archived_faeries))
+        .route("/api/archive/faeries/:faery_id", delete(endpoints::archive::purge_faery))
+        .route("/api/archive/faeries/:faery_id/restore", post(endpoints::archive::restore_faery))
+        .route("/api/archive/players", get(endpoints::archive::list_archived_players))
+        .route("/api/archive/players/:player_id", delete(endpoints::archive::purge_player))
+        .route("/api/archive/players/:player_id/restore", post(endpoints::archive::restore_player))
+        .route("/api/achievements", post(endpoints::achievement::create_achievement))
+        .route("/api/achievements/:achievement_id", put(endpoints::achievement::update_achievement).delete(endpoints::achievement::delete_achievement))
+        .route("/api/admin/branding", get(endpoints::template::get_branding).put(endpoints::template::update_branding))
+        .route("/api/admin/email-templates", get(endpoints::template::list_templates))
+        .route("/api/admin/email-templates/:name", get(endpoints::template::get_template_versions).post(endpoints::template::update_template))
+        .route("/api/admin/email-templates/:name/preview", post(endpoints::template::preview_template))
+        .route("/api/admin/email-templates/:name/versions/:version/restore", post(endpoints::template::restore_template_version))
+        .route("/api/admin/mail", get(endpoints::mail::get_mail_status))
+        .route("/api/admin/mail/outbox", get(endpoints::mail::list_outbox))
+        .route("/api/admin/mail/outbox/:message_id", get(endpoints::mail::get_outbox_message))
+        .route("/api/admin/mail/outbox/:message_id/resend", post(endpoints::mail::resend_outbox_message))
+        .route("/api/admin/mail/suppressions", get(endpoints::mail::list_suppressions))
+        .route("/api/admin/mail/suppressions/:email", delete(endpoints::mail::delete_suppression))
+        .route("/api/broadcasts", get(endpoints::broadcast::list_broadcasts).post(endpoints::broadcast::create_broadcast))
+        .route("/api/broadcasts/preview", post(endpoints::broadcast::preview_broadcast))
+        .route("/api/broadcasts/test", post(endpoints::broadcast::test_broadcast))
+        .route("/api/broadcasts/:broadcast_id", get(endpoints::broadcast::get_broadcast))
+        .route("/api/broadcasts/:broadcast_id/cancel", post(endpoints::broadcast::cancel_broadcast))
+        .route("/api/admin/reconcile", post(endpoints::ledger::reconcile_ledger))
+        .route("/api/admin/ledger/export", get(endpoints::ledger::export_ledger))
+        .route("/api/snapshots", get(endpoints::snapshot::list_snapshots).post(endpoints::snapshot::create_snapshot))
+        .route("/api/snapshots/:snapshot_id", get(endpoints::snapshot::get_snapshot).delete(endpoints::snapshot::delete_snapshot))
+        .route("/api/snapshots/:snapshot_id/diff/:other_id", get(endpoints::snapshot::diff_snapshots))
+        .route_layer(middleware::from_fn(auth::jwt::require_admin))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::jwt::authenticate));
+
+    let player_routes = Router::new()
+        .route("/api/me", get(endpoints::player::get_me).put(endpoints::player::update_me))
+        .route("/api/me/export", get(endpoints::privacy::export_me))
+        .route("/api/me/erase", post(endpoints::privacy::erase_me))
+        .route("/api/me/stream", get(endpoints::live::get_my_stream))
+        .route("/api/me/statement", get(endpoints::player::get_my_statement))
+        .route("/api/me/notifications", get(endpoints::notification::list_my_notifications))
+        .route("/api/me/notifications/read", post(endpoints::notification::mark_all_read))
+        .route("/api/me/notifications/:notification_id/read", post(endpoints::notification::mark_read))
+        .route("/api/me/preferences", get(endpoints::preference::get_my_preferences).put(endpoints::preference::update_my_preferences))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::jwt::authenticate));
+
+    Router::new()
+        .route("/api/hello", get(hello_world))
+        .route("/api/register", post(endpoints::registration::register))
+        .route("/api/register/verify", get(endpoints::registration::verify_registration))
+        .route("/api/webhooks/mail", post(endpoints::mail::receive_mail_webhook))
+        .route("/api/unsubscribe", get(endpoints::preference::unsubscribe_page).post(endpoints::preference::unsubscribe))
+        .route("/api/faeries", get(endpoints::list_faeries))
+        .route("/api/faeries/:faery_id", get(endpoints::get_faery))
+        .route("/api/faeries/:faery_id/tags", get(endpoints::get_faery_tags))
+        .route("/api/faeries/:faery_id/groups", get(endpoints::group::get_faery_groups))
+        .route("/api/faeries/:faery_id/attributes", get(endpoints::attribute::get_faery_attributes))
+        .route("/api/faeries/:faery_id/avatar", get(endpoints::avatar::get_avatar))
+        .route("/api/faeries/:faery_id/avatar/thumbnail", get(endpoints::avatar::get_avatar_thumbnail))
+        .route("/api/attributes", get(endpoints::attribute::list_attributes))
+        .route("/api/groups", get(endpoints::group::list_groups))
+        .route("/api/groups/:group_id", get(endpoints::group::get_group))
+        .route("/api/faeries/:faery_id/ledger", get(endpoints::ledger::get_ledger))
+        .route("/api/faeries/:faery_id/balance", get(endpoints::ledger::get_balance))
+        .route("/api/ledger/verify", get(endpoints::ledger::verify_ledger))
+        .route("/api/ledger/public-key", get(endpoints::ledger::get_public_key))
+        .route("/api/stats", get(endpoints::stats::get_stats))
+        .route("/api/achievements", get(endpoints::achievement::list_achievements))
+        // .route("/api/test_email", get(send_test_email))
+        .merge(admin_routes)
+        .merge(player_routes)
+        .layer(ServiceBuilder::new().layer(cors))
+        .with_state(state)
+        .nest_service("/", ServeDir::new("dross-manager-frontend/dist"))
+}
+
 #[shuttle_runtime::main]
 async fn axum(
     #[shuttle_secrets::Secrets] store: shuttle_secrets::SecretStore,
@@ -47,9 +208,7 @@ async fn axum(
     )] turso: Connection
 ) -> Result<DrossManagerService, shuttle_runtime::Error> {
 
-    let mailgun_user = store.get("MAILGUN_USER").unwrap();
-    let mailgun_token = store.get("MAILGUN_PASSWORD").unwrap();
-    let mailgun_domain = store.get("MAILGUN_DOMAIN").unwrap();
+    let mailer = build_mailer(&store);
     let admin_email = store.get("ADMIN_EMAIL").unwrap();
     std::env::set_var("ADMIN_EMAIL", admin_email);
 
@@ -57,31 +216,70 @@ async fn axum(
     let state = Arc::new(DrossManagerState {
         player_repository: Arc::new(PlayerRepository::new(db.clone())),
         faery_repository: Arc::new(FaeryRepository::new(db.clone())),
-        email_repository: Arc::new(EmailRepository::new(mailgun_user, mailgun_token, mailgun_domain)),
+        email_repository: Arc::new(EmailRepository::new(db.clone(), mailer)),
+        ledger_repository: Arc::new(LedgerRepository::new(db.clone())),
+        achievement_repository: Arc::new(AchievementRepository::new(db.clone())),
+        snapshot_repository: Arc::new(SnapshotRepository::new(db.clone())),
+        settings_repository: Arc::new(SettingsRepository::new(db.clone())),
+        group_repository: Arc::new(GroupRepository::new(db.clone())),
+        attribute_repository: Arc::new(AttributeRepository::new(db.clone())),
+        template_repository: Arc::new(TemplateRepository::new(db.clone())),
+        preference_repository: Arc::new(PreferenceRepository::new(db.clone())),
+        broadcast_repository: Arc::new(BroadcastRepository::new(db.clone())),
+        notification_repository: Arc::new(NotificationRepository::new(db.clone())),
+        avatar_store: avatar::AvatarStore::new(
+            store.get("AVATAR_DIR").unwrap_or_else(|| "avatars".to_string()),
+            store.get("AVATAR_MAX_BYTES").and_then(|bytes| bytes.parse().ok()).unwrap_or(2 * 1024 * 1024)
+        ),
+        stats_cache: stats::StatsCache::default(),
+        live_events: live::LiveEvents::default(),
         jwt_key_pair: JWTKeyPair {
             public_key: store.get("ACCESS_TOKEN_PUBLIC_KEY").unwrap(),
             private_key: store.get("ACCESS_TOKEN_PRIVATE_KEY").unwrap()
-        }
+        },
+        ledger_signing_key: store.get("LEDGER_SIGNING_PRIVATE_KEY")
+            .zip(store.get("LEDGER_SIGNING_PUBLIC_KEY"))
+            .map(|(private_key, public_key)| JWTKeyPair { public_key, private_key }),
+        app_url: store.get("APP_URL").unwrap_or_else(|| "http://localhost:8000".to_string()),
+        mail_webhook_key: store.get("MAIL_WEBHOOK_SIGNING_KEY").or_else(|| store.get("MAILGUN_WEBHOOK_SIGNING_KEY")),
+        webhook_replays: mailer::WebhookReplayGuard::default(),
     });
 
     // TODO: Handle errors
-    let manager = migrations::Manager::new(db.clone(), state.player_repository.clone(), state.faery_repository.clone());
+    let manager = migrations::Manager::new(db.clone(), state.clone());
     log::info!("Running migrations");
     manager.migrate().await.unwrap();
 
-    log::info!("Creating CORS middleware");
-    let cors = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE]);
+    let reconcile_hours: u64 = store.get("RECONCILE_INTERVAL_HOURS")
+        .and_then(|hours| hours.parse().ok())
+        .unwrap_or(24)
+        // tokio's interval panics on a zero period
+        .max(1);
+    let reconcile_correct = store.get("RECONCILE_AUTO_CORRECT").map(|v| v == "true").unwrap_or(false);
+    log::info!("Scheduling ledger reconciliation every {} hours", reconcile_hours);
+    tasks::spawn_reconciliation(state.clone(), Duration::from_secs(reconcile_hours * 60 * 60), reconcile_correct);
 
-    log::info!("Creating router");
-    let router = Router::new()
-        .route("/api/hello", get(hello_world))
-        .route("/api/faeries", get(endpoints::list_faeries).post(endpoints::create_faery))
-        .route("/api/faeries/:faery_id", get(endpoints::get_faery).put(endpoints::update_faery).delete(endpoints::delete_faery))
-        // .route("/api/test_email", get(send_test_email))
-        .layer(ServiceBuilder::new().layer(cors))
-        .with_state(state)
-        .nest_service("/", ServeDir::new("dross-manager-frontend/dist"));
+    let mail_retry_seconds: u64 = store.get("MAIL_RETRY_INTERVAL_SECONDS")
+        .and_then(|seconds| seconds.parse().ok())
+        .unwrap_or(30)
+        // A zero timeout would have the worker spin without waiting
+        .max(1);
+    tasks::spawn_mail_worker(state.clone(), Duration::from_secs(mail_retry_seconds));
+    let outbox_retention_days: i64 = store.get("OUTBOX_RETENTION_DAYS")
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(30)
+        .clamp(1, 3650);
+    tasks::spawn_outbox_pruning(state.clone(), Duration::from_secs(24 * 60 * 60), outbox_retention_days);
+    tasks::spawn_daily_digests(state.clone(), Duration::from_secs(5 * 60));
+    let notification_retention_days: i64 = store.get("NOTIFICATION_RETENTION_DAYS")
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(90)
+        .clamp(1, 3650);
+    tasks::spawn_notification_pruning(state.clone(), Duration::from_secs(24 * 60 * 60), notification_retention_days);
+    tasks::spawn_broadcasts(state.clone(), Duration::from_secs(60));
+    tasks::spawn_weekly_statements(state.clone(), Duration::from_secs(5 * 60));
+
+    let router = router(state);
 
     Ok(DrossManagerService {
         router
@@ -107,8 +305,12 @@ impl shuttle_runtime::Service for DrossManagerService {
 
 #[cfg(test)]
 mod tests {
+    use tower::ServiceExt;
+    use http::StatusCode;
     use crate::dross::{DrossHolder, transfer_dross};
     use crate::repository::faery::Model;
+    use crate::repository::Repository;
+    use crate::testing;
 
     fn new_faery() -> Model {
         Model::new("Tinkerbell".to_string(), "me@example.com".to_string(), false, 0, None)
@@ -156,4 +358,50 @@ mod tests {
         assert_eq!(faery.dross(), 0);
         assert_eq!(faery_two.dross(), 1);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_admin_routes_require_an_admin() {
+        let state = testing::state().await;
+        let admin_id = testing::create_player(&state, "admin@example.com", true).await;
+        let player_id = testing::create_player(&state, "player@example.com", false).await;
+        let faery_id = state.faery_repository.create(Some(new_faery())).await.unwrap();
+        let admin = testing::token(&state, admin_id, 60);
+        let player = testing::token(&state, player_id, 60);
+        let update = serde_json::json!({ "id": faery_id, "name": "Tink", "email": "me@example.com", "is_admin": false, "dross": 0 });
+
+        let requests = [
+            ("GET", "/api/players".to_string(), None),
+            ("POST", "/api/faeries".to_string(), Some(serde_json::json!({ "name": "Puck", "email": "puck@example.com" }))),
+            ("PUT", format!("/api/faeries/{}", faery_id), Some(update.clone())),
+            ("DELETE", format!("/api/faeries/{}", faery_id), None),
+        ];
+        for (method, uri, body) in requests.clone() {
+            let response = super::router(state.clone()).oneshot(testing::request(method, &uri, None, body)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{} {} without a token", method, uri);
+        }
+        for (method, uri, body) in requests.clone() {
+            let response = super::router(state.clone()).oneshot(testing::request(method, &uri, Some(&player), body)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{} {} as a player", method, uri);
+        }
+        assert_eq!(state.faery_repository.get(faery_id).await.unwrap().name(), "Tinkerbell");
+
+        let expected = [StatusCode::OK, StatusCode::CREATED, StatusCode::OK, StatusCode::NO_CONTENT];
+        for ((method, uri, body), status) in requests.into_iter().zip(expected) {
+            let response = super::router(state.clone()).oneshot(testing::request(method, &uri, Some(&admin), body)).await.unwrap();
+            assert_eq!(response.status(), status, "{} {} as an admin", method, uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_player_routes_require_a_login() {
+        let state = testing::state().await;
+        let player_id = testing::create_player(&state, "player@example.com", false).await;
+        let player = testing::token(&state, player_id, 60);
+
+        let response = super::router(state.clone()).oneshot(testing::request("GET", "/api/me", None, None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = super::router(state.clone()).oneshot(testing::request("GET", "/api/me", Some(&player), None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(testing::json(response).await["player"]["id"], player_id);
+    }
+}
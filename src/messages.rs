@@ -0,0 +1,327 @@
+use std::collections::BTreeMap;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use crate::DrossManagerState;
+use crate::auth::jwt::sign_claims;
+use crate::live::publish_notification;
+use crate::mailer::Email;
+use crate::repository::{Repository, RepositoryError, RepositoryResult};
+use crate::repository::broadcast::{Broadcast, BroadcastAudience};
+use crate::repository::ledger::{Entry, EntryKind};
+use crate::repository::notification::Notification;
+use crate::repository::player::Model as Player;
+use crate::repository::preference::{Delivery, HeldNotification, NotificationCategory};
+use crate::repository::settings::Branding;
+use crate::repository::template::Variables;
+
+// Unsubscribe links stay valid this long
+const UNSUBSCRIBE_TTL_DAYS: i64 = 365;
+const UNSUBSCRIBE_PURPOSE: &str = "unsubscribe";
+// Emails from these templates carry credentials, so their bodies are kept out of admin views
+const SENSITIVE_TEMPLATES: [&str; 2] = ["login_token", "verification"];
+// Broadcasts are spread out in the outbox so a big audience doesn't go out all at once
+const BROADCAST_RATE_PER_MINUTE: usize = 60;
+
+// UnsubscribeClaims are signed into unsubscribe links, so following one needs no login.
+// A missing category unsubscribes from everything.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnsubscribeClaims {
+    pub sub: String,
+    pub category: Option<NotificationCategory>,
+    pub purpose: String,
+    pub exp: i64,
+    pub iat: i64,
+}
+
+impl UnsubscribeClaims {
+    pub fn player_id(&self) -> Option<i64> {
+        match self.purpose == UNSUBSCRIBE_PURPOSE {
+            true => self.sub.parse().ok(),
+            false => None,
+        }
+    }
+}
+
+pub fn unsubscribe_url(state: &DrossManagerState, player_id: i64, category: Option<NotificationCategory>) -> RepositoryResult<String> {
+    let now = Utc::now();
+    let claims = UnsubscribeClaims {
+        sub: player_id.to_string(),
+        category,
+        purpose: UNSUBSCRIBE_PURPOSE.to_string(),
+        exp: (now + Duration::days(UNSUBSCRIBE_TTL_DAYS)).timestamp(),
+        iat: now.timestamp(),
+    };
+    let token = sign_claims(&claims, state.jwt_key_pair.private_key.to_owned()).map_err(|err| {
+        log::error!("Error signing unsubscribe link: {:?}", err);
+        RepositoryError::Other
+    })?;
+    Ok(format!("{}/api/unsubscribe?token={}", state.app_url, token))
+}
+
+pub(crate) async fn render(state: &DrossManagerState, name: &str, to: &str, variables: &Variables) -> RepositoryResult<Email> {
+    let template = state.template_repository.latest(name).await?;
+    let branding: Branding = state.settings_repository.load(Branding::KEY).await?;
+    Ok(template.render(to, variables, &branding))
+}
+
+// send_template renders a named template with the game's branding and queues it in the outbox.
+// It ignores notification preferences; use notify for anything a player can opt out of.
+pub async fn send_template(state: &DrossManagerState, name: &str, to: &str, variables: Variables) -> RepositoryResult<()> {
+    let mut email = render(state, name, to, &variables).await?;
+    email.sensitive = SENSITIVE_TEMPLATES.contains(&name);
+    state.email_repository.queue(email).await
+}
+
+// notify sends a templated email in a notification category, the way the player asked for it:
+// straight away with an unsubscribe link, held for their daily digest, or not at all. Either way
+// it's added to the player's in-app notifications.
+pub async fn notify(
+    state: &DrossManagerState,
+    player: &Player,
+    category: NotificationCategory,
+    name: &str,
+    variables: Variables
+) -> RepositoryResult<()> {
+    notify_at(state, player, category, name, variables, Utc::now().timestamp_millis()).await?;
+    Ok(())
+}
+
+// notify_at is notify with the email held in the outbox until `at`. It returns the outbox
+// message, if the email was queued rather than held or dropped.
+async fn notify_at(
+    state: &DrossManagerState,
+    player: &Player,
+    category: NotificationCategory,
+    name: &str,
+    mut variables: Variables,
+    at: i64
+) -> RepositoryResult<Option<i64>> {
+    let player_id = player.id.ok_or(RepositoryError::InvalidModel)?;
+    let email = render(state, name, &player.auth_email, &variables).await?;
+    let notification = Notification::new(player_id, category, &email.subject, &email.body);
+    let id = state.notification_repository.save(notification.clone()).await?;
+    publish_notification(state, &Notification { id: Some(id), ..notification });
+    match state.preference_repository.get(player_id).await?.delivery(category) {
+        Delivery::Off => Ok(None),
+        Delivery::Immediate => {
+            variables.insert("unsubscribe_url".to_string(), unsubscribe_url(state, player_id, Some(category))?);
+            let email = render(state, name, &player.auth_email, &variables).await?;
+            state.email_repository.queue_at(email, at).await.map(Some)
+        },
+        Delivery::DailyDigest => {
+            state.preference_repository.hold(player_id, category, &email.subject, &email.body).await?;
+            Ok(None)
+        },
+    }
+}
+
+// send_digest queues one player's digest, returning false when there was nothing left to send.
+async fn send_digest(state: &DrossManagerState, player_id: i64, held: &[HeldNotification]) -> RepositoryResult<bool> {
+    let player = match state.player_repository.get(player_id).await {
+        Ok(player) => player,
+        Err(RepositoryError::NotFound) => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    // Skip anything the player turned off since it was held
+    let preferences = state.preference_repository.get(player_id).await?;
+    let summary: Vec<String> = held.iter()
+        .filter(|notification| preferences.delivery(notification.category) != Delivery::Off)
+        .map(|notification| format!("{}\n\n{}", notification.subject, notification.text))
+        .collect();
+    if summary.is_empty() {
+        return Ok(false);
+    }
+    let variables = Variables::from([
+        ("summary".to_string(), summary.join("\n\n----\n\n")),
+        ("unsubscribe_url".to_string(), unsubscribe_url(state, player_id, None)?),
+    ]);
+    send_template(state, "daily_digest", &player.auth_email, variables).await?;
+    Ok(true)
+}
+
+// send_daily_digests sends every player one email with the notifications held for them. A
+// player's notifications are only released once their digest is queued, so one failure keeps
+// theirs for the next run without holding up everyone else's.
+pub async fn send_daily_digests(state: &DrossManagerState) -> RepositoryResult<usize> {
+    let mut sent = 0;
+    for (player_id, held) in state.preference_repository.held().await? {
+        let through_id = match held.last() {
+            Some(notification) => notification.id,
+            None => continue,
+        };
+        match send_digest(state, player_id, &held).await {
+            Ok(queued) => {
+                if queued {
+                    sent += 1;
+                }
+                if let Err(err) = state.preference_repository.release(player_id, through_id).await {
+                    log::error!("Error releasing held notifications for player {}: {:?}", player_id, err);
+                }
+            },
+            Err(err) => log::error!("Error sending the daily digest to player {}: {:?}", player_id, err),
+        }
+    }
+    Ok(sent)
+}
+
+// send_receipt tells a faery's owner that its balance changed. Faeries whose owner has no
+// player account don't get receipts, since there'd be no preferences to honor.
+pub async fn send_receipt(state: &DrossManagerState, entry: &Entry, counterparty: Option<&str>) -> RepositoryResult<()> {
+    if entry.kind == EntryKind::Opening || entry.amount == 0 {
+        return Ok(());
+    }
+    let faery = state.faery_repository.get(entry.faery_id).await?;
+    let player = match state.player_repository.find_by_email(faery.email()).await {
+        Ok(player) => player,
+        Err(RepositoryError::NotFound) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    let counterparty = match counterparty {
+        Some(counterparty) => counterparty.to_string(),
+        None => state.settings_repository.load::<Branding>(Branding::KEY).await?.game_name,
+    };
+    let variables = Variables::from([
+        ("faery".to_string(), faery.name().to_string()),
+        ("amount".to_string(), format!("{:+}", entry.amount)),
+        ("kind".to_string(), entry.kind.as_str().to_string()),
+        ("memo".to_string(), entry.memo.clone()),
+        ("counterparty".to_string(), counterparty),
+        ("balance".to_string(), entry.balance.to_string()),
+    ]);
+    notify(state, &player, NotificationCategory::Transactions, "transfer_receipt", variables).await
+}
+
+pub fn broadcast_variables(broadcast: &Broadcast) -> Variables {
+    Variables::from([
+        ("subject".to_string(), broadcast.subject.clone()),
+        ("message".to_string(), broadcast.message.clone()),
+    ])
+}
+
+// broadcast_audience finds the active players a broadcast is for. A group's audience is the
+// players who own its member faeries.
+pub async fn broadcast_audience(state: &DrossManagerState, broadcast: &Broadcast) -> RepositoryResult<Vec<Player>> {
+    let players = match (broadcast.audience, broadcast.group_id) {
+        (BroadcastAudience::Group, Some(group_id)) => {
+            let mut players = BTreeMap::new();
+            for faery_id in state.group_repository.member_ids(group_id, broadcast.include_subgroups).await? {
+                let faery = state.faery_repository.get(faery_id).await?;
+                match state.player_repository.find_by_email(faery.email()).await {
+                    Ok(player) => { players.insert(player.id, player); },
+                    Err(RepositoryError::NotFound) => {},
+                    Err(err) => return Err(err),
+                }
+            }
+            players.into_values().collect()
+        },
+        (BroadcastAudience::Group, None) => return Err(RepositoryError::InvalidModel),
+        (BroadcastAudience::All, _) => state.player_repository.get_all().await?,
+    };
+    Ok(players.into_iter().filter(|player| player.is_active()).collect())
+}
+
+// send_broadcast queues a scheduled broadcast for its audience, spread out at
+// BROADCAST_RATE_PER_MINUTE. It returns false if the broadcast was already sent or cancelled.
+// If it fails partway, the broadcast goes back to scheduled and the next run picks up with the
+// players it hadn't reached.
+pub async fn send_broadcast(state: &DrossManagerState, broadcast_id: i64) -> RepositoryResult<bool> {
+    if !state.broadcast_repository.claim(broadcast_id).await? {
+        return Ok(false);
+    }
+    if let Err(err) = queue_broadcast(state, broadcast_id).await {
+        if let Err(release_err) = state.broadcast_repository.release(broadcast_id).await {
+            log::error!("Error releasing broadcast {}: {:?}", broadcast_id, release_err);
+        }
+        return Err(err);
+    }
+    Ok(true)
+}
+
+async fn queue_broadcast(state: &DrossManagerState, broadcast_id: i64) -> RepositoryResult<()> {
+    let broadcast = state.broadcast_repository.get(broadcast_id).await?;
+    let variables = broadcast_variables(&broadcast);
+    let reached = state.broadcast_repository.recipients(broadcast_id).await?;
+    let start = Utc::now().timestamp_millis();
+    let mut queued = 0;
+    for player in broadcast_audience(state, &broadcast).await? {
+        let Some(player_id) = player.id else {
+            continue;
+        };
+        if reached.contains(&player_id) {
+            continue;
+        }
+        let at = start + (queued / BROADCAST_RATE_PER_MINUTE) as i64 * 60 * 1000;
+        match notify_at(state, &player, NotificationCategory::Broadcasts, "broadcast", variables.clone(), at).await {
+            Ok(outbox_id) => {
+                if outbox_id.is_some() {
+                    queued += 1;
+                }
+                if let Err(err) = state.broadcast_repository.add_recipient(broadcast_id, player_id, outbox_id).await {
+                    log::error!("Error recording player {} as a recipient of broadcast {}: {:?}", player_id, broadcast_id, err);
+                }
+            },
+            Err(err) => log::error!("Error sending broadcast {} to player {}: {:?}", broadcast_id, player_id, err),
+        }
+    }
+    state.broadcast_repository.finish(broadcast_id).await?;
+    log::info!("Queued broadcast {} for {} players", broadcast_id, queued);
+    Ok(())
+}
+
+pub async fn send_due_broadcasts(state: &DrossManagerState) -> RepositoryResult<usize> {
+    let mut sent = 0;
+    for broadcast in state.broadcast_repository.due().await? {
+        let Some(broadcast_id) = broadcast.id else {
+            continue;
+        };
+        match send_broadcast(state, broadcast_id).await {
+            Ok(true) => sent += 1,
+            Ok(false) => {},
+            Err(err) => log::error!("Error sending broadcast {}: {:?}", broadcast_id, err),
+        }
+    }
+    Ok(sent)
+}
+
+#[allow(dead_code)]
+pub async fn send_login_token(state: &DrossManagerState, to: &str, token: &str) -> RepositoryResult<()> {
+    send_template(state, "login_token", to, Variables::from([("token".to_string(), token.to_string())])).await
+}
+
+pub async fn send_verification_link(state: &DrossManagerState, to: &str, link: &str) -> RepositoryResult<()> {
+    send_template(state, "verification", to, Variables::from([("link".to_string(), link.to_string())])).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repository::Repository;
+    use crate::repository::broadcast::{BroadcastAudience, BroadcastRequest};
+    use crate::repository::notification::NotificationQuery;
+    use crate::repository::preference::{Delivery, NotificationPreferences};
+    use crate::testing;
+    use super::send_broadcast;
+
+    #[tokio::test]
+    async fn test_broadcasts_reach_the_notification_center_even_when_emails_are_off() {
+        let state = testing::state().await;
+        let admin_id = testing::create_player(&state, "admin@example.com", true).await;
+        let player_id = testing::create_player(&state, "wendy@example.com", false).await;
+        let preferences = NotificationPreferences { broadcasts: Delivery::Off, ..NotificationPreferences::default() };
+        state.preference_repository.store(player_id, &preferences).await.unwrap();
+        let request = BroadcastRequest {
+            subject: "Moon festival".to_string(),
+            message: "Tonight at the old oak".to_string(),
+            audience: BroadcastAudience::All,
+            group_id: None,
+            include_subgroups: false,
+            scheduled_at: None,
+        };
+        let broadcast_id = state.broadcast_repository.save(request.into_broadcast(Some(admin_id))).await.unwrap();
+
+        assert!(send_broadcast(&state, broadcast_id).await.unwrap());
+        let outbox = state.email_repository.outbox(None).await.unwrap();
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox[0].recipient, "admin@example.com");
+        assert_eq!(state.notification_repository.for_player(player_id, &NotificationQuery::default()).await.unwrap().unread, 1);
+    }
+}
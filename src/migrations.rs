@@ -3,9 +3,10 @@ use libsql::Connection;
 use serde::{Deserialize, Serialize};
 use semver::{Version, VersionReq};
 use tokio::sync::Mutex;
-use crate::repository::{RepositoryError, RepositoryItem, RepositoryResult};
+use crate::repository::{finish_transaction, RepositoryError, RepositoryItem, RepositoryResult};
 use crate::prelude::*;
 use crate::repository::player;
+use crate::DrossManagerState;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Migration {
@@ -91,22 +92,22 @@ impl Migration {
     }
 
     async fn new_install_check(&mut self) -> bool {
-        if self.current_version.is_none() && (self.target_version.to_string() == VERSION.to_string()) {
+        if self.current_version.is_none() && (self.target_version.to_string() == VERSION) {
             self.current_version = Some(Version::parse("0.0.0").unwrap());
             return true
         }
-        return false
+        false
     }
 
     fn needs_migration(&self) -> bool {
         match self.current_version.clone() {
             Some(current_version) => {
                 let version_req = VersionReq::parse(
-                    &format!(">={}", VERSION.to_string())).unwrap();
+                    &format!(">={}", VERSION)).unwrap();
                 !version_req.matches(&current_version)
             },
             None => {
-                return true;
+                true
             }
         }
     }
@@ -134,42 +135,47 @@ impl RepositoryItem for MigrationData {
 #[derive(Clone)]
 pub struct Manager {
     db: Arc<Mutex<Connection>>,
-    player_repository: Arc<PlayerRepository>,
-    faery_repository: Arc<FaeryRepository>,
+    state: Arc<DrossManagerState>,
 }
 
 impl Manager {
-    pub fn new(db: Arc<Mutex<Connection>>, player_repository: Arc<PlayerRepository>, faery_repository: Arc<FaeryRepository>) -> Manager {
+    pub fn new(db: Arc<Mutex<Connection>>, state: Arc<DrossManagerState>) -> Manager {
         Manager {
             db,
-            player_repository,
-            faery_repository
+            state,
         }
     }
 
-    async fn create_tables(&self) -> RepositoryResult<()> {
-        let migration = self.create_table().await;
-        let player = self.player_repository.create_table().await;
-        let faery = self.faery_repository.create_table().await;
-        match migration {
-            Ok(_) => {
-                log::debug!("Migration table created");
-                match player {
-                    Ok(_) => {
-                        log::debug!("Player table created");
-                        match faery {
-                            Ok(_) => {
-                                log::debug!("Faery table created");
-                                Ok(())
-                            },
-                            Err(err) => Err(err),
-                        }
-                    },
-                    Err(err) => Err(err),
-                }
-            },
-            Err(err) => Err(err),
-        }
+    pub(crate) async fn create_tables(&self) -> RepositoryResult<()> {
+        self.create_table().await?;
+        log::debug!("Migration table created");
+        self.state.player_repository.create_table().await?;
+        log::debug!("Player table created");
+        self.state.faery_repository.create_table().await?;
+        log::debug!("Faery table created");
+        self.state.ledger_repository.create_table().await?;
+        log::debug!("Ledger table created");
+        self.state.achievement_repository.create_table().await?;
+        log::debug!("Achievement tables created");
+        self.state.snapshot_repository.create_table().await?;
+        log::debug!("Snapshot tables created");
+        self.state.settings_repository.create_table().await?;
+        log::debug!("Settings table created");
+        self.state.email_repository.create_table().await?;
+        log::debug!("Email log table created");
+        self.state.group_repository.create_table().await?;
+        log::debug!("Group tables created");
+        self.state.attribute_repository.create_table().await?;
+        log::debug!("Attribute tables created");
+        self.state.template_repository.create_table().await?;
+        log::debug!("Email template tables created");
+        self.state.preference_repository.create_table().await?;
+        log::debug!("Notification preference tables created");
+        self.state.broadcast_repository.create_table().await?;
+        log::debug!("Broadcast tables created");
+        self.state.notification_repository.create_table().await?;
+        log::debug!("Notification table created");
+        Ok(())
     }
 
     pub async fn migrate(&self) -> RepositoryResult<()> {
@@ -182,9 +188,10 @@ impl Manager {
         if current_state.new_install_check().await {
             log::info!("New installation detected. Running initial table creation.");
             self.create_tables().await?;
-            return self.migrate_023().await
+            self.migrate_023().await?;
+            return self.complete_migration(VERSION).await
         }
-        log::info!("Migrating to {}", VERSION.to_string());
+        log::info!("Migrating to {}", VERSION);
         let current_migration: Migration = Migration::new(
             current_state.current_version.clone().map(|v| v.to_string()),
             Some(VERSION.to_string())
@@ -196,7 +203,7 @@ impl Manager {
                     match target.clone() {
                         version if version == "0.2.3" => {
                             if let Some(current_version) = migration_data.current_version {
-                                self.player_repository.create_table().await?;
+                                self.state.player_repository.create_table().await?;
                                 match current_version.as_str() {
                                     "0.2.1" => {
                                         log::info!("migrating from 0.2.1");
@@ -214,7 +221,30 @@ impl Manager {
                             }
                         },
                         version if version == "0.2.4" => {
-                            // 0.2.4 migrations
+                            if let Some(current_version) = migration_data.current_version {
+                                match current_version.as_str() {
+                                    "0.2.1" => {
+                                        log::info!("migrating from 0.2.1");
+                                        self.state.player_repository.create_table().await?;
+                                        self.migrate_021_to_022().await?;
+                                        self.migrate_023().await?;
+                                        self.migrate_024().await?;
+                                    },
+                                    "0.2.2" => {
+                                        log::info!("migrating from 0.2.2");
+                                        self.state.player_repository.create_table().await?;
+                                        self.migrate_023().await?;
+                                        self.migrate_024().await?;
+                                    },
+                                    "0.2.3" => {
+                                        log::info!("migrating from 0.2.3");
+                                        self.migrate_024().await?;
+                                    },
+                                    _ => { }
+                                }
+                            } else {
+                                log::info!("No current version found. Skipping migration.");
+                            }
                         },
                         _ => {
                             log::info!("Unknown target version: {}", target);
@@ -259,13 +289,13 @@ impl Manager {
         let migration = self.start_migration("0.2.2", "0.2.3").await;
         match migration {
             Ok(_) => {
-                let admin_count = self.player_repository.admin_count().await.unwrap_or(0);
+                let admin_count = self.state.player_repository.admin_count().await.unwrap_or(0);
                 if admin_count == 0 {
                     log::info!("Inserting admin user");
                     let admin_email = std::env::var("ADMIN_EMAIL").unwrap_or_else(|_| {
                         "email@example.com".to_string()
                     });
-                    match self.player_repository.create(Some(
+                    match self.state.player_repository.create(Some(
                         player::Model::new(
                             None,
                             "Admin".to_string(),
@@ -290,6 +320,105 @@ impl Manager {
         }
     }
 
+    pub async fn migrate_024(&self) -> RepositoryResult<()> {
+        log::info!("Starting migration record 0.2.3 -> 0.2.4");
+        self.start_migration("0.2.3", "0.2.4").await?;
+        log::info!("Creating ledger, achievement, snapshot and settings tables");
+        self.state.ledger_repository.create_table().await?;
+        self.state.achievement_repository.create_table().await?;
+        self.state.snapshot_repository.create_table().await?;
+        self.state.settings_repository.create_table().await?;
+        self.state.email_repository.create_table().await?;
+        self.state.group_repository.create_table().await?;
+        self.state.attribute_repository.create_table().await?;
+        self.state.template_repository.create_table().await?;
+        self.state.preference_repository.create_table().await?;
+        self.state.broadcast_repository.create_table().await?;
+        self.state.notification_repository.create_table().await?;
+        // Creates faery_tags; the faeries table itself already exists
+        self.state.faery_repository.create_table().await?;
+        self.state.ledger_repository.open_balances().await?;
+        self.add_column("faeries", "leaderboard_opt_out", "BOOLEAN NOT NULL DEFAULT 0").await?;
+        self.add_column("players", "status", "TEXT NOT NULL DEFAULT 'active'").await?;
+        self.add_column("players", "verification_token", "TEXT").await?;
+        self.add_column("players", "verification_expires", "INTEGER").await?;
+        self.add_column("faeries", "deleted_at", "INTEGER").await?;
+        self.add_column("players", "deleted_at", "INTEGER").await?;
+        self.add_column("players", "pending_email", "TEXT").await?;
+        self.add_column("players", "email_suppressed", "TEXT").await?;
+        self.add_column("email_outbox", "sensitive", "BOOLEAN NOT NULL DEFAULT 0").await?;
+        self.add_column("faery_redirects", "debit_entry_id", "INTEGER").await?;
+        self.add_column("faery_redirects", "credit_entry_id", "INTEGER").await?;
+        self.autoincrement_ids("faeries", Some("SELECT faery_id FROM ledger UNION ALL SELECT source_id FROM faery_redirects")).await?;
+        self.autoincrement_ids("players", None).await?;
+        // Adds the unique index on player emails; the players table itself already exists
+        self.state.player_repository.create_table().await?;
+        self.complete_migration("0.2.4").await
+    }
+
+    // add_column adds a column unless the table already has it, since tables created fresh
+    // during an upgrade already carry the current schema.
+    async fn add_column(&self, table: &str, column: &str, definition: &str) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        let mut res = db.query(&format!("PRAGMA table_info({})", table), ()).await?;
+        while let Some(row) = res.next()? {
+            let name: String = row.get(1)?;
+            if name == column {
+                log::debug!("{}.{} already exists", table, column);
+                return Ok(());
+            }
+        }
+        let statement = format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition);
+        match db.execute(&statement, ()).await {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                log::error!("Error running \"{}\": {:?}", statement, err);
+                Err(RepositoryError::Other)
+            },
+        }
+    }
+
+    // autoincrement_ids rebuilds a table whose ids SQLite would hand out again once the last row is
+    // removed, so a purged id can't come back as a different row. Ids still referenced by `reserved`
+    // are skipped as well.
+    async fn autoincrement_ids(&self, table: &str, reserved: Option<&str>) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        let sql: String = match db.query("SELECT sql FROM sqlite_master WHERE type = 'table' AND name = ?1", [table]).await?.next()? {
+            Some(row) => row.get(0)?,
+            None => return Ok(()),
+        };
+        if sql.contains("AUTOINCREMENT") {
+            log::debug!("{} already has autoincrementing ids", table);
+            return Ok(());
+        }
+        let rebuilt = format!("{}_rebuilt", table);
+        let create = sql
+            .replacen(&format!("CREATE TABLE {}", table), &format!("CREATE TABLE {}", rebuilt), 1)
+            .replacen("id INTEGER PRIMARY KEY", "id INTEGER PRIMARY KEY AUTOINCREMENT", 1);
+        if !create.contains(&rebuilt) || !create.contains("AUTOINCREMENT") {
+            log::error!("Can't rebuild {} with autoincrementing ids from \"{}\"", table, sql);
+            return Err(RepositoryError::Other);
+        }
+        let floor = match reserved {
+            Some(reserved) => format!("SELECT MAX(id) FROM (SELECT id FROM {table} UNION ALL {reserved})"),
+            None => format!("SELECT MAX(id) FROM {table}"),
+        };
+        db.execute("BEGIN", ()).await?;
+        let result = async {
+            db.execute(&create, ()).await?;
+            db.execute(&format!("INSERT INTO {rebuilt} SELECT * FROM {table}"), ()).await?;
+            db.execute(&format!("DROP TABLE {table}"), ()).await?;
+            db.execute(&format!("ALTER TABLE {rebuilt} RENAME TO {table}"), ()).await?;
+            db.execute("DELETE FROM sqlite_sequence WHERE name = ?1", [table]).await?;
+            db.execute(&format!("INSERT INTO sqlite_sequence (name, seq) SELECT ?1, COALESCE(({floor}), 0)"), [table]).await?;
+            Ok(())
+        }.await;
+        if let Err(err) = &result {
+            log::error!("Error rebuilding {} with autoincrementing ids: {:?}", table, err);
+        }
+        finish_transaction(&db, result).await
+    }
+
     pub async fn migrate_021_to_022(&self) -> RepositoryResult<()> {
         log::info!("Starting migration record 0.2.1 -> 0.2.2");
         let migration = self.start_migration("0.2.1", "0.2.2").await;
@@ -437,4 +566,33 @@ impl From<Version> for Migration {
     fn from(version: Version) -> Self {
         Migration::new(None, Some(version.to_string()))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use crate::testing;
+    use super::Manager;
+
+    #[tokio::test]
+    async fn test_autoincrement_ids() {
+        let db = Arc::new(Mutex::new(libsql::Database::open_in_memory().unwrap().connect().unwrap()));
+        db.lock().await.execute_batch(r#"CREATE TABLE IF NOT EXISTS faeries (id INTEGER PRIMARY KEY, name TEXT NOT NULL);
+CREATE TABLE ledger (faery_id INTEGER NOT NULL);
+INSERT INTO faeries (name) VALUES ('Puck'), ('Mab');
+INSERT INTO ledger (faery_id) VALUES (5)"#).await.unwrap();
+        let manager = Manager::new(db.clone(), testing::state().await);
+        for _ in 0..2 {
+            manager.autoincrement_ids("faeries", Some("SELECT faery_id FROM ledger")).await.unwrap();
+        }
+
+        let db = db.lock().await;
+        db.execute("DELETE FROM faeries WHERE id = 2", ()).await.unwrap();
+        db.execute("INSERT INTO faeries (name) VALUES ('Tink')", ()).await.unwrap();
+        // Past both the removed faery and the one only the ledger still remembers
+        assert_eq!(db.last_insert_rowid(), 6);
+        let mut res = db.query("SELECT group_concat(name) FROM (SELECT name FROM faeries ORDER BY id)", ()).await.unwrap();
+        assert_eq!(res.next().unwrap().unwrap().get::<String>(0).unwrap(), "Puck,Tink");
+    }
+}
@@ -5,4 +5,14 @@ pub use crate::version::VERSION;
 pub use crate::repository::email::EmailRepository;
 pub use crate::repository::player::PlayerRepository;
 pub use crate::repository::player::PlayerData;
-pub use crate::repository::session::SessionRepository;
\ No newline at end of file
+pub use crate::repository::session::SessionRepository;
+pub use crate::repository::ledger::LedgerRepository;
+pub use crate::repository::achievement::AchievementRepository;
+pub use crate::repository::snapshot::SnapshotRepository;
+pub use crate::repository::settings::SettingsRepository;
+pub use crate::repository::group::GroupRepository;
+pub use crate::repository::attribute::AttributeRepository;
+pub use crate::repository::template::TemplateRepository;
+pub use crate::repository::preference::PreferenceRepository;
+pub use crate::repository::broadcast::BroadcastRepository;
+pub use crate::repository::notification::NotificationRepository;
\ No newline at end of file
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use crate::DrossManagerState;
+use crate::dross::announce_entry;
+use crate::repository::RepositoryResult;
+use crate::repository::faery::Model;
+use crate::repository::ledger::{Entry, EntryKind};
+
+// Discrepancy is a faery whose stored balance doesn't match the sum of its ledger entries.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Discrepancy {
+    pub faery_id: i64,
+    pub name: String,
+    pub stored: i64,
+    pub computed: i64,
+    pub difference: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReconciliationReport {
+    pub checked: usize,
+    pub discrepancies: Vec<Discrepancy>,
+    pub corrected: bool,
+    pub ran_at: i64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ReconcileQuery {
+    #[serde(default)]
+    pub correct: bool,
+}
+
+// find_discrepancies compares stored balances against the balances recomputed from the ledger.
+pub fn find_discrepancies(faeries: &[Model], computed: &HashMap<i64, i64>) -> Vec<Discrepancy> {
+    faeries.iter()
+        .filter_map(|faery| {
+            let faery_id = faery.id?;
+            let stored = faery.dross() as i64;
+            let computed = computed.get(&faery_id).copied().unwrap_or(0);
+            if stored == computed {
+                return None;
+            }
+            Some(Discrepancy {
+                faery_id,
+                name: faery.name().to_string(),
+                stored,
+                computed,
+                difference: stored - computed,
+            })
+        })
+        .collect()
+}
+
+// corrections records an adjustment for each discrepancy so the ledger matches the stored balance.
+fn corrections(discrepancies: &[Discrepancy]) -> Vec<Entry> {
+    discrepancies.iter()
+        .map(|discrepancy| Entry::new(
+            discrepancy.faery_id,
+            discrepancy.difference,
+            discrepancy.stored,
+            EntryKind::Adjustment,
+            format!(
+                "Reconciliation: ledger totalled {} but stored balance was {}",
+                discrepancy.computed, discrepancy.stored
+            )
+        ))
+        .collect()
+}
+
+// reconcile recomputes every balance from the ledger and reports any that disagree.
+// When `correct` is set, an adjustment entry is recorded so the ledger matches the stored balance.
+pub async fn reconcile(state: &DrossManagerState, correct: bool) -> RepositoryResult<ReconciliationReport> {
+    // The corrections are recorded in the same transaction as the read, so a balance can't change
+    // in between and a failure can't leave the ledger half corrected
+    let (faeries, computed, corrections) = state.ledger_repository.reconcile(|faeries, computed| {
+        match correct {
+            true => corrections(&find_discrepancies(faeries, computed)),
+            false => vec![],
+        }
+    }).await?;
+    let discrepancies = find_discrepancies(&faeries, &computed);
+    if discrepancies.is_empty() {
+        log::info!("Reconciled {} faeries, no discrepancies", faeries.len());
+    } else {
+        log::warn!("Reconciled {} faeries, {} discrepancies", faeries.len(), discrepancies.len());
+    }
+    for entry in &corrections {
+        announce_entry(state, entry, None).await;
+    }
+
+    Ok(ReconciliationReport {
+        checked: faeries.len(),
+        discrepancies,
+        corrected: correct,
+        ran_at: Utc::now().timestamp_millis(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::{find_discrepancies, reconcile};
+    use crate::repository::Repository;
+    use crate::repository::faery::Model;
+    use crate::testing;
+
+    #[test]
+    fn test_find_discrepancies() {
+        let faeries = vec![
+            Model::new("Tinkerbell".to_string(), "me@example.com".to_string(), false, 10, Some(1)),
+            Model::new("Silvermist".to_string(), "you@example.com".to_string(), false, 5, Some(2)),
+            Model::new("Iridessa".to_string(), "them@example.com".to_string(), false, 3, Some(3)),
+        ];
+        let computed = HashMap::from([(1, 10), (2, 8)]);
+        let discrepancies = find_discrepancies(&faeries, &computed);
+        assert_eq!(discrepancies.len(), 2);
+        assert_eq!((discrepancies[0].faery_id, discrepancies[0].difference), (2, -3));
+        assert_eq!((discrepancies[1].faery_id, discrepancies[1].computed, discrepancies[1].difference), (3, 0, 3));
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_corrects_ledger() {
+        let state = testing::state().await;
+        let faery_id = state.faery_repository.create(Some(Model::new("Puck".to_string(), "puck@example.com".to_string(), false, 5, None))).await.unwrap();
+
+        let report = reconcile(&state, false).await.unwrap();
+        assert_eq!(report.discrepancies.len(), 1);
+        assert!(state.ledger_repository.get_for_faery(faery_id).await.unwrap().is_empty());
+
+        let report = reconcile(&state, true).await.unwrap();
+        assert_eq!((report.discrepancies[0].faery_id, report.discrepancies[0].difference), (faery_id, 5));
+        let entries = state.ledger_repository.get_for_faery(faery_id).await.unwrap();
+        assert_eq!((entries.len(), entries[0].amount, entries[0].balance), (1, 5, 5));
+        assert!(reconcile(&state, true).await.unwrap().discrepancies.is_empty());
+    }
+}
@@ -0,0 +1,290 @@
+use std::sync::Arc;
+use chrono::Utc;
+use libsql::{Connection, params, Row};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use crate::repository::{finish_transaction, Repository, RepositoryError, RepositoryItem, RepositoryResult};
+use crate::repository::ledger::LedgerSummary;
+
+// Criteria decides when an achievement is awarded automatically.
+// It's stored as JSON alongside the achievement, e.g. {"type": "total_spent", "amount": 500}.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Criteria {
+    TotalEarned { amount: i64 },
+    TotalSpent { amount: i64 },
+    Balance { amount: i64 },
+    Transactions { count: i64 },
+    // Only ever granted by an admin
+    Manual,
+}
+
+impl Criteria {
+    pub fn is_met(&self, summary: &LedgerSummary) -> bool {
+        match self {
+            Criteria::TotalEarned { amount } => summary.earned >= *amount,
+            Criteria::TotalSpent { amount } => summary.spent >= *amount,
+            Criteria::Balance { amount } => summary.balance >= *amount,
+            Criteria::Transactions { count } => summary.transactions >= *count,
+            Criteria::Manual => false,
+        }
+    }
+}
+
+// Achievement is the definition of a badge a faery can earn, with an optional dross reward.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Achievement {
+    pub(crate) id: Option<i64>,
+    pub name: String,
+    pub description: String,
+    pub criteria: Criteria,
+    #[serde(default)]
+    pub reward: u32,
+}
+
+impl Achievement {
+    pub fn from_response(row: &Row) -> RepositoryResult<Achievement> {
+        let criteria: String = row.get(3)?;
+        Ok(Achievement {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            description: row.get(2)?,
+            criteria: serde_json::from_str(&criteria).map_err(|_| RepositoryError::InvalidModel)?,
+            reward: row.get(4)?,
+        })
+    }
+}
+
+impl RepositoryItem for Achievement {
+    fn masked_columns(_: bool) -> Vec<String> {
+        vec![]
+    }
+
+    fn saved_columns() -> Vec<String> {
+        vec![
+            "name".to_string(),
+            "description".to_string(),
+            "criteria".to_string(),
+            "reward".to_string(),
+        ]
+    }
+
+    fn all_columns() -> Vec<String> {
+        vec![
+            "id".to_string(),
+            "name".to_string(),
+            "description".to_string(),
+            "criteria".to_string(),
+            "reward".to_string(),
+        ]
+    }
+
+    fn table_name() -> String where Self: Sized {
+        "achievements".to_string()
+    }
+}
+
+// Badge is an achievement that has been awarded to a faery.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Badge {
+    pub achievement_id: i64,
+    pub name: String,
+    pub description: String,
+    pub awarded_at: i64,
+    pub manual: bool,
+}
+
+impl Badge {
+    pub fn from_response(row: &Row) -> RepositoryResult<Badge> {
+        Ok(Badge {
+            achievement_id: row.get(0)?,
+            name: row.get(1)?,
+            description: row.get(2)?,
+            awarded_at: row.get(3)?,
+            manual: row.get(4)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GrantBadgeRequest {
+    pub achievement_id: i64,
+}
+
+pub struct AchievementRepository {
+    db: Arc<Mutex<Connection>>,
+}
+
+impl AchievementRepository {
+    pub fn new(db: Arc<Mutex<Connection>>) -> AchievementRepository {
+        AchievementRepository {
+            db,
+        }
+    }
+
+    pub async fn badges_for(&self, faery_id: i64) -> RepositoryResult<Vec<Badge>> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            r#"SELECT a.id, a.name, a.description, b.awarded_at, b.manual
+FROM faery_badges b JOIN achievements a ON a.id = b.achievement_id
+WHERE b.faery_id = ?1 ORDER BY b.awarded_at"#, [faery_id]).await?;
+        let mut badges = Vec::new();
+        while let Some(row) = res.next()? {
+            badges.push(Badge::from_response(&row)?);
+        }
+        Ok(badges)
+    }
+
+    // unearned returns the achievements the faery doesn't hold a badge for yet.
+    pub async fn unearned(&self, faery_id: i64) -> RepositoryResult<Vec<Achievement>> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            "SELECT * FROM achievements WHERE id NOT IN (SELECT achievement_id FROM faery_badges WHERE faery_id = ?1)",
+            [faery_id]).await?;
+        let mut achievements = Vec::new();
+        while let Some(row) = res.next()? {
+            achievements.push(Achievement::from_response(&row)?);
+        }
+        Ok(achievements)
+    }
+
+    // award gives the faery a badge, returning AlreadyExists if it was already held.
+    pub async fn award(&self, faery_id: i64, achievement_id: i64, manual: bool) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        let inserted = db.execute(
+            "INSERT OR IGNORE INTO faery_badges (faery_id, achievement_id, awarded_at, manual) VALUES (?1, ?2, ?3, ?4)",
+            params![faery_id, achievement_id, Utc::now().timestamp_millis(), manual]
+        ).await?;
+        match inserted {
+            0 => Err(RepositoryError::AlreadyExists),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[shuttle_runtime::async_trait]
+impl Repository for AchievementRepository {
+    type Item = Achievement;
+    type RowIdentifier = i64;
+
+    async fn save(&self, achievement: Achievement) -> RepositoryResult<i64> {
+        let criteria = serde_json::to_string(&achievement.criteria).map_err(|_| RepositoryError::InvalidModel)?;
+        let db = self.db.lock().await;
+        let result = match achievement.id {
+            Some(id) => {
+                db.execute(
+                    "UPDATE achievements SET name = ?1, description = ?2, criteria = ?3, reward = ?4 WHERE id = ?5",
+                    params![achievement.name, achievement.description, criteria, achievement.reward, id]
+                ).await.map(|_| id)
+            },
+            None => {
+                db.execute(
+                    "INSERT INTO achievements (name, description, criteria, reward) VALUES (?1, ?2, ?3, ?4)",
+                    params![achievement.name, achievement.description, criteria, achievement.reward]
+                ).await.map(|_| db.last_insert_rowid())
+            },
+        };
+        match result {
+            Ok(id) => Ok(id),
+            Err(err) => {
+                log::error!("Error saving achievement: {:?}", err);
+                Err(RepositoryError::Other)
+            },
+        }
+    }
+
+    async fn get(&self, id: i64) -> RepositoryResult<Achievement> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM achievements WHERE id = ?1", [id]).await?;
+        match res.next()? {
+            Some(row) => Achievement::from_response(&row),
+            None => Err(RepositoryError::NotFound),
+        }
+    }
+
+    async fn get_all(&self) -> RepositoryResult<Vec<Achievement>> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM achievements ORDER BY id", ()).await?;
+        let mut achievements = Vec::new();
+        while let Some(row) = res.next()? {
+            achievements.push(Achievement::from_response(&row)?);
+        }
+        Ok(achievements)
+    }
+
+    async fn delete(&self, id: i64) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        db.execute("BEGIN", ()).await?;
+        let result = async {
+            db.execute("DELETE FROM faery_badges WHERE achievement_id = ?1", [id]).await?;
+            match db.execute("DELETE FROM achievements WHERE id = ?1", [id]).await? {
+                0 => Err(RepositoryError::NotFound),
+                _ => Ok(()),
+            }
+        }.await;
+        finish_transaction(&db, result).await
+    }
+
+    async fn create_table(&self) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        let stmts = [
+            "BEGIN".to_string(),
+            "CREATE TABLE IF NOT EXISTS achievements (
+                id INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                description TEXT NOT NULL,
+                criteria TEXT NOT NULL,
+                reward INTEGER NOT NULL DEFAULT 0
+            )".to_string(),
+            "CREATE TABLE IF NOT EXISTS faery_badges (
+                id INTEGER PRIMARY KEY,
+                faery_id INTEGER NOT NULL,
+                achievement_id INTEGER NOT NULL,
+                awarded_at INTEGER NOT NULL,
+                manual BOOLEAN NOT NULL
+            )".to_string(),
+            "CREATE UNIQUE INDEX IF NOT EXISTS faery_badges_idx ON faery_badges (faery_id, achievement_id)".to_string(),
+            "COMMIT".to_string(),
+        ];
+
+        let stmts = stmts.join(";");
+        match db.execute_batch(&stmts).await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other)
+        }
+    }
+
+    async fn drop_table(&self) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        match db.execute_batch("DROP TABLE IF EXISTS faery_badges;DROP TABLE IF EXISTS achievements").await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Criteria;
+    use crate::repository::ledger::LedgerSummary;
+
+    fn summary() -> LedgerSummary {
+        LedgerSummary { earned: 600, spent: 500, balance: 100, transactions: 10 }
+    }
+
+    #[test]
+    fn test_criteria_thresholds() {
+        assert!(Criteria::TotalSpent { amount: 500 }.is_met(&summary()));
+        assert!(!Criteria::TotalSpent { amount: 501 }.is_met(&summary()));
+        assert!(Criteria::TotalEarned { amount: 600 }.is_met(&summary()));
+        assert!(!Criteria::Balance { amount: 101 }.is_met(&summary()));
+        assert!(Criteria::Transactions { count: 10 }.is_met(&summary()));
+        assert!(!Criteria::Manual.is_met(&summary()));
+    }
+
+    #[test]
+    fn test_criteria_json() {
+        let criteria: Criteria = serde_json::from_str(r#"{"type": "total_spent", "amount": 500}"#).unwrap();
+        assert_eq!(criteria, Criteria::TotalSpent { amount: 500 });
+    }
+}
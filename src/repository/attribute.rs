@@ -0,0 +1,417 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use chrono::NaiveDate;
+use libsql::{Connection, params, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use tokio::sync::Mutex;
+use crate::repository::{finish_transaction, is_constraint_violation, Repository, RepositoryError, RepositoryItem, RepositoryResult};
+
+// AttributeKind is the type of a custom faery attribute and the rules its values must follow.
+// It's stored as JSON alongside the definition, e.g. {"type": "enum", "options": ["Pooka", "Sidhe"]}.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AttributeKind {
+    Text {
+        #[serde(default)]
+        max_length: Option<usize>,
+    },
+    Number {
+        #[serde(default)]
+        min: Option<f64>,
+        #[serde(default)]
+        max: Option<f64>,
+        // Only whole numbers are accepted
+        #[serde(default)]
+        integer: bool,
+    },
+    Enum { options: Vec<String> },
+    // Dates are written as YYYY-MM-DD
+    Date,
+}
+
+// AttributeDefinition is one field of the per-game character schema, such as kith or rank.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttributeDefinition {
+    pub(crate) id: Option<i64>,
+    // Identifier used in the API and list filters, e.g. "kith"
+    pub key: String,
+    pub label: String,
+    pub kind: AttributeKind,
+    #[serde(default)]
+    pub required: bool,
+}
+
+impl AttributeDefinition {
+    pub fn from_response(row: &Row) -> RepositoryResult<AttributeDefinition> {
+        let kind: String = row.get(3)?;
+        Ok(AttributeDefinition {
+            id: row.get(0)?,
+            key: row.get(1)?,
+            label: row.get(2)?,
+            kind: serde_json::from_str(&kind).map_err(|_| RepositoryError::InvalidModel)?,
+            required: row.get(4)?,
+        })
+    }
+
+    // is_valid_key keeps keys usable in query strings.
+    pub fn is_valid_key(&self) -> bool {
+        !self.key.is_empty() && self.key.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    }
+
+    // validate checks a value against the definition and returns it in its stored form.
+    pub fn validate(&self, value: &JsonValue) -> Result<String, String> {
+        match &self.kind {
+            AttributeKind::Text { max_length } => {
+                let text = value.as_str().ok_or_else(|| format!("{} must be text", self.key))?;
+                match max_length {
+                    Some(max) if text.chars().count() > *max => Err(format!("{} must be at most {} characters", self.key, max)),
+                    _ => Ok(text.to_string()),
+                }
+            },
+            AttributeKind::Number { min, max, integer } => {
+                let number = match value {
+                    JsonValue::Number(number) => number.as_f64(),
+                    JsonValue::String(text) => text.trim().parse::<f64>().ok(),
+                    _ => None,
+                }.filter(|number| number.is_finite()).ok_or_else(|| format!("{} must be a number", self.key))?;
+                if *integer && number.fract() != 0.0 {
+                    return Err(format!("{} must be a whole number", self.key));
+                }
+                if min.is_some_and(|min| number < min) || max.is_some_and(|max| number > max) {
+                    return Err(format!("{} is out of range", self.key));
+                }
+                Ok(number.to_string())
+            },
+            AttributeKind::Enum { options } => {
+                let text = value.as_str().ok_or_else(|| format!("{} must be text", self.key))?;
+                options.iter()
+                    .find(|option| option.eq_ignore_ascii_case(text.trim()))
+                    .cloned()
+                    .ok_or_else(|| format!("{} must be one of: {}", self.key, options.join(", ")))
+            },
+            AttributeKind::Date => {
+                let text = value.as_str().ok_or_else(|| format!("{} must be a date", self.key))?;
+                NaiveDate::parse_from_str(text.trim(), "%Y-%m-%d")
+                    .map(|date| date.format("%Y-%m-%d").to_string())
+                    .map_err(|_| format!("{} must be a date like 2024-03-21", self.key))
+            },
+        }
+    }
+
+    // present turns a stored value back into JSON, as a number for number attributes.
+    pub fn present(&self, stored: &str) -> JsonValue {
+        match self.kind {
+            AttributeKind::Number { .. } => stored.parse::<f64>().ok()
+                .and_then(|number| {
+                    if number.fract() == 0.0 && number.abs() < i64::MAX as f64 {
+                        Some(JsonValue::from(number as i64))
+                    } else {
+                        serde_json::Number::from_f64(number).map(JsonValue::Number)
+                    }
+                })
+                .unwrap_or_else(|| JsonValue::String(stored.to_string())),
+            _ => JsonValue::String(stored.to_string()),
+        }
+    }
+}
+
+impl RepositoryItem for AttributeDefinition {
+    fn masked_columns(_: bool) -> Vec<String> {
+        vec![]
+    }
+
+    fn saved_columns() -> Vec<String> {
+        vec!["key".to_string(), "label".to_string(), "kind".to_string(), "required".to_string()]
+    }
+
+    fn all_columns() -> Vec<String> {
+        vec![
+            "id".to_string(),
+            "key".to_string(),
+            "label".to_string(),
+            "kind".to_string(),
+            "required".to_string(),
+        ]
+    }
+
+    fn table_name() -> String where Self: Sized {
+        "attribute_definitions".to_string()
+    }
+}
+
+// Attributes maps attribute keys to values for a single faery.
+pub type Attributes = BTreeMap<String, JsonValue>;
+
+// validate_attributes checks an update against the schema. A null value removes the attribute.
+// Returns the values to store (None to remove), or every problem found.
+pub fn validate_attributes(
+    definitions: &[AttributeDefinition],
+    current: &Attributes,
+    update: &Attributes
+) -> Result<BTreeMap<String, Option<String>>, Vec<String>> {
+    let mut changes = BTreeMap::new();
+    let mut errors = Vec::new();
+    for (key, value) in update {
+        match definitions.iter().find(|definition| &definition.key == key) {
+            None => errors.push(format!("{} isn't a known attribute", key)),
+            Some(_) if value.is_null() => { changes.insert(key.clone(), None); },
+            Some(definition) => match definition.validate(value) {
+                Ok(stored) => { changes.insert(key.clone(), Some(stored)); },
+                Err(err) => errors.push(err),
+            },
+        }
+    }
+    for definition in definitions.iter().filter(|definition| definition.required) {
+        let present = match changes.get(&definition.key) {
+            Some(change) => change.is_some(),
+            None => current.contains_key(&definition.key),
+        };
+        if !present {
+            errors.push(format!("{} is required", definition.key));
+        }
+    }
+    if errors.is_empty() { Ok(changes) } else { Err(errors) }
+}
+
+pub struct AttributeRepository {
+    db: Arc<Mutex<Connection>>,
+}
+
+impl AttributeRepository {
+    pub fn new(db: Arc<Mutex<Connection>>) -> AttributeRepository {
+        AttributeRepository {
+            db,
+        }
+    }
+
+    // values_for returns a faery's attributes, typed according to the current schema.
+    pub async fn values_for(&self, faery_id: i64) -> RepositoryResult<Attributes> {
+        let definitions = self.get_all().await?;
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT key, value FROM faery_attributes WHERE faery_id = ?1", [faery_id]).await?;
+        let mut attributes = Attributes::new();
+        while let Some(row) = res.next()? {
+            let key: String = row.get(0)?;
+            let value: String = row.get(1)?;
+            let value = match definitions.iter().find(|definition| definition.key == key) {
+                Some(definition) => definition.present(&value),
+                None => JsonValue::String(value),
+            };
+            attributes.insert(key, value);
+        }
+        Ok(attributes)
+    }
+
+    pub async fn apply(&self, faery_id: i64, changes: &BTreeMap<String, Option<String>>) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        db.execute("BEGIN", ()).await?;
+        let result = async {
+            for (key, value) in changes {
+                match value {
+                    Some(value) => db.execute(
+                        r#"INSERT INTO faery_attributes (faery_id, key, value) VALUES (?1, ?2, ?3)
+ON CONFLICT (faery_id, key) DO UPDATE SET value = excluded.value"#,
+                        params![faery_id, key.clone(), value.clone()]
+                    ).await?,
+                    None => db.execute("DELETE FROM faery_attributes WHERE faery_id = ?1 AND key = ?2", params![faery_id, key.clone()]).await?,
+                };
+            }
+            Ok(())
+        }.await;
+        finish_transaction(&db, result).await
+    }
+}
+
+#[shuttle_runtime::async_trait]
+impl Repository for AttributeRepository {
+    type Item = AttributeDefinition;
+    type RowIdentifier = i64;
+
+    // Renaming a key carries the stored values over to the new key.
+    async fn save(&self, definition: AttributeDefinition) -> RepositoryResult<i64> {
+        if !definition.is_valid_key() {
+            return Err(RepositoryError::InvalidModel);
+        }
+        let kind = serde_json::to_string(&definition.kind).map_err(|_| RepositoryError::InvalidModel)?;
+        let db = self.db.lock().await;
+        db.execute("BEGIN", ()).await?;
+        let result = match definition.id {
+            Some(id) => {
+                let renamed = db.execute(
+                    "UPDATE faery_attributes SET key = ?1 WHERE key = (SELECT key FROM attribute_definitions WHERE id = ?2)",
+                    params![definition.key.clone(), id]
+                ).await;
+                match renamed {
+                    Ok(_) => db.execute(
+                        "UPDATE attribute_definitions SET key = ?1, label = ?2, kind = ?3, required = ?4 WHERE id = ?5",
+                        params![definition.key, definition.label, kind, definition.required, id]
+                    ).await.map(|_| id),
+                    Err(err) => Err(err),
+                }
+            },
+            None => {
+                db.execute(
+                    "INSERT INTO attribute_definitions (key, label, kind, required) VALUES (?1, ?2, ?3, ?4)",
+                    params![definition.key, definition.label, kind, definition.required]
+                ).await.map(|_| db.last_insert_rowid())
+            },
+        };
+        let result = result.map_err(|err| {
+            log::error!("Error saving attribute definition: {:?}", err);
+            match is_constraint_violation(&err) {
+                true => RepositoryError::AlreadyExists,
+                false => RepositoryError::Other,
+            }
+        });
+        finish_transaction(&db, result).await
+    }
+
+    async fn get(&self, id: i64) -> RepositoryResult<AttributeDefinition> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM attribute_definitions WHERE id = ?1", [id]).await?;
+        match res.next()? {
+            Some(row) => AttributeDefinition::from_response(&row),
+            None => Err(RepositoryError::NotFound),
+        }
+    }
+
+    async fn get_all(&self) -> RepositoryResult<Vec<AttributeDefinition>> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM attribute_definitions ORDER BY id", ()).await?;
+        let mut definitions = Vec::new();
+        while let Some(row) = res.next()? {
+            definitions.push(AttributeDefinition::from_response(&row)?);
+        }
+        Ok(definitions)
+    }
+
+    // Deleting a definition removes every faery's value for it.
+    async fn delete(&self, id: i64) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        db.execute("BEGIN", ()).await?;
+        let result = async {
+            db.execute("DELETE FROM faery_attributes WHERE key = (SELECT key FROM attribute_definitions WHERE id = ?1)", [id]).await?;
+            match db.execute("DELETE FROM attribute_definitions WHERE id = ?1", [id]).await? {
+                0 => Err(RepositoryError::NotFound),
+                _ => Ok(()),
+            }
+        }.await;
+        finish_transaction(&db, result).await
+    }
+
+    async fn create_table(&self) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        let stmts = [
+            "BEGIN".to_string(),
+            "CREATE TABLE IF NOT EXISTS attribute_definitions (
+                id INTEGER PRIMARY KEY,
+                key TEXT NOT NULL UNIQUE,
+                label TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                required BOOLEAN NOT NULL DEFAULT 0
+            )".to_string(),
+            "CREATE TABLE IF NOT EXISTS faery_attributes (
+                faery_id INTEGER NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (faery_id, key)
+            )".to_string(),
+            "CREATE INDEX IF NOT EXISTS faery_attributes_value_idx ON faery_attributes (key, value)".to_string(),
+            "COMMIT".to_string(),
+        ];
+
+        let stmts = stmts.join(";");
+        match db.execute_batch(&stmts).await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other)
+        }
+    }
+
+    async fn drop_table(&self) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        match db.execute_batch("DROP TABLE IF EXISTS faery_attributes;DROP TABLE IF EXISTS attribute_definitions").await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use serde_json::json;
+    use tokio::sync::Mutex;
+    use crate::repository::{Repository, RepositoryError};
+    use super::{validate_attributes, AttributeDefinition, AttributeKind, AttributeRepository, Attributes};
+
+    fn definition(key: &str, kind: AttributeKind, required: bool) -> AttributeDefinition {
+        AttributeDefinition { id: None, key: key.to_string(), label: key.to_string(), kind, required }
+    }
+
+    #[test]
+    fn test_validate_values() {
+        let kith = definition("kith", AttributeKind::Enum { options: vec!["Pooka".to_string(), "Sidhe".to_string()] }, false);
+        assert_eq!(kith.validate(&json!("pooka")), Ok("Pooka".to_string()));
+        assert!(kith.validate(&json!("Troll")).is_err());
+
+        let rank = definition("rank", AttributeKind::Number { min: Some(0.0), max: Some(5.0), integer: true }, false);
+        assert_eq!(rank.validate(&json!(3)), Ok("3".to_string()));
+        assert_eq!(rank.validate(&json!("4")), Ok("4".to_string()));
+        assert!(rank.validate(&json!(2.5)).is_err());
+        assert!(rank.validate(&json!(6)).is_err());
+        let ratio = definition("ratio", AttributeKind::Number { min: None, max: None, integer: false }, false);
+        for value in ["NaN", "inf", "-infinity"] {
+            assert!(ratio.validate(&json!(value)).is_err());
+        }
+        assert_eq!(rank.present("3"), json!(3));
+
+        let joined = definition("joined", AttributeKind::Date, false);
+        assert_eq!(joined.validate(&json!("2024-3-1")), Ok("2024-03-01".to_string()));
+        assert!(joined.validate(&json!("March 1st")).is_err());
+
+        let motto = definition("motto", AttributeKind::Text { max_length: Some(5) }, false);
+        assert!(motto.validate(&json!("Onward")).is_err());
+        assert!(motto.validate(&json!(5)).is_err());
+    }
+
+    #[test]
+    fn test_validate_update() {
+        let definitions = vec![
+            definition("kith", AttributeKind::Enum { options: vec!["Pooka".to_string()] }, true),
+            definition("rank", AttributeKind::Number { min: None, max: None, integer: false }, false),
+        ];
+        let mut current = Attributes::new();
+        current.insert("kith".to_string(), json!("Pooka"));
+
+        let update: Attributes = [("rank".to_string(), json!(1.5))].into_iter().collect();
+        let changes = validate_attributes(&definitions, &current, &update).unwrap();
+        assert_eq!(changes.get("rank"), Some(&Some("1.5".to_string())));
+
+        // Removing a required attribute, and setting an unknown one, are both refused
+        let update: Attributes = [("kith".to_string(), json!(null)), ("seeming".to_string(), json!("Wilder"))].into_iter().collect();
+        let errors = validate_attributes(&definitions, &current, &update).unwrap_err();
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_rename_and_delete_definitions() {
+        let db = libsql::Database::open_in_memory().unwrap().connect().unwrap();
+        let repository = AttributeRepository::new(Arc::new(Mutex::new(db)));
+        repository.create_table().await.unwrap();
+
+        let kith = definition("kith", AttributeKind::Text { max_length: None }, false);
+        let id = repository.save(kith.clone()).await.unwrap();
+        assert!(matches!(repository.save(kith).await, Err(RepositoryError::AlreadyExists)));
+        repository.apply(1, &BTreeMap::from([("kith".to_string(), Some("Pooka".to_string()))])).await.unwrap();
+
+        let renamed = AttributeDefinition { id: Some(id), ..definition("seeming", AttributeKind::Text { max_length: None }, false) };
+        repository.save(renamed).await.unwrap();
+        assert_eq!(repository.values_for(1).await.unwrap().get("seeming"), Some(&json!("Pooka")));
+
+        repository.delete(id).await.unwrap();
+        assert!(repository.values_for(1).await.unwrap().is_empty());
+        assert!(matches!(repository.delete(id).await, Err(RepositoryError::NotFound)));
+    }
+}
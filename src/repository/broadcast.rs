@@ -0,0 +1,468 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use chrono::Utc;
+use libsql::{Connection, params, Row};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use crate::repository::{finish_transaction, Repository, RepositoryError, RepositoryItem, RepositoryResult};
+
+// Recipients and delivery counts come from the outbox, so they're joined in on every read
+const BROADCAST_SELECT: &str = r#"SELECT b.*,
+    COUNT(r.player_id),
+    COALESCE(SUM(o.status = 'pending'), 0),
+    COALESCE(SUM(o.status = 'sent'), 0),
+    COALESCE(SUM(o.status = 'dead'), 0),
+    COALESCE(SUM(r.bounced), 0),
+    COALESCE(SUM(r.player_id IS NOT NULL AND r.outbox_id IS NULL), 0)
+FROM broadcasts b
+LEFT JOIN broadcast_recipients r ON r.broadcast_id = b.id
+LEFT JOIN email_outbox o ON o.id = r.outbox_id"#;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BroadcastAudience {
+    // Every active player
+    #[default]
+    All,
+    // The owners of a group's members
+    Group,
+}
+
+impl BroadcastAudience {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BroadcastAudience::All => "all",
+            BroadcastAudience::Group => "group",
+        }
+    }
+}
+
+impl From<String> for BroadcastAudience {
+    fn from(audience: String) -> Self {
+        match audience.as_str() {
+            "group" => BroadcastAudience::Group,
+            _ => BroadcastAudience::All,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BroadcastStatus {
+    Scheduled,
+    // Recipients are being queued in the outbox
+    Sending,
+    Sent,
+    Cancelled,
+}
+
+impl BroadcastStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BroadcastStatus::Scheduled => "scheduled",
+            BroadcastStatus::Sending => "sending",
+            BroadcastStatus::Sent => "sent",
+            BroadcastStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl From<String> for BroadcastStatus {
+    fn from(status: String) -> Self {
+        match status.as_str() {
+            "sending" => BroadcastStatus::Sending,
+            "sent" => BroadcastStatus::Sent,
+            "cancelled" => BroadcastStatus::Cancelled,
+            _ => BroadcastStatus::Scheduled,
+        }
+    }
+}
+
+// BroadcastCounts tracks a broadcast's recipients through the outbox. Players who get broadcasts
+// in their daily digest, or only in the notification center, are counted as held.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BroadcastCounts {
+    pub recipients: i64,
+    pub queued: i64,
+    pub sent: i64,
+    pub failed: i64,
+    pub bounced: i64,
+    pub held: i64,
+}
+
+// Broadcast is an announcement emailed to every player in its audience, straight away or at
+// `scheduled_at`. Sent broadcasts are kept as history.
+#[derive(Debug, Clone, Serialize)]
+pub struct Broadcast {
+    pub id: Option<i64>,
+    pub subject: String,
+    pub message: String,
+    pub audience: BroadcastAudience,
+    pub group_id: Option<i64>,
+    pub include_subgroups: bool,
+    pub status: BroadcastStatus,
+    pub scheduled_at: i64,
+    pub created_by: Option<i64>,
+    pub created_at: i64,
+    pub sent_at: Option<i64>,
+    pub counts: BroadcastCounts,
+}
+
+impl Broadcast {
+    pub fn from_response(row: &Row) -> RepositoryResult<Broadcast> {
+        Ok(Broadcast {
+            id: row.get(0)?,
+            subject: row.get(1)?,
+            message: row.get(2)?,
+            audience: row.get::<String>(3)?.into(),
+            group_id: row.get(4)?,
+            include_subgroups: row.get(5)?,
+            status: row.get::<String>(6)?.into(),
+            scheduled_at: row.get(7)?,
+            created_by: row.get(8)?,
+            created_at: row.get(9)?,
+            sent_at: row.get(10)?,
+            counts: BroadcastCounts {
+                recipients: row.get(11)?,
+                queued: row.get(12)?,
+                sent: row.get(13)?,
+                failed: row.get(14)?,
+                bounced: row.get(15)?,
+                held: row.get(16)?,
+            },
+        })
+    }
+}
+
+// BroadcastRequest is what an admin sends to create, preview or test a broadcast.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BroadcastRequest {
+    pub subject: String,
+    pub message: String,
+    #[serde(default)]
+    pub audience: BroadcastAudience,
+    #[serde(default)]
+    pub group_id: Option<i64>,
+    #[serde(default)]
+    pub include_subgroups: bool,
+    // Sent straight away when missing or in the past
+    #[serde(default)]
+    pub scheduled_at: Option<i64>,
+}
+
+impl BroadcastRequest {
+    pub fn is_valid(&self) -> bool {
+        !self.subject.trim().is_empty()
+            && !self.message.trim().is_empty()
+            && (self.audience != BroadcastAudience::Group || self.group_id.is_some())
+    }
+
+    pub fn into_broadcast(self, created_by: Option<i64>) -> Broadcast {
+        let now = Utc::now().timestamp_millis();
+        Broadcast {
+            id: None,
+            subject: self.subject,
+            message: self.message,
+            audience: self.audience,
+            group_id: self.group_id,
+            include_subgroups: self.include_subgroups,
+            status: BroadcastStatus::Scheduled,
+            scheduled_at: self.scheduled_at.unwrap_or(now).max(now),
+            created_by,
+            created_at: now,
+            sent_at: None,
+            counts: BroadcastCounts::default(),
+        }
+    }
+}
+
+impl RepositoryItem for Broadcast {
+    fn masked_columns(_: bool) -> Vec<String> {
+        vec![]
+    }
+
+    fn saved_columns() -> Vec<String> {
+        vec![
+            "subject".to_string(),
+            "message".to_string(),
+            "audience".to_string(),
+            "group_id".to_string(),
+            "include_subgroups".to_string(),
+            "status".to_string(),
+            "scheduled_at".to_string(),
+            "created_by".to_string(),
+            "created_at".to_string(),
+        ]
+    }
+
+    fn all_columns() -> Vec<String> {
+        vec![
+            "id".to_string(),
+            "subject".to_string(),
+            "message".to_string(),
+            "audience".to_string(),
+            "group_id".to_string(),
+            "include_subgroups".to_string(),
+            "status".to_string(),
+            "scheduled_at".to_string(),
+            "created_by".to_string(),
+            "created_at".to_string(),
+            "sent_at".to_string(),
+        ]
+    }
+
+    fn table_name() -> String where Self: Sized {
+        "broadcasts".to_string()
+    }
+}
+
+pub struct BroadcastRepository {
+    db: Arc<Mutex<Connection>>,
+}
+
+impl BroadcastRepository {
+    pub fn new(db: Arc<Mutex<Connection>>) -> BroadcastRepository {
+        BroadcastRepository {
+            db,
+        }
+    }
+
+    // due returns the scheduled broadcasts whose time has come.
+    pub async fn due(&self) -> RepositoryResult<Vec<Broadcast>> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            &format!("{} WHERE b.status = 'scheduled' AND b.scheduled_at <= ?1 GROUP BY b.id ORDER BY b.scheduled_at, b.id", BROADCAST_SELECT),
+            [Utc::now().timestamp_millis()]).await?;
+        let mut broadcasts = Vec::new();
+        while let Some(row) = res.next()? {
+            broadcasts.push(Broadcast::from_response(&row)?);
+        }
+        Ok(broadcasts)
+    }
+
+    // claim moves a scheduled broadcast to sending. Only one caller gets to send each broadcast.
+    pub async fn claim(&self, id: i64) -> RepositoryResult<bool> {
+        let db = self.db.lock().await;
+        let updated = db.execute(
+            "UPDATE broadcasts SET status = 'sending' WHERE id = ?1 AND status = 'scheduled'",
+            [id]).await?;
+        Ok(updated > 0)
+    }
+
+    // release puts a claimed broadcast back to scheduled, so it's picked up again on the next run.
+    pub async fn release(&self, id: i64) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        db.execute("UPDATE broadcasts SET status = 'scheduled' WHERE id = ?1 AND status = 'sending'", [id]).await?;
+        Ok(())
+    }
+
+    pub async fn finish(&self, id: i64) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        db.execute(
+            "UPDATE broadcasts SET status = 'sent', sent_at = ?2 WHERE id = ?1",
+            params![id, Utc::now().timestamp_millis()]).await?;
+        Ok(())
+    }
+
+    // cancel stops a broadcast that hasn't gone out yet.
+    pub async fn cancel(&self, id: i64) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        match db.execute("UPDATE broadcasts SET status = 'cancelled' WHERE id = ?1 AND status = 'scheduled'", [id]).await? {
+            0 => Err(RepositoryError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    // add_recipient records who a broadcast went to, and the outbox message it went out as.
+    // recipients returns the players a broadcast has already been sent to.
+    pub async fn recipients(&self, broadcast_id: i64) -> RepositoryResult<HashSet<i64>> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT player_id FROM broadcast_recipients WHERE broadcast_id = ?1", [broadcast_id]).await?;
+        let mut recipients = HashSet::new();
+        while let Some(row) = res.next()? {
+            recipients.insert(row.get(0)?);
+        }
+        Ok(recipients)
+    }
+
+    pub async fn add_recipient(&self, broadcast_id: i64, player_id: i64, outbox_id: Option<i64>) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        db.execute(
+            "INSERT OR IGNORE INTO broadcast_recipients (broadcast_id, player_id, outbox_id) VALUES (?1, ?2, ?3)",
+            params![broadcast_id, player_id, outbox_id]).await?;
+        Ok(())
+    }
+
+    // mark_bounced counts a bounce against the broadcast, if the last email sent to the address
+    // was one.
+    pub async fn mark_bounced(&self, email: &str) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        db.execute(
+            r#"UPDATE broadcast_recipients SET bounced = 1 WHERE outbox_id = (
+    SELECT id FROM email_outbox WHERE recipient = ?1 COLLATE NOCASE AND status = 'sent' ORDER BY sent_at DESC, id DESC LIMIT 1
+)"#,
+            [email]).await?;
+        Ok(())
+    }
+}
+
+#[shuttle_runtime::async_trait]
+impl Repository for BroadcastRepository {
+    type Item = Broadcast;
+    type RowIdentifier = i64;
+
+    // save creates a broadcast. Broadcasts only change through claim, finish and cancel.
+    async fn save(&self, broadcast: Broadcast) -> RepositoryResult<i64> {
+        if broadcast.id.is_some() {
+            return Err(RepositoryError::InvalidModel);
+        }
+        let db = self.db.lock().await;
+        db.execute(
+            "INSERT INTO broadcasts (subject, message, audience, group_id, include_subgroups, status, scheduled_at, created_by, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                broadcast.subject,
+                broadcast.message,
+                broadcast.audience.as_str(),
+                broadcast.group_id,
+                broadcast.include_subgroups,
+                broadcast.status.as_str(),
+                broadcast.scheduled_at,
+                broadcast.created_by,
+                broadcast.created_at
+            ]).await?;
+        Ok(db.last_insert_rowid())
+    }
+
+    async fn get(&self, id: i64) -> RepositoryResult<Broadcast> {
+        let db = self.db.lock().await;
+        let mut res = db.query(&format!("{} WHERE b.id = ?1 GROUP BY b.id", BROADCAST_SELECT), [id]).await?;
+        match res.next()? {
+            Some(row) => Broadcast::from_response(&row),
+            None => Err(RepositoryError::NotFound),
+        }
+    }
+
+    // get_all is the broadcast history, newest first.
+    async fn get_all(&self) -> RepositoryResult<Vec<Broadcast>> {
+        let db = self.db.lock().await;
+        let mut res = db.query(&format!("{} GROUP BY b.id ORDER BY b.id DESC", BROADCAST_SELECT), ()).await?;
+        let mut broadcasts = Vec::new();
+        while let Some(row) = res.next()? {
+            broadcasts.push(Broadcast::from_response(&row)?);
+        }
+        Ok(broadcasts)
+    }
+
+    async fn delete(&self, id: i64) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        db.execute("BEGIN", ()).await?;
+        let result = async {
+            if db.execute("DELETE FROM broadcasts WHERE id = ?1", [id]).await? == 0 {
+                return Err(RepositoryError::NotFound);
+            }
+            db.execute("DELETE FROM broadcast_recipients WHERE broadcast_id = ?1", [id]).await?;
+            Ok(())
+        }.await;
+        finish_transaction(&db, result).await
+    }
+
+    async fn create_table(&self) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        let stmts = [
+            "BEGIN".to_string(),
+            "CREATE TABLE IF NOT EXISTS broadcasts (
+                id INTEGER PRIMARY KEY,
+                subject TEXT NOT NULL,
+                message TEXT NOT NULL,
+                audience TEXT NOT NULL DEFAULT 'all',
+                group_id INTEGER,
+                include_subgroups INTEGER NOT NULL DEFAULT 0,
+                status TEXT NOT NULL DEFAULT 'scheduled',
+                scheduled_at INTEGER NOT NULL,
+                created_by INTEGER,
+                created_at INTEGER NOT NULL,
+                sent_at INTEGER
+            )".to_string(),
+            "CREATE TABLE IF NOT EXISTS broadcast_recipients (
+                broadcast_id INTEGER NOT NULL,
+                player_id INTEGER NOT NULL,
+                outbox_id INTEGER,
+                bounced INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (broadcast_id, player_id)
+            )".to_string(),
+            "CREATE INDEX IF NOT EXISTS broadcast_recipients_outbox_idx ON broadcast_recipients (outbox_id)".to_string(),
+            "COMMIT".to_string(),
+        ];
+
+        let stmts = stmts.join(";");
+        match db.execute_batch(&stmts).await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other)
+        }
+    }
+
+    async fn drop_table(&self) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        match db.execute_batch("DROP TABLE IF EXISTS broadcast_recipients;DROP TABLE IF EXISTS broadcasts").await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use crate::mailer::{Email, MemoryMailer};
+    use crate::repository::Repository;
+    use crate::repository::email::EmailRepository;
+    use super::{BroadcastAudience, BroadcastRepository, BroadcastRequest, BroadcastStatus};
+
+    #[tokio::test]
+    async fn test_broadcast_counts() {
+        let db = Arc::new(Mutex::new(libsql::Database::open_in_memory().unwrap().connect().unwrap()));
+        let email_repository = EmailRepository::new(db.clone(), Arc::new(MemoryMailer::default()));
+        let repository = BroadcastRepository::new(db);
+        email_repository.create_table().await.unwrap();
+        repository.create_table().await.unwrap();
+
+        let request = BroadcastRequest {
+            subject: "Moon festival".to_string(),
+            message: "Tonight at the old oak".to_string(),
+            audience: BroadcastAudience::All,
+            group_id: None,
+            include_subgroups: false,
+            scheduled_at: None,
+        };
+        assert!(request.is_valid());
+        assert!(!BroadcastRequest { audience: BroadcastAudience::Group, ..request.clone() }.is_valid());
+
+        let id = repository.save(request.into_broadcast(Some(1))).await.unwrap();
+        assert_eq!(repository.due().await.unwrap().len(), 1);
+        assert!(repository.claim(id).await.unwrap());
+        assert!(!repository.claim(id).await.unwrap());
+
+        let outbox_id = email_repository.queue_at(Email::new("tink@example.com", "Moon festival", "Tonight"), 0).await.unwrap();
+        repository.add_recipient(id, 1, Some(outbox_id)).await.unwrap();
+        repository.add_recipient(id, 2, None).await.unwrap();
+        // A broadcast that fails partway goes back to scheduled and remembers who it reached
+        repository.release(id).await.unwrap();
+        assert_eq!(repository.get(id).await.unwrap().status, BroadcastStatus::Scheduled);
+        assert!(repository.claim(id).await.unwrap());
+        assert_eq!(repository.recipients(id).await.unwrap().len(), 2);
+        email_repository.deliver_due(10).await.unwrap();
+        repository.finish(id).await.unwrap();
+        repository.mark_bounced("Tink@example.com").await.unwrap();
+
+        let broadcast = repository.get(id).await.unwrap();
+        assert_eq!(broadcast.status, BroadcastStatus::Sent);
+        assert_eq!(broadcast.counts.recipients, 2);
+        assert_eq!(broadcast.counts.sent, 1);
+        assert_eq!(broadcast.counts.held, 1);
+        assert_eq!(broadcast.counts.bounced, 1);
+        assert!(repository.due().await.unwrap().is_empty());
+        assert!(repository.cancel(id).await.is_err());
+    }
+}
@@ -1,134 +1,730 @@
-use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
-use lettre::message::header::ContentType;
-use lettre::message::Mailbox;
-use lettre::transport::smtp::authentication::Credentials;
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::Utc;
+use libsql::{Connection, params, Row};
 use serde::{Deserialize, Serialize};
-use crate::repository::{Repository, RepositoryError, RepositoryItem, RepositoryResult};
+use tokio::sync::{Mutex, Notify};
+use crate::mailer::{Email, Mailer, MailerError};
+use crate::repository::{finish_transaction, Repository, RepositoryError, RepositoryItem, RepositoryResult};
+
+// Messages that fail this many times are dead-lettered
+pub const MAX_ATTEMPTS: i64 = 8;
+const FIRST_RETRY_SECONDS: i64 = 30;
+const MAX_RETRY_SECONDS: i64 = 6 * 60 * 60;
+const REDACTED: &str = "[redacted]";
 
 #[derive(Clone)]
 pub struct EmailRepository {
-    smtp_username: String,
-    smtp_token: String,
-    smtp_domain: String,
+    db: Arc<Mutex<Connection>>,
+    mailer: Arc<dyn Mailer>,
+    // Wakes the outbox worker when something is queued
+    queued: Arc<Notify>,
 }
 
-impl From<lettre::transport::smtp::Error> for RepositoryError {
-    fn from(_: lettre::transport::smtp::Error) -> Self {
+impl From<MailerError> for RepositoryError {
+    fn from(_: MailerError) -> Self {
         RepositoryError::Other
     }
 }
 
-impl From<lettre::address::AddressError> for RepositoryError {
-    fn from(_: lettre::address::AddressError) -> Self {
-        RepositoryError::Other
+impl EmailRepository {
+    pub fn new(db: Arc<Mutex<Connection>>, mailer: Arc<dyn Mailer>) -> Self {
+        EmailRepository {
+            db,
+            mailer,
+            queued: Arc::new(Notify::new()),
+        }
+    }
+
+    // send_email queues a plain-text message. Most mail should go through a template instead;
+    // see crate::messages.
+    pub async fn send_email(&self, subject: &str, email: &str, message: &str) -> RepositoryResult<()> {
+        self.queue(Email::new(email, subject, message)).await
+    }
+
+    // queue puts a message in the outbox; the outbox worker delivers it.
+    pub async fn queue(&self, email: Email) -> RepositoryResult<()> {
+        self.save(OutboxMessage::new(email)).await?;
+        Ok(())
+    }
+
+    // queue_at puts a message in the outbox to go out no earlier than `at`, and returns its ID.
+    // Bulk mail uses it to spread delivery out.
+    pub async fn queue_at(&self, email: Email, at: i64) -> RepositoryResult<i64> {
+        self.save(OutboxMessage { next_attempt_at: at, ..OutboxMessage::new(email) }).await
+    }
+
+    // wait_for_mail returns when a message is queued, or after `timeout` at the latest.
+    pub async fn wait_for_mail(&self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, self.queued.notified()).await;
+    }
+
+    // deliver_due tries to send every pending message whose next attempt is due, and returns
+    // how many were sent. Messages to suppressed addresses are dead-lettered instead. The
+    // database isn't locked while the mailer is working.
+    pub async fn deliver_due(&self, limit: i64) -> RepositoryResult<usize> {
+        let due = {
+            let db = self.db.lock().await;
+            let mut res = db.query(
+                r#"SELECT o.*, s.reason FROM email_outbox o LEFT JOIN email_suppressions s ON s.email = o.recipient COLLATE NOCASE
+WHERE o.status = 'pending' AND o.next_attempt_at <= ?1 ORDER BY o.next_attempt_at, o.id LIMIT ?2"#,
+                params![Utc::now().timestamp_millis(), limit]).await?;
+            let mut due = Vec::new();
+            while let Some(row) = res.next()? {
+                let suppressed: Option<String> = row.get(14)?;
+                due.push((OutboxMessage::from_response(&row)?, suppressed.map(SuppressionReason::from)));
+            }
+            due
+        };
+        let mut sent = 0;
+        for (message, suppressed) in due {
+            if let Some(reason) = suppressed {
+                self.mark_suppressed(&message, reason).await?;
+                continue;
+            }
+            match self.mailer.send(&message.email()).await {
+                Ok(_) => {
+                    self.mark_sent(&message).await?;
+                    sent += 1;
+                },
+                Err(err) => {
+                    log::error!("Error delivering email {:?}: {:?}", message.id, err);
+                    self.mark_failed(&message, err).await?;
+                }
+            }
+        }
+        Ok(sent)
+    }
+
+    async fn mark_sent(&self, message: &OutboxMessage) -> RepositoryResult<()> {
+        let now = Utc::now().timestamp_millis();
+        let db = self.db.lock().await;
+        // A sent login or verification email has done its job, so its credentials go
+        db.execute(
+            r#"UPDATE email_outbox SET status = 'sent', attempts = attempts + 1, sent_at = ?2, last_error = NULL,
+    body = CASE WHEN sensitive THEN ?3 ELSE body END, html = CASE WHEN sensitive THEN NULL ELSE html END
+WHERE id = ?1"#,
+            params![message.id, now, REDACTED]).await?;
+        // email_log keeps a record of every email sent, so players can see what we've sent them
+        db.execute(
+            "INSERT INTO email_log (recipient, subject, sent_at) VALUES (?1, ?2, ?3)",
+            params![message.recipient.clone(), message.subject.clone(), now]
+        ).await?;
+        Ok(())
+    }
+
+    // mark_failed schedules another attempt, or dead-letters the message when retrying is pointless.
+    async fn mark_failed(&self, message: &OutboxMessage, err: MailerError) -> RepositoryResult<()> {
+        let attempts = message.attempts + 1;
+        let status = match err {
+            MailerError::InvalidAddress => OutboxStatus::Dead,
+            MailerError::Transport(_) if attempts >= MAX_ATTEMPTS => OutboxStatus::Dead,
+            MailerError::Transport(_) => OutboxStatus::Pending,
+        };
+        let next_attempt_at = Utc::now().timestamp_millis() + retry_delay(attempts) * 1000;
+        let db = self.db.lock().await;
+        db.execute(
+            "UPDATE email_outbox SET status = ?2, attempts = ?3, next_attempt_at = ?4, last_error = ?5 WHERE id = ?1",
+            params![message.id, status.as_str(), attempts, next_attempt_at, format!("{:?}", err)]).await?;
+        Ok(())
+    }
+
+    async fn mark_suppressed(&self, message: &OutboxMessage, reason: SuppressionReason) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        db.execute(
+            "UPDATE email_outbox SET status = 'dead', last_error = ?2 WHERE id = ?1",
+            params![message.id, format!("Suppressed: {}", reason.as_str())]).await?;
+        Ok(())
+    }
+
+    // suppress stops all further email to an address, until an admin lifts it. Players with the
+    // address are flagged, so admins can see who needs to fix their email.
+    pub async fn suppress(&self, email: &str, reason: SuppressionReason, detail: &str) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        db.execute("BEGIN", ()).await?;
+        let result = async {
+            db.execute(
+                "INSERT INTO email_suppressions (email, reason, detail, created_at) VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT (email) DO UPDATE SET reason = excluded.reason, detail = excluded.detail, created_at = excluded.created_at",
+                params![email, reason.as_str(), detail, Utc::now().timestamp_millis()]).await?;
+            db.execute("UPDATE players SET email_suppressed = ?2 WHERE auth_email = ?1 COLLATE NOCASE", params![email, reason.as_str()]).await?;
+            Ok(())
+        }.await;
+        finish_transaction(&db, result).await
+    }
+
+    pub async fn unsuppress(&self, email: &str) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        db.execute("BEGIN", ()).await?;
+        let result = async {
+            if db.execute("DELETE FROM email_suppressions WHERE email = ?1", [email]).await? == 0 {
+                return Err(RepositoryError::NotFound);
+            }
+            db.execute("UPDATE players SET email_suppressed = NULL WHERE auth_email = ?1 COLLATE NOCASE", [email]).await?;
+            Ok(())
+        }.await;
+        finish_transaction(&db, result).await
+    }
+
+    // suppressions lists suppressed addresses along with the player each one belongs to, if any,
+    // so admins can follow up with them.
+    pub async fn suppressions(&self) -> RepositoryResult<Vec<Suppression>> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            r#"SELECT s.email, s.reason, s.detail, s.created_at, p.id, p.first_name || ' ' || p.last_name
+FROM email_suppressions s LEFT JOIN players p ON p.auth_email = s.email COLLATE NOCASE ORDER BY s.created_at DESC"#,
+            ()).await?;
+        let mut suppressions = Vec::new();
+        while let Some(row) = res.next()? {
+            suppressions.push(Suppression::from_response(&row)?);
+        }
+        Ok(suppressions)
+    }
+
+    pub async fn outbox(&self, status: Option<OutboxStatus>) -> RepositoryResult<Vec<OutboxMessage>> {
+        let db = self.db.lock().await;
+        let mut res = match status {
+            Some(status) => db.query("SELECT * FROM email_outbox WHERE status = ?1 ORDER BY id DESC", [status.as_str()]).await?,
+            None => db.query("SELECT * FROM email_outbox ORDER BY id DESC", ()).await?,
+        };
+        let mut messages = Vec::new();
+        while let Some(row) = res.next()? {
+            messages.push(OutboxMessage::from_response(&row)?);
+        }
+        Ok(messages)
+    }
+
+    // resend puts a dead-lettered message back in the queue with a fresh set of attempts.
+    pub async fn resend(&self, id: i64) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        let updated = db.execute(
+            "UPDATE email_outbox SET status = 'pending', attempts = 0, next_attempt_at = ?2, last_error = NULL WHERE id = ?1 AND status = 'dead'",
+            params![id, Utc::now().timestamp_millis()]).await?;
+        if updated == 0 {
+            return Err(RepositoryError::NotFound);
+        }
+        self.queued.notify_one();
+        Ok(())
+    }
+
+    pub async fn status(&self) -> RepositoryResult<OutboxSummary> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            "SELECT
+                COUNT(*) FILTER (WHERE status = 'pending'),
+                COUNT(*) FILTER (WHERE status = 'pending' AND attempts > 0),
+                COUNT(*) FILTER (WHERE status = 'dead')
+            FROM email_outbox", ()).await?;
+        let row = res.next()?.ok_or(RepositoryError::Other)?;
+        let (pending, retrying, dead): (i64, i64, i64) = (row.get(0)?, row.get(1)?, row.get(2)?);
+        let status = if dead > 0 {
+            MailerStatus::Error
+        } else if retrying > 0 {
+            MailerStatus::Retrying
+        } else if pending > 0 {
+            MailerStatus::Sending
+        } else {
+            MailerStatus::Idle
+        };
+        Ok(OutboxSummary { status, pending, retrying, dead })
+    }
+
+    // prune_sent deletes delivered messages sent before `before`, and returns how many went.
+    // The email log keeps its record of them.
+    pub async fn prune_sent(&self, before: i64) -> RepositoryResult<u64> {
+        let db = self.db.lock().await;
+        Ok(db.execute("DELETE FROM email_outbox WHERE status = 'sent' AND sent_at < ?1", [before]).await?)
+    }
+
+    pub async fn sent_to(&self, email: &str) -> RepositoryResult<Vec<SentEmail>> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            "SELECT id, recipient, subject, sent_at FROM email_log WHERE recipient = ?1 COLLATE NOCASE ORDER BY id",
+            [email]).await?;
+        let mut emails = Vec::new();
+        while let Some(row) = res.next()? {
+            emails.push(SentEmail::from_response(&row)?);
+        }
+        Ok(emails)
     }
 }
 
-impl From<lettre::error::Error> for RepositoryError {
-    fn from(_: lettre::error::Error) -> Self {
-        RepositoryError::Other
+// retry_delay is how many seconds to wait after a message has failed `attempts` times.
+// It doubles with every attempt, up to a ceiling.
+pub fn retry_delay(attempts: i64) -> i64 {
+    let doublings = (attempts - 1).clamp(0, 20) as u32;
+    (FIRST_RETRY_SECONDS * 2i64.pow(doublings)).min(MAX_RETRY_SECONDS)
+}
+
+// SentEmail is a record of one email that was sent. The body isn't kept.
+#[derive(Debug, Clone, Serialize)]
+pub struct SentEmail {
+    pub id: i64,
+    pub recipient: String,
+    pub subject: String,
+    pub sent_at: i64,
+}
+
+impl SentEmail {
+    pub fn from_response(row: &Row) -> RepositoryResult<SentEmail> {
+        Ok(SentEmail {
+            id: row.get(0)?,
+            recipient: row.get(1)?,
+            subject: row.get(2)?,
+            sent_at: row.get(3)?,
+        })
     }
 }
 
-impl EmailRepository {
-    pub fn new(
-        smtp_username: String,
-        smtp_token: String,
-        smtp_domain: String,
-    ) -> Self {
-        EmailRepository {
-            smtp_username,
-            smtp_token,
-            smtp_domain
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutboxStatus {
+    Pending,
+    Sent,
+    // Failed too often, or can never be delivered; waits for an admin to resend it
+    Dead,
+}
+
+impl OutboxStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutboxStatus::Pending => "pending",
+            OutboxStatus::Sent => "sent",
+            OutboxStatus::Dead => "dead",
         }
     }
+}
 
-    pub async fn send_auth_token(&self, email: &str, token: &str) -> RepositoryResult<()> {
-        // TODO: produce a link back to the app or send it to this method
-        let message = format!("Your auth token is: {}", token);
-        self.send_email("Fe-Vault Login Token", email, &message).await
+impl From<String> for OutboxStatus {
+    fn from(status: String) -> Self {
+        match status.as_str() {
+            "sent" => OutboxStatus::Sent,
+            "dead" => OutboxStatus::Dead,
+            _ => OutboxStatus::Pending,
+        }
     }
+}
 
-    pub async fn send_email(&self, subject: &str, email: &str, message: &str) -> RepositoryResult<()> {
-        let creds = Credentials::new(self.smtp_username.clone(), self.smtp_token.clone());
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SuppressionReason {
+    // The address permanently failed
+    Bounced,
+    // The recipient marked our email as spam
+    Complained,
+}
+
+impl SuppressionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SuppressionReason::Bounced => "bounced",
+            SuppressionReason::Complained => "complained",
+        }
+    }
+}
+
+impl From<String> for SuppressionReason {
+    fn from(reason: String) -> Self {
+        match reason.as_str() {
+            "complained" => SuppressionReason::Complained,
+            _ => SuppressionReason::Bounced,
+        }
+    }
+}
 
-        let mailer: AsyncSmtpTransport<Tokio1Executor> =
-            AsyncSmtpTransport::<Tokio1Executor>::relay("smtp.mailgun.org")?
-                .credentials(creds)
-                .build();
+// Suppression is an address we no longer send to.
+#[derive(Debug, Clone, Serialize)]
+pub struct Suppression {
+    pub email: String,
+    pub reason: SuppressionReason,
+    pub detail: String,
+    pub created_at: i64,
+    pub player_id: Option<i64>,
+    pub player_name: Option<String>,
+}
 
-        let from: Mailbox = "Fe-Vault <noreply@fe-vault.thehe.art>".parse()?;
-        log::info!("sending from: {}", from.email);
-        // TODO: handle unwrap
-        let message = Message::builder()
-            .from(from)
-            .to(email.parse()?)
-            .subject(subject)
-            .header(ContentType::TEXT_PLAIN)
-            .body(message.to_string())?;
+impl Suppression {
+    pub fn from_response(row: &Row) -> RepositoryResult<Suppression> {
+        Ok(Suppression {
+            email: row.get(0)?,
+            reason: row.get::<String>(1)?.into(),
+            detail: row.get(2)?,
+            created_at: row.get(3)?,
+            player_id: row.get(4)?,
+            player_name: row.get(5)?,
+        })
+    }
+}
 
-        log::info!("Sending email to {}.", email);
-        if let Err(e) = mailer.send(message).await {
-            log::error!("Failed to send email: {}", e);
-            return Err(RepositoryError::Other);
+// OutboxMessage is an email waiting to be delivered, or the record of one that was.
+#[derive(Debug, Clone, Serialize)]
+pub struct OutboxMessage {
+    pub id: Option<i64>,
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+    pub status: OutboxStatus,
+    pub attempts: i64,
+    pub next_attempt_at: i64,
+    pub last_error: Option<String>,
+    pub created_at: i64,
+    pub sent_at: Option<i64>,
+    pub html: Option<String>,
+    pub sender: Option<String>,
+    pub unsubscribe_url: Option<String>,
+    pub sensitive: bool,
+}
+
+impl OutboxMessage {
+    pub fn new(email: Email) -> OutboxMessage {
+        let now = Utc::now().timestamp_millis();
+        OutboxMessage {
+            id: None,
+            recipient: email.to,
+            subject: email.subject,
+            body: email.body,
+            status: OutboxStatus::Pending,
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+            created_at: now,
+            sent_at: None,
+            html: email.html,
+            sender: email.from,
+            unsubscribe_url: email.unsubscribe_url,
+            sensitive: email.sensitive,
         }
+    }
 
-        Ok(())
+    // redacted hides the body of a sensitive message, for showing the outbox to admins.
+    pub fn redacted(self) -> OutboxMessage {
+        match self.sensitive {
+            true => OutboxMessage { body: REDACTED.to_string(), html: None, ..self },
+            false => self,
+        }
+    }
+
+    pub fn email(&self) -> Email {
+        Email {
+            html: self.html.clone(),
+            from: self.sender.clone(),
+            unsubscribe_url: self.unsubscribe_url.clone(),
+            sensitive: self.sensitive,
+            ..Email::new(&self.recipient, &self.subject, &self.body)
+        }
+    }
+
+    pub fn from_response(row: &Row) -> RepositoryResult<OutboxMessage> {
+        let status: String = row.get(4)?;
+        Ok(OutboxMessage {
+            id: row.get(0)?,
+            recipient: row.get(1)?,
+            subject: row.get(2)?,
+            body: row.get(3)?,
+            status: status.into(),
+            attempts: row.get(5)?,
+            next_attempt_at: row.get(6)?,
+            last_error: row.get(7)?,
+            created_at: row.get(8)?,
+            sent_at: row.get(9)?,
+            html: row.get(10)?,
+            sender: row.get(11)?,
+            unsubscribe_url: row.get(12)?,
+            sensitive: row.get(13).unwrap_or(false),
+        })
+    }
+}
+
+impl RepositoryItem for OutboxMessage {
+    fn masked_columns(_: bool) -> Vec<String> {
+        vec![]
+    }
+
+    fn saved_columns() -> Vec<String> {
+        vec![
+            "recipient".to_string(),
+            "subject".to_string(),
+            "body".to_string(),
+            "status".to_string(),
+            "attempts".to_string(),
+            "next_attempt_at".to_string(),
+            "created_at".to_string(),
+            "html".to_string(),
+            "sender".to_string(),
+            "unsubscribe_url".to_string(),
+            "sensitive".to_string(),
+        ]
+    }
+
+    fn all_columns() -> Vec<String> {
+        vec![
+            "id".to_string(),
+            "recipient".to_string(),
+            "subject".to_string(),
+            "body".to_string(),
+            "status".to_string(),
+            "attempts".to_string(),
+            "next_attempt_at".to_string(),
+            "last_error".to_string(),
+            "created_at".to_string(),
+            "sent_at".to_string(),
+            "html".to_string(),
+            "sender".to_string(),
+            "unsubscribe_url".to_string(),
+            "sensitive".to_string(),
+        ]
     }
+
+    fn table_name() -> String where Self: Sized {
+        "email_outbox".to_string()
+    }
+}
+
+// MailerStatus sums up how the outbox is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum MailerStatus {
+    // Nothing is waiting to be sent
+    Idle,
+    Sending,
+    // Some messages failed and are waiting to be tried again
+    Retrying,
+    // Some messages were dead-lettered
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OutboxSummary {
+    pub status: MailerStatus,
+    pub pending: i64,
+    pub retrying: i64,
+    pub dead: i64,
 }
 
 #[shuttle_runtime::async_trait]
 impl Repository for EmailRepository {
-    type Item = MailerStatus;
+    type Item = OutboxMessage;
     type RowIdentifier = i64;
 
-    // TODO: make default implementations for cases where the database is not used beyond updating
-    // TODO: ... the user's token once default type implementations for traits is stable
-    async fn save(&self, _: MailerStatus) -> RepositoryResult<i64> {
-        Ok(0)
+    // save queues a new message. Queued messages only change through delivery.
+    async fn save(&self, message: OutboxMessage) -> RepositoryResult<i64> {
+        if message.id.is_some() {
+            return Err(RepositoryError::InvalidModel);
+        }
+        let id = {
+            let db = self.db.lock().await;
+            db.execute(
+                "INSERT INTO email_outbox (recipient, subject, body, status, attempts, next_attempt_at, created_at, html, sender, unsubscribe_url, sensitive) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    message.recipient,
+                    message.subject,
+                    message.body,
+                    message.status.as_str(),
+                    message.attempts,
+                    message.next_attempt_at,
+                    message.created_at,
+                    message.html,
+                    message.sender,
+                    message.unsubscribe_url,
+                    message.sensitive
+                ]).await?;
+            db.last_insert_rowid()
+        };
+        self.queued.notify_one();
+        Ok(id)
     }
 
-    async fn get(&self, _: i64) -> RepositoryResult<MailerStatus> {
-        Ok(MailerStatus::Idle)
+    async fn get(&self, id: i64) -> RepositoryResult<OutboxMessage> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM email_outbox WHERE id = ?1", [id]).await?;
+        match res.next()? {
+            Some(row) => OutboxMessage::from_response(&row),
+            None => Err(RepositoryError::NotFound),
+        }
     }
 
-    async fn get_all(&self) -> RepositoryResult<Vec<MailerStatus>> {
-        Ok(vec![MailerStatus::Idle])
+    async fn get_all(&self) -> RepositoryResult<Vec<OutboxMessage>> {
+        self.outbox(None).await
     }
 
-    async fn delete(&self, _: i64) -> RepositoryResult<()> {
-        Ok(())
+    async fn delete(&self, id: i64) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        match db.execute("DELETE FROM email_outbox WHERE id = ?1", [id]).await? {
+            0 => Err(RepositoryError::NotFound),
+            _ => Ok(()),
+        }
     }
 
     async fn create_table(&self) -> RepositoryResult<()> {
-        Ok(())
+        let db = self.db.lock().await;
+        let stmts = [
+            "BEGIN".to_string(),
+            "CREATE TABLE IF NOT EXISTS email_log (
+                id INTEGER PRIMARY KEY,
+                recipient TEXT NOT NULL,
+                subject TEXT NOT NULL,
+                sent_at INTEGER NOT NULL
+            )".to_string(),
+            "CREATE TABLE IF NOT EXISTS email_outbox (
+                id INTEGER PRIMARY KEY,
+                recipient TEXT NOT NULL,
+                subject TEXT NOT NULL,
+                body TEXT NOT NULL,
+                status TEXT NOT NULL DEFAULT 'pending',
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt_at INTEGER NOT NULL,
+                last_error TEXT,
+                created_at INTEGER NOT NULL,
+                sent_at INTEGER,
+                html TEXT,
+                sender TEXT,
+                unsubscribe_url TEXT,
+                sensitive BOOLEAN NOT NULL DEFAULT 0
+            )".to_string(),
+            "CREATE INDEX IF NOT EXISTS email_outbox_due_idx ON email_outbox (status, next_attempt_at)".to_string(),
+            "CREATE TABLE IF NOT EXISTS email_suppressions (
+                email TEXT PRIMARY KEY COLLATE NOCASE,
+                reason TEXT NOT NULL,
+                detail TEXT NOT NULL DEFAULT '',
+                created_at INTEGER NOT NULL
+            )".to_string(),
+            "COMMIT".to_string(),
+        ];
+
+        let stmts = stmts.join(";");
+        match db.execute_batch(&stmts).await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other)
+        }
     }
 
     async fn drop_table(&self) -> RepositoryResult<()> {
-        Ok(())
+        let db = self.db.lock().await;
+        match db.execute_batch("DROP TABLE IF EXISTS email_suppressions;DROP TABLE IF EXISTS email_outbox;DROP TABLE IF EXISTS email_log").await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other),
+        }
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub enum MailerStatus {
-    Idle,
-    Error,
-}
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use chrono::Utc;
+    use tokio::sync::Mutex;
+    use crate::mailer::{Email, MemoryMailer};
+    use crate::repository::Repository;
+    use crate::repository::player::{Model as Player, PlayerRepository};
+    use super::{retry_delay, EmailRepository, MailerStatus, OutboxStatus, SuppressionReason};
 
-impl RepositoryItem for MailerStatus {
-    fn masked_columns(_: bool) -> Vec<String> {
-        Self::all_columns()
+    async fn repository() -> (EmailRepository, Arc<MemoryMailer>) {
+        let (repository, mailer, _) = repositories().await;
+        (repository, mailer)
     }
 
-    fn saved_columns() -> Vec<String> {
-        Self::all_columns()
+    // repositories also sets up players, for tests that flag them.
+    async fn repositories() -> (EmailRepository, Arc<MemoryMailer>, PlayerRepository) {
+        let db = Arc::new(Mutex::new(libsql::Database::open_in_memory().unwrap().connect().unwrap()));
+        let mailer = Arc::new(MemoryMailer::default());
+        let repository = EmailRepository::new(db.clone(), mailer.clone());
+        repository.create_table().await.unwrap();
+        let players = PlayerRepository::new(db);
+        players.create_table().await.unwrap();
+        (repository, mailer, players)
     }
 
-    fn all_columns() -> Vec<String> {
-        vec![]
+    #[tokio::test]
+    async fn test_queued_emails_are_delivered_and_recorded() {
+        let (repository, mailer) = repository().await;
+        repository.send_email("Fe-Vault Login Token", "someone@example.com", "Your auth token is: 123456").await.unwrap();
+        assert!(mailer.sent().is_empty());
+        assert_eq!(repository.status().await.unwrap().status, MailerStatus::Sending);
+
+        assert_eq!(repository.deliver_due(10).await.unwrap(), 1);
+        assert_eq!(
+            mailer.sent(),
+            vec![Email::new("someone@example.com", "Fe-Vault Login Token", "Your auth token is: 123456")]
+        );
+        let logged = repository.sent_to("someone@example.com").await.unwrap();
+        assert_eq!(logged.len(), 1);
+        assert_eq!(logged[0].subject, "Fe-Vault Login Token");
+        assert_eq!(repository.status().await.unwrap().status, MailerStatus::Idle);
+        assert_eq!(repository.deliver_due(10).await.unwrap(), 0);
     }
 
-    fn table_name() -> String where Self: Sized {
-        "email_manager".to_string()
+    #[tokio::test]
+    async fn test_undeliverable_emails_are_dead_lettered() {
+        let (repository, mailer) = repository().await;
+        repository.send_email("Hello", "not an address", "Body").await.unwrap();
+        assert_eq!(repository.deliver_due(10).await.unwrap(), 0);
+        let dead = repository.outbox(Some(OutboxStatus::Dead)).await.unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].attempts, 1);
+        assert_eq!(repository.status().await.unwrap().status, MailerStatus::Error);
+        assert!(mailer.sent().is_empty());
+
+        let id = dead[0].id.unwrap();
+        repository.resend(id).await.unwrap();
+        assert_eq!(repository.get(id).await.unwrap().status, OutboxStatus::Pending);
+        assert!(repository.resend(id).await.is_err());
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_suppressed_addresses_are_not_sent_to() {
+        let (repository, mailer, players) = repositories().await;
+        let player = Player::new(None, "Wendy".to_string(), "Darling".to_string(), "gone@example.com".to_string(), None, None, "".to_string(), false);
+        let player_id = players.create(Some(player)).await.unwrap();
+        repository.suppress("Gone@Example.com", SuppressionReason::Bounced, "No such mailbox").await.unwrap();
+        assert_eq!(players.get(player_id).await.unwrap().email_suppressed, Some(SuppressionReason::Bounced));
+        repository.send_email("Hello", "gone@example.com", "Body").await.unwrap();
+        assert_eq!(repository.deliver_due(10).await.unwrap(), 0);
+        assert!(mailer.sent().is_empty());
+        let dead = repository.outbox(Some(OutboxStatus::Dead)).await.unwrap();
+        assert_eq!(dead[0].last_error.as_deref(), Some("Suppressed: bounced"));
+
+        repository.unsuppress("gone@example.com").await.unwrap();
+        assert_eq!(players.get(player_id).await.unwrap().email_suppressed, None);
+        repository.resend(dead[0].id.unwrap()).await.unwrap();
+        assert_eq!(repository.deliver_due(10).await.unwrap(), 1);
+        assert!(repository.unsuppress("gone@example.com").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sensitive_emails_are_redacted() {
+        let (repository, mailer) = repository().await;
+        let email = Email { sensitive: true, ..Email::new("someone@example.com", "Login", "Your auth token is: 123456") };
+        repository.queue(email).await.unwrap();
+        let queued = repository.outbox(None).await.unwrap().remove(0);
+        assert_eq!(queued.body, "Your auth token is: 123456");
+        assert_eq!(queued.clone().redacted().body, "[redacted]");
+
+        assert_eq!(repository.deliver_due(10).await.unwrap(), 1);
+        assert_eq!(mailer.sent()[0].body, "Your auth token is: 123456");
+        let sent = repository.get(queued.id.unwrap()).await.unwrap();
+        assert_eq!(sent.status, OutboxStatus::Sent);
+        assert_eq!(sent.body, "[redacted]");
+        assert_eq!(sent.html, None);
+    }
+
+    #[tokio::test]
+    async fn test_prune_sent() {
+        let (repository, _) = repository().await;
+        repository.send_email("Hello", "someone@example.com", "Body").await.unwrap();
+        repository.deliver_due(10).await.unwrap();
+        repository.send_email("Hello", "not an address", "Body").await.unwrap();
+        repository.deliver_due(10).await.unwrap();
+
+        assert_eq!(repository.prune_sent(0).await.unwrap(), 0);
+        assert_eq!(repository.prune_sent(Utc::now().timestamp_millis() + 1).await.unwrap(), 1);
+        // Dead letters stay for admins to look at, and the log keeps its record
+        assert_eq!(repository.outbox(None).await.unwrap().len(), 1);
+        assert_eq!(repository.sent_to("someone@example.com").await.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), 30);
+        assert_eq!(retry_delay(2), 60);
+        assert_eq!(retry_delay(5), 480);
+        assert_eq!(retry_delay(30), 6 * 60 * 60);
+    }
+}
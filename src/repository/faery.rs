@@ -1,10 +1,14 @@
-use libsql::{Connection, params, Row};
+use base64::{engine::general_purpose, Engine as _};
+use libsql::{Connection, params, Row, Value};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use crate::dross::{DrossError, DrossHolder, DrossResult};
 use crate::prelude::Repository;
-use crate::repository::{RepositoryError, RepositoryItem, RepositoryResult};
+use crate::repository::{finish_transaction, RepositoryError, RepositoryItem, RepositoryResult};
+use crate::repository::achievement::Badge;
+use crate::repository::attribute::Attributes;
+use crate::repository::ledger::{append, Entry, EntryKind, LeaderboardEntry};
 
 #[derive(Clone)]
 pub struct FaeryRepository {
@@ -17,6 +21,223 @@ impl FaeryRepository {
             db,
         }
     }
+
+    // balances returns every faery's dross, lowest first, optionally only for one group's members.
+    pub async fn balances(&self, group: Option<i64>) -> RepositoryResult<Vec<i64>> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            "SELECT COALESCE(dross, 0) FROM faeries WHERE deleted_at IS NULL AND (?1 IS NULL OR id IN (SELECT faery_id FROM group_members WHERE group_id = ?1)) ORDER BY dross",
+            [group]).await?;
+        let mut balances = Vec::new();
+        while let Some(row) = res.next()? {
+            balances.push(row.get(0)?);
+        }
+        Ok(balances)
+    }
+
+    // search runs a filtered, sorted page of faeries described by the query.
+    // Every user-supplied value is bound as a parameter; only whitelisted column names are formatted in.
+    pub async fn search(&self, query: &FaeryQuery) -> RepositoryResult<FaeryPage> {
+        let (filters, filter_params) = query.filters();
+        let limit = query.limit();
+        let sort = query.sort.unwrap_or_default();
+        let direction = query.direction.unwrap_or_default();
+
+        let mut conditions = filters.clone();
+        let mut params = filter_params.clone();
+        if let Some(cursor) = query.cursor.as_deref() {
+            // A cursor from another sort, or a mangled one, would silently restart from the first page
+            let cursor = Cursor::decode(cursor, sort).ok_or(RepositoryError::InvalidModel)?;
+            let comparison = match direction {
+                SortDirection::Asc => ">",
+                SortDirection::Desc => "<",
+            };
+            conditions.push(format!(
+                "({column} {comparison} ? OR ({column} = ? AND id {comparison} ?))",
+                column = sort.column()
+            ));
+            params.push(cursor.value.clone());
+            params.push(cursor.value);
+            params.push(Value::Integer(cursor.id));
+        }
+
+        let db = self.db.lock().await;
+        let total_sql = format!("SELECT COUNT(*) FROM faeries{}", where_clause(&filters));
+        let total: i64 = match db.query(&total_sql, filter_params).await?.next()? {
+            Some(row) => row.get(0)?,
+            None => 0,
+        };
+
+        let order = direction.keyword();
+        let page_sql = format!(
+            "SELECT * FROM faeries{} ORDER BY {} {order}, id {order} LIMIT ?",
+            where_clause(&conditions), sort.column()
+        );
+        // Fetch one extra row to learn whether there's another page
+        params.push(Value::Integer(limit + 1));
+        let mut res = db.query(&page_sql, params).await?;
+        let mut faeries = Vec::new();
+        while let Some(row) = res.next()? {
+            faeries.push(Model::from_response(&row));
+        }
+        let next_cursor = if faeries.len() as i64 > limit {
+            faeries.truncate(limit as usize);
+            faeries.last().map(|faery| Cursor::from_faery(faery, sort).encode())
+        } else {
+            None
+        };
+        Ok(FaeryPage { faeries, total, next_cursor })
+    }
+
+    pub async fn tags_for(&self, faery_id: i64) -> RepositoryResult<Vec<String>> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT tag FROM faery_tags WHERE faery_id = ?1 ORDER BY tag", [faery_id]).await?;
+        let mut tags = Vec::new();
+        while let Some(row) = res.next()? {
+            tags.push(row.get(0)?);
+        }
+        Ok(tags)
+    }
+
+    // set_tags replaces all of a faery's tags.
+    pub async fn set_tags(&self, faery_id: i64, tags: &[String]) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        db.execute("BEGIN", ()).await?;
+        let result = async {
+            db.execute("DELETE FROM faery_tags WHERE faery_id = ?1", [faery_id]).await?;
+            for tag in tags {
+                db.execute(
+                    "INSERT OR IGNORE INTO faery_tags (faery_id, tag) VALUES (?1, ?2)",
+                    params![faery_id, tag.trim().to_lowercase()]
+                ).await?;
+            }
+            Ok(())
+        }.await;
+        finish_transaction(&db, result).await
+    }
+
+    // owned_by returns every faery registered to an email address, archived ones included.
+    pub async fn owned_by(&self, email: &str) -> RepositoryResult<Vec<Model>> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM faeries WHERE email = ?1 COLLATE NOCASE ORDER BY id", [email]).await?;
+        let mut faeries = Vec::new();
+        while let Some(row) = res.next()? {
+            faeries.push(Model::from_response(&row));
+        }
+        Ok(faeries)
+    }
+
+    // archived lists faeries that have been deleted but not purged.
+    pub async fn archived(&self) -> RepositoryResult<Vec<Model>> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM faeries WHERE deleted_at IS NOT NULL ORDER BY deleted_at DESC", ()).await?;
+        let mut faeries = Vec::new();
+        while let Some(row) = res.next()? {
+            faeries.push(Model::from_response(&row));
+        }
+        Ok(faeries)
+    }
+
+    pub async fn restore(&self, id: i64) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        match db.execute("UPDATE faeries SET deleted_at = NULL WHERE id = ?1 AND deleted_at IS NOT NULL", [id]).await? {
+            0 => Err(RepositoryError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    // purge permanently removes an archived faery with its tags, badges, attributes, group
+    // memberships and redirects. Ledger entries are kept, since removing them would break the hash
+    // chain, and ids are never reused, so they can't end up pointing at a different faery.
+    pub async fn purge(&self, id: i64) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        db.execute("BEGIN", ()).await?;
+        let result = async {
+            let removed = db.execute("DELETE FROM faeries WHERE id = ?1 AND deleted_at IS NOT NULL", [id]).await?;
+            if removed == 0 {
+                return Err(RepositoryError::NotFound);
+            }
+            db.execute("DELETE FROM faery_tags WHERE faery_id = ?1", [id]).await?;
+            db.execute("DELETE FROM faery_badges WHERE faery_id = ?1", [id]).await?;
+            db.execute("DELETE FROM faery_attributes WHERE faery_id = ?1", [id]).await?;
+            db.execute("DELETE FROM group_members WHERE faery_id = ?1", [id]).await?;
+            db.execute("DELETE FROM faery_redirects WHERE source_id = ?1 OR target_id = ?1", [id]).await?;
+            Ok(())
+        }.await;
+        finish_transaction(&db, result).await
+    }
+
+    // redirect_for returns the faery a merged-away id now points to.
+    pub async fn redirect_for(&self, id: i64) -> RepositoryResult<Option<i64>> {
+        let db = self.db.lock().await;
+        match db.query("SELECT target_id FROM faery_redirects WHERE source_id = ?1", [id]).await?.next()? {
+            Some(row) => Ok(Some(row.get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    // merge folds the source faery into the target in one transaction: the balance moves with a
+    // pair of transfer entries, badges, tags, attributes and group memberships move across, and the source is archived behind a
+    // redirect. The transfer pair is recorded on the redirect so the target's ledger view, which
+    // already includes the source's history, doesn't count the balance twice. Returns the entry
+    // that credited the target, if there was a balance to move.
+    pub async fn merge(&self, source_id: i64, target_id: i64) -> RepositoryResult<Option<Entry>> {
+        let db = self.db.lock().await;
+        db.execute("BEGIN", ()).await?;
+        let result = async {
+            let now = chrono::Utc::now().timestamp_millis();
+            let mut balances = [0u32; 2];
+            for (balance, id) in balances.iter_mut().zip([source_id, target_id]) {
+                let mut res = db.query("SELECT COALESCE(dross, 0) FROM faeries WHERE id = ?1 AND deleted_at IS NULL", [id]).await?;
+                *balance = match res.next()? {
+                    Some(row) => row.get(0)?,
+                    None => return Err(RepositoryError::NotFound),
+                };
+            }
+            let [amount, target_balance] = balances;
+            let (mut debit_entry_id, mut credit_entry_id, mut credit) = (None, None, None);
+            if amount > 0 {
+                let target_balance = target_balance.checked_add(amount).ok_or(RepositoryError::InvalidModel)?;
+                db.execute("UPDATE faeries SET dross = 0 WHERE id = ?1", [source_id]).await?;
+                db.execute("UPDATE faeries SET dross = ?1 WHERE id = ?2", params![target_balance, target_id]).await?;
+                debit_entry_id = Some(append(&db, Entry::new(
+                    source_id, -(amount as i64), 0, EntryKind::Transfer, format!("Merged into faery {}", target_id)
+                )).await?);
+                let entry = Entry::new(target_id, amount as i64, target_balance as i64, EntryKind::Transfer, format!("Merged from faery {}", source_id));
+                let id = append(&db, entry.clone()).await?;
+                credit_entry_id = Some(id);
+                credit = Some(Entry { id: Some(id), ..entry });
+            }
+            for table in ["faery_badges", "faery_tags", "faery_attributes", "group_members"] {
+                // Rows the target already has stay behind and are dropped with the source's leftovers
+                db.execute(&format!("UPDATE OR IGNORE {table} SET faery_id = ?1 WHERE faery_id = ?2"), [target_id, source_id]).await?;
+                db.execute(&format!("DELETE FROM {table} WHERE faery_id = ?1"), [source_id]).await?;
+            }
+            db.execute("UPDATE faery_redirects SET target_id = ?1 WHERE target_id = ?2", [target_id, source_id]).await?;
+            db.execute(
+                "INSERT OR REPLACE INTO faery_redirects (source_id, target_id, merged_at, debit_entry_id, credit_entry_id) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![source_id, target_id, now, debit_entry_id, credit_entry_id]
+            ).await?;
+            db.execute("UPDATE faeries SET deleted_at = ?1 WHERE id = ?2", [now, source_id]).await?;
+            Ok(credit)
+        }.await;
+        finish_transaction(&db, result).await
+    }
+
+    // top_holders returns the richest faeries, skipping those that opted out of leaderboards.
+    pub async fn top_holders(&self, limit: i64, group: Option<i64>) -> RepositoryResult<Vec<LeaderboardEntry>> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            r#"SELECT id, name, COALESCE(dross, 0) FROM faeries
+WHERE leaderboard_opt_out = 0 AND deleted_at IS NULL AND (?2 IS NULL OR id IN (SELECT faery_id FROM group_members WHERE group_id = ?2))
+ORDER BY dross DESC LIMIT ?1"#,
+            params![limit, group]).await?;
+        let mut holders = Vec::new();
+        while let Some(row) = res.next()? {
+            holders.push(LeaderboardEntry::from_response(&row)?);
+        }
+        Ok(holders)
+    }
 }
 
 impl RepositoryItem for Model {
@@ -43,6 +264,8 @@ impl RepositoryItem for Model {
             "is_admin".to_string(),
             "email".to_string(),
             "dross".to_string(),
+            "leaderboard_opt_out".to_string(),
+            "deleted_at".to_string(),
         ]
     }
 
@@ -63,12 +286,13 @@ impl Repository for FaeryRepository {
         let db = self.db.lock().await;
         let result = match faery.id {
             Some(id) => {
-                let mut stmt = db.prepare("UPDATE faeries SET name = ?1, is_admin = ?2, email = ?3, dross = ?4 WHERE id = ?5").await.unwrap();
-                stmt.query(params![faery.name, faery.is_admin, faery.email, faery.dross, id]).await
+                // Balances only change through the ledger, so an update never writes dross
+                let mut stmt = db.prepare("UPDATE faeries SET name = ?1, is_admin = ?2, email = ?3, leaderboard_opt_out = ?4 WHERE id = ?5").await.unwrap();
+                stmt.query(params![faery.name, faery.is_admin, faery.email, faery.leaderboard_opt_out, id]).await
             },
             None => {
-                let mut stmt = db.prepare("INSERT INTO faeries (name, is_admin, email, dross) VALUES (?1, ?2, ?3, ?4)").await.unwrap();
-                stmt.query(params![faery.name, faery.is_admin, faery.email, faery.dross]).await
+                let mut stmt = db.prepare("INSERT INTO faeries (name, is_admin, email, dross, leaderboard_opt_out) VALUES (?1, ?2, ?3, ?4, ?5)").await.unwrap();
+                stmt.query(params![faery.name, faery.is_admin, faery.email, faery.dross, faery.leaderboard_opt_out]).await
             },
         };
         match result {
@@ -81,7 +305,7 @@ impl Repository for FaeryRepository {
     async fn get(&self, id: i64) -> RepositoryResult<Model> {
         let db = self.db.lock().await;
         let mut stmt = db
-            .prepare("SELECT * FROM faeries WHERE id = ?1")
+            .prepare("SELECT * FROM faeries WHERE id = ?1 AND deleted_at IS NULL")
             .await
             .unwrap();
         let mut res = stmt.query([id]).await.unwrap();
@@ -96,7 +320,7 @@ impl Repository for FaeryRepository {
 
     async fn get_all(&self) -> RepositoryResult<Vec<Model>> {
         let db = self.db.lock().await;
-        let result = db.query("SELECT * FROM faeries", ()).await;
+        let result = db.query("SELECT * FROM faeries WHERE deleted_at IS NULL", ()).await;
         let mut res = match result {
             Ok(res) => res,
             Err(err) => {
@@ -117,25 +341,44 @@ impl Repository for FaeryRepository {
         Ok(faeries)
     }
 
+    // Deleting a faery archives it so its ledger entries still point somewhere; see purge.
     async fn delete(&self, id: i64) -> RepositoryResult<()> {
         let db = self.db.lock().await;
-        let result = db.execute("DELETE FROM faeries WHERE id = ?1", [id]).await;
+        let result = db.execute(
+            "UPDATE faeries SET deleted_at = ?1 WHERE id = ?2 AND deleted_at IS NULL",
+            params![chrono::Utc::now().timestamp_millis(), id]
+        ).await;
         match result {
+            Ok(0) => Err(RepositoryError::NotFound),
             Ok(_) => Ok(()),
-            Err(_) => Err(RepositoryError::NotFound),
+            Err(_) => Err(RepositoryError::Other),
         }
     }
 
     async fn create_table(&self) -> RepositoryResult<()> {
         let db = self.db.lock().await;
-        let result = db.execute(
+        let result = db.execute_batch(
             r#"CREATE TABLE IF NOT EXISTS faeries (
-    id INTEGER PRIMARY KEY,
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
     name VARCHAR(255) NOT NULL,
     is_admin BOOLEAN NOT NULL,
     email VARCHAR(255) NOT NULL,
-    dross INTEGER
-)"#, ()).await;
+    dross INTEGER,
+    leaderboard_opt_out BOOLEAN NOT NULL DEFAULT 0,
+    deleted_at INTEGER
+);
+CREATE TABLE IF NOT EXISTS faery_tags (
+    faery_id INTEGER NOT NULL,
+    tag TEXT NOT NULL,
+    PRIMARY KEY (faery_id, tag)
+);
+CREATE TABLE IF NOT EXISTS faery_redirects (
+    source_id INTEGER PRIMARY KEY,
+    target_id INTEGER NOT NULL,
+    merged_at INTEGER NOT NULL,
+    debit_entry_id INTEGER,
+    credit_entry_id INTEGER
+)"#).await;
         match result {
             Ok(_) => Ok(()),
             Err(_) => Err(RepositoryError::Other),
@@ -144,7 +387,7 @@ impl Repository for FaeryRepository {
 
     async fn drop_table(&self) -> RepositoryResult<()> {
         let db = self.db.lock().await;
-        let result = db.execute("DROP TABLE IF EXISTS faeries", ()).await;
+        let result = db.execute_batch("DROP TABLE IF EXISTS faery_redirects;DROP TABLE IF EXISTS faery_tags;DROP TABLE IF EXISTS faeries").await;
         match result {
             Ok(_) => Ok(()),
             Err(_) => Err(RepositoryError::Other),
@@ -162,6 +405,12 @@ pub struct Model {
     // TODO: deprecated
     pub is_admin: bool,
     pub dross: u32,
+    // Hides the faery from public leaderboards
+    #[serde(default)]
+    pub leaderboard_opt_out: bool,
+    // Set when the faery is archived; archived faeries are hidden but keep their ledger history
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<i64>,
 }
 
 #[allow(dead_code)]
@@ -175,17 +424,23 @@ impl Model {
             email,
             is_admin,
             dross,
+            leaderboard_opt_out: false,
+            deleted_at: None,
         }
     }
 
     pub fn from_response(row: &Row) -> Model {
-        Model::new(
+        let mut faery = Model::new(
             row.get(1).unwrap(),
             row.get(3).unwrap(),
             row.get(2).unwrap(),
-            row.get(4).unwrap(),
+            // A NULL balance reads as zero, as it does in search and balances
+            row.get(4).unwrap_or(0),
             row.get(0).unwrap_or(None),
-        )
+        );
+        faery.leaderboard_opt_out = row.get(5).unwrap_or(false);
+        faery.deleted_at = row.get(6).unwrap_or(None);
+        faery
     }
 
     // This is a method that returns the name of the Faery.
@@ -217,6 +472,8 @@ impl Clone for Model {
             email: self.email.clone(),
             is_admin: self.is_admin,
             dross: self.dross,
+            leaderboard_opt_out: self.leaderboard_opt_out,
+            deleted_at: self.deleted_at,
         }
     }
 
@@ -226,16 +483,18 @@ impl Clone for Model {
         self.email = source.email.clone();
         self.is_admin = source.is_admin;
         self.dross = source.dross;
+        self.leaderboard_opt_out = source.leaderboard_opt_out;
+        self.deleted_at = source.deleted_at;
     }
 }
 
 impl DrossHolder for Model {
     // This is a method that increments the dross of the Faery.
     fn increment_dross(&mut self, amount: u32) -> DrossResult {
-        if amount <= 0 {
+        if amount == 0 {
             return Err(DrossError::InvalidIncrement);
         }
-        self.dross += amount;
+        self.dross = self.dross.checked_add(amount).ok_or(DrossError::InvalidIncrement)?;
         Ok(self.dross)
     }
 
@@ -269,3 +528,310 @@ impl From<CreateFaeryRequest> for Model {
         Model::new(req.name, req.email, false, 0, None)
     }
 }
+
+// FaeryResponse is a faery along with the badges it has earned and its custom attributes.
+#[derive(Debug, Serialize)]
+pub struct FaeryResponse {
+    #[serde(flatten)]
+    pub faery: Model,
+    pub badges: Vec<Badge>,
+    pub attributes: Attributes,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    #[default]
+    Id,
+    Name,
+    Dross,
+}
+
+impl SortField {
+    // Older rows may have a NULL balance, which compares as neither above nor below a cursor
+    fn column(&self) -> &'static str {
+        match self {
+            SortField::Id => "id",
+            SortField::Name => "name",
+            SortField::Dross => "COALESCE(dross, 0)",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortDirection {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortDirection {
+    fn keyword(&self) -> &'static str {
+        match self {
+            SortDirection::Asc => "ASC",
+            SortDirection::Desc => "DESC",
+        }
+    }
+}
+
+// FaeryQuery holds the query string accepted by GET /api/faeries.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct FaeryQuery {
+    // Case-insensitive substring of the faery's name
+    pub name: Option<String>,
+    pub min_dross: Option<i64>,
+    pub max_dross: Option<i64>,
+    // Player id of the faery's owner
+    pub owner: Option<i64>,
+    // Comma separated; a faery must have every tag listed
+    pub tags: Option<String>,
+    // Comma separated key:value pairs of custom attributes, e.g. kith:pooka,rank:3
+    pub attributes: Option<String>,
+    pub sort: Option<SortField>,
+    pub direction: Option<SortDirection>,
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+}
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+impl FaeryQuery {
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+    }
+
+    // filters returns the WHERE conditions and their parameters, without pagination.
+    fn filters(&self) -> (Vec<String>, Vec<Value>) {
+        let mut conditions = vec!["deleted_at IS NULL".to_string()];
+        let mut params = Vec::new();
+        if let Some(name) = self.name.as_ref().filter(|name| !name.is_empty()) {
+            conditions.push("name LIKE ? ESCAPE '\\'".to_string());
+            let escaped = name.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+            params.push(Value::Text(format!("%{}%", escaped)));
+        }
+        if let Some(min_dross) = self.min_dross {
+            conditions.push("COALESCE(dross, 0) >= ?".to_string());
+            params.push(Value::Integer(min_dross));
+        }
+        if let Some(max_dross) = self.max_dross {
+            conditions.push("COALESCE(dross, 0) <= ?".to_string());
+            params.push(Value::Integer(max_dross));
+        }
+        if let Some(owner) = self.owner {
+            conditions.push("email IN (SELECT auth_email FROM players WHERE id = ?)".to_string());
+            params.push(Value::Integer(owner));
+        }
+        for tag in self.tag_list() {
+            conditions.push("id IN (SELECT faery_id FROM faery_tags WHERE tag = ?)".to_string());
+            params.push(Value::Text(tag));
+        }
+        for (key, value) in self.attribute_list() {
+            conditions.push("id IN (SELECT faery_id FROM faery_attributes WHERE key = ? AND value = ? COLLATE NOCASE)".to_string());
+            params.push(Value::Text(key));
+            params.push(Value::Text(value));
+        }
+        (conditions, params)
+    }
+
+    fn tag_list(&self) -> Vec<String> {
+        self.tags.as_deref()
+            .unwrap_or("")
+            .split(',')
+            .map(|tag| tag.trim().to_lowercase())
+            .filter(|tag| !tag.is_empty())
+            .collect()
+    }
+
+    fn attribute_list(&self) -> Vec<(String, String)> {
+        self.attributes.as_deref()
+            .unwrap_or("")
+            .split(',')
+            .filter_map(|pair| pair.split_once(':'))
+            .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            .filter(|(key, value)| !key.is_empty() && !value.is_empty())
+            .collect()
+    }
+}
+
+fn where_clause(conditions: &[String]) -> String {
+    if conditions.is_empty() {
+        String::new()
+    } else {
+        format!(" WHERE {}", conditions.join(" AND "))
+    }
+}
+
+// FaeryPage is one page of search results.
+#[derive(Debug)]
+pub struct FaeryPage {
+    pub faeries: Vec<Model>,
+    // Matching faeries across every page
+    pub total: i64,
+    pub next_cursor: Option<String>,
+}
+
+// Cursor marks the last faery on a page by its sort value and id, so the next page carries on
+// from there even if faeries are added or removed in between.
+#[derive(Debug, Clone, PartialEq)]
+struct Cursor {
+    id: i64,
+    value: Value,
+}
+
+impl Cursor {
+    fn from_faery(faery: &Model, sort: SortField) -> Cursor {
+        let id = faery.id.unwrap_or(0);
+        let value = match sort {
+            SortField::Id => Value::Integer(id),
+            SortField::Name => Value::Text(faery.name.clone()),
+            SortField::Dross => Value::Integer(faery.dross as i64),
+        };
+        Cursor { id, value }
+    }
+
+    fn encode(&self) -> String {
+        let value = match &self.value {
+            Value::Integer(value) => value.to_string(),
+            Value::Text(value) => value.clone(),
+            _ => String::new(),
+        };
+        general_purpose::URL_SAFE_NO_PAD.encode(format!("{}:{}", self.id, value))
+    }
+
+    fn decode(cursor: &str, sort: SortField) -> Option<Cursor> {
+        let decoded = String::from_utf8(general_purpose::URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()?;
+        let (id, value) = decoded.split_once(':')?;
+        let id = id.parse().ok()?;
+        let value = match sort {
+            SortField::Name => Value::Text(value.to_string()),
+            SortField::Id | SortField::Dross => Value::Integer(value.parse().ok()?),
+        };
+        Some(Cursor { id, value })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use libsql::Value;
+    use tokio::sync::Mutex;
+    use crate::repository::{Repository, RepositoryError};
+    use crate::repository::achievement::AchievementRepository;
+    use crate::repository::attribute::AttributeRepository;
+    use crate::repository::group::{Group, GroupRepository, GroupRole};
+    use crate::repository::ledger::{verify_chain, Entry, EntryKind, LedgerRepository};
+    use super::{Cursor, FaeryQuery, FaeryRepository, Model, SortField};
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let faery = Model::new("Fawn: the animal talent".to_string(), "me@example.com".to_string(), false, 12, Some(7));
+        for sort in [SortField::Id, SortField::Name, SortField::Dross] {
+            let cursor = Cursor::from_faery(&faery, sort);
+            assert_eq!(Cursor::decode(&cursor.encode(), sort), Some(cursor));
+        }
+        assert_eq!(Cursor::decode("not a cursor", SortField::Id), None);
+    }
+
+    #[test]
+    fn test_filters_are_parameterized() {
+        let query = FaeryQuery {
+            name: Some("50%_off'".to_string()),
+            min_dross: Some(10),
+            tags: Some("Court, ,Seelie".to_string()),
+            ..FaeryQuery::default()
+        };
+        let (conditions, params) = query.filters();
+        assert_eq!(conditions.len(), 5);
+        assert!(conditions.iter().all(|condition| !condition.contains("50")));
+        assert_eq!(params[0], Value::Text("%50\\%\\_off'%".to_string()));
+        assert_eq!(params[2], Value::Text("court".to_string()));
+        assert_eq!(params[3], Value::Text("seelie".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_search_pages_through_null_balances() {
+        let db = libsql::Database::open_in_memory().unwrap().connect().unwrap();
+        let db = Arc::new(Mutex::new(db));
+        let faeries = FaeryRepository::new(db.clone());
+        faeries.create_table().await.unwrap();
+        for (name, dross) in [("Puck", Some(3)), ("Pook", None), ("Peaseblossom", Some(0)), ("Moth", None), ("Cobweb", Some(1))] {
+            db.lock().await.execute(
+                "INSERT INTO faeries (name, is_admin, email, dross) VALUES (?1, 0, 'fae@example.com', ?2)",
+                libsql::params![name, dross]
+            ).await.unwrap();
+        }
+
+        let mut query = FaeryQuery { sort: Some(SortField::Dross), limit: Some(2), ..FaeryQuery::default() };
+        let mut seen = Vec::new();
+        loop {
+            let page = faeries.search(&query).await.unwrap();
+            assert_eq!(page.total, 5);
+            seen.extend(page.faeries.iter().map(|faery| faery.name.clone()));
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        assert_eq!(seen, vec!["Pook", "Peaseblossom", "Moth", "Cobweb", "Puck"]);
+
+        query.cursor = Some("not a cursor".to_string());
+        assert!(matches!(faeries.search(&query).await, Err(RepositoryError::InvalidModel)));
+    }
+
+    #[tokio::test]
+    async fn test_merge_moves_balance_and_history_once() {
+        let db = libsql::Database::open_in_memory().unwrap().connect().unwrap();
+        let db = Arc::new(Mutex::new(db));
+        let faeries = FaeryRepository::new(db.clone());
+        let ledger = LedgerRepository::new(db.clone());
+        faeries.create_table().await.unwrap();
+        ledger.create_table().await.unwrap();
+        AchievementRepository::new(db.clone()).create_table().await.unwrap();
+        AttributeRepository::new(db.clone()).create_table().await.unwrap();
+        let groups = GroupRepository::new(db.clone());
+        groups.create_table().await.unwrap();
+
+        let target = faeries.save(Model::new("Puck".to_string(), "puck@example.com".to_string(), false, 10, None)).await.unwrap();
+        let source = faeries.save(Model::new("Pook".to_string(), "pook@example.com".to_string(), false, 5, None)).await.unwrap();
+        ledger.save(Entry::new(target, 10, 10, EntryKind::Grant, "Welcome".to_string())).await.unwrap();
+        ledger.save(Entry::new(source, 5, 5, EntryKind::Grant, "Welcome".to_string())).await.unwrap();
+        faeries.set_tags(source, &["seelie".to_string()]).await.unwrap();
+        let court = groups.save(Group { id: None, name: "Seelie Court".to_string(), kind: String::new(), parent_id: None, treasury: 0 }).await.unwrap();
+        let guild = groups.save(Group { id: None, name: "Tinkers".to_string(), kind: String::new(), parent_id: None, treasury: 0 }).await.unwrap();
+        groups.set_member(court, target, GroupRole::Leader).await.unwrap();
+        groups.set_member(court, source, GroupRole::Member).await.unwrap();
+        groups.set_member(guild, source, GroupRole::Member).await.unwrap();
+
+        // Merging into a missing faery changes nothing
+        assert!(matches!(faeries.merge(source, 99).await, Err(RepositoryError::NotFound)));
+        assert_eq!(faeries.get(source).await.unwrap().dross, 5);
+
+        faeries.merge(source, target).await.unwrap();
+        assert_eq!(faeries.get(target).await.unwrap().dross, 15);
+        assert!(faeries.get(source).await.is_err());
+        assert_eq!(faeries.redirect_for(source).await.unwrap(), Some(target));
+        assert_eq!(faeries.tags_for(target).await.unwrap(), vec!["seelie".to_string()]);
+        // The target keeps its own role where both were members
+        let court_members = groups.members(court).await.unwrap();
+        assert_eq!((court_members.len(), court_members[0].faery_id, court_members[0].role), (1, target, GroupRole::Leader));
+        assert_eq!(groups.members(guild).await.unwrap()[0].faery_id, target);
+
+        // The target's history holds both grants but not the transfers that moved the balance
+        let history = ledger.get_for_faery(target).await.unwrap();
+        assert_eq!(history.iter().map(|entry| entry.amount).collect::<Vec<_>>(), vec![10, 5]);
+        assert_eq!(history.iter().map(|entry| entry.amount).sum::<i64>(), 15);
+        let (entries, head) = ledger.chain().await.unwrap();
+        assert!(verify_chain(&entries, &head).valid);
+
+        // Purging the merged-away faery takes its redirect with it
+        faeries.purge(source).await.unwrap();
+        assert_eq!(faeries.redirect_for(source).await.unwrap(), None);
+
+        // A balance the target can't hold is refused and nothing moves
+        let full = faeries.save(Model::new("Mab".to_string(), "mab@example.com".to_string(), false, u32::MAX, None)).await.unwrap();
+        assert!(matches!(faeries.merge(target, full).await, Err(RepositoryError::InvalidModel)));
+        assert_eq!(faeries.get(target).await.unwrap().dross, 15);
+    }
+}
@@ -0,0 +1,397 @@
+use std::sync::Arc;
+use chrono::Utc;
+use libsql::{Connection, params, Row};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use crate::repository::{finish_transaction, is_constraint_violation, ledger, Repository, RepositoryError, RepositoryItem, RepositoryResult};
+use crate::repository::ledger::{Entry, EntryKind};
+
+// Group is a court, house or any other collection of faeries. Groups nest through parent_id,
+// and each one has a shared treasury that's separate from its members' own dross.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Group {
+    pub(crate) id: Option<i64>,
+    pub name: String,
+    // Free-form label such as "court" or "house"
+    #[serde(default)]
+    pub kind: String,
+    #[serde(default)]
+    pub parent_id: Option<i64>,
+    // Only changed through the treasury endpoints
+    #[serde(default)]
+    pub treasury: i64,
+}
+
+impl Group {
+    pub fn from_response(row: &Row) -> RepositoryResult<Group> {
+        Ok(Group {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            kind: row.get(2)?,
+            parent_id: row.get(3)?,
+            treasury: row.get(4)?,
+        })
+    }
+}
+
+impl RepositoryItem for Group {
+    fn masked_columns(_: bool) -> Vec<String> {
+        vec![]
+    }
+
+    fn saved_columns() -> Vec<String> {
+        vec!["name".to_string(), "kind".to_string(), "parent_id".to_string()]
+    }
+
+    fn all_columns() -> Vec<String> {
+        vec![
+            "id".to_string(),
+            "name".to_string(),
+            "kind".to_string(),
+            "parent_id".to_string(),
+            "treasury".to_string(),
+        ]
+    }
+
+    fn table_name() -> String where Self: Sized {
+        "faery_groups".to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupRole {
+    Leader,
+    #[default]
+    Member,
+}
+
+impl GroupRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GroupRole::Leader => "leader",
+            GroupRole::Member => "member",
+        }
+    }
+}
+
+impl From<String> for GroupRole {
+    fn from(role: String) -> Self {
+        match role.as_str() {
+            "leader" => GroupRole::Leader,
+            _ => GroupRole::Member,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupMember {
+    pub faery_id: i64,
+    pub name: String,
+    pub role: GroupRole,
+    pub joined_at: i64,
+}
+
+impl GroupMember {
+    pub fn from_response(row: &Row) -> RepositoryResult<GroupMember> {
+        let role: String = row.get(2)?;
+        Ok(GroupMember {
+            faery_id: row.get(0)?,
+            name: row.get(1)?,
+            role: role.into(),
+            joined_at: row.get(3)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MembershipRequest {
+    #[serde(default)]
+    pub role: GroupRole,
+}
+
+// TreasuryEntry is one change to a group's treasury. faery_id is set when dross was paid
+// out to, or collected from, a faery.
+#[derive(Debug, Clone, Serialize)]
+pub struct TreasuryEntry {
+    pub id: i64,
+    pub group_id: i64,
+    pub amount: i64,
+    pub balance: i64,
+    pub faery_id: Option<i64>,
+    pub memo: String,
+    pub created_at: i64,
+}
+
+impl TreasuryEntry {
+    pub fn from_response(row: &Row) -> RepositoryResult<TreasuryEntry> {
+        Ok(TreasuryEntry {
+            id: row.get(0)?,
+            group_id: row.get(1)?,
+            amount: row.get(2)?,
+            balance: row.get(3)?,
+            faery_id: row.get(4)?,
+            memo: row.get(5)?,
+            created_at: row.get(6)?,
+        })
+    }
+}
+
+pub struct GroupRepository {
+    db: Arc<Mutex<Connection>>,
+}
+
+impl GroupRepository {
+    pub fn new(db: Arc<Mutex<Connection>>) -> GroupRepository {
+        GroupRepository {
+            db,
+        }
+    }
+
+    pub async fn members(&self, group_id: i64) -> RepositoryResult<Vec<GroupMember>> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            r#"SELECT f.id, f.name, m.role, m.joined_at FROM group_members m JOIN faeries f ON f.id = m.faery_id
+WHERE m.group_id = ?1 AND f.deleted_at IS NULL ORDER BY m.role, f.name"#,
+            [group_id]).await?;
+        let mut members = Vec::new();
+        while let Some(row) = res.next()? {
+            members.push(GroupMember::from_response(&row)?);
+        }
+        Ok(members)
+    }
+
+    // member_ids returns every faery in the group, and optionally in its subgroups, once each.
+    pub async fn member_ids(&self, group_id: i64, include_subgroups: bool) -> RepositoryResult<Vec<i64>> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            r#"WITH RECURSIVE tree(id) AS (
+    SELECT ?1
+    UNION SELECT g.id FROM faery_groups g JOIN tree t ON g.parent_id = t.id WHERE ?2
+)
+SELECT DISTINCT m.faery_id FROM group_members m JOIN faeries f ON f.id = m.faery_id
+WHERE m.group_id IN (SELECT id FROM tree) AND f.deleted_at IS NULL ORDER BY m.faery_id"#,
+            params![group_id, include_subgroups]).await?;
+        let mut ids = Vec::new();
+        while let Some(row) = res.next()? {
+            ids.push(row.get(0)?);
+        }
+        Ok(ids)
+    }
+
+    pub async fn groups_for(&self, faery_id: i64) -> RepositoryResult<Vec<Group>> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            "SELECT g.* FROM faery_groups g JOIN group_members m ON m.group_id = g.id WHERE m.faery_id = ?1 ORDER BY g.name",
+            [faery_id]).await?;
+        let mut groups = Vec::new();
+        while let Some(row) = res.next()? {
+            groups.push(Group::from_response(&row)?);
+        }
+        Ok(groups)
+    }
+
+    // ancestors returns the ids above a group, nearest first.
+    pub async fn ancestors(&self, group_id: i64) -> RepositoryResult<Vec<i64>> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            r#"WITH RECURSIVE up(id, depth) AS (
+    SELECT parent_id, 1 FROM faery_groups WHERE id = ?1 AND parent_id IS NOT NULL
+    UNION SELECT g.parent_id, up.depth + 1 FROM faery_groups g JOIN up ON g.id = up.id WHERE g.parent_id IS NOT NULL AND up.depth < 64
+)
+SELECT id FROM up ORDER BY depth"#,
+            [group_id]).await?;
+        let mut ids = Vec::new();
+        while let Some(row) = res.next()? {
+            ids.push(row.get(0)?);
+        }
+        Ok(ids)
+    }
+
+    // set_member adds a faery to the group, or changes its role if it's already a member.
+    pub async fn set_member(&self, group_id: i64, faery_id: i64, role: GroupRole) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        db.execute(
+            r#"INSERT INTO group_members (group_id, faery_id, role, joined_at) VALUES (?1, ?2, ?3, ?4)
+ON CONFLICT (group_id, faery_id) DO UPDATE SET role = excluded.role"#,
+            params![group_id, faery_id, role.as_str(), Utc::now().timestamp_millis()]
+        ).await?;
+        Ok(())
+    }
+
+    pub async fn remove_member(&self, group_id: i64, faery_id: i64) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        match db.execute("DELETE FROM group_members WHERE group_id = ?1 AND faery_id = ?2", [group_id, faery_id]).await? {
+            0 => Err(RepositoryError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    // adjust_treasury applies a signed change to the treasury, refusing to overdraw it.
+    pub async fn adjust_treasury(&self, group_id: i64, amount: i64, memo: String) -> RepositoryResult<TreasuryEntry> {
+        let db = self.db.lock().await;
+        db.execute("BEGIN", ()).await?;
+        let result = adjust_treasury(&db, group_id, amount, None, memo).await;
+        finish_transaction(&db, result).await
+    }
+
+    // transfer moves dross between a faery and the treasury in one transaction: a positive amount
+    // is paid in from the faery's balance and a negative one paid out to it. If either side can't
+    // cover it, neither changes. Returns the treasury entry and the faery's ledger entry.
+    pub async fn transfer(&self, group_id: i64, faery_id: i64, amount: i64, memo: String, ledger_memo: String) -> RepositoryResult<(TreasuryEntry, Entry)> {
+        let db = self.db.lock().await;
+        db.execute("BEGIN", ()).await?;
+        let result = async {
+            let treasury_entry = adjust_treasury(&db, group_id, amount, Some(faery_id), memo).await?;
+            let entry = ledger::adjust(&db, Entry::new(faery_id, -amount, 0, EntryKind::Transfer, ledger_memo)).await?;
+            Ok((treasury_entry, entry))
+        }.await;
+        finish_transaction(&db, result).await
+    }
+
+    pub async fn treasury_history(&self, group_id: i64) -> RepositoryResult<Vec<TreasuryEntry>> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM group_treasury WHERE group_id = ?1 ORDER BY id", [group_id]).await?;
+        let mut entries = Vec::new();
+        while let Some(row) = res.next()? {
+            entries.push(TreasuryEntry::from_response(&row)?);
+        }
+        Ok(entries)
+    }
+}
+
+// adjust_treasury applies a signed change to a group's treasury and records it. The caller must
+// hold the lock, inside a transaction.
+async fn adjust_treasury(db: &Connection, group_id: i64, amount: i64, faery_id: Option<i64>, memo: String) -> RepositoryResult<TreasuryEntry> {
+    let balance: i64 = match db.query("SELECT treasury FROM faery_groups WHERE id = ?1", [group_id]).await?.next()? {
+        Some(row) => row.get::<i64>(0)? + amount,
+        None => return Err(RepositoryError::NotFound),
+    };
+    if balance < 0 {
+        return Err(RepositoryError::InvalidModel);
+    }
+    let created_at = Utc::now().timestamp_millis();
+    db.execute("UPDATE faery_groups SET treasury = ?1 WHERE id = ?2", [balance, group_id]).await?;
+    db.execute(
+        "INSERT INTO group_treasury (group_id, amount, balance, faery_id, memo, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![group_id, amount, balance, faery_id, memo.clone(), created_at]
+    ).await?;
+    let id = db.last_insert_rowid();
+    Ok(TreasuryEntry { id, group_id, amount, balance, faery_id, memo, created_at })
+}
+
+#[shuttle_runtime::async_trait]
+impl Repository for GroupRepository {
+    type Item = Group;
+    type RowIdentifier = i64;
+
+    async fn save(&self, group: Group) -> RepositoryResult<i64> {
+        let db = self.db.lock().await;
+        let result = match group.id {
+            Some(id) => {
+                db.execute(
+                    "UPDATE faery_groups SET name = ?1, kind = ?2, parent_id = ?3 WHERE id = ?4",
+                    params![group.name.clone(), group.kind, group.parent_id, id]
+                ).await.map(|_| id)
+            },
+            None => {
+                db.execute(
+                    "INSERT INTO faery_groups (name, kind, parent_id, treasury) VALUES (?1, ?2, ?3, 0)",
+                    params![group.name.clone(), group.kind, group.parent_id]
+                ).await.map(|_| db.last_insert_rowid())
+            },
+        };
+        match result {
+            Ok(id) => Ok(id),
+            Err(err) => {
+                log::error!("Error saving group {}: {:?}", group.name, err);
+                match is_constraint_violation(&err) {
+                    true => Err(RepositoryError::AlreadyExists),
+                    false => Err(RepositoryError::Other),
+                }
+            },
+        }
+    }
+
+    async fn get(&self, id: i64) -> RepositoryResult<Group> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM faery_groups WHERE id = ?1", [id]).await?;
+        match res.next()? {
+            Some(row) => Group::from_response(&row),
+            None => Err(RepositoryError::NotFound),
+        }
+    }
+
+    async fn get_all(&self) -> RepositoryResult<Vec<Group>> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM faery_groups ORDER BY name", ()).await?;
+        let mut groups = Vec::new();
+        while let Some(row) = res.next()? {
+            groups.push(Group::from_response(&row)?);
+        }
+        Ok(groups)
+    }
+
+    // Subgroups of a deleted group move up to its parent.
+    async fn delete(&self, id: i64) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        db.execute("BEGIN", ()).await?;
+        let result = async {
+            db.execute(
+                "UPDATE faery_groups SET parent_id = (SELECT parent_id FROM faery_groups WHERE id = ?1) WHERE parent_id = ?1",
+                [id]).await?;
+            db.execute("DELETE FROM group_members WHERE group_id = ?1", [id]).await?;
+            match db.execute("DELETE FROM faery_groups WHERE id = ?1", [id]).await? {
+                0 => Err(RepositoryError::NotFound),
+                _ => Ok(()),
+            }
+        }.await;
+        finish_transaction(&db, result).await
+    }
+
+    async fn create_table(&self) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        let stmts = [
+            "BEGIN".to_string(),
+            "CREATE TABLE IF NOT EXISTS faery_groups (
+                id INTEGER PRIMARY KEY,
+                name TEXT NOT NULL UNIQUE,
+                kind TEXT NOT NULL DEFAULT '',
+                parent_id INTEGER,
+                treasury INTEGER NOT NULL DEFAULT 0
+            )".to_string(),
+            "CREATE TABLE IF NOT EXISTS group_members (
+                group_id INTEGER NOT NULL,
+                faery_id INTEGER NOT NULL,
+                role TEXT NOT NULL DEFAULT 'member',
+                joined_at INTEGER NOT NULL,
+                PRIMARY KEY (group_id, faery_id)
+            )".to_string(),
+            "CREATE INDEX IF NOT EXISTS group_members_faery_idx ON group_members (faery_id)".to_string(),
+            "CREATE TABLE IF NOT EXISTS group_treasury (
+                id INTEGER PRIMARY KEY,
+                group_id INTEGER NOT NULL,
+                amount INTEGER NOT NULL,
+                balance INTEGER NOT NULL,
+                faery_id INTEGER,
+                memo TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )".to_string(),
+            "COMMIT".to_string(),
+        ];
+
+        let stmts = stmts.join(";");
+        match db.execute_batch(&stmts).await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other)
+        }
+    }
+
+    async fn drop_table(&self) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        match db.execute_batch("DROP TABLE IF EXISTS group_treasury;DROP TABLE IF EXISTS group_members;DROP TABLE IF EXISTS faery_groups").await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other),
+        }
+    }
+}
@@ -0,0 +1,679 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use chrono::Utc;
+use libsql::{Connection, params, Row};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use crate::repository::{finish_transaction, Repository, RepositoryError, RepositoryItem, RepositoryResult};
+use crate::repository::faery::Model;
+
+// EntryKind describes why a faery's balance changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    Opening,
+    Grant,
+    Spend,
+    Transfer,
+    Reward,
+    Adjustment,
+    Reversal,
+}
+
+impl EntryKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EntryKind::Opening => "opening",
+            EntryKind::Grant => "grant",
+            EntryKind::Spend => "spend",
+            EntryKind::Transfer => "transfer",
+            EntryKind::Reward => "reward",
+            EntryKind::Adjustment => "adjustment",
+            EntryKind::Reversal => "reversal",
+        }
+    }
+}
+
+impl From<String> for EntryKind {
+    fn from(kind: String) -> Self {
+        match kind.as_str() {
+            "opening" => EntryKind::Opening,
+            "grant" => EntryKind::Grant,
+            "spend" => EntryKind::Spend,
+            "transfer" => EntryKind::Transfer,
+            "reward" => EntryKind::Reward,
+            "reversal" => EntryKind::Reversal,
+            _ => EntryKind::Adjustment,
+        }
+    }
+}
+
+// The previous hash of the first entry in the chain
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// Entry is a single, append-only change to a faery's dross.
+// The amount is signed, and balance is the faery's dross after the change was applied.
+// Entries form a single hash chain: each hash covers the entry's contents and the hash before it,
+// so editing or removing any historical entry breaks every hash that follows.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub(crate) id: Option<i64>,
+    pub faery_id: i64,
+    pub amount: i64,
+    pub balance: i64,
+    pub kind: EntryKind,
+    pub memo: String,
+    pub created_at: i64,
+    #[serde(default)]
+    pub prev_hash: String,
+    #[serde(default)]
+    pub hash: String,
+}
+
+impl Entry {
+    pub fn new(faery_id: i64, amount: i64, balance: i64, kind: EntryKind, memo: String) -> Entry {
+        Entry {
+            id: None,
+            faery_id,
+            amount,
+            balance,
+            kind,
+            memo,
+            created_at: Utc::now().timestamp_millis(),
+            prev_hash: String::new(),
+            hash: String::new(),
+        }
+    }
+
+    // compute_hash hashes the entry's contents chained onto `prev_hash`.
+    pub fn compute_hash(&self, prev_hash: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(format!(
+            "{}|{}|{}|{}|{}|{}|{}",
+            prev_hash, self.faery_id, self.amount, self.balance, self.kind.as_str(), self.memo, self.created_at
+        ));
+        hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    // chained returns the entry sealed onto the end of a chain whose last hash is `prev_hash`.
+    pub fn chained(self, prev_hash: &str) -> Entry {
+        let hash = self.compute_hash(prev_hash);
+        Entry { prev_hash: prev_hash.to_string(), hash, ..self }
+    }
+
+    pub fn from_response(row: &Row) -> Entry {
+        let kind: String = row.get(4).unwrap();
+        Entry {
+            id: row.get(0).unwrap(),
+            faery_id: row.get(1).unwrap(),
+            amount: row.get(2).unwrap(),
+            balance: row.get(3).unwrap(),
+            kind: kind.into(),
+            memo: row.get(5).unwrap(),
+            created_at: row.get(6).unwrap(),
+            prev_hash: row.get(7).unwrap(),
+            hash: row.get(8).unwrap(),
+        }
+    }
+}
+
+impl RepositoryItem for Entry {
+    fn masked_columns(_: bool) -> Vec<String> {
+        vec![]
+    }
+
+    fn saved_columns() -> Vec<String> {
+        vec![
+            "faery_id".to_string(),
+            "amount".to_string(),
+            "balance".to_string(),
+            "kind".to_string(),
+            "memo".to_string(),
+            "created_at".to_string(),
+            "prev_hash".to_string(),
+            "hash".to_string(),
+        ]
+    }
+
+    fn all_columns() -> Vec<String> {
+        vec![
+            "id".to_string(),
+            "faery_id".to_string(),
+            "amount".to_string(),
+            "balance".to_string(),
+            "kind".to_string(),
+            "memo".to_string(),
+            "created_at".to_string(),
+            "prev_hash".to_string(),
+            "hash".to_string(),
+        ]
+    }
+
+    fn table_name() -> String where Self: Sized {
+        "ledger".to_string()
+    }
+}
+
+// LedgerSummary holds the running totals used to evaluate achievements and statistics.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LedgerSummary {
+    pub earned: i64,
+    pub spent: i64,
+    pub balance: i64,
+    pub transactions: i64,
+}
+
+// LeaderboardEntry is a faery's position on one of the economy leaderboards.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeaderboardEntry {
+    pub faery_id: i64,
+    pub name: String,
+    pub amount: i64,
+}
+
+impl LeaderboardEntry {
+    pub fn from_response(row: &Row) -> RepositoryResult<LeaderboardEntry> {
+        Ok(LeaderboardEntry {
+            faery_id: row.get(0)?,
+            name: row.get(1)?,
+            amount: row.get(2)?,
+        })
+    }
+}
+
+// ChainVerification is the result of walking the ledger's hash chain from the first entry.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChainVerification {
+    pub valid: bool,
+    pub entries: usize,
+    pub head_hash: String,
+    // The first entry that doesn't match, if any
+    pub broken_at: Option<i64>,
+    pub reason: Option<String>,
+}
+
+// LedgerHead is the hash of the last entry and how many entries there are, kept apart from the
+// entries themselves. The chain alone can't show that entries were cut off its end; the head can.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LedgerHead {
+    pub head_hash: String,
+    pub entries: usize,
+}
+
+impl Default for LedgerHead {
+    fn default() -> Self {
+        LedgerHead { head_hash: GENESIS_HASH.to_string(), entries: 0 }
+    }
+}
+
+// verify_chain checks that each entry links to the one before it, that its hash matches its
+// contents, and that the chain ends at the recorded head.
+pub fn verify_chain(entries: &[Entry], head: &LedgerHead) -> ChainVerification {
+    let mut prev_hash = GENESIS_HASH.to_string();
+    for (index, entry) in entries.iter().enumerate() {
+        let reason = if entry.prev_hash != prev_hash {
+            Some("entry does not link to the previous entry; an entry was removed or reordered")
+        } else if entry.compute_hash(&prev_hash) != entry.hash {
+            Some("entry contents do not match its hash; the entry was altered")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            return ChainVerification {
+                valid: false,
+                entries: index,
+                head_hash: prev_hash,
+                broken_at: entry.id,
+                reason: Some(reason.to_string()),
+            };
+        }
+        prev_hash = entry.hash.clone();
+    }
+    let reason = (prev_hash != head.head_hash || entries.len() != head.entries)
+        .then(|| "chain does not end at the recorded head; entries were removed from the end".to_string());
+    ChainVerification {
+        valid: reason.is_none(),
+        entries: entries.len(),
+        head_hash: prev_hash,
+        broken_at: None,
+        reason,
+    }
+}
+
+pub struct LedgerRepository {
+    db: Arc<Mutex<Connection>>,
+}
+
+impl LedgerRepository {
+    pub fn new(db: Arc<Mutex<Connection>>) -> LedgerRepository {
+        LedgerRepository {
+            db,
+        }
+    }
+
+    // chain returns every entry along with the recorded head, read together so an entry appended
+    // in between can't look like tampering.
+    pub async fn chain(&self) -> RepositoryResult<(Vec<Entry>, LedgerHead)> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM ledger ORDER BY id", ()).await?;
+        let mut entries = Vec::new();
+        while let Some(row) = res.next()? {
+            entries.push(Entry::from_response(&row));
+        }
+        let mut res = db.query("SELECT head_hash, entries FROM ledger_head WHERE id = 0", ()).await?;
+        let head = match res.next()? {
+            Some(row) => LedgerHead { head_hash: row.get(0)?, entries: row.get::<i64>(1)? as usize },
+            None => LedgerHead::default(),
+        };
+        Ok((entries, head))
+    }
+
+    // adjust changes a faery's dross and records the change in one transaction, returning the entry
+    // with its id and the faery's new balance.
+    pub async fn adjust(&self, entry: Entry) -> RepositoryResult<Entry> {
+        let db = self.db.lock().await;
+        db.execute("BEGIN", ()).await?;
+        let result = adjust(&db, entry).await;
+        finish_transaction(&db, result).await
+    }
+
+    // adjust_all applies several entries in one transaction, so either every balance changes or none does.
+    pub async fn adjust_all(&self, entries: Vec<Entry>) -> RepositoryResult<Vec<Entry>> {
+        let db = self.db.lock().await;
+        db.execute("BEGIN", ()).await?;
+        let result = async {
+            let mut adjusted = Vec::new();
+            for entry in entries {
+                adjusted.push(adjust(&db, entry).await?);
+            }
+            Ok(adjusted)
+        }.await;
+        finish_transaction(&db, result).await
+    }
+
+    // get_for_faery includes the history of any faeries that were merged into this one. The
+    // transfers that moved their balances are left out, since that history already adds up to it.
+    pub async fn get_for_faery(&self, faery_id: i64) -> RepositoryResult<Vec<Entry>> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            r#"SELECT * FROM ledger
+WHERE (faery_id = ?1 OR faery_id IN (SELECT source_id FROM faery_redirects WHERE target_id = ?1))
+AND id NOT IN (
+    SELECT debit_entry_id FROM faery_redirects WHERE target_id = ?1 AND debit_entry_id IS NOT NULL
+    UNION SELECT credit_entry_id FROM faery_redirects WHERE target_id = ?1 AND credit_entry_id IS NOT NULL
+)
+ORDER BY id"#,
+            [faery_id]).await?;
+        let mut entries = Vec::new();
+        while let Some(row) = res.next()? {
+            entries.push(Entry::from_response(&row));
+        }
+        Ok(entries)
+    }
+
+    pub async fn summary(&self, faery_id: i64) -> RepositoryResult<LedgerSummary> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            r#"SELECT
+    COALESCE(SUM(CASE WHEN amount > 0 THEN amount ELSE 0 END), 0),
+    COALESCE(SUM(CASE WHEN amount < 0 THEN -amount ELSE 0 END), 0),
+    COALESCE(SUM(amount), 0),
+    COUNT(*)
+FROM ledger WHERE faery_id = ?1"#, [faery_id]).await?;
+        match res.next()? {
+            Some(row) => Ok(LedgerSummary {
+                earned: row.get(0)?,
+                spent: row.get(1)?,
+                balance: row.get(2)?,
+                transactions: row.get(3)?,
+            }),
+            None => Ok(LedgerSummary::default()),
+        }
+    }
+
+    // entries_between returns a faery's own entries from `since` up to, but not including, `until`.
+    pub async fn entries_between(&self, faery_id: i64, since: i64, until: i64) -> RepositoryResult<Vec<Entry>> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            "SELECT * FROM ledger WHERE faery_id = ?1 AND created_at >= ?2 AND created_at < ?3 ORDER BY id",
+            [faery_id, since, until]).await?;
+        let mut entries = Vec::new();
+        while let Some(row) = res.next()? {
+            entries.push(Entry::from_response(&row));
+        }
+        Ok(entries)
+    }
+
+    // balance_at recomputes a faery's balance from every entry recorded up to and including `at`.
+    pub async fn balance_at(&self, faery_id: i64, at: i64) -> RepositoryResult<i64> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            "SELECT COALESCE(SUM(amount), 0) FROM ledger WHERE faery_id = ?1 AND created_at <= ?2",
+            [faery_id, at]).await?;
+        match res.next()? {
+            Some(row) => Ok(row.get(0)?),
+            None => Ok(0),
+        }
+    }
+
+    // reconcile reads every faery and the balance its ledger entries add up to under one lock, so no
+    // change can land between the two reads. The entries `corrections` returns for what was read are
+    // appended in the same transaction, so they're based on the balances as they still are.
+    pub async fn reconcile<F>(&self, corrections: F) -> RepositoryResult<(Vec<Model>, HashMap<i64, i64>, Vec<Entry>)>
+    where F: FnOnce(&[Model], &HashMap<i64, i64>) -> Vec<Entry> {
+        let db = self.db.lock().await;
+        db.execute("BEGIN", ()).await?;
+        let result = async {
+            let mut res = db.query("SELECT * FROM faeries WHERE deleted_at IS NULL", ()).await?;
+            let mut faeries = Vec::new();
+            while let Some(row) = res.next()? {
+                faeries.push(Model::from_response(&row));
+            }
+            let mut res = db.query("SELECT faery_id, SUM(amount) FROM ledger GROUP BY faery_id", ()).await?;
+            let mut computed = HashMap::new();
+            while let Some(row) = res.next()? {
+                computed.insert(row.get(0)?, row.get(1)?);
+            }
+            let mut recorded = Vec::new();
+            for entry in corrections(&faeries, &computed) {
+                let id = append(&db, entry.clone()).await?;
+                recorded.push(Entry { id: Some(id), ..entry });
+            }
+            Ok((faeries, computed, recorded))
+        }.await;
+        finish_transaction(&db, result).await
+    }
+
+    // volume_since returns the total dross moved since the given time, ignoring opening balances.
+    pub async fn volume_since(&self, since: i64, group: Option<i64>) -> RepositoryResult<i64> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            r#"SELECT COALESCE(SUM(ABS(amount)), 0) FROM ledger WHERE kind != 'opening' AND created_at >= ?1
+AND (?2 IS NULL OR faery_id IN (SELECT faery_id FROM group_members WHERE group_id = ?2))"#,
+            params![since, group]).await?;
+        match res.next()? {
+            Some(row) => Ok(row.get(0)?),
+            None => Ok(0),
+        }
+    }
+
+    // top_earners ranks faeries by dross received since the given time.
+    pub async fn top_earners(&self, since: i64, limit: i64, group: Option<i64>) -> RepositoryResult<Vec<LeaderboardEntry>> {
+        self.leaderboard("amount > 0", "SUM(l.amount)", since, limit, group).await
+    }
+
+    // top_spenders ranks faeries by dross spent since the given time.
+    pub async fn top_spenders(&self, since: i64, limit: i64, group: Option<i64>) -> RepositoryResult<Vec<LeaderboardEntry>> {
+        self.leaderboard("amount < 0", "-SUM(l.amount)", since, limit, group).await
+    }
+
+    async fn leaderboard(&self, filter: &str, total: &str, since: i64, limit: i64, group: Option<i64>) -> RepositoryResult<Vec<LeaderboardEntry>> {
+        let db = self.db.lock().await;
+        let query = format!(
+            r#"SELECT f.id, f.name, {total} AS total
+FROM ledger l JOIN faeries f ON f.id = l.faery_id
+WHERE l.{filter} AND l.kind != 'opening' AND l.created_at >= ?1 AND f.leaderboard_opt_out = 0 AND f.deleted_at IS NULL
+AND (?3 IS NULL OR f.id IN (SELECT faery_id FROM group_members WHERE group_id = ?3))
+GROUP BY f.id, f.name ORDER BY total DESC LIMIT ?2"#
+        );
+        let mut res = db.query(&query, params![since, limit, group]).await?;
+        let mut entries = Vec::new();
+        while let Some(row) = res.next()? {
+            entries.push(LeaderboardEntry::from_response(&row)?);
+        }
+        Ok(entries)
+    }
+
+    // open_balances seeds the ledger for faeries that held dross before the ledger existed,
+    // so every stored balance can be traced back to at least one entry.
+    pub async fn open_balances(&self) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            "SELECT id, dross FROM faeries WHERE dross > 0 AND id NOT IN (SELECT faery_id FROM ledger)",
+            ()).await?;
+        let mut openings = Vec::new();
+        while let Some(row) = res.next()? {
+            let faery_id: i64 = row.get(0)?;
+            let dross: i64 = row.get(1)?;
+            openings.push(Entry::new(faery_id, dross, dross, EntryKind::Opening, "Opening balance".to_string()));
+        }
+        db.execute("BEGIN", ()).await?;
+        let result = async {
+            for entry in &openings {
+                append(&db, entry.clone()).await?;
+            }
+            Ok(())
+        }.await;
+        finish_transaction(&db, result).await?;
+        log::info!("Opened ledger balances for {} faeries", openings.len());
+        Ok(())
+    }
+}
+
+// append seals an entry onto the end of the hash chain, inserts it and moves the recorded head.
+// The caller must hold the connection lock for the whole call, inside a transaction, so no other
+// entry can slip in between and the head never disagrees with the chain.
+pub(crate) async fn append(db: &Connection, entry: Entry) -> RepositoryResult<i64> {
+    let mut res = db.query("SELECT head_hash FROM ledger_head WHERE id = 0", ()).await?;
+    let prev_hash: String = match res.next()? {
+        Some(row) => row.get(0)?,
+        None => GENESIS_HASH.to_string(),
+    };
+    let entry = entry.chained(&prev_hash);
+    let result = db.execute(
+        "INSERT INTO ledger (faery_id, amount, balance, kind, memo, created_at, prev_hash, hash) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![entry.faery_id, entry.amount, entry.balance, entry.kind.as_str(), entry.memo, entry.created_at, entry.prev_hash, entry.hash.clone()]
+    ).await;
+    let id = match result {
+        Ok(_) => db.last_insert_rowid(),
+        Err(err) => {
+            log::error!("Error recording ledger entry: {:?}", err);
+            return Err(RepositoryError::Other);
+        },
+    };
+    db.execute(
+        r#"INSERT INTO ledger_head (id, head_hash, entries) VALUES (0, ?1, 1)
+ON CONFLICT (id) DO UPDATE SET head_hash = excluded.head_hash, entries = entries + 1"#,
+        [entry.hash]
+    ).await?;
+    Ok(id)
+}
+
+// adjust applies an entry's amount to its faery's dross and appends the entry with the new balance.
+// The update only goes through if the balance stays within what a u32 holds, so an overdraw or
+// overflow leaves the faery untouched. Like append, the caller must hold the lock, inside a transaction.
+pub(crate) async fn adjust(db: &Connection, entry: Entry) -> RepositoryResult<Entry> {
+    if entry.id.is_some() || entry.amount == 0 {
+        return Err(RepositoryError::InvalidModel);
+    }
+    let updated = db.execute(
+        r#"UPDATE faeries SET dross = COALESCE(dross, 0) + ?1
+WHERE id = ?2 AND deleted_at IS NULL AND COALESCE(dross, 0) + ?1 BETWEEN 0 AND 4294967295"#,
+        params![entry.amount, entry.faery_id]
+    ).await?;
+    let mut res = db.query("SELECT COALESCE(dross, 0) FROM faeries WHERE id = ?1 AND deleted_at IS NULL", [entry.faery_id]).await?;
+    let balance: i64 = match res.next()? {
+        Some(row) => row.get(0)?,
+        None => return Err(RepositoryError::NotFound),
+    };
+    if updated == 0 {
+        return Err(RepositoryError::InvalidModel);
+    }
+    let entry = Entry { balance, ..entry };
+    let id = append(db, entry.clone()).await?;
+    Ok(Entry { id: Some(id), ..entry })
+}
+
+#[shuttle_runtime::async_trait]
+impl Repository for LedgerRepository {
+    type Item = Entry;
+    type RowIdentifier = i64;
+
+    // The ledger is append-only; existing entries are never rewritten.
+    async fn save(&self, entry: Entry) -> RepositoryResult<i64> {
+        if entry.id.is_some() {
+            return Err(RepositoryError::InvalidModel);
+        }
+        let db = self.db.lock().await;
+        db.execute("BEGIN", ()).await?;
+        let result = append(&db, entry).await;
+        finish_transaction(&db, result).await
+    }
+
+    async fn get(&self, id: i64) -> RepositoryResult<Entry> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM ledger WHERE id = ?1", [id]).await?;
+        match res.next()? {
+            Some(row) => Ok(Entry::from_response(&row)),
+            None => Err(RepositoryError::NotFound),
+        }
+    }
+
+    async fn get_all(&self) -> RepositoryResult<Vec<Entry>> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM ledger ORDER BY id", ()).await?;
+        let mut entries = Vec::new();
+        while let Some(row) = res.next()? {
+            entries.push(Entry::from_response(&row));
+        }
+        Ok(entries)
+    }
+
+    async fn delete(&self, _: i64) -> RepositoryResult<()> {
+        // Corrections are made with a reversal or adjustment entry instead
+        Err(RepositoryError::InvalidModel)
+    }
+
+    async fn create_table(&self) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        let mut stmts = vec![];
+        stmts.push("BEGIN".to_string());
+        stmts.push("CREATE TABLE IF NOT EXISTS ledger (
+            id INTEGER PRIMARY KEY,
+            faery_id INTEGER NOT NULL,
+            amount INTEGER NOT NULL,
+            balance INTEGER NOT NULL,
+            kind TEXT NOT NULL,
+            memo TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            prev_hash TEXT NOT NULL,
+            hash TEXT NOT NULL
+        )".to_string());
+        stmts.push("CREATE INDEX IF NOT EXISTS ledger_faery_idx ON ledger (faery_id, created_at)".to_string());
+        stmts.push("CREATE TABLE IF NOT EXISTS ledger_head (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            head_hash TEXT NOT NULL,
+            entries INTEGER NOT NULL
+        )".to_string());
+        // A ledger that predates the head starts from wherever its chain ends now
+        stmts.push(format!(
+            "INSERT OR IGNORE INTO ledger_head (id, head_hash, entries) SELECT 0, COALESCE((SELECT hash FROM ledger ORDER BY id DESC LIMIT 1), '{}'), COUNT(*) FROM ledger",
+            GENESIS_HASH
+        ));
+        stmts.push("COMMIT".to_string());
+
+        let stmts = stmts.join(";");
+        match db.execute_batch(&stmts).await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other)
+        }
+    }
+
+    async fn drop_table(&self) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        match db.execute_batch("DROP TABLE IF EXISTS ledger_head;DROP TABLE IF EXISTS ledger").await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use crate::repository::{Repository, RepositoryError};
+    use crate::repository::faery::{FaeryRepository, Model};
+    use super::{verify_chain, Entry, EntryKind, LedgerHead, LedgerRepository, GENESIS_HASH};
+
+    fn chain() -> Vec<Entry> {
+        let mut prev_hash = GENESIS_HASH.to_string();
+        let mut entries = Vec::new();
+        for (id, amount) in [(1, 10), (2, -4), (3, 6)] {
+            let mut entry = Entry::new(1, amount, 0, EntryKind::Grant, "Test".to_string()).chained(&prev_hash);
+            entry.id = Some(id);
+            prev_hash = entry.hash.clone();
+            entries.push(entry);
+        }
+        entries
+    }
+
+    fn head(entries: &[Entry]) -> LedgerHead {
+        LedgerHead { head_hash: entries[entries.len() - 1].hash.clone(), entries: entries.len() }
+    }
+
+    #[test]
+    fn test_verify_intact_chain() {
+        let entries = chain();
+        let verification = verify_chain(&entries, &head(&entries));
+        assert!(verification.valid);
+        assert_eq!(verification.entries, 3);
+        assert_eq!(verification.head_hash, entries[2].hash);
+    }
+
+    #[test]
+    fn test_verify_altered_entry() {
+        let mut entries = chain();
+        let head = head(&entries);
+        entries[1].amount = -1;
+        let verification = verify_chain(&entries, &head);
+        assert!(!verification.valid);
+        assert_eq!(verification.broken_at, Some(2));
+    }
+
+    #[test]
+    fn test_verify_removed_entry() {
+        let mut entries = chain();
+        let head = head(&entries);
+        entries.remove(1);
+        let verification = verify_chain(&entries, &head);
+        assert!(!verification.valid);
+        assert_eq!(verification.broken_at, Some(3));
+    }
+
+    #[test]
+    fn test_verify_truncated_chain() {
+        let mut entries = chain();
+        let head = head(&entries);
+        entries.pop();
+        let verification = verify_chain(&entries, &head);
+        assert!(!verification.valid);
+        assert_eq!((verification.entries, verification.broken_at), (2, None));
+        assert!(!verify_chain(&[], &head).valid);
+    }
+
+    #[tokio::test]
+    async fn test_adjust() {
+        let db = Arc::new(Mutex::new(libsql::Database::open_in_memory().unwrap().connect().unwrap()));
+        let repository = LedgerRepository::new(db.clone());
+        let faeries = FaeryRepository::new(db);
+        repository.create_table().await.unwrap();
+        faeries.create_table().await.unwrap();
+        let faery_id = faeries.create(Some(Model::new("Tink".to_string(), "wendy@example.com".to_string(), false, 5, None))).await.unwrap();
+
+        let entry = repository.adjust(Entry::new(faery_id, -3, 0, EntryKind::Spend, "Thimbles".to_string())).await.unwrap();
+        assert_eq!((entry.id, entry.balance), (Some(1), 2));
+        assert_eq!(faeries.get(faery_id).await.unwrap().dross, 2);
+
+        // An overdraw or overflow leaves both the balance and the ledger alone
+        for amount in [-3, u32::MAX as i64] {
+            let result = repository.adjust(Entry::new(faery_id, amount, 0, EntryKind::Grant, "Too much".to_string())).await;
+            assert!(matches!(result, Err(RepositoryError::InvalidModel)));
+        }
+        assert_eq!(faeries.get(faery_id).await.unwrap().dross, 2);
+        assert_eq!(repository.get_all().await.unwrap().len(), 1);
+        let result = repository.adjust(Entry::new(faery_id + 1, 1, 0, EntryKind::Grant, "Nobody".to_string())).await;
+        assert!(matches!(result, Err(RepositoryError::NotFound)));
+    }
+}
@@ -2,10 +2,20 @@ pub mod faery;
 pub mod email;
 pub mod player;
 pub mod session;
+pub mod ledger;
+pub mod achievement;
+pub mod snapshot;
+pub mod settings;
+pub mod group;
+pub mod attribute;
+pub mod template;
+pub mod preference;
+pub mod broadcast;
+pub mod notification;
 
 use serde::Serialize;
 use semver::Version;
-use libsql::Error as LibSqlError;
+use libsql::{Connection, Error as LibSqlError};
 use axum::extract::rejection::JsonRejection;
 
 // TODO: move
@@ -32,10 +42,68 @@ impl From<LibSqlError> for RepositoryError {
 
 pub type RepositoryResult<T> = Result<T, RepositoryError>;
 
+// is_constraint_violation tells a UNIQUE/NOT NULL/CHECK failure apart from other database errors.
+pub fn is_constraint_violation(err: &LibSqlError) -> bool {
+    const SQLITE_CONSTRAINT: i32 = 19;
+    match err {
+        LibSqlError::SqliteFailure(code, _) => code & 0xff == SQLITE_CONSTRAINT,
+        LibSqlError::RemoteSqliteFailure(code, extended, _) => code & 0xff == SQLITE_CONSTRAINT || extended & 0xff == SQLITE_CONSTRAINT,
+        _ => false,
+    }
+}
+
+// finish_transaction commits a transaction started with a plain `BEGIN` if `result` is Ok, and
+// rolls it back otherwise. libsql's Transaction isn't Send, so it can't be held across an await
+// in a handler; every repository shares one connection behind a mutex, so BEGIN/COMMIT on it
+// while holding the lock is just as safe.
+pub async fn finish_transaction<T>(db: &Connection, result: RepositoryResult<T>) -> RepositoryResult<T> {
+    match result {
+        Ok(value) => {
+            db.execute("COMMIT", ()).await?;
+            Ok(value)
+        },
+        Err(err) => {
+            if let Err(rollback_err) = db.execute("ROLLBACK", ()).await {
+                log::error!("Error rolling back transaction: {:?}", rollback_err);
+            }
+            Err(err)
+        },
+    }
+}
+
+// mask serializes a response and strips every field the item type masks for the caller's role.
+// Nested objects and lists are masked too, so wrappers like FaeryResponse or Vec<Model> are covered.
+pub fn mask<I: RepositoryItem, T: Serialize>(value: &T, is_admin: bool) -> serde_json::Value {
+    let masked = I::masked_columns(is_admin);
+    let mut value = serde_json::to_value(value).unwrap_or(serde_json::Value::Null);
+    strip_columns(&mut value, &masked);
+    value
+}
+
+fn strip_columns(value: &mut serde_json::Value, masked: &[String]) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            fields.retain(|name, _| !masked.contains(name));
+            for field in fields.values_mut() {
+                strip_columns(field, masked);
+            }
+        },
+        serde_json::Value::Array(items) => {
+            for item in items.iter_mut() {
+                strip_columns(item, masked);
+            }
+        },
+        _ => {},
+    }
+}
+
 pub trait RepositoryItem {
     fn masked_columns(is_admin: bool) -> Vec<String>;
+    #[allow(dead_code)]
     fn saved_columns() -> Vec<String>;
+    #[allow(dead_code)]
     fn all_columns() -> Vec<String>;
+    #[allow(dead_code)]
     fn table_name() -> String where Self: Sized;
 }
 
@@ -56,7 +124,9 @@ pub trait Repository: Sized + Send + Sync {
     async fn get_all(&self) -> RepositoryResult<Vec<Self::Item>>;
     async fn delete(&self, id: Self::RowIdentifier) -> RepositoryResult<()>;
     async fn create_table(&self) -> RepositoryResult<()>;
+    #[allow(dead_code)]
     async fn drop_table(&self) -> RepositoryResult<()>;
+    #[allow(dead_code)]
     fn table_name() -> String {
         Self::Item::table_name()
     }
@@ -74,6 +144,8 @@ impl RepositoryRowIdentifier for i64 {}
 
 impl RepositoryRowIdentifier for () {}
 
+impl RepositoryRowIdentifier for String {}
+
 impl From<JsonRejection> for RepositoryError {
     fn from(err: JsonRejection) -> Self {
         match err {
@@ -83,3 +155,72 @@ impl From<JsonRejection> for RepositoryError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::mask;
+    use crate::repository::faery::{FaeryResponse, Model as Faery};
+    use crate::repository::player::{Model as Player, PlayerResponse};
+
+    fn faery() -> Faery {
+        Faery::new("Tinkerbell".to_string(), "me@example.com".to_string(), false, 0, Some(1))
+    }
+
+    fn player() -> Player {
+        let mut player = Player::new(
+            Some(1),
+            "Wendy".to_string(),
+            "Darling".to_string(),
+            "wendy@example.com".to_string(),
+            Some("secret-token".to_string()),
+            Some(0),
+            "14 Kensington Gardens".to_string(),
+            false,
+        );
+        player.verification_token = Some("verify-token".to_string());
+        player
+    }
+
+    fn contains(value: &serde_json::Value, needle: &str) -> bool {
+        value.to_string().contains(needle)
+    }
+
+    #[test]
+    fn test_non_admin_never_sees_faery_email() {
+        let single = mask::<Faery, _>(&faery(), false);
+        let list = mask::<Faery, _>(&vec![faery(), faery()], false);
+        let detail = mask::<Faery, _>(&FaeryResponse { faery: faery(), badges: vec![], attributes: Default::default() }, false);
+        for value in [single, list, detail] {
+            assert!(!contains(&value, "me@example.com"));
+            assert!(contains(&value, "Tinkerbell"));
+        }
+    }
+
+    #[test]
+    fn test_admin_sees_faery_email() {
+        assert!(contains(&mask::<Faery, _>(&faery(), true), "me@example.com"));
+    }
+
+    #[test]
+    fn test_non_admin_never_sees_player_private_fields() {
+        for value in [
+            mask::<Player, _>(&player(), false),
+            mask::<Player, _>(&vec![PlayerResponse::from(player())], false),
+        ] {
+            assert!(!contains(&value, "wendy@example.com"));
+            assert!(!contains(&value, "Kensington"));
+            assert!(!contains(&value, "secret-token"));
+            assert!(!contains(&value, "verify-token"));
+            assert!(contains(&value, "Wendy"));
+        }
+    }
+
+    #[test]
+    fn test_admin_never_sees_player_tokens() {
+        let value = mask::<Player, _>(&player(), true);
+        assert!(contains(&value, "wendy@example.com"));
+        assert!(contains(&value, "Kensington"));
+        assert!(!contains(&value, "secret-token"));
+        assert!(value.get("auth_token_expires").is_none());
+    }
+}
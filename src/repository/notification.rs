@@ -0,0 +1,267 @@
+use std::sync::Arc;
+use chrono::Utc;
+use libsql::{Connection, params, Row};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use crate::repository::{Repository, RepositoryError, RepositoryItem, RepositoryResult};
+use crate::repository::preference::NotificationCategory;
+
+// Notification is the in-app copy of something we emailed, or would have emailed, a player.
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub id: Option<i64>,
+    pub player_id: i64,
+    pub category: NotificationCategory,
+    pub subject: String,
+    pub text: String,
+    pub read_at: Option<i64>,
+    pub created_at: i64,
+}
+
+impl Notification {
+    pub fn new(player_id: i64, category: NotificationCategory, subject: &str, text: &str) -> Notification {
+        Notification {
+            id: None,
+            player_id,
+            category,
+            subject: subject.to_string(),
+            text: text.to_string(),
+            read_at: None,
+            created_at: Utc::now().timestamp_millis(),
+        }
+    }
+
+    pub fn from_response(row: &Row) -> RepositoryResult<Notification> {
+        Ok(Notification {
+            id: row.get(0)?,
+            player_id: row.get(1)?,
+            category: row.get::<String>(2)?.into(),
+            subject: row.get(3)?,
+            text: row.get(4)?,
+            read_at: row.get(5)?,
+            created_at: row.get(6)?,
+        })
+    }
+}
+
+impl RepositoryItem for Notification {
+    fn masked_columns(_: bool) -> Vec<String> {
+        vec![]
+    }
+
+    fn saved_columns() -> Vec<String> {
+        vec![
+            "player_id".to_string(),
+            "category".to_string(),
+            "subject".to_string(),
+            "text".to_string(),
+            "created_at".to_string(),
+        ]
+    }
+
+    fn all_columns() -> Vec<String> {
+        vec![
+            "id".to_string(),
+            "player_id".to_string(),
+            "category".to_string(),
+            "subject".to_string(),
+            "text".to_string(),
+            "read_at".to_string(),
+            "created_at".to_string(),
+        ]
+    }
+
+    fn table_name() -> String where Self: Sized {
+        "notifications".to_string()
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct NotificationQuery {
+    #[serde(default)]
+    pub unread: bool,
+    pub limit: Option<i64>,
+    // Only notifications older than this ID, for paging back
+    pub before: Option<i64>,
+}
+
+// NotificationPage is a page of a player's notifications, newest first.
+#[derive(Debug, Clone, Serialize)]
+pub struct NotificationPage {
+    pub notifications: Vec<Notification>,
+    pub unread: i64,
+}
+
+pub struct NotificationRepository {
+    db: Arc<Mutex<Connection>>,
+}
+
+impl NotificationRepository {
+    pub const DEFAULT_LIMIT: i64 = 50;
+
+    pub fn new(db: Arc<Mutex<Connection>>) -> NotificationRepository {
+        NotificationRepository {
+            db,
+        }
+    }
+
+    pub async fn for_player(&self, player_id: i64, query: &NotificationQuery) -> RepositoryResult<NotificationPage> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            r#"SELECT * FROM notifications WHERE player_id = ?1 AND (NOT ?2 OR read_at IS NULL) AND (?3 IS NULL OR id < ?3)
+ORDER BY id DESC LIMIT ?4"#,
+            params![player_id, query.unread, query.before, query.limit.unwrap_or(Self::DEFAULT_LIMIT).clamp(1, 200)]).await?;
+        let mut notifications = Vec::new();
+        while let Some(row) = res.next()? {
+            notifications.push(Notification::from_response(&row)?);
+        }
+        let unread = match db.query("SELECT COUNT(*) FROM notifications WHERE player_id = ?1 AND read_at IS NULL", [player_id]).await?.next()? {
+            Some(row) => row.get(0)?,
+            None => 0,
+        };
+        Ok(NotificationPage { notifications, unread })
+    }
+
+    pub async fn mark_read(&self, player_id: i64, id: i64) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        match db.execute(
+            "UPDATE notifications SET read_at = COALESCE(read_at, ?3) WHERE id = ?1 AND player_id = ?2",
+            params![id, player_id, Utc::now().timestamp_millis()]).await? {
+            0 => Err(RepositoryError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    // mark_all_read returns how many notifications were unread.
+    pub async fn mark_all_read(&self, player_id: i64) -> RepositoryResult<u64> {
+        let db = self.db.lock().await;
+        let updated = db.execute(
+            "UPDATE notifications SET read_at = ?2 WHERE player_id = ?1 AND read_at IS NULL",
+            params![player_id, Utc::now().timestamp_millis()]).await?;
+        Ok(updated)
+    }
+
+    // prune deletes every notification created before `before`, read or not.
+    pub async fn prune(&self, before: i64) -> RepositoryResult<u64> {
+        let db = self.db.lock().await;
+        let deleted = db.execute("DELETE FROM notifications WHERE created_at < ?1", [before]).await?;
+        Ok(deleted)
+    }
+}
+
+#[shuttle_runtime::async_trait]
+impl Repository for NotificationRepository {
+    type Item = Notification;
+    type RowIdentifier = i64;
+
+    // save adds a notification. Notifications only change by being read.
+    async fn save(&self, notification: Notification) -> RepositoryResult<i64> {
+        if notification.id.is_some() {
+            return Err(RepositoryError::InvalidModel);
+        }
+        let db = self.db.lock().await;
+        db.execute(
+            "INSERT INTO notifications (player_id, category, subject, text, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                notification.player_id,
+                notification.category.as_str(),
+                notification.subject,
+                notification.text,
+                notification.created_at
+            ]).await?;
+        Ok(db.last_insert_rowid())
+    }
+
+    async fn get(&self, id: i64) -> RepositoryResult<Notification> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM notifications WHERE id = ?1", [id]).await?;
+        match res.next()? {
+            Some(row) => Notification::from_response(&row),
+            None => Err(RepositoryError::NotFound),
+        }
+    }
+
+    async fn get_all(&self) -> RepositoryResult<Vec<Notification>> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM notifications ORDER BY id DESC", ()).await?;
+        let mut notifications = Vec::new();
+        while let Some(row) = res.next()? {
+            notifications.push(Notification::from_response(&row)?);
+        }
+        Ok(notifications)
+    }
+
+    async fn delete(&self, id: i64) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        match db.execute("DELETE FROM notifications WHERE id = ?1", [id]).await? {
+            0 => Err(RepositoryError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    async fn create_table(&self) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        let stmts = [
+            "BEGIN".to_string(),
+            "CREATE TABLE IF NOT EXISTS notifications (
+                id INTEGER PRIMARY KEY,
+                player_id INTEGER NOT NULL,
+                category TEXT NOT NULL,
+                subject TEXT NOT NULL,
+                text TEXT NOT NULL,
+                read_at INTEGER,
+                created_at INTEGER NOT NULL
+            )".to_string(),
+            "CREATE INDEX IF NOT EXISTS notifications_player_idx ON notifications (player_id, id)".to_string(),
+            "COMMIT".to_string(),
+        ];
+
+        let stmts = stmts.join(";");
+        match db.execute_batch(&stmts).await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other)
+        }
+    }
+
+    async fn drop_table(&self) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        match db.execute_batch("DROP TABLE IF EXISTS notifications").await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use crate::repository::Repository;
+    use crate::repository::preference::NotificationCategory;
+    use super::{Notification, NotificationQuery, NotificationRepository};
+
+    #[tokio::test]
+    async fn test_notifications_are_read_and_pruned() {
+        let db = libsql::Database::open_in_memory().unwrap().connect().unwrap();
+        let repository = NotificationRepository::new(Arc::new(Mutex::new(db)));
+        repository.create_table().await.unwrap();
+
+        let first = repository.save(Notification::new(1, NotificationCategory::Transactions, "Receipt", "+5 dross")).await.unwrap();
+        repository.save(Notification::new(1, NotificationCategory::Broadcasts, "Moon festival", "Tonight")).await.unwrap();
+        repository.save(Notification { created_at: 0, ..Notification::new(2, NotificationCategory::Events, "Old", "News") }).await.unwrap();
+
+        let page = repository.for_player(1, &NotificationQuery::default()).await.unwrap();
+        assert_eq!(page.unread, 2);
+        assert_eq!(page.notifications[0].subject, "Moon festival");
+
+        repository.mark_read(1, first).await.unwrap();
+        assert!(repository.mark_read(2, first).await.is_err());
+        let unread = repository.for_player(1, &NotificationQuery { unread: true, ..NotificationQuery::default() }).await.unwrap();
+        assert_eq!(unread.unread, 1);
+        assert_eq!(unread.notifications.len(), 1);
+        assert_eq!(repository.mark_all_read(1).await.unwrap(), 1);
+
+        assert_eq!(repository.prune(1).await.unwrap(), 1);
+        assert!(repository.for_player(2, &NotificationQuery::default()).await.unwrap().notifications.is_empty());
+    }
+}
@@ -3,7 +3,8 @@ use chrono::{Duration, Utc};
 use libsql::{Connection, params};
 use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
-use crate::repository::{Repository, RepositoryError, RepositoryItem, RepositoryResult};
+use crate::repository::email::SuppressionReason;
+use crate::repository::{finish_transaction, is_constraint_violation, Repository, RepositoryError, RepositoryItem, RepositoryResult};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Model {
@@ -15,6 +16,52 @@ pub struct Model {
     pub auth_token_expires: Option<i64>,
     pub mailing_address: String,
     pub is_admin: bool,
+    #[serde(default)]
+    pub status: PlayerStatus,
+    #[serde(default, skip_serializing)]
+    pub verification_token: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub verification_expires: Option<i64>,
+    // Set when the player is archived; archived players can't log in and are hidden from lists
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<i64>,
+    // A new address the player asked for; it replaces auth_email once they follow the verification link
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_email: Option<String>,
+    // Why mail to auth_email is suppressed, if it is; kept in step with email_suppressions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_suppressed: Option<SuppressionReason>,
+}
+
+// PlayerStatus tracks a player through self-service registration.
+// Only active players can log in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlayerStatus {
+    PendingVerification,
+    PendingApproval,
+    #[default]
+    Active,
+}
+
+impl PlayerStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PlayerStatus::PendingVerification => "pending_verification",
+            PlayerStatus::PendingApproval => "pending_approval",
+            PlayerStatus::Active => "active",
+        }
+    }
+}
+
+impl From<String> for PlayerStatus {
+    fn from(status: String) -> Self {
+        match status.as_str() {
+            "pending_verification" => PlayerStatus::PendingVerification,
+            "pending_approval" => PlayerStatus::PendingApproval,
+            _ => PlayerStatus::Active,
+        }
+    }
 }
 
 struct TokenData {
@@ -34,6 +81,7 @@ impl TokenData {
 }
 
 impl Model {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: Option<i64>,
         first_name: String,
@@ -53,10 +101,17 @@ impl Model {
             auth_token_expires,
             mailing_address,
             is_admin,
+            status: PlayerStatus::Active,
+            verification_token: None,
+            verification_expires: None,
+            deleted_at: None,
+            pending_email: None,
+            email_suppressed: None,
         }
     }
 
     pub fn from_response(row: &libsql::Row) -> Model {
+        let status: String = row.get(8).unwrap();
         Model {
             id: row.get(0).unwrap(),
             first_name: row.get(1).unwrap(),
@@ -66,7 +121,49 @@ impl Model {
             auth_token_expires: row.get(5).unwrap(),
             mailing_address: row.get(6).unwrap(),
             is_admin: row.get(7).unwrap(),
+            status: status.into(),
+            verification_token: row.get(9).unwrap(),
+            verification_expires: row.get(10).unwrap(),
+            deleted_at: row.get(11).unwrap_or(None),
+            pending_email: row.get(12).unwrap_or(None),
+            email_suppressed: row.get::<Option<String>>(13).unwrap_or(None).map(SuppressionReason::from),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.status == PlayerStatus::Active
+    }
+
+    // apply_profile copies the self-service fields from a request, leaving auth and admin state alone.
+    // A changed email only becomes pending; it's swapped in by verify_email.
+    pub fn apply_profile(&mut self, request: PlayerRequest) {
+        self.first_name = request.first_name;
+        self.last_name = request.last_name;
+        self.mailing_address = request.mailing_address;
+        self.pending_email = match request.auth_email.eq_ignore_ascii_case(&self.auth_email) {
+            true => None,
+            false => Some(request.auth_email),
+        };
+    }
+
+    // verify_email moves a pending address into auth_email. Login tokens sent to the old
+    // address stop working.
+    pub fn verify_email(&mut self) {
+        if let Some(email) = self.pending_email.take() {
+            self.auth_email = email;
+            self.auth_token = None;
+            self.auth_token_expires = None;
         }
+        self.verification_token = None;
+        self.verification_expires = None;
+    }
+
+    // start_verification issues a fresh verification token for the link emailed to the player.
+    pub fn start_verification(&mut self) -> String {
+        let token = self.new_hash();
+        self.verification_token = Some(token.clone());
+        self.verification_expires = Some((Utc::now() + Duration::hours(48)).timestamp_millis());
+        token
     }
 
     pub fn new_hash(&self) -> String {
@@ -77,8 +174,20 @@ impl Model {
 
 impl RepositoryItem for Model {
     fn masked_columns(is_admin: bool) -> Vec<String> {
-        // TODO: Implement masking with responses
-        vec![]
+        // Login and verification tokens are never returned, not even to admins
+        let mut columns = vec![
+            "auth_token".to_string(),
+            "auth_token_expires".to_string(),
+            "verification_token".to_string(),
+            "verification_expires".to_string(),
+        ];
+        if !is_admin {
+            columns.push("auth_email".to_string());
+            columns.push("mailing_address".to_string());
+            columns.push("pending_email".to_string());
+            columns.push("email_suppressed".to_string());
+        }
+        columns
     }
 
     fn saved_columns() -> Vec<String> {
@@ -88,7 +197,8 @@ impl RepositoryItem for Model {
             "last_name".to_string(),
             "auth_email".to_string(),
             "mailing_address".to_string(),
-            "is_admin".to_string()
+            "is_admin".to_string(),
+            "status".to_string()
         ]
     }
 
@@ -101,7 +211,13 @@ impl RepositoryItem for Model {
             "auth_token".to_string(),
             "auth_token_expires".to_string(),
             "mailing_address".to_string(),
-            "is_admin".to_string()
+            "is_admin".to_string(),
+            "status".to_string(),
+            "verification_token".to_string(),
+            "verification_expires".to_string(),
+            "deleted_at".to_string(),
+            "pending_email".to_string(),
+            "email_suppressed".to_string()
         ]
     }
 
@@ -118,6 +234,14 @@ pub struct PlayerData {
     pub auth_email: String,
     pub mailing_address: String,
     pub is_admin: bool,
+    #[serde(default)]
+    pub status: PlayerStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_email: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_suppressed: Option<SuppressionReason>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -129,6 +253,7 @@ pub struct LoginResponse {
     pub is_admin: bool
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Serialize)]
 pub struct PlayerClaim {
     pub id: i64,
@@ -158,6 +283,10 @@ impl From<Model> for PlayerData {
             auth_email: model.auth_email,
             mailing_address: model.mailing_address,
             is_admin: model.is_admin,
+            status: model.status,
+            deleted_at: model.deleted_at,
+            pending_email: model.pending_email,
+            email_suppressed: model.email_suppressed,
         }
     }
 }
@@ -203,6 +332,12 @@ impl From<PlayerRequest> for Model {
             auth_token_expires: None,
             mailing_address: request.mailing_address,
             is_admin: false,
+            status: PlayerStatus::Active,
+            verification_token: None,
+            verification_expires: None,
+            deleted_at: None,
+            pending_email: None,
+            email_suppressed: None,
         }
     }
 }
@@ -218,6 +353,12 @@ impl From<PlayerUpdateRequest> for Model {
             auth_token_expires: None,
             mailing_address: request.mailing_address,
             is_admin: request.is_admin,
+            status: PlayerStatus::Active,
+            verification_token: None,
+            verification_expires: None,
+            deleted_at: None,
+            pending_email: None,
+            email_suppressed: None,
         }
     }
 
@@ -266,12 +407,12 @@ impl PlayerRepository {
     pub async fn login(&self, email: String, token: String) -> RepositoryResult<LoginResponse> {
         let db = self.db.lock().await;
         // this validates the email and token existing in the same row (valid login)
-        let mut stmt = db.prepare("SELECT * FROM players WHERE auth_email = ?1 AND auth_token = ?2").await.unwrap();
+        let mut stmt = db.prepare("SELECT * FROM players WHERE auth_email = ?1 AND auth_token = ?2 AND status = 'active' AND deleted_at IS NULL").await.unwrap();
         match stmt.query(params![email, token]).await?.next()? {
             Some(row) => {
                 let player = Model::from_response(&row);
                 // Ensure the token hasn't expired
-                return if validate_token_age(player.auth_token_expires.unwrap()) {
+                if validate_token_age(player.auth_token_expires.unwrap()) {
                     Ok(player.into())
                 } else {
                     // TODO: implement expired token error
@@ -282,9 +423,108 @@ impl PlayerRepository {
         }
     }
 
+    pub async fn find_by_email(&self, email: &str) -> RepositoryResult<Model> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM players WHERE auth_email = ?1 COLLATE NOCASE AND deleted_at IS NULL", [email]).await?;
+        match res.next()? {
+            Some(row) => Ok(Model::from_response(&row)),
+            None => Err(RepositoryError::NotFound),
+        }
+    }
+
+    // email_in_use checks every player, archived ones and addresses awaiting verification included,
+    // so an address can't end up on two accounts.
+    pub async fn email_in_use(&self, email: &str, except_id: Option<i64>) -> RepositoryResult<bool> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            "SELECT COUNT(*) FROM players WHERE (auth_email = ?1 COLLATE NOCASE OR pending_email = ?1 COLLATE NOCASE) AND id IS NOT ?2",
+            params![email, except_id]
+        ).await?;
+        match res.next()? {
+            Some(row) => Ok(row.get::<i64>(0)? > 0),
+            None => Err(RepositoryError::Other),
+        }
+    }
+
+    pub async fn find_by_verification_token(&self, token: &str) -> RepositoryResult<Model> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM players WHERE verification_token = ?1 AND deleted_at IS NULL", [token]).await?;
+        match res.next()? {
+            Some(row) => Ok(Model::from_response(&row)),
+            None => Err(RepositoryError::NotFound),
+        }
+    }
+
+    // archived lists players that have been deleted but not purged.
+    pub async fn archived(&self) -> RepositoryResult<Vec<Model>> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM players WHERE deleted_at IS NOT NULL ORDER BY deleted_at DESC", ()).await?;
+        let mut players = Vec::new();
+        while let Some(row) = res.next()? {
+            players.push(Model::from_response(&row));
+        }
+        Ok(players)
+    }
+
+    pub async fn restore(&self, id: i64) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        match db.execute("UPDATE players SET deleted_at = NULL WHERE id = ?1 AND deleted_at IS NOT NULL", [id]).await? {
+            0 => Err(RepositoryError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    // purge permanently removes an archived player along with their notifications and settings.
+    // Ids are never reused, so a token issued to them can't sign in as whoever is created next.
+    pub async fn purge(&self, id: i64) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        db.execute("BEGIN", ()).await?;
+        let result = async {
+            if db.execute("DELETE FROM players WHERE id = ?1 AND deleted_at IS NOT NULL", [id]).await? == 0 {
+                return Err(RepositoryError::NotFound);
+            }
+            db.execute("DELETE FROM notification_preferences WHERE player_id = ?1", [id]).await?;
+            db.execute("DELETE FROM held_notifications WHERE player_id = ?1", [id]).await?;
+            db.execute("DELETE FROM notifications WHERE player_id = ?1", [id]).await?;
+            Ok(())
+        }.await;
+        finish_transaction(&db, result).await
+    }
+
+    // erase anonymizes a player's personal details, along with the email on their faeries and in
+    // the email log, drops their queued and held mail and notifications, then archives them. Faeries and ledger
+    // entries stay, tied to a pseudonymous address.
+    pub async fn erase(&self, id: i64) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        let email: String = match db.query("SELECT auth_email FROM players WHERE id = ?1", [id]).await?.next()? {
+            Some(row) => row.get(0)?,
+            None => return Err(RepositoryError::NotFound),
+        };
+        let pseudonym = format!("erased-player-{}@invalid", id);
+        db.execute("BEGIN", ()).await?;
+        let result = async {
+            db.execute(
+                r#"UPDATE players SET first_name = 'Erased', last_name = ?1, auth_email = ?2, mailing_address = '',
+    auth_token = NULL, auth_token_expires = NULL, verification_token = NULL, verification_expires = NULL,
+    pending_email = NULL, email_suppressed = NULL, deleted_at = COALESCE(deleted_at, ?3)
+WHERE id = ?4"#,
+                params![format!("Player {}", id), pseudonym.clone(), Utc::now().timestamp_millis(), id]
+            ).await?;
+            db.execute("UPDATE faeries SET email = ?1 WHERE email = ?2 COLLATE NOCASE", params![pseudonym.clone(), email.clone()]).await?;
+            db.execute("UPDATE email_log SET recipient = ?1 WHERE recipient = ?2 COLLATE NOCASE", params![pseudonym.clone(), email.clone()]).await?;
+            // Queued and delivered messages carry their bodies, so they go entirely
+            db.execute("DELETE FROM email_outbox WHERE recipient = ?1 COLLATE NOCASE", [email.clone()]).await?;
+            db.execute("DELETE FROM held_notifications WHERE player_id = ?1", [id]).await?;
+            db.execute("DELETE FROM email_suppressions WHERE email = ?1", [email.clone()]).await?;
+            db.execute("DELETE FROM notifications WHERE player_id = ?1", [id]).await?;
+            Ok(())
+        }.await;
+        finish_transaction(&db, result).await
+    }
+
     pub async fn admin_count(&self) -> RepositoryResult<i64> {
         let db = self.db.lock().await;
-        match db.query("SELECT COUNT(is_admin) from players", ()).await?.next()? {
+        match db.query("SELECT COUNT(is_admin) from players WHERE deleted_at IS NULL", ()).await?.next()? {
             Some(row) => {
                 let count: i64 = row.get(0).unwrap();
                 Ok(count)
@@ -308,8 +548,9 @@ impl Repository for PlayerRepository {
         let db = self.db.lock().await;
         let result = match player.id {
             Some(id) => {
-                let mut stmt = db.prepare("UPDATE players SET first_name = ?1, last_name = ?2, auth_email = ?3, auth_token = ?4, auth_token_expires = ?5, mailing_address = ?6, is_admin = ?7 WHERE id = ?8").await.unwrap();
-                stmt.query(params![
+                let mut stmt = db.prepare("UPDATE players SET first_name = ?1, last_name = ?2, auth_email = ?3, auth_token = ?4, auth_token_expires = ?5, mailing_address = ?6, is_admin = ?7, status = ?8, verification_token = ?9, verification_expires = ?10, pending_email = ?11,
+    email_suppressed = (SELECT reason FROM email_suppressions WHERE email = ?3) WHERE id = ?12").await.unwrap();
+                stmt.execute(params![
                     player.first_name,
                     player.last_name,
                     player.auth_email,
@@ -317,23 +558,32 @@ impl Repository for PlayerRepository {
                     player.auth_token_expires,
                     player.mailing_address,
                     player.is_admin,
+                    player.status.as_str(),
+                    player.verification_token,
+                    player.verification_expires,
+                    player.pending_email,
                     id
                 ]).await
             },
             None => {
                 // We'll let a custom method handle auth token data
-                let mut stmt = db.prepare("INSERT INTO players (first_name, last_name, auth_email, mailing_address, is_admin) VALUES (?1, ?2, ?3, ?4, ?5)").await.unwrap();
-                stmt.query(params![
+                let mut stmt = db.prepare("INSERT INTO players (first_name, last_name, auth_email, mailing_address, is_admin, status, verification_token, verification_expires, email_suppressed) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, (SELECT reason FROM email_suppressions WHERE email = ?3))").await.unwrap();
+                stmt.execute(params![
                     player.first_name,
                     player.last_name,
                     player.auth_email,
                     player.mailing_address,
-                    player.is_admin
+                    player.is_admin,
+                    player.status.as_str(),
+                    player.verification_token,
+                    player.verification_expires
                 ]).await
             },
         };
         match result {
             Ok(_) => Ok(db.last_insert_rowid()),
+            // Another player already signs in with this address
+            Err(err) if is_constraint_violation(&err) => Err(RepositoryError::AlreadyExists),
             Err(_) => Err(RepositoryError::Other),
         }
     }
@@ -341,7 +591,7 @@ impl Repository for PlayerRepository {
     async fn get(&self, id: i64) -> RepositoryResult<Model> {
         let db = self.db.lock().await;
         let mut stmt = db
-            .prepare("SELECT * FROM players WHERE id = ?1").await
+            .prepare("SELECT * FROM players WHERE id = ?1 AND deleted_at IS NULL").await
             .unwrap();
         let mut res = stmt.query([id]).await.unwrap();
         match res.next().unwrap() {
@@ -354,33 +604,74 @@ impl Repository for PlayerRepository {
     }
 
     async fn get_all(&self) -> RepositoryResult<Vec<Model>> {
-        todo!()
+        let db = self.db.lock().await;
+        let result = db.query("SELECT * FROM players WHERE deleted_at IS NULL ORDER BY last_name, first_name", ()).await;
+        let mut res = match result {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("Error getting all players: {:?}", err);
+                return Err(RepositoryError::Other)
+            },
+        };
+
+        let mut players: Vec<Model> = Vec::new();
+        while let Some(row) = res.next()? {
+            players.push(Model::from_response(&row));
+        }
+        Ok(players)
     }
 
+    // Deleting a player archives it; see purge for removing it for good.
     async fn delete(&self, id: i64) -> RepositoryResult<()> {
-        todo!()
+        let db = self.db.lock().await;
+        match db.execute(
+            "UPDATE players SET deleted_at = ?1, auth_token = NULL, auth_token_expires = NULL WHERE id = ?2 AND deleted_at IS NULL",
+            params![Utc::now().timestamp_millis(), id]
+        ).await {
+            Ok(0) => Err(RepositoryError::NotFound),
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other),
+        }
     }
 
     async fn create_table(&self) -> RepositoryResult<()> {
         let db = self.db.lock().await;
         let result = db.execute(
             r#"CREATE TABLE IF NOT EXISTS players (
-    id INTEGER PRIMARY KEY,
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
     first_name TEXT NOT NULL,
     last_name TEXT NOT NULL,
     auth_email TEXT NOT NULL,
     auth_token TEXT,
     auth_token_expires INTEGER,
     mailing_address TEXT NOT NULL,
-    is_admin BOOLEAN NOT NULL
+    is_admin BOOLEAN NOT NULL,
+    status TEXT NOT NULL DEFAULT 'active',
+    verification_token TEXT,
+    verification_expires INTEGER,
+    deleted_at INTEGER,
+    pending_email TEXT,
+    email_suppressed TEXT
 )"#, ()).await;
-        match result {
+        if let Err(err) = result {
+            log::error!("Error creating the players table: {:?}", err);
+            return Err(RepositoryError::Other);
+        }
+        // Addresses are compared case-insensitively everywhere else, so the index does too
+        match db.execute("CREATE UNIQUE INDEX IF NOT EXISTS players_auth_email_idx ON players (auth_email COLLATE NOCASE)", ()).await {
             Ok(_) => Ok(()),
-            Err(_) => Err(RepositoryError::Other),
+            Err(err) => {
+                log::error!("Error indexing player emails; players may share an address: {:?}", err);
+                Err(RepositoryError::Other)
+            },
         }
     }
 
     async fn drop_table(&self) -> RepositoryResult<()> {
-        todo!()
+        let db = self.db.lock().await;
+        match db.execute("DROP TABLE IF EXISTS players", ()).await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other),
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,351 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use chrono::Utc;
+use libsql::{Connection, params, Row};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use crate::repository::{Repository, RepositoryError, RepositoryItem, RepositoryResult};
+
+// NotificationCategory groups the email players can opt out of. Login and verification
+// email isn't in a category and is always sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationCategory {
+    Transactions,
+    Events,
+    Broadcasts,
+    Digests,
+}
+
+impl NotificationCategory {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationCategory::Transactions => "transactions",
+            NotificationCategory::Events => "events",
+            NotificationCategory::Broadcasts => "broadcasts",
+            NotificationCategory::Digests => "digests",
+        }
+    }
+}
+
+impl From<String> for NotificationCategory {
+    fn from(category: String) -> Self {
+        match category.as_str() {
+            "transactions" => NotificationCategory::Transactions,
+            "events" => NotificationCategory::Events,
+            "digests" => NotificationCategory::Digests,
+            _ => NotificationCategory::Broadcasts,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Delivery {
+    #[default]
+    Immediate,
+    // Held and sent together once a day
+    DailyDigest,
+    Off,
+}
+
+impl Delivery {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Delivery::Immediate => "immediate",
+            Delivery::DailyDigest => "daily_digest",
+            Delivery::Off => "off",
+        }
+    }
+}
+
+impl From<String> for Delivery {
+    fn from(delivery: String) -> Self {
+        match delivery.as_str() {
+            "daily_digest" => Delivery::DailyDigest,
+            "off" => Delivery::Off,
+            _ => Delivery::Immediate,
+        }
+    }
+}
+
+// NotificationPreferences is how a player wants each category of email delivered.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NotificationPreferences {
+    #[serde(default)]
+    pub transactions: Delivery,
+    #[serde(default)]
+    pub events: Delivery,
+    #[serde(default)]
+    pub broadcasts: Delivery,
+    #[serde(default)]
+    pub digests: Delivery,
+    // Don't send a weekly statement when nothing happened that week
+    #[serde(default)]
+    pub skip_quiet_statements: bool,
+}
+
+impl NotificationPreferences {
+    pub fn delivery(&self, category: NotificationCategory) -> Delivery {
+        match category {
+            NotificationCategory::Transactions => self.transactions,
+            NotificationCategory::Events => self.events,
+            NotificationCategory::Broadcasts => self.broadcasts,
+            NotificationCategory::Digests => self.digests,
+        }
+    }
+
+    pub fn set(&mut self, category: NotificationCategory, delivery: Delivery) {
+        match category {
+            NotificationCategory::Transactions => self.transactions = delivery,
+            NotificationCategory::Events => self.events = delivery,
+            NotificationCategory::Broadcasts => self.broadcasts = delivery,
+            NotificationCategory::Digests => self.digests = delivery,
+        }
+    }
+
+    pub fn from_response(row: &Row) -> RepositoryResult<NotificationPreferences> {
+        Ok(NotificationPreferences {
+            transactions: row.get::<String>(1)?.into(),
+            events: row.get::<String>(2)?.into(),
+            broadcasts: row.get::<String>(3)?.into(),
+            digests: row.get::<String>(4)?.into(),
+            skip_quiet_statements: row.get(5)?,
+        })
+    }
+}
+
+impl RepositoryItem for NotificationPreferences {
+    fn masked_columns(_: bool) -> Vec<String> {
+        vec![]
+    }
+
+    fn saved_columns() -> Vec<String> {
+        Self::all_columns()
+    }
+
+    fn all_columns() -> Vec<String> {
+        vec![
+            "player_id".to_string(),
+            "transactions".to_string(),
+            "events".to_string(),
+            "broadcasts".to_string(),
+            "digests".to_string(),
+            "skip_quiet_statements".to_string(),
+        ]
+    }
+
+    fn table_name() -> String where Self: Sized {
+        "notification_preferences".to_string()
+    }
+}
+
+// HeldNotification is an email held back for a player's daily digest.
+#[derive(Debug, Clone, Serialize)]
+pub struct HeldNotification {
+    pub id: i64,
+    pub player_id: i64,
+    pub category: NotificationCategory,
+    pub subject: String,
+    pub text: String,
+    pub created_at: i64,
+}
+
+impl HeldNotification {
+    pub fn from_response(row: &Row) -> RepositoryResult<HeldNotification> {
+        Ok(HeldNotification {
+            id: row.get(0)?,
+            player_id: row.get(1)?,
+            category: row.get::<String>(2)?.into(),
+            subject: row.get(3)?,
+            text: row.get(4)?,
+            created_at: row.get(5)?,
+        })
+    }
+}
+
+pub struct PreferenceRepository {
+    db: Arc<Mutex<Connection>>,
+}
+
+impl PreferenceRepository {
+    pub fn new(db: Arc<Mutex<Connection>>) -> PreferenceRepository {
+        PreferenceRepository {
+            db,
+        }
+    }
+
+    pub async fn store(&self, player_id: i64, preferences: &NotificationPreferences) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        db.execute(
+            "INSERT INTO notification_preferences (player_id, transactions, events, broadcasts, digests, skip_quiet_statements)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            ON CONFLICT (player_id) DO UPDATE SET transactions = excluded.transactions, events = excluded.events,
+                broadcasts = excluded.broadcasts, digests = excluded.digests, skip_quiet_statements = excluded.skip_quiet_statements",
+            params![
+                player_id,
+                preferences.transactions.as_str(),
+                preferences.events.as_str(),
+                preferences.broadcasts.as_str(),
+                preferences.digests.as_str(),
+                preferences.skip_quiet_statements
+            ]).await?;
+        Ok(())
+    }
+
+    // unsubscribe turns off one category, or every category when none is given.
+    pub async fn unsubscribe(&self, player_id: i64, category: Option<NotificationCategory>) -> RepositoryResult<NotificationPreferences> {
+        let mut preferences = self.get(player_id).await?;
+        match category {
+            Some(category) => preferences.set(category, Delivery::Off),
+            None => preferences = NotificationPreferences {
+                transactions: Delivery::Off,
+                events: Delivery::Off,
+                broadcasts: Delivery::Off,
+                digests: Delivery::Off,
+                ..preferences
+            },
+        }
+        self.store(player_id, &preferences).await?;
+        Ok(preferences)
+    }
+
+    pub async fn hold(&self, player_id: i64, category: NotificationCategory, subject: &str, text: &str) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        db.execute(
+            "INSERT INTO held_notifications (player_id, category, subject, text, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![player_id, category.as_str(), subject, text, Utc::now().timestamp_millis()]).await?;
+        Ok(())
+    }
+
+    // held returns every held notification, grouped by player. They stay held until released.
+    pub async fn held(&self) -> RepositoryResult<BTreeMap<i64, Vec<HeldNotification>>> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM held_notifications ORDER BY player_id, id", ()).await?;
+        let mut held: BTreeMap<i64, Vec<HeldNotification>> = BTreeMap::new();
+        while let Some(row) = res.next()? {
+            let notification = HeldNotification::from_response(&row)?;
+            held.entry(notification.player_id).or_default().push(notification);
+        }
+        Ok(held)
+    }
+
+    // release drops a player's held notifications up to and including `through_id`, once their
+    // digest is queued. Anything held since stays for the next digest.
+    pub async fn release(&self, player_id: i64, through_id: i64) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        db.execute("DELETE FROM held_notifications WHERE player_id = ?1 AND id <= ?2", [player_id, through_id]).await?;
+        Ok(())
+    }
+}
+
+#[shuttle_runtime::async_trait]
+impl Repository for PreferenceRepository {
+    type Item = NotificationPreferences;
+    // Preferences are keyed by player
+    type RowIdentifier = i64;
+
+    async fn save(&self, _: NotificationPreferences) -> RepositoryResult<i64> {
+        // Preferences don't know their player; use store
+        Err(RepositoryError::InvalidModel)
+    }
+
+    // get returns a player's preferences, or the defaults if they haven't changed any.
+    async fn get(&self, player_id: i64) -> RepositoryResult<NotificationPreferences> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM notification_preferences WHERE player_id = ?1", [player_id]).await?;
+        match res.next()? {
+            Some(row) => NotificationPreferences::from_response(&row),
+            None => Ok(NotificationPreferences::default()),
+        }
+    }
+
+    async fn get_all(&self) -> RepositoryResult<Vec<NotificationPreferences>> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM notification_preferences ORDER BY player_id", ()).await?;
+        let mut preferences = Vec::new();
+        while let Some(row) = res.next()? {
+            preferences.push(NotificationPreferences::from_response(&row)?);
+        }
+        Ok(preferences)
+    }
+
+    async fn delete(&self, player_id: i64) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        db.execute("DELETE FROM held_notifications WHERE player_id = ?1", [player_id]).await?;
+        db.execute("DELETE FROM notification_preferences WHERE player_id = ?1", [player_id]).await?;
+        Ok(())
+    }
+
+    async fn create_table(&self) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        let stmts = [
+            "BEGIN".to_string(),
+            "CREATE TABLE IF NOT EXISTS notification_preferences (
+                player_id INTEGER PRIMARY KEY,
+                transactions TEXT NOT NULL DEFAULT 'immediate',
+                events TEXT NOT NULL DEFAULT 'immediate',
+                broadcasts TEXT NOT NULL DEFAULT 'immediate',
+                digests TEXT NOT NULL DEFAULT 'immediate',
+                skip_quiet_statements INTEGER NOT NULL DEFAULT 0
+            )".to_string(),
+            "CREATE TABLE IF NOT EXISTS held_notifications (
+                id INTEGER PRIMARY KEY,
+                player_id INTEGER NOT NULL,
+                category TEXT NOT NULL,
+                subject TEXT NOT NULL,
+                text TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )".to_string(),
+            "COMMIT".to_string(),
+        ];
+
+        let stmts = stmts.join(";");
+        match db.execute_batch(&stmts).await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other)
+        }
+    }
+
+    async fn drop_table(&self) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        match db.execute_batch("DROP TABLE IF EXISTS held_notifications;DROP TABLE IF EXISTS notification_preferences").await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use crate::repository::Repository;
+    use super::{Delivery, NotificationCategory, NotificationPreferences, PreferenceRepository};
+
+    #[tokio::test]
+    async fn test_preferences_and_held_notifications() {
+        let db = libsql::Database::open_in_memory().unwrap().connect().unwrap();
+        let repository = PreferenceRepository::new(Arc::new(Mutex::new(db)));
+        repository.create_table().await.unwrap();
+        assert_eq!(repository.get(1).await.unwrap(), NotificationPreferences::default());
+
+        let preferences = NotificationPreferences { events: Delivery::DailyDigest, ..NotificationPreferences::default() };
+        repository.store(1, &preferences).await.unwrap();
+        let preferences = repository.unsubscribe(1, Some(NotificationCategory::Transactions)).await.unwrap();
+        assert_eq!(preferences.delivery(NotificationCategory::Transactions), Delivery::Off);
+        assert_eq!(repository.get(1).await.unwrap().events, Delivery::DailyDigest);
+
+        repository.hold(1, NotificationCategory::Events, "Moon festival", "Tonight").await.unwrap();
+        repository.hold(2, NotificationCategory::Broadcasts, "News", "Hello").await.unwrap();
+        let held = repository.held().await.unwrap();
+        assert_eq!(held.len(), 2);
+        assert_eq!(held[&1][0].subject, "Moon festival");
+        repository.hold(1, NotificationCategory::Events, "Moon festival", "Moved to tomorrow").await.unwrap();
+        repository.release(1, held[&1][0].id).await.unwrap();
+        let held = repository.held().await.unwrap();
+        assert_eq!(held[&1].len(), 1);
+        assert_eq!(held[&1][0].text, "Moved to tomorrow");
+        assert_eq!(held[&2].len(), 1);
+    }
+}
@@ -38,15 +38,15 @@ impl Repository for SessionRepository {
     type Item = Session;
     type RowIdentifier = i64;
 
-    async fn create(&self, template_item: Option<Session>) -> RepositoryResult<i64> {
+    async fn create(&self, _template_item: Option<Session>) -> RepositoryResult<i64> {
         todo!()
     }
 
-    async fn save(&self, item: Session) -> RepositoryResult<i64> {
+    async fn save(&self, _item: Session) -> RepositoryResult<i64> {
         todo!()
     }
 
-    async fn get(&self, id: i64) -> RepositoryResult<Session> {
+    async fn get(&self, _id: i64) -> RepositoryResult<Session> {
         todo!()
     }
 
@@ -55,22 +55,23 @@ impl Repository for SessionRepository {
         todo!()
     }
 
-    async fn delete(&self, id: i64) -> RepositoryResult<()> {
+    async fn delete(&self, _id: i64) -> RepositoryResult<()> {
         todo!()
     }
 
     async fn create_table(&self) -> RepositoryResult<()> {
         let db = self.db.lock().await;
-        let mut stmts = vec![];
-        stmts.push("BEGIN".to_string());
-        stmts.push("CREATE TABLE IF NOT EXISTS sessions (
-            id INTEGER PRIMARY KEY,
-            user_id INTEGER NOT NULL,
-            session_token TEXT NOT NULL,
-            expires_in INTEGER NOT NULL
-        )".to_string());
-        stmts.push("CREATE UNIQUE INDEX IF NOT EXISTS user_id_token_idx ON sessions (user_id, session_token)".to_string());
-        stmts.push("COMMIT".to_string());
+        let stmts = [
+            "BEGIN".to_string(),
+            "CREATE TABLE IF NOT EXISTS sessions (
+                id INTEGER PRIMARY KEY,
+                user_id INTEGER NOT NULL,
+                session_token TEXT NOT NULL,
+                expires_in INTEGER NOT NULL
+            )".to_string(),
+            "CREATE UNIQUE INDEX IF NOT EXISTS user_id_token_idx ON sessions (user_id, session_token)".to_string(),
+            "COMMIT".to_string(),
+        ];
 
         let stmts = stmts.join(";");
         match db.execute_batch(&stmts).await {
@@ -85,12 +86,12 @@ impl Repository for SessionRepository {
 }
 
 impl SessionRepository {
+    #[allow(dead_code)]
     pub async fn clean_up_expired(&self) -> RepositoryResult<()> {
         let db = self.db.lock().await;
         let now = chrono::Utc::now().timestamp_millis();
         let mut stmt = db.prepare("DELETE FROM sessions WHERE expires_in < ?").await.unwrap();
-        match stmt.query(params![now]).await.unwrap() {
-            _ => Ok(()),
-        }
+        stmt.query(params![now]).await.unwrap();
+        Ok(())
     }
 }
\ No newline at end of file
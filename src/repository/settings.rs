@@ -0,0 +1,306 @@
+use std::sync::Arc;
+use libsql::{Connection, params, Row};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use crate::repository::{Repository, RepositoryError, RepositoryItem, RepositoryResult};
+
+// Setting is a single admin-editable value, stored as JSON under a key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Setting {
+    pub key: String,
+    pub value: serde_json::Value,
+}
+
+impl Setting {
+    pub fn from_response(row: &Row) -> RepositoryResult<Setting> {
+        let value: String = row.get(1)?;
+        Ok(Setting {
+            key: row.get(0)?,
+            value: serde_json::from_str(&value).map_err(|_| RepositoryError::InvalidModel)?,
+        })
+    }
+}
+
+impl RepositoryItem for Setting {
+    fn masked_columns(_: bool) -> Vec<String> {
+        vec![]
+    }
+
+    fn saved_columns() -> Vec<String> {
+        Self::all_columns()
+    }
+
+    fn all_columns() -> Vec<String> {
+        vec!["key".to_string(), "value".to_string()]
+    }
+
+    fn table_name() -> String where Self: Sized {
+        "settings".to_string()
+    }
+}
+
+// RegistrationSettings controls who can sign up through POST /api/register.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RegistrationSettings {
+    // Verified players wait for an admin to approve them before they can log in
+    #[serde(default)]
+    pub require_approval: bool,
+    #[serde(default)]
+    pub blocked_domains: Vec<String>,
+}
+
+impl RegistrationSettings {
+    pub const KEY: &'static str = "registration";
+
+    pub fn is_blocked(&self, email: &str) -> bool {
+        let domain = match email.rsplit_once('@') {
+            Some((_, domain)) => domain.trim().to_lowercase(),
+            None => return true,
+        };
+        self.blocked_domains.iter().any(|blocked| {
+            let blocked = blocked.trim().trim_start_matches('@').to_lowercase();
+            domain == blocked || domain.ends_with(&format!(".{}", blocked))
+        })
+    }
+}
+
+// Branding is how the game presents itself in email.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Branding {
+    pub game_name: String,
+    #[serde(default)]
+    pub logo_url: Option<String>,
+    pub primary_color: String,
+    pub accent_color: String,
+    // Sender for templated mail; the mailer's MAIL_FROM is used when it's not set
+    #[serde(default)]
+    pub from_address: Option<String>,
+}
+
+impl Default for Branding {
+    fn default() -> Self {
+        Branding {
+            game_name: "Fe-Vault".to_string(),
+            logo_url: None,
+            primary_color: "#4b2e83".to_string(),
+            accent_color: "#c9a227".to_string(),
+            from_address: None,
+        }
+    }
+}
+
+impl Branding {
+    pub const KEY: &'static str = "branding";
+
+    // problems lists what's wrong with the branding, if anything.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.game_name.trim().is_empty() {
+            problems.push("game_name can't be empty".to_string());
+        }
+        for (field, color) in [("primary_color", &self.primary_color), ("accent_color", &self.accent_color)] {
+            let hex = color.strip_prefix('#').unwrap_or_default();
+            if !matches!(hex.len(), 3 | 6) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                problems.push(format!("{} must be a hex color like #4b2e83", field));
+            }
+        }
+        if let Some(from_address) = &self.from_address {
+            if from_address.parse::<lettre::message::Mailbox>().is_err() {
+                problems.push("from_address must be an email address".to_string());
+            }
+        }
+        problems
+    }
+}
+
+// DigestSchedule remembers when the daily digests last went out, so a restart neither skips a
+// day nor sends a second digest early.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DigestSchedule {
+    #[serde(default)]
+    pub last_digest_at: Option<i64>,
+}
+
+impl DigestSchedule {
+    pub const KEY: &'static str = "digest_schedule";
+    pub const PERIOD_MILLIS: i64 = 24 * 60 * 60 * 1000;
+
+    pub fn is_due(&self, now: i64) -> bool {
+        self.last_digest_at.is_none_or(|last| now - last >= Self::PERIOD_MILLIS)
+    }
+}
+
+// StatementSchedule remembers where the last weekly statements' period ended, so the next
+// period starts exactly there.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StatementSchedule {
+    #[serde(default)]
+    pub last_period_end: Option<i64>,
+}
+
+impl StatementSchedule {
+    pub const KEY: &'static str = "statement_schedule";
+    pub const PERIOD_MILLIS: i64 = 7 * 24 * 60 * 60 * 1000;
+
+    // next_period is the (since, until) the next statements cover, once that week is over.
+    // The very first statements cover the week up to now.
+    pub fn next_period(&self, now: i64) -> Option<(i64, i64)> {
+        match self.last_period_end {
+            None => Some((now - Self::PERIOD_MILLIS, now)),
+            Some(end) if now - end >= Self::PERIOD_MILLIS => Some((end, end + Self::PERIOD_MILLIS)),
+            Some(_) => None,
+        }
+    }
+
+    // current_since is where the statement in progress starts, for previews.
+    pub fn current_since(&self, now: i64) -> i64 {
+        self.last_period_end.unwrap_or(now - Self::PERIOD_MILLIS)
+    }
+}
+
+pub struct SettingsRepository {
+    db: Arc<Mutex<Connection>>,
+}
+
+impl SettingsRepository {
+    pub fn new(db: Arc<Mutex<Connection>>) -> SettingsRepository {
+        SettingsRepository {
+            db,
+        }
+    }
+
+    // load reads a typed setting, falling back to its default when it hasn't been saved yet.
+    pub async fn load<T: DeserializeOwned + Default>(&self, key: &str) -> RepositoryResult<T> {
+        match self.get(key.to_string()).await {
+            Ok(setting) => serde_json::from_value(setting.value).map_err(|_| RepositoryError::InvalidModel),
+            Err(RepositoryError::NotFound) => Ok(T::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub async fn store<T: Serialize>(&self, key: &str, value: &T) -> RepositoryResult<()> {
+        let value = serde_json::to_value(value).map_err(|_| RepositoryError::InvalidModel)?;
+        self.save(Setting { key: key.to_string(), value }).await.map(|_| ())
+    }
+}
+
+#[shuttle_runtime::async_trait]
+impl Repository for SettingsRepository {
+    type Item = Setting;
+    type RowIdentifier = String;
+
+    async fn save(&self, setting: Setting) -> RepositoryResult<String> {
+        let db = self.db.lock().await;
+        let result = db.execute(
+            "INSERT INTO settings (key, value) VALUES (?1, ?2) ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            params![setting.key.clone(), setting.value.to_string()]
+        ).await;
+        match result {
+            Ok(_) => Ok(setting.key),
+            Err(err) => {
+                log::error!("Error saving setting {}: {:?}", setting.key, err);
+                Err(RepositoryError::Other)
+            },
+        }
+    }
+
+    async fn get(&self, key: String) -> RepositoryResult<Setting> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT key, value FROM settings WHERE key = ?1", [key]).await?;
+        match res.next()? {
+            Some(row) => Setting::from_response(&row),
+            None => Err(RepositoryError::NotFound),
+        }
+    }
+
+    async fn get_all(&self) -> RepositoryResult<Vec<Setting>> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT key, value FROM settings ORDER BY key", ()).await?;
+        let mut settings = Vec::new();
+        while let Some(row) = res.next()? {
+            settings.push(Setting::from_response(&row)?);
+        }
+        Ok(settings)
+    }
+
+    async fn delete(&self, key: String) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        match db.execute("DELETE FROM settings WHERE key = ?1", [key]).await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::NotFound),
+        }
+    }
+
+    async fn create_table(&self) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        let result = db.execute(
+            r#"CREATE TABLE IF NOT EXISTS settings (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+)"#, ()).await;
+        match result {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other),
+        }
+    }
+
+    async fn drop_table(&self) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        match db.execute("DROP TABLE IF EXISTS settings", ()).await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Branding, DigestSchedule, RegistrationSettings, StatementSchedule};
+
+    #[test]
+    fn test_blocked_domains() {
+        let settings = RegistrationSettings {
+            require_approval: false,
+            blocked_domains: vec!["spam.example".to_string(), "@Mailinator.com".to_string()],
+        };
+        assert!(settings.is_blocked("someone@spam.example"));
+        assert!(settings.is_blocked("someone@eu.spam.example"));
+        assert!(settings.is_blocked("someone@MAILINATOR.com"));
+        assert!(!settings.is_blocked("someone@notspam.example"));
+        assert!(!settings.is_blocked("someone@example.com"));
+        assert!(settings.is_blocked("not-an-email"));
+    }
+
+    #[test]
+    fn test_branding_problems() {
+        assert!(Branding::default().problems().is_empty());
+        let branding = Branding {
+            game_name: " ".to_string(),
+            primary_color: "purple".to_string(),
+            from_address: Some("nobody".to_string()),
+            ..Branding::default()
+        };
+        assert_eq!(branding.problems().len(), 3);
+    }
+
+    #[test]
+    fn test_digest_schedule() {
+        assert!(DigestSchedule::default().is_due(0));
+        let schedule = DigestSchedule { last_digest_at: Some(1_000) };
+        assert!(!schedule.is_due(1_000 + DigestSchedule::PERIOD_MILLIS - 1));
+        assert!(schedule.is_due(1_000 + DigestSchedule::PERIOD_MILLIS));
+    }
+
+    #[test]
+    fn test_statement_schedule() {
+        let week = StatementSchedule::PERIOD_MILLIS;
+        assert_eq!(StatementSchedule::default().next_period(10 * week), Some((9 * week, 10 * week)));
+        let schedule = StatementSchedule { last_period_end: Some(10 * week) };
+        assert_eq!(schedule.next_period(11 * week - 1), None);
+        assert_eq!(schedule.current_since(11 * week - 1), 10 * week);
+        assert_eq!(schedule.next_period(11 * week), Some((10 * week, 11 * week)));
+        // After downtime, every missed week still gets its statement, one after another
+        assert_eq!(schedule.next_period(13 * week + 5), Some((10 * week, 11 * week)));
+    }
+}
@@ -0,0 +1,291 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use chrono::Utc;
+use libsql::{Connection, params, Row};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use crate::repository::{finish_transaction, is_constraint_violation, Repository, RepositoryError, RepositoryItem, RepositoryResult};
+
+// Snapshot freezes every faery's balance under a name, e.g. at the end of a season.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub(crate) id: Option<i64>,
+    pub name: String,
+    #[serde(default)]
+    pub taken_at: i64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub balances: Vec<SnapshotBalance>,
+}
+
+impl Snapshot {
+    pub fn from_response(row: &Row) -> RepositoryResult<Snapshot> {
+        Ok(Snapshot {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            taken_at: row.get(2)?,
+            balances: vec![],
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotBalance {
+    pub faery_id: i64,
+    pub name: String,
+    pub dross: i64,
+}
+
+impl SnapshotBalance {
+    pub fn from_response(row: &Row) -> RepositoryResult<SnapshotBalance> {
+        Ok(SnapshotBalance {
+            faery_id: row.get(0)?,
+            name: row.get(1)?,
+            dross: row.get(2)?,
+        })
+    }
+}
+
+impl RepositoryItem for Snapshot {
+    fn masked_columns(_: bool) -> Vec<String> {
+        vec![]
+    }
+
+    fn saved_columns() -> Vec<String> {
+        vec!["name".to_string(), "taken_at".to_string()]
+    }
+
+    fn all_columns() -> Vec<String> {
+        vec!["id".to_string(), "name".to_string(), "taken_at".to_string()]
+    }
+
+    fn table_name() -> String where Self: Sized {
+        "snapshots".to_string()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateSnapshotRequest {
+    pub name: String,
+}
+
+// SnapshotDiff is one faery's change in balance between two snapshots.
+// A missing side means the faery didn't exist when that snapshot was taken.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SnapshotDiff {
+    pub faery_id: i64,
+    pub name: String,
+    pub before: Option<i64>,
+    pub after: Option<i64>,
+    pub change: i64,
+}
+
+// diff compares the balances of two snapshots, returning only the faeries whose balance changed.
+pub fn diff(before: &Snapshot, after: &Snapshot) -> Vec<SnapshotDiff> {
+    let mut rows: BTreeMap<i64, SnapshotDiff> = BTreeMap::new();
+    for balance in &before.balances {
+        rows.insert(balance.faery_id, SnapshotDiff {
+            faery_id: balance.faery_id,
+            name: balance.name.clone(),
+            before: Some(balance.dross),
+            after: None,
+            change: -balance.dross,
+        });
+    }
+    for balance in &after.balances {
+        let row = rows.entry(balance.faery_id).or_insert(SnapshotDiff {
+            faery_id: balance.faery_id,
+            name: balance.name.clone(),
+            before: None,
+            after: None,
+            change: 0,
+        });
+        row.name = balance.name.clone();
+        row.after = Some(balance.dross);
+        row.change = balance.dross - row.before.unwrap_or(0);
+    }
+    rows.into_values().filter(|row| row.change != 0 || row.before.is_none() || row.after.is_none()).collect()
+}
+
+pub struct SnapshotRepository {
+    db: Arc<Mutex<Connection>>,
+}
+
+impl SnapshotRepository {
+    pub fn new(db: Arc<Mutex<Connection>>) -> SnapshotRepository {
+        SnapshotRepository {
+            db,
+        }
+    }
+}
+
+#[shuttle_runtime::async_trait]
+impl Repository for SnapshotRepository {
+    type Item = Snapshot;
+    type RowIdentifier = i64;
+
+    // Snapshots can't be edited once taken; saving always takes a new one from the current balances.
+    async fn save(&self, snapshot: Snapshot) -> RepositoryResult<i64> {
+        if snapshot.id.is_some() {
+            return Err(RepositoryError::InvalidModel);
+        }
+        let db = self.db.lock().await;
+        let taken_at = Utc::now().timestamp_millis();
+        db.execute("BEGIN", ()).await?;
+        let result = async {
+            if let Err(err) = db.execute(
+                "INSERT INTO snapshots (name, taken_at) VALUES (?1, ?2)",
+                params![snapshot.name.clone(), taken_at]
+            ).await {
+                log::error!("Error creating snapshot {}: {:?}", snapshot.name, err);
+                return Err(match is_constraint_violation(&err) {
+                    true => RepositoryError::AlreadyExists,
+                    false => RepositoryError::Other,
+                });
+            }
+            let id = db.last_insert_rowid();
+            match db.execute(
+                "INSERT INTO snapshot_balances (snapshot_id, faery_id, name, dross) SELECT ?1, id, name, COALESCE(dross, 0) FROM faeries WHERE deleted_at IS NULL",
+                [id]
+            ).await {
+                Ok(count) => {
+                    log::info!("Snapshot {} recorded {} balances", snapshot.name, count);
+                    Ok(id)
+                },
+                Err(err) => {
+                    log::error!("Error recording balances for snapshot {}: {:?}", snapshot.name, err);
+                    Err(RepositoryError::Other)
+                },
+            }
+        }.await;
+        finish_transaction(&db, result).await
+    }
+
+    async fn get(&self, id: i64) -> RepositoryResult<Snapshot> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT id, name, taken_at FROM snapshots WHERE id = ?1", [id]).await?;
+        let mut snapshot = match res.next()? {
+            Some(row) => Snapshot::from_response(&row)?,
+            None => return Err(RepositoryError::NotFound),
+        };
+        let mut res = db.query(
+            "SELECT faery_id, name, dross FROM snapshot_balances WHERE snapshot_id = ?1 ORDER BY faery_id",
+            [id]).await?;
+        while let Some(row) = res.next()? {
+            snapshot.balances.push(SnapshotBalance::from_response(&row)?);
+        }
+        Ok(snapshot)
+    }
+
+    async fn get_all(&self) -> RepositoryResult<Vec<Snapshot>> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT id, name, taken_at FROM snapshots ORDER BY taken_at", ()).await?;
+        let mut snapshots = Vec::new();
+        while let Some(row) = res.next()? {
+            snapshots.push(Snapshot::from_response(&row)?);
+        }
+        Ok(snapshots)
+    }
+
+    async fn delete(&self, id: i64) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        db.execute("BEGIN", ()).await?;
+        let result = async {
+            db.execute("DELETE FROM snapshot_balances WHERE snapshot_id = ?1", [id]).await?;
+            match db.execute("DELETE FROM snapshots WHERE id = ?1", [id]).await? {
+                0 => Err(RepositoryError::NotFound),
+                _ => Ok(()),
+            }
+        }.await;
+        finish_transaction(&db, result).await
+    }
+
+    async fn create_table(&self) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        let stmts = [
+            "BEGIN".to_string(),
+            "CREATE TABLE IF NOT EXISTS snapshots (
+                id INTEGER PRIMARY KEY,
+                name TEXT NOT NULL UNIQUE,
+                taken_at INTEGER NOT NULL
+            )".to_string(),
+            "CREATE TABLE IF NOT EXISTS snapshot_balances (
+                snapshot_id INTEGER NOT NULL,
+                faery_id INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                dross INTEGER NOT NULL,
+                PRIMARY KEY (snapshot_id, faery_id)
+            )".to_string(),
+            "COMMIT".to_string(),
+        ];
+
+        let stmts = stmts.join(";");
+        match db.execute_batch(&stmts).await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other)
+        }
+    }
+
+    async fn drop_table(&self) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        match db.execute_batch("DROP TABLE IF EXISTS snapshot_balances;DROP TABLE IF EXISTS snapshots").await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use crate::repository::{Repository, RepositoryError};
+    use super::{diff, Snapshot, SnapshotBalance, SnapshotRepository};
+
+    fn snapshot(balances: &[(i64, i64)]) -> Snapshot {
+        Snapshot {
+            id: None,
+            name: "Season".to_string(),
+            taken_at: 0,
+            balances: balances.iter().map(|(faery_id, dross)| SnapshotBalance {
+                faery_id: *faery_id,
+                name: format!("Faery {}", faery_id),
+                dross: *dross,
+            }).collect(),
+        }
+    }
+
+    #[test]
+    fn test_diff_snapshots() {
+        let before = snapshot(&[(1, 10), (2, 5), (3, 7)]);
+        let after = snapshot(&[(1, 15), (2, 5), (4, 3)]);
+        let changes = diff(&before, &after);
+        assert_eq!(changes.len(), 3);
+        assert_eq!((changes[0].faery_id, changes[0].change), (1, 5));
+        assert_eq!((changes[1].faery_id, changes[1].after, changes[1].change), (3, None, -7));
+        assert_eq!((changes[2].faery_id, changes[2].before, changes[2].change), (4, None, 3));
+    }
+
+    #[tokio::test]
+    async fn test_save_is_atomic() {
+        let db = libsql::Database::open_in_memory().unwrap().connect().unwrap();
+        db.execute_batch("CREATE TABLE faeries (id INTEGER PRIMARY KEY, name TEXT, dross INTEGER, deleted_at INTEGER)").await.unwrap();
+        db.execute("INSERT INTO faeries (name, dross) VALUES ('Puck', 7), ('Mab', NULL)", ()).await.unwrap();
+        let db = Arc::new(Mutex::new(db));
+        let repository = SnapshotRepository::new(db.clone());
+        repository.create_table().await.unwrap();
+
+        let id = repository.save(snapshot(&[])).await.unwrap();
+        let saved = repository.get(id).await.unwrap();
+        assert_eq!(saved.balances.iter().map(|balance| balance.dross).collect::<Vec<_>>(), vec![7, 0]);
+        assert!(matches!(repository.save(snapshot(&[])).await, Err(RepositoryError::AlreadyExists)));
+
+        // A failure recording the balances leaves no snapshot behind
+        db.lock().await.execute_batch("DROP TABLE faeries").await.unwrap();
+        let failed = Snapshot { name: "Broken".to_string(), ..snapshot(&[]) };
+        assert!(matches!(repository.save(failed).await, Err(RepositoryError::Other)));
+        assert_eq!(repository.get_all().await.unwrap().len(), 1);
+
+        repository.delete(id).await.unwrap();
+        assert!(matches!(repository.delete(id).await, Err(RepositoryError::NotFound)));
+    }
+}
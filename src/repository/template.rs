@@ -0,0 +1,378 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use chrono::Utc;
+use libsql::{Connection, params, Row};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use crate::mailer::Email;
+use crate::repository::{Repository, RepositoryError, RepositoryItem, RepositoryResult};
+use crate::repository::settings::Branding;
+
+pub type Variables = BTreeMap<String, String>;
+
+// EmailTemplate is one version of a named email. Saving a template adds a new version; the
+// newest version is the one that's used. Templates that were never edited use the built-in copy,
+// which has version 0.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailTemplate {
+    #[serde(default)]
+    pub(crate) id: Option<i64>,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub version: i64,
+    pub subject: String,
+    // Only the content; it's wrapped in the branded layout when rendered
+    pub html: String,
+    pub text: String,
+    #[serde(default)]
+    pub created_at: i64,
+}
+
+impl EmailTemplate {
+    pub fn from_response(row: &Row) -> RepositoryResult<EmailTemplate> {
+        Ok(EmailTemplate {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            version: row.get(2)?,
+            subject: row.get(3)?,
+            html: row.get(4)?,
+            text: row.get(5)?,
+            created_at: row.get(6)?,
+        })
+    }
+
+    fn builtin_parts(name: &str) -> Option<(&'static str, &'static str, &'static str)> {
+        match name {
+            "login_token" => Some((
+                "Your {{game_name}} login token",
+                "<p>Your login token is:</p><p style=\"font-size: 24px\"><strong>{{token}}</strong></p>",
+                "Your login token is: {{token}}",
+            )),
+            "verification" => Some((
+                "Confirm your {{game_name}} account",
+                "<p>Welcome to {{game_name}}!</p><p>Confirm your email address to finish registering:</p>\
+                <p><a href=\"{{link}}\">Confirm my account</a></p><p>This link expires in 48 hours.</p>",
+                "Welcome to {{game_name}}!\n\nConfirm your email address to finish registering:\n\n{{link}}\n\nThis link expires in 48 hours.",
+            )),
+            "transfer_receipt" => Some((
+                "{{game_name}} receipt: {{amount}} dross",
+                "<p>{{faery}}'s balance changed by <strong>{{amount}}</strong> dross.</p>\
+                <table><tr><td>Type</td><td>{{kind}}</td></tr><tr><td>With</td><td>{{counterparty}}</td></tr>\
+                <tr><td>Memo</td><td>{{memo}}</td></tr><tr><td>New balance</td><td>{{balance}}</td></tr></table>",
+                "{{faery}}'s balance changed by {{amount}} dross.\n\nType: {{kind}}\nWith: {{counterparty}}\nMemo: {{memo}}\nNew balance: {{balance}}",
+            )),
+            "weekly_digest" => Some((
+                "Your week in {{game_name}}",
+                "<p>Here's what happened to your faeries since {{since}}:</p><pre>{{summary}}</pre>",
+                "Here's what happened to your faeries since {{since}}:\n\n{{summary}}",
+            )),
+            "daily_digest" => Some((
+                "Your {{game_name}} notifications",
+                "<p>Here's what we held back for your daily digest:</p><pre>{{summary}}</pre>",
+                "Here's what we held back for your daily digest:\n\n{{summary}}",
+            )),
+            "broadcast" => Some((
+                "{{subject}}",
+                "<div style=\"white-space: pre-line\">{{message}}</div>",
+                "{{message}}",
+            )),
+            _ => None,
+        }
+    }
+
+    pub fn builtin_names() -> Vec<&'static str> {
+        vec!["login_token", "verification", "transfer_receipt", "weekly_digest", "daily_digest", "broadcast"]
+    }
+
+    pub fn builtin(name: &str) -> Option<EmailTemplate> {
+        EmailTemplate::builtin_parts(name).map(|(subject, html, text)| EmailTemplate {
+            id: None,
+            name: name.to_string(),
+            version: 0,
+            subject: subject.to_string(),
+            html: html.to_string(),
+            text: text.to_string(),
+            created_at: 0,
+        })
+    }
+
+    // render fills in the variables and the branding. Values are escaped in the HTML part;
+    // unknown variables render as nothing. An `unsubscribe_url` variable adds an unsubscribe
+    // link to both parts and the List-Unsubscribe header.
+    pub fn render(&self, to: &str, variables: &Variables, branding: &Branding) -> Email {
+        let mut variables = variables.clone();
+        variables.insert("game_name".to_string(), branding.game_name.clone());
+        variables.insert("logo_url".to_string(), branding.logo_url.clone().unwrap_or_default());
+        variables.insert("primary_color".to_string(), branding.primary_color.clone());
+        variables.insert("accent_color".to_string(), branding.accent_color.clone());
+        let unsubscribe_url = variables.get("unsubscribe_url").cloned();
+        let content = substitute(&self.html, &variables, true);
+        let mut text = substitute(&self.text, &variables, false);
+        if let Some(unsubscribe_url) = &unsubscribe_url {
+            text.push_str(&format!("\n\n--\nUnsubscribe: {}", unsubscribe_url));
+        }
+        Email {
+            html: Some(layout(&content, branding, unsubscribe_url.as_deref())),
+            from: branding.from_address.clone(),
+            unsubscribe_url,
+            ..Email::new(to, &substitute(&self.subject, &variables, false), &text)
+        }
+    }
+}
+
+impl RepositoryItem for EmailTemplate {
+    fn masked_columns(_: bool) -> Vec<String> {
+        vec![]
+    }
+
+    fn saved_columns() -> Vec<String> {
+        vec![
+            "name".to_string(),
+            "version".to_string(),
+            "subject".to_string(),
+            "html".to_string(),
+            "text".to_string(),
+            "created_at".to_string(),
+        ]
+    }
+
+    fn all_columns() -> Vec<String> {
+        vec![
+            "id".to_string(),
+            "name".to_string(),
+            "version".to_string(),
+            "subject".to_string(),
+            "html".to_string(),
+            "text".to_string(),
+            "created_at".to_string(),
+        ]
+    }
+
+    fn table_name() -> String where Self: Sized {
+        "email_templates".to_string()
+    }
+}
+
+// substitute replaces every {{name}} in the template with its value.
+fn substitute(template: &str, variables: &Variables, escape: bool) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        rendered.push_str(&rest[..start]);
+        let name = rest[start + 2..start + end].trim();
+        let value = variables.get(name).map(String::as_str).unwrap_or_default();
+        if escape {
+            rendered.push_str(&escape_html(value));
+        } else {
+            rendered.push_str(value);
+        }
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+pub fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// layout wraps rendered content in the game's branded page.
+fn layout(content: &str, branding: &Branding, unsubscribe_url: Option<&str>) -> String {
+    let name = escape_html(&branding.game_name);
+    let footer = match unsubscribe_url {
+        Some(unsubscribe_url) => format!("{} &middot; <a href=\"{}\">Unsubscribe</a>", name, escape_html(unsubscribe_url)),
+        None => name.clone(),
+    };
+    let header = match &branding.logo_url {
+        Some(logo_url) => format!("<img src=\"{}\" alt=\"{}\" height=\"48\">", escape_html(logo_url), name),
+        None => name.clone(),
+    };
+    format!(
+        "<!DOCTYPE html><html><body style=\"margin: 0; font-family: sans-serif; color: #222\">\
+        <div style=\"background: {primary}; color: #fff; padding: 16px; font-size: 20px\">{header}</div>\
+        <div style=\"padding: 16px\">{content}</div>\
+        <div style=\"border-top: 2px solid {accent}; padding: 16px; font-size: 12px; color: #666\">{footer}</div>\
+        </body></html>",
+        primary = escape_html(&branding.primary_color),
+        accent = escape_html(&branding.accent_color),
+    )
+}
+
+pub struct TemplateRepository {
+    db: Arc<Mutex<Connection>>,
+}
+
+impl TemplateRepository {
+    pub fn new(db: Arc<Mutex<Connection>>) -> TemplateRepository {
+        TemplateRepository {
+            db,
+        }
+    }
+
+    // latest returns the newest saved version of a template, or the built-in copy.
+    pub async fn latest(&self, name: &str) -> RepositoryResult<EmailTemplate> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            "SELECT * FROM email_templates WHERE name = ?1 ORDER BY version DESC LIMIT 1",
+            [name]).await?;
+        match res.next()? {
+            Some(row) => EmailTemplate::from_response(&row),
+            None => EmailTemplate::builtin(name).ok_or(RepositoryError::NotFound),
+        }
+    }
+
+    pub async fn versions(&self, name: &str) -> RepositoryResult<Vec<EmailTemplate>> {
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            "SELECT * FROM email_templates WHERE name = ?1 ORDER BY version DESC",
+            [name]).await?;
+        let mut versions = Vec::new();
+        while let Some(row) = res.next()? {
+            versions.push(EmailTemplate::from_response(&row)?);
+        }
+        versions.extend(EmailTemplate::builtin(name));
+        Ok(versions)
+    }
+
+    pub async fn version(&self, name: &str, version: i64) -> RepositoryResult<EmailTemplate> {
+        if version == 0 {
+            return EmailTemplate::builtin(name).ok_or(RepositoryError::NotFound);
+        }
+        let db = self.db.lock().await;
+        let mut res = db.query(
+            "SELECT * FROM email_templates WHERE name = ?1 AND version = ?2",
+            params![name, version]).await?;
+        match res.next()? {
+            Some(row) => EmailTemplate::from_response(&row),
+            None => Err(RepositoryError::NotFound),
+        }
+    }
+}
+
+#[shuttle_runtime::async_trait]
+impl Repository for TemplateRepository {
+    type Item = EmailTemplate;
+    type RowIdentifier = i64;
+
+    // save stores the template as the next version of its name.
+    async fn save(&self, template: EmailTemplate) -> RepositoryResult<i64> {
+        if EmailTemplate::builtin(&template.name).is_none() {
+            return Err(RepositoryError::NotFound);
+        }
+        let db = self.db.lock().await;
+        db.execute(
+            "INSERT INTO email_templates (name, version, subject, html, text, created_at)
+            VALUES (?1, (SELECT COALESCE(MAX(version), 0) + 1 FROM email_templates WHERE name = ?1), ?2, ?3, ?4, ?5)",
+            params![template.name, template.subject, template.html, template.text, Utc::now().timestamp_millis()]
+        ).await?;
+        Ok(db.last_insert_rowid())
+    }
+
+    async fn get(&self, id: i64) -> RepositoryResult<EmailTemplate> {
+        let db = self.db.lock().await;
+        let mut res = db.query("SELECT * FROM email_templates WHERE id = ?1", [id]).await?;
+        match res.next()? {
+            Some(row) => EmailTemplate::from_response(&row),
+            None => Err(RepositoryError::NotFound),
+        }
+    }
+
+    // get_all returns the version of every template that's currently in use.
+    async fn get_all(&self) -> RepositoryResult<Vec<EmailTemplate>> {
+        let mut templates = Vec::new();
+        for name in EmailTemplate::builtin_names() {
+            templates.push(self.latest(name).await?);
+        }
+        Ok(templates)
+    }
+
+    async fn delete(&self, id: i64) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        match db.execute("DELETE FROM email_templates WHERE id = ?1", [id]).await? {
+            0 => Err(RepositoryError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    async fn create_table(&self) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        let result = db.execute(
+            r#"CREATE TABLE IF NOT EXISTS email_templates (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    subject TEXT NOT NULL,
+    html TEXT NOT NULL,
+    text TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    UNIQUE (name, version)
+)"#, ()).await;
+        match result {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other),
+        }
+    }
+
+    async fn drop_table(&self) -> RepositoryResult<()> {
+        let db = self.db.lock().await;
+        match db.execute("DROP TABLE IF EXISTS email_templates", ()).await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RepositoryError::Other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repository::settings::Branding;
+    use super::{substitute, EmailTemplate, Variables};
+
+    #[test]
+    fn test_substitute() {
+        let variables = Variables::from([("name".to_string(), "<Tink & co>".to_string())]);
+        assert_eq!(substitute("Hi {{ name }}{{missing}}!", &variables, false), "Hi <Tink & co>!");
+        assert_eq!(substitute("Hi {{name}}", &variables, true), "Hi &lt;Tink &amp; co&gt;");
+        assert_eq!(substitute("Unclosed {{name", &variables, false), "Unclosed {{name");
+    }
+
+    #[test]
+    fn test_render_with_branding() {
+        let branding = Branding {
+            game_name: "Moonfall".to_string(),
+            logo_url: Some("https://example.com/logo.png".to_string()),
+            from_address: Some("Moonfall <game@example.com>".to_string()),
+            ..Branding::default()
+        };
+        let variables = Variables::from([("link".to_string(), "https://example.com/verify?token=a&b".to_string())]);
+        let email = EmailTemplate::builtin("verification").unwrap().render("someone@example.com", &variables, &branding);
+        assert_eq!(email.subject, "Confirm your Moonfall account");
+        assert!(email.body.contains("https://example.com/verify?token=a&b"));
+        let html = email.html.unwrap();
+        assert!(html.contains("href=\"https://example.com/verify?token=a&amp;b\""));
+        assert!(html.contains("<img src=\"https://example.com/logo.png\" alt=\"Moonfall\""));
+        assert!(html.contains(&branding.primary_color));
+        assert_eq!(email.from, branding.from_address);
+        assert_eq!(email.unsubscribe_url, None);
+    }
+
+    #[test]
+    fn test_render_unsubscribe_link() {
+        let variables = Variables::from([
+            ("token".to_string(), "123456".to_string()),
+            ("unsubscribe_url".to_string(), "https://example.com/api/unsubscribe?token=t".to_string()),
+        ]);
+        let email = EmailTemplate::builtin("login_token").unwrap().render("someone@example.com", &variables, &Branding::default());
+        assert_eq!(email.unsubscribe_url.as_deref(), Some("https://example.com/api/unsubscribe?token=t"));
+        assert!(email.body.ends_with("Unsubscribe: https://example.com/api/unsubscribe?token=t"));
+        assert!(email.html.unwrap().contains("<a href=\"https://example.com/api/unsubscribe?token=t\">Unsubscribe</a>"));
+    }
+}
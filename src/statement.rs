@@ -0,0 +1,179 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use crate::DrossManagerState;
+use crate::mailer::Email;
+use crate::messages::{notify, render};
+use crate::repository::{Repository, RepositoryResult};
+use crate::repository::ledger::Entry;
+use crate::repository::player::Model as Player;
+use crate::repository::preference::{Delivery, NotificationCategory};
+use crate::repository::settings::StatementSchedule;
+use crate::repository::template::Variables;
+
+// FaeryStatement is one faery's activity over a statement's period.
+#[derive(Debug, Clone, Serialize)]
+pub struct FaeryStatement {
+    pub faery_id: i64,
+    pub name: String,
+    pub opening_balance: i64,
+    pub closing_balance: i64,
+    pub entries: Vec<Entry>,
+}
+
+// Statement sums up a player's faeries between `since` and `until`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Statement {
+    pub player_id: i64,
+    pub since: i64,
+    pub until: i64,
+    pub faeries: Vec<FaeryStatement>,
+}
+
+// StatementPreview is the next statement along with the email it would go out as.
+#[derive(Debug, Clone, Serialize)]
+pub struct StatementPreview {
+    pub statement: Statement,
+    pub email: Email,
+}
+
+fn date(millis: i64) -> String {
+    DateTime::<Utc>::from_timestamp_millis(millis)
+        .map(|date| date.format("%Y-%m-%d").to_string())
+        .unwrap_or_default()
+}
+
+impl Statement {
+    pub fn has_activity(&self) -> bool {
+        self.faeries.iter().any(|faery| !faery.entries.is_empty())
+    }
+
+    // summary is the plain-text body of the statement.
+    pub fn summary(&self) -> String {
+        if self.faeries.is_empty() {
+            return "You don't have any faeries yet.".to_string();
+        }
+        let mut sections = Vec::new();
+        for faery in &self.faeries {
+            let mut lines = vec![
+                faery.name.clone(),
+                format!("  Opening balance: {}", faery.opening_balance),
+            ];
+            if faery.entries.is_empty() {
+                lines.push("  No transactions".to_string());
+            }
+            for entry in &faery.entries {
+                lines.push(format!("  {}  {:+}  {}  {}", date(entry.created_at), entry.amount, entry.kind.as_str(), entry.memo));
+            }
+            lines.push(format!("  Closing balance: {}", faery.closing_balance));
+            sections.push(lines.join("\n"));
+        }
+        sections.join("\n\n")
+    }
+
+    pub fn variables(&self) -> Variables {
+        Variables::from([
+            ("since".to_string(), date(self.since)),
+            ("until".to_string(), date(self.until)),
+            ("summary".to_string(), self.summary()),
+        ])
+    }
+}
+
+// build_statement collects the activity of a player's current faeries between `since` and `until`.
+pub async fn build_statement(state: &DrossManagerState, player: &Player, since: i64, until: i64) -> RepositoryResult<Statement> {
+    let mut faeries = Vec::new();
+    for faery in state.faery_repository.owned_by(&player.auth_email).await? {
+        let Some(faery_id) = faery.id else {
+            continue;
+        };
+        if faery.deleted_at.is_some() {
+            continue;
+        }
+        faeries.push(FaeryStatement {
+            faery_id,
+            name: faery.name().to_string(),
+            opening_balance: state.ledger_repository.balance_at(faery_id, since - 1).await?,
+            closing_balance: state.ledger_repository.balance_at(faery_id, until - 1).await?,
+            entries: state.ledger_repository.entries_between(faery_id, since, until).await?,
+        });
+    }
+    Ok(Statement {
+        player_id: player.id.unwrap_or_default(),
+        since,
+        until,
+        faeries,
+    })
+}
+
+// preview_statement renders the statement a player would get next, so far, without sending it.
+pub async fn preview_statement(state: &DrossManagerState, player: &Player) -> RepositoryResult<StatementPreview> {
+    let schedule: StatementSchedule = state.settings_repository.load(StatementSchedule::KEY).await?;
+    let until = Utc::now().timestamp_millis();
+    let statement = build_statement(state, player, schedule.current_since(until), until).await?;
+    let email = render(state, "weekly_digest", &player.auth_email, &statement.variables()).await?;
+    Ok(StatementPreview { statement, email })
+}
+
+// send_statement queues one player's statement, unless they don't want it. Returns whether it was sent.
+async fn send_statement(state: &DrossManagerState, player: &Player, player_id: i64, since: i64, until: i64) -> RepositoryResult<bool> {
+    let preferences = state.preference_repository.get(player_id).await?;
+    if preferences.digests == Delivery::Off {
+        return Ok(false);
+    }
+    let statement = build_statement(state, player, since, until).await?;
+    if preferences.skip_quiet_statements && !statement.has_activity() {
+        return Ok(false);
+    }
+    notify(state, player, NotificationCategory::Digests, "weekly_digest", statement.variables()).await?;
+    Ok(true)
+}
+
+// send_weekly_statements queues the statement for `since` to `until` to every active player who wants one.
+// A player whose statement fails is logged and skipped, so the period still counts as sent and the
+// next run doesn't send everyone else theirs a second time.
+pub async fn send_weekly_statements(state: &DrossManagerState, since: i64, until: i64) -> RepositoryResult<usize> {
+    let mut sent = 0;
+    for player in state.player_repository.get_all().await? {
+        let Some(player_id) = player.id else {
+            continue;
+        };
+        if !player.is_active() {
+            continue;
+        }
+        match send_statement(state, &player, player_id, since, until).await {
+            Ok(true) => sent += 1,
+            Ok(false) => {},
+            Err(err) => log::error!("Error sending a statement to player {}: {:?}", player_id, err),
+        }
+    }
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repository::ledger::{Entry, EntryKind};
+    use super::{FaeryStatement, Statement};
+
+    #[test]
+    fn test_statement_summary() {
+        let mut entry = Entry::new(1, -5, 15, EntryKind::Spend, "Moon cakes".to_string());
+        // 2024-03-04
+        entry.created_at = 1_709_553_600_000;
+        let statement = Statement {
+            player_id: 1,
+            since: 1_709_251_200_000,
+            until: 1_709_856_000_000,
+            faeries: vec![
+                FaeryStatement { faery_id: 1, name: "Tinkerbell".to_string(), opening_balance: 20, closing_balance: 15, entries: vec![entry] },
+                FaeryStatement { faery_id: 2, name: "Silvermist".to_string(), opening_balance: 3, closing_balance: 3, entries: vec![] },
+            ],
+        };
+        assert!(statement.has_activity());
+        assert_eq!(
+            statement.summary(),
+            "Tinkerbell\n  Opening balance: 20\n  2024-03-04  -5  spend  Moon cakes\n  Closing balance: 15\n\n\
+            Silvermist\n  Opening balance: 3\n  No transactions\n  Closing balance: 3"
+        );
+        assert_eq!(statement.variables()["since"], "2024-03-01");
+    }
+}
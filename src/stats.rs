@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use crate::DrossManagerState;
+use crate::repository::RepositoryResult;
+use crate::repository::ledger::LeaderboardEntry;
+
+// How long computed statistics are served before being recomputed
+const CACHE_TTL: Duration = Duration::from_secs(60);
+// How many distinct queries are cached at once
+const CACHE_CAPACITY: usize = 256;
+const DAY_MILLIS: i64 = 24 * 60 * 60 * 1000;
+const MAX_PERIOD_DAYS: i64 = 10 * 365;
+const MAX_LIMIT: i64 = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+pub struct StatsQuery {
+    #[serde(default = "default_period_days")]
+    pub period_days: i64,
+    #[serde(default = "default_limit")]
+    pub limit: i64,
+    // Only count members of this group
+    #[serde(default)]
+    pub group: Option<i64>,
+}
+
+impl StatsQuery {
+    // clamped returns the query as it's actually computed, so out-of-range values share a result.
+    pub fn clamped(&self) -> StatsQuery {
+        StatsQuery {
+            period_days: self.period_days.clamp(1, MAX_PERIOD_DAYS),
+            limit: self.limit.clamp(1, MAX_LIMIT),
+            group: self.group,
+        }
+    }
+}
+
+fn default_period_days() -> i64 {
+    30
+}
+
+fn default_limit() -> i64 {
+    10
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsResponse {
+    pub period_days: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<i64>,
+    // Total dross held by all faeries
+    pub circulation: i64,
+    // Average dross moved per week over the period
+    pub velocity: f64,
+    // Inequality of balances, from 0 (everyone holds the same) to 1 (one faery holds everything)
+    pub gini: f64,
+    pub top_holders: Vec<LeaderboardEntry>,
+    pub top_earners: Vec<LeaderboardEntry>,
+    pub top_spenders: Vec<LeaderboardEntry>,
+    pub generated_at: i64,
+}
+
+// gini calculates the Gini coefficient of a set of balances.
+pub fn gini(balances: &[i64]) -> f64 {
+    let mut sorted: Vec<i64> = balances.iter().map(|b| (*b).max(0)).collect();
+    sorted.sort_unstable();
+    let n = sorted.len() as f64;
+    let total: i64 = sorted.iter().sum();
+    if sorted.is_empty() || total == 0 {
+        return 0.0;
+    }
+    let weighted: f64 = sorted.iter()
+        .enumerate()
+        .map(|(i, balance)| (i as f64 + 1.0) * *balance as f64)
+        .sum();
+    (2.0 * weighted) / (n * total as f64) - (n + 1.0) / n
+}
+
+pub async fn compute(state: &DrossManagerState, query: &StatsQuery) -> RepositoryResult<StatsResponse> {
+    let StatsQuery { period_days, limit, .. } = query.clamped();
+    let since = Utc::now().timestamp_millis() - period_days * DAY_MILLIS;
+
+    let group = query.group;
+
+    let balances = state.faery_repository.balances(group).await?;
+    let volume = state.ledger_repository.volume_since(since, group).await?;
+    Ok(StatsResponse {
+        period_days,
+        group,
+        circulation: balances.iter().sum(),
+        velocity: volume as f64 / (period_days as f64 / 7.0),
+        gini: gini(&balances),
+        top_holders: state.faery_repository.top_holders(limit, group).await?,
+        top_earners: state.ledger_repository.top_earners(since, limit, group).await?,
+        top_spenders: state.ledger_repository.top_spenders(since, limit, group).await?,
+        generated_at: Utc::now().timestamp_millis(),
+    })
+}
+
+// StatsCache keeps recently computed statistics so busy pages don't rescan the ledger.
+#[derive(Default)]
+pub struct StatsCache {
+    entries: Mutex<HashMap<StatsQuery, (Instant, StatsResponse)>>,
+}
+
+impl StatsCache {
+    // get_or_compute serves a cached result if there's a fresh one. The lock isn't held while
+    // computing, so one slow query doesn't hold up the others.
+    pub async fn get_or_compute(&self, state: &DrossManagerState, query: StatsQuery) -> RepositoryResult<StatsResponse> {
+        let query = query.clamped();
+        if let Some((computed, stats)) = self.entries.lock().await.get(&query) {
+            if computed.elapsed() < CACHE_TTL {
+                return Ok(stats.clone());
+            }
+        }
+        let stats = compute(state, &query).await?;
+        self.insert(query, stats.clone()).await;
+        Ok(stats)
+    }
+
+    async fn insert(&self, query: StatsQuery, stats: StatsResponse) {
+        let mut entries = self.entries.lock().await;
+        if entries.len() >= CACHE_CAPACITY && !entries.contains_key(&query) {
+            entries.retain(|_, (computed, _)| computed.elapsed() < CACHE_TTL);
+            // Still full of fresh results: make room by dropping the oldest
+            if entries.len() >= CACHE_CAPACITY {
+                let oldest = entries.iter().min_by_key(|(_, (computed, _))| *computed).map(|(query, _)| query.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(query, (Instant::now(), stats));
+    }
+
+    #[cfg(test)]
+    async fn len(&self) -> usize {
+        self.entries.lock().await.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use super::{gini, StatsCache, StatsQuery, StatsResponse, CACHE_CAPACITY, DAY_MILLIS};
+
+    #[test]
+    fn test_gini_equal() {
+        assert_eq!(gini(&[10, 10, 10, 10]), 0.0);
+        assert_eq!(gini(&[]), 0.0);
+        assert_eq!(gini(&[0, 0]), 0.0);
+    }
+
+    #[test]
+    fn test_gini_unequal() {
+        // One of four faeries holds everything
+        assert!((gini(&[0, 0, 0, 100]) - 0.75).abs() < 1e-9);
+        assert!((gini(&[1, 2, 3, 4]) - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_query_is_clamped() {
+        let query = StatsQuery { period_days: i64::MAX, limit: -5, group: None }.clamped();
+        assert_eq!((query.period_days, query.limit), (3650, 1));
+        // The period can't overflow when turned into a time range
+        assert!(Utc::now().timestamp_millis().checked_sub(query.period_days * DAY_MILLIS).is_some());
+        assert_eq!(StatsQuery { period_days: 1_000_000, ..query.clone() }.clamped(), StatsQuery { period_days: 99_999, ..query }.clamped());
+    }
+
+    #[tokio::test]
+    async fn test_cache_is_bounded() {
+        let cache = StatsCache::default();
+        let stats = StatsResponse {
+            period_days: 1,
+            group: None,
+            circulation: 0,
+            velocity: 0.0,
+            gini: 0.0,
+            top_holders: vec![],
+            top_earners: vec![],
+            top_spenders: vec![],
+            generated_at: 0,
+        };
+        for group in 0..(CACHE_CAPACITY as i64 * 2) {
+            cache.insert(StatsQuery { period_days: 30, limit: 10, group: Some(group) }, stats.clone()).await;
+        }
+        assert_eq!(cache.len().await, CACHE_CAPACITY);
+    }
+}
@@ -0,0 +1,147 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::Utc;
+use crate::DrossManagerState;
+use crate::messages::{send_daily_digests, send_due_broadcasts};
+use crate::reconcile::reconcile;
+use crate::repository::settings::{DigestSchedule, StatementSchedule};
+use crate::statement::send_weekly_statements;
+
+// spawn_interval runs `task` every `every` for the life of the service. The first run is
+// straight away, or after a full period when `wait_first` is set.
+fn spawn_interval<F, Fut>(state: Arc<DrossManagerState>, every: Duration, wait_first: bool, task: F)
+where
+    F: Fn(Arc<DrossManagerState>) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        if wait_first {
+            // The first tick completes immediately
+            interval.tick().await;
+        }
+        loop {
+            interval.tick().await;
+            task(state.clone()).await;
+        }
+    });
+}
+
+// spawn_reconciliation runs the ledger reconciliation on a fixed interval for the life of the service.
+pub fn spawn_reconciliation(state: Arc<DrossManagerState>, every: Duration, correct: bool) {
+    spawn_interval(state, every, true, move |state| async move {
+        log::info!("Running scheduled ledger reconciliation");
+        if let Err(err) = reconcile(&state, correct).await {
+            log::error!("Scheduled reconciliation failed: {:?}", err);
+        }
+    });
+}
+
+// spawn_mail_worker delivers queued email. It wakes when something is queued, and at least
+// every `every` so that retries go out when they're due.
+pub fn spawn_mail_worker(state: Arc<DrossManagerState>, every: Duration) {
+    tokio::spawn(async move {
+        loop {
+            match state.email_repository.deliver_due(50).await {
+                Ok(0) => {},
+                Ok(sent) => log::info!("Delivered {} queued emails", sent),
+                Err(err) => log::error!("Error delivering queued email: {:?}", err),
+            }
+            state.email_repository.wait_for_mail(every).await;
+        }
+    });
+}
+
+// spawn_outbox_pruning deletes delivered outbox messages older than `retention_days`.
+pub fn spawn_outbox_pruning(state: Arc<DrossManagerState>, every: Duration, retention_days: i64) {
+    spawn_interval(state, every, false, move |state| async move {
+        let before = Utc::now().timestamp_millis() - chrono::Duration::days(retention_days).num_milliseconds();
+        match state.email_repository.prune_sent(before).await {
+            Ok(0) => {},
+            Ok(pruned) => log::info!("Pruned {} sent emails from the outbox", pruned),
+            Err(err) => log::error!("Pruning the outbox failed: {:?}", err),
+        }
+    });
+}
+
+// spawn_daily_digests checks every `every` whether a day has passed since the digests last went
+// out, and sends them if so. The last run is stored, so restarts don't reset the day.
+pub fn spawn_daily_digests(state: Arc<DrossManagerState>, every: Duration) {
+    spawn_interval(state, every, false, |state| async move {
+        let schedule: DigestSchedule = match state.settings_repository.load(DigestSchedule::KEY).await {
+            Ok(schedule) => schedule,
+            Err(err) => {
+                log::error!("Error loading the digest schedule: {:?}", err);
+                return;
+            }
+        };
+        let now = Utc::now().timestamp_millis();
+        if !schedule.is_due(now) {
+            return;
+        }
+        match send_daily_digests(&state).await {
+            Ok(sent) => log::info!("Sent {} daily digests", sent),
+            Err(err) => {
+                log::error!("Sending daily digests failed: {:?}", err);
+                return;
+            }
+        }
+        let schedule = DigestSchedule { last_digest_at: Some(now) };
+        if let Err(err) = state.settings_repository.store(DigestSchedule::KEY, &schedule).await {
+            log::error!("Error saving the digest schedule: {:?}", err);
+        }
+    });
+}
+
+// spawn_notification_pruning deletes in-app notifications older than `retention_days`.
+pub fn spawn_notification_pruning(state: Arc<DrossManagerState>, every: Duration, retention_days: i64) {
+    spawn_interval(state, every, false, move |state| async move {
+        let before = Utc::now().timestamp_millis() - chrono::Duration::days(retention_days).num_milliseconds();
+        match state.notification_repository.prune(before).await {
+            Ok(0) => {},
+            Ok(pruned) => log::info!("Pruned {} old notifications", pruned),
+            Err(err) => log::error!("Pruning notifications failed: {:?}", err),
+        }
+    });
+}
+
+// spawn_broadcasts sends scheduled broadcasts once they're due.
+pub fn spawn_broadcasts(state: Arc<DrossManagerState>, every: Duration) {
+    spawn_interval(state, every, false, |state| async move {
+        match send_due_broadcasts(&state).await {
+            Ok(0) => {},
+            Ok(sent) => log::info!("Sent {} scheduled broadcasts", sent),
+            Err(err) => log::error!("Sending scheduled broadcasts failed: {:?}", err),
+        }
+    });
+}
+
+// spawn_weekly_statements checks every `every` whether a week has passed since the last
+// statements' period ended, and sends the next week's if so. Each period starts where the
+// stored one ended, so restarts neither repeat nor skip any activity.
+pub fn spawn_weekly_statements(state: Arc<DrossManagerState>, every: Duration) {
+    spawn_interval(state, every, false, |state| async move {
+        let schedule: StatementSchedule = match state.settings_repository.load(StatementSchedule::KEY).await {
+            Ok(schedule) => schedule,
+            Err(err) => {
+                log::error!("Error loading the statement schedule: {:?}", err);
+                return;
+            }
+        };
+        let Some((since, until)) = schedule.next_period(Utc::now().timestamp_millis()) else {
+            return;
+        };
+        match send_weekly_statements(&state, since, until).await {
+            Ok(sent) => log::info!("Sent {} weekly statements", sent),
+            Err(err) => {
+                log::error!("Sending weekly statements failed: {:?}", err);
+                return;
+            }
+        }
+        let schedule = StatementSchedule { last_period_end: Some(until) };
+        if let Err(err) = state.settings_repository.store(StatementSchedule::KEY, &schedule).await {
+            log::error!("Error saving the statement schedule: {:?}", err);
+        }
+    });
+}
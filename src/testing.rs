@@ -0,0 +1,91 @@
+// Helpers for tests that drive the whole app: an in-memory database with every table, an
+// in-memory mailer, and tokens signed with a key pair that's only ever used here.
+use std::sync::Arc;
+use axum::body::Body;
+use axum::response::Response;
+use base64::{engine::general_purpose, Engine as _};
+use http::{header, Request};
+use tokio::sync::Mutex;
+use crate::{avatar, live, migrations, stats, DrossManagerState, JWTKeyPair};
+use crate::auth::jwt::generate_jwt_token;
+use crate::mailer::{MemoryMailer, WebhookReplayGuard};
+use crate::prelude::*;
+use crate::repository::player::Model as Player;
+
+pub const WEBHOOK_KEY: &str = "test-webhook-key";
+
+pub async fn state() -> Arc<DrossManagerState> {
+    let db = Arc::new(Mutex::new(libsql::Database::open_in_memory().unwrap().connect().unwrap()));
+    let state = Arc::new(DrossManagerState {
+        player_repository: Arc::new(PlayerRepository::new(db.clone())),
+        faery_repository: Arc::new(FaeryRepository::new(db.clone())),
+        email_repository: Arc::new(EmailRepository::new(db.clone(), Arc::new(MemoryMailer::default()))),
+        ledger_repository: Arc::new(LedgerRepository::new(db.clone())),
+        achievement_repository: Arc::new(AchievementRepository::new(db.clone())),
+        snapshot_repository: Arc::new(SnapshotRepository::new(db.clone())),
+        settings_repository: Arc::new(SettingsRepository::new(db.clone())),
+        group_repository: Arc::new(GroupRepository::new(db.clone())),
+        attribute_repository: Arc::new(AttributeRepository::new(db.clone())),
+        template_repository: Arc::new(TemplateRepository::new(db.clone())),
+        preference_repository: Arc::new(PreferenceRepository::new(db.clone())),
+        broadcast_repository: Arc::new(BroadcastRepository::new(db.clone())),
+        notification_repository: Arc::new(NotificationRepository::new(db.clone())),
+        avatar_store: avatar::AvatarStore::new(std::env::temp_dir().join("dross-manager-test-avatars"), 1024 * 1024),
+        stats_cache: stats::StatsCache::default(),
+        live_events: live::LiveEvents::default(),
+        jwt_key_pair: JWTKeyPair {
+            public_key: general_purpose::STANDARD.encode(include_str!("testing/public_key.pem")),
+            private_key: general_purpose::STANDARD.encode(include_str!("testing/private_key.pem")),
+        },
+        ledger_signing_key: Some(JWTKeyPair {
+            public_key: general_purpose::STANDARD.encode(include_str!("testing/ledger_signing_public_key.pem")),
+            private_key: general_purpose::STANDARD.encode(include_str!("testing/ledger_signing_private_key.pem")),
+        }),
+        app_url: "http://localhost:8000".to_string(),
+        mail_webhook_key: Some(WEBHOOK_KEY.to_string()),
+        webhook_replays: WebhookReplayGuard::default(),
+    });
+    migrations::Manager::new(db, state.clone()).create_tables().await.unwrap();
+    state
+}
+
+pub async fn create_player(state: &DrossManagerState, email: &str, is_admin: bool) -> i64 {
+    let player = Player::new(
+        None,
+        "Wendy".to_string(),
+        "Darling".to_string(),
+        email.to_string(),
+        None,
+        None,
+        "14 Kensington Gardens".to_string(),
+        is_admin
+    );
+    state.player_repository.create(Some(player)).await.unwrap()
+}
+
+// token signs an access token for a player, good for ttl_minutes.
+pub fn token(state: &DrossManagerState, player_id: i64, ttl_minutes: i64) -> String {
+    generate_jwt_token(player_id, ttl_minutes, state.jwt_key_pair.private_key.clone())
+        .unwrap()
+        .token
+        .unwrap()
+}
+
+pub fn request(method: &str, uri: &str, token: Option<&str>, body: Option<serde_json::Value>) -> Request<Body> {
+    let mut builder = Request::builder().method(method).uri(uri);
+    if let Some(token) = token {
+        builder = builder.header(header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+    match body {
+        Some(body) => builder
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap(),
+        None => builder.body(Body::empty()).unwrap(),
+    }
+}
+
+pub async fn json(response: Response) -> serde_json::Value {
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    serde_json::from_slice(&bytes).unwrap()
+}